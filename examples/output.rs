@@ -21,6 +21,7 @@ fn process(
     let EncodeResult {
         encoded_packets: packets,
         expected_return_packet_lengths: lengths,
+        ..
     } = rainbow.encode_write(
         data,
         is_client,
@@ -72,6 +73,7 @@ fn process(
             data: decoded,
             expected_return_length,
             is_read_end,
+            ..
         } = rainbow.decrypt_single_read(packet.clone(), i, is_client)?;
 
         info!(
@@ -0,0 +1,576 @@
+/*!
+ * Async stream module adapts a [`NetworkSteganographyProcessor`] to tokio's
+ * [`AsyncRead`] and [`AsyncWrite`]. Only available with the `tokio` feature.
+ *
+ * An [`AsyncStegoStream`] is the async counterpart of [`crate::stream::StegoStream`]: it wraps a
+ * transport such as a `TcpStream`, frames steganographic HTTP packets on it and handles the
+ * client/server request-response alternation.
+ *
+ * The protocol is driven by a task spawned on the current runtime, which encodes and decodes
+ * packets on the blocking thread pool. The user side talks to that task through an in-memory
+ * pipe of bounded size, so a slow reader or writer on either end applies backpressure to the
+ * other end instead of buffering without limit.
+ */
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf},
+    task::{self, JoinHandle},
+    time::{sleep, timeout},
+};
+use tracing::debug;
+
+use crate::{
+    session::DecodeSession, utils::http_message_len, EncodeOptions, EncodeResult,
+    NetworkSteganographyProcessor, ReplyResult,
+};
+
+/// Configuration of an [`AsyncStegoStream`]
+#[derive(Debug, Clone)]
+pub struct AsyncStegoConfig {
+    /// Options used to encode written data
+    pub options: EncodeOptions,
+    /// A client with nothing to send polls the server this often
+    pub poll_interval: Duration,
+    /// How long a server waits for the user to produce data before answering a request
+    pub response_delay: Duration,
+    /// Size of the buffer between the user and the protocol task, in each direction
+    pub buffer_size: usize,
+}
+
+impl Default for AsyncStegoConfig {
+    fn default() -> Self {
+        Self {
+            options: EncodeOptions::default(),
+            poll_interval: Duration::from_millis(50),
+            response_delay: Duration::from_millis(10),
+            buffer_size: 64 * 1024,
+        }
+    }
+}
+
+/// An [`AsyncRead`] + [`AsyncWrite`] adapter carrying data in steganographic HTTP packets.
+///
+/// The server can only speak when the client asks, so the server ends the conversation:
+/// shutting down its write side makes its protocol task send what is left and close the
+/// transport. Shutting down the write side of the client only stops it from sending data, it
+/// keeps polling for what the server has left to send until the server closes the transport.
+/// When the peer closes the transport, reads return end of stream.
+///
+/// Dropping the stream aborts its protocol task and closes the transport, whatever is left to
+/// send; [`join`](Self::join) it instead to let the conversation end.
+///
+/// Like [`crate::stream::StegoStream`], the stream encodes with
+/// [`NetworkSteganographyProcessor::for_connection`] of the processor it is given.
+pub struct AsyncStegoStream {
+    io: DuplexStream,
+    /// Taken by [`AsyncStegoStream::join`]
+    driver: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl AsyncStegoStream {
+    /// Wrap a transport connected to a Rainbow server, we send requests
+    pub fn client<T>(transport: T, processor: Box<dyn NetworkSteganographyProcessor>) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        Self::client_with_config(transport, processor, AsyncStegoConfig::default())
+    }
+
+    /// Wrap a transport connected to a Rainbow client, we send responses
+    pub fn server<T>(transport: T, processor: Box<dyn NetworkSteganographyProcessor>) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        Self::server_with_config(transport, processor, AsyncStegoConfig::default())
+    }
+
+    pub fn client_with_config<T>(
+        transport: T,
+        processor: Box<dyn NetworkSteganographyProcessor>,
+        config: AsyncStegoConfig,
+    ) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (io, user) = tokio::io::duplex(config.buffer_size);
+        let driver = tokio::spawn(Driver::new(transport, user, processor, config, true).run());
+        Self {
+            io,
+            driver: Some(driver),
+        }
+    }
+
+    pub fn server_with_config<T>(
+        transport: T,
+        processor: Box<dyn NetworkSteganographyProcessor>,
+        config: AsyncStegoConfig,
+    ) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (io, user) = tokio::io::duplex(config.buffer_size);
+        let driver = tokio::spawn(Driver::new(transport, user, processor, config, false).run());
+        Self {
+            io,
+            driver: Some(driver),
+        }
+    }
+
+    /// Wait for the protocol task to end, returning its error if it failed.
+    ///
+    /// Call this after shutting down the write side to make sure everything was sent. A client
+    /// waits for the server to close the transport.
+    pub async fn join(mut self) -> io::Result<()> {
+        let driver = self
+            .driver
+            .take()
+            .expect("the driver is only taken by join");
+        drop(self);
+        driver.await.map_err(io::Error::other)?
+    }
+}
+
+impl Drop for AsyncStegoStream {
+    fn drop(&mut self) {
+        // 没有人再读取，客户端的任务不能一直轮询服务器
+        if let Some(driver) = &self.driver {
+            driver.abort();
+        }
+    }
+}
+
+impl AsyncRead for AsyncStegoStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for AsyncStegoStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+/// The protocol task, between the transport and the user side of the pipe
+struct Driver<T> {
+    transport: T,
+    user: DuplexStream,
+    /// Shared with the blocking tasks encoding and decoding packets
+    processor: Arc<dyn NetworkSteganographyProcessor>,
+    config: AsyncStegoConfig,
+    is_client: bool,
+    session: DecodeSession,
+    recv_buf: Vec<u8>,
+    /// Encoded packets waiting to be sent, with the delay to wait before sending each one,
+    /// only used by the client
+    outgoing: VecDeque<(Vec<u8>, Duration)>,
+    /// Data read from the user not sent yet, only used by the server
+    pending: Vec<u8>,
+    user_open: bool,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Driver<T> {
+    fn new(
+        transport: T,
+        user: DuplexStream,
+        processor: Box<dyn NetworkSteganographyProcessor>,
+        config: AsyncStegoConfig,
+        is_client: bool,
+    ) -> Self {
+        // 每个连接有自己的序号和连接状态，客户端接收响应，服务器接收请求
+        let processor = processor.for_connection();
+        let session = DecodeSession::new(processor.clone(), !is_client);
+        Self {
+            transport,
+            user,
+            processor: Arc::from(processor),
+            config,
+            is_client,
+            session,
+            recv_buf: Vec::new(),
+            outgoing: VecDeque::new(),
+            pending: Vec::new(),
+            user_open: true,
+        }
+    }
+
+    async fn run(mut self) -> io::Result<()> {
+        let result = if self.is_client {
+            self.run_client().await
+        } else {
+            self.run_server().await
+        };
+        debug!("Stego stream task ended: {:?}", result);
+        result
+    }
+
+    async fn run_client(&mut self) -> io::Result<()> {
+        loop {
+            // 等待用户写入数据，超时则发送一个空的轮询请求
+            // 用户关闭写入后继续轮询，直到服务器关闭连接
+            let data = self.read_user(self.config.poll_interval).await?;
+            self.queue_message(data).await?;
+
+            while let Some((packet, delay)) = self.outgoing.pop_front() {
+                if !delay.is_zero() {
+                    sleep(delay).await;
+                }
+                self.transport.write_all(&packet).await?;
+                self.transport.flush().await?;
+
+                let Some(response) = self.read_packet().await? else {
+                    // 服务器已结束会话，未发出的数据无法送达
+                    if self.outgoing.is_empty() {
+                        return Ok(());
+                    }
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "server closed the transport before all data was sent",
+                    ));
+                };
+                self.receive(response).await?;
+            }
+        }
+    }
+
+    async fn run_server(&mut self) -> io::Result<()> {
+        while let Some(request) = self.read_packet().await? {
+            let (target_length, reply_mime_type) = self.receive(request).await?;
+
+            // 给用户一点时间，让回复数据能在这次响应中发出
+            if self.pending.is_empty() {
+                self.pending = self.read_user(self.config.response_delay).await?;
+            }
+
+            // 按请求中的预期长度回复，携带待发送的数据或掩护数据
+            let pending = self.pending.clone();
+            let is_client = self.is_client;
+            let options = EncodeOptions {
+                preferred_mime_type: reply_mime_type,
+                ..self.config.options.clone()
+            };
+            let ReplyResult {
+                packet, consumed, ..
+            } = self
+                .blocking(move |processor| {
+                    processor.encode_reply(&pending, is_client, target_length, options)
+                })
+                .await?;
+            self.pending.drain(..consumed);
+
+            self.transport.write_all(&packet).await?;
+            self.transport.flush().await?;
+
+            // 用户关闭写入且数据都已发出，关闭连接以结束会话
+            if !self.user_open && self.pending.is_empty() {
+                return self.transport.shutdown().await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Run CPU-heavy encoding or decoding on the blocking thread pool, so that it does not
+    /// stall the other tasks of the runtime
+    fn blocking<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&dyn NetworkSteganographyProcessor) -> crate::Result<R> + Send + 'static,
+    ) -> impl Future<Output = io::Result<R>> {
+        let processor = self.processor.clone();
+        async move {
+            task::spawn_blocking(move || f(processor.as_ref()))
+                .await
+                .map_err(io::Error::other)?
+                .map_err(io::Error::from)
+        }
+    }
+
+    /// Read what the user has written, waiting at most `wait` for the first byte, or `wait`
+    /// in full once the user has shut down writing
+    async fn read_user(&mut self, wait: Duration) -> io::Result<Vec<u8>> {
+        if !self.user_open {
+            sleep(wait).await;
+            return Ok(Vec::new());
+        }
+
+        let mut buf = vec![0u8; self.config.buffer_size];
+        match timeout(wait, self.user.read(&mut buf)).await {
+            Ok(Ok(0)) => {
+                self.user_open = false;
+                Ok(Vec::new())
+            }
+            Ok(Ok(n)) => {
+                buf.truncate(n);
+                Ok(buf)
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => Ok(Vec::new()),
+        }
+    }
+
+    async fn queue_message(&mut self, data: Vec<u8>) -> io::Result<()> {
+        let is_client = self.is_client;
+        let options = self.config.options.clone();
+        let EncodeResult {
+            encoded_packets,
+            send_delays,
+            ..
+        } = self
+            .blocking(move |processor| processor.encode_write(&data, is_client, options))
+            .await?;
+        self.outgoing
+            .extend(encoded_packets.into_iter().zip(send_delays));
+        Ok(())
+    }
+
+    /// Decode a packet and hand its data to the user, waiting while the user's buffer is full.
+    ///
+    /// Returns the length and the preferred MIME type of the packet the peer expects in return.
+    async fn receive(&mut self, packet: Vec<u8>) -> io::Result<(usize, Option<String>)> {
+        let (received, is_client) = (self.session.received(), !self.is_client);
+        let result = self
+            .blocking(move |processor| processor.decrypt_single_read(packet, received, is_client))
+            .await?;
+        let expected_return_length = result.expected_return_length;
+        let reply_mime_type = result.reply_mime_type.clone();
+        if let Some(message) = self.session.accept(result)? {
+            if !message.is_empty() {
+                self.user.write_all(&message).await?;
+            }
+        }
+        Ok((expected_return_length, reply_mime_type))
+    }
+
+    /// Read one whole HTTP packet from the transport, `None` on a clean end of stream
+    async fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(len) = http_message_len(&self.recv_buf)? {
+                return Ok(Some(self.recv_buf.drain(..len).collect()));
+            }
+
+            let mut buf = [0u8; 8192];
+            let n = self.transport.read(&mut buf).await?;
+            if n == 0 {
+                return if self.recv_buf.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "stream closed in the middle of a packet",
+                    ))
+                };
+            }
+            self.recv_buf.extend_from_slice(&buf[..n]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::rainbow::Rainbow;
+
+    fn init() {
+        let _ = tracing_subscriber::fmt()
+            .with_test_writer()
+            .with_max_level(tracing::Level::DEBUG)
+            .try_init();
+    }
+
+    fn config() -> AsyncStegoConfig {
+        AsyncStegoConfig {
+            options: EncodeOptions {
+                encoder: Some("octet".to_string()),
+                ..Default::default()
+            },
+            poll_interval: Duration::from_millis(5),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_echo_over_tcp() {
+        init();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut stream = AsyncStegoStream::server_with_config(
+                socket,
+                Box::new(Rainbow::from_secret(b"async")),
+                config(),
+            );
+
+            let mut received = vec![0u8; 3000];
+            stream.read_exact(&mut received).await.unwrap();
+            stream.write_all(&received).await.unwrap();
+            stream.shutdown().await.unwrap();
+
+            // 客户端关闭后读到流结束
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+            stream.join().await.unwrap();
+        });
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut stream = AsyncStegoStream::client_with_config(
+            socket,
+            Box::new(Rainbow::from_secret(b"async")),
+            config(),
+        );
+
+        let message: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        stream.write_all(&message).await.unwrap();
+
+        let mut echoed = vec![0u8; 3000];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, message);
+
+        stream.shutdown().await.unwrap();
+        stream.join().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_half_close() {
+        init();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut stream = AsyncStegoStream::server_with_config(
+                socket,
+                Box::new(Rainbow::from_secret(b"async")),
+                config(),
+            );
+
+            let mut request = vec![0u8; 500];
+            stream.read_exact(&mut request).await.unwrap();
+            // 客户端已关闭写入，服务器稍后才回复
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            stream.write_all(&request.repeat(4)).await.unwrap();
+            stream.shutdown().await.unwrap();
+            stream.join().await.unwrap();
+        });
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut stream = AsyncStegoStream::client_with_config(
+            socket,
+            Box::new(Rainbow::from_secret(b"async")),
+            config(),
+        );
+
+        let message: Vec<u8> = (0..500).map(|i| (i % 241) as u8).collect();
+        stream.write_all(&message).await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        // 关闭写入后仍能收到服务器的全部回复，直到服务器关闭连接
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, message.repeat(4));
+        stream.join().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drop_closes_transport() {
+        init();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+        let stream = AsyncStegoStream::client_with_config(
+            socket,
+            Box::new(Rainbow::from_secret(b"async")),
+            config(),
+        );
+
+        // 丢弃后不再轮询，对方读到连接关闭
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(stream);
+        let mut polls = Vec::new();
+        timeout(Duration::from_secs(5), peer.read_to_end(&mut polls))
+            .await
+            .expect("the transport is closed")
+            .unwrap();
+        assert!(!polls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_backpressure() {
+        init();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let small = AsyncStegoConfig {
+            buffer_size: 1024,
+            ..config()
+        };
+
+        let server_config = small.clone();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut stream = AsyncStegoStream::server_with_config(
+                socket,
+                Box::new(Rainbow::from_secret(b"async")),
+                server_config,
+            );
+
+            // 读取缓慢，但数据完整到达
+            let mut received = Vec::new();
+            let mut buf = [0u8; 100];
+            while received.len() < 20_000 {
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0);
+                received.extend_from_slice(&buf[..n]);
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            received
+        });
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut stream = AsyncStegoStream::client_with_config(
+            socket,
+            Box::new(Rainbow::from_secret(b"async")),
+            small,
+        );
+
+        let message: Vec<u8> = (0..20_000).map(|i| (i % 253) as u8).collect();
+        stream.write_all(&message).await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        assert_eq!(server.await.unwrap(), message);
+        stream.join().await.unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+/*!
+ * Chunking module decides how much data each packet of a message carries.
+ *
+ * With [`ChunkingStrategy::Adaptive`], every packet draws a target size from a
+ * [`PacketSizeDistribution`], and carries as much data as its encoder fits in a body of that
+ * size according to [`Encoder::capacity`]. Dense encoders then carry large chunks in few
+ * packets, and encoders expanding every byte into long content carry small chunks, so that
+ * packet sizes look like ordinary web traffic either way.
+ */
+
+use std::ops::Range;
+
+use rand::Rng;
+
+use crate::stego::Encoder;
+
+/// Chunk size of [`ChunkingStrategy::Fixed`] by default, and of encoders that do not describe
+/// their capacity
+pub const DEFAULT_CHUNK_SIZE: usize = 256;
+
+/// Adaptive chunks never carry less than this, so that encoders with a large overhead do not
+/// split data into a flood of packets
+const MIN_ADAPTIVE_CHUNK_SIZE: usize = 64;
+
+/// Rough size of the HTTP headers of a packet, subtracted from the target packet size
+const ESTIMATED_HEADER_LEN: usize = 600;
+
+/// How data is split into packets
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChunkingStrategy {
+    /// Every packet carries this many bytes, except the last one
+    Fixed(usize),
+    /// Every packet is sized from the distribution and the capacity of its encoder
+    Adaptive(PacketSizeDistribution),
+}
+
+impl Default for ChunkingStrategy {
+    fn default() -> Self {
+        Self::Adaptive(PacketSizeDistribution::default())
+    }
+}
+
+impl ChunkingStrategy {
+    /// Number of bytes of data the next packet, encoded by `encoder`, should carry
+    pub fn chunk_len<R: Rng + ?Sized>(&self, encoder: &dyn Encoder, rng: &mut R) -> usize {
+        let capacity = encoder.capacity();
+        let len = match self {
+            Self::Fixed(size) => *size,
+            Self::Adaptive(distribution) => {
+                let packet_len = distribution.sample(rng);
+                capacity
+                    .data_len_for(packet_len.saturating_sub(ESTIMATED_HEADER_LEN))
+                    .max(MIN_ADAPTIVE_CHUNK_SIZE)
+            }
+        };
+        capacity.max_data_len.map_or(len, |max| len.min(max)).max(1)
+    }
+}
+
+/// A weighted set of ranges of packet sizes, in bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct PacketSizeDistribution {
+    buckets: Vec<(Range<usize>, u32)>,
+}
+
+impl Default for PacketSizeDistribution {
+    /// Mostly small and medium responses, with an occasional larger one
+    fn default() -> Self {
+        Self::new()
+            .with_bucket(1_000..4_000, 4)
+            .with_bucket(4_000..16_000, 4)
+            .with_bucket(16_000..32_000, 2)
+    }
+}
+
+impl PacketSizeDistribution {
+    /// An empty distribution, add buckets with [`with_bucket`](Self::with_bucket)
+    pub fn new() -> Self {
+        Self {
+            buckets: Vec::new(),
+        }
+    }
+
+    /// Sizes drawn uniformly from `range`
+    pub fn uniform(range: Range<usize>) -> Self {
+        Self::new().with_bucket(range, 1)
+    }
+
+    /// Draw sizes from `range` with the given relative weight
+    pub fn with_bucket(mut self, range: Range<usize>, weight: u32) -> Self {
+        if !range.is_empty() && weight > 0 {
+            self.buckets.push((range, weight));
+        }
+        self
+    }
+
+    /// Draw a packet size, [`DEFAULT_CHUNK_SIZE`] plus the headers if the distribution is empty
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> usize {
+        let total: u32 = self.buckets.iter().map(|(_, weight)| weight).sum();
+        if total == 0 {
+            return DEFAULT_CHUNK_SIZE + ESTIMATED_HEADER_LEN;
+        }
+
+        let mut pick = rng.gen_range(0..total);
+        for (range, weight) in &self.buckets {
+            if pick < *weight {
+                return rng.gen_range(range.clone());
+            }
+            pick -= weight;
+        }
+        unreachable!("pick is below the total weight")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stego::{cfg::CFGEncoder, octet::OctetEncoder};
+
+    #[test]
+    fn test_distribution_sample() {
+        let mut rng = rand::thread_rng();
+        let distribution = PacketSizeDistribution::new()
+            .with_bucket(100..200, 1)
+            .with_bucket(1000..1001, 1)
+            .with_bucket(5..5, 100);
+
+        let mut seen_large = false;
+        for _ in 0..200 {
+            let size = distribution.sample(&mut rng);
+            assert!((100..200).contains(&size) || size == 1000);
+            seen_large |= size == 1000;
+        }
+        assert!(seen_large);
+
+        assert_eq!(
+            PacketSizeDistribution::new().sample(&mut rng),
+            DEFAULT_CHUNK_SIZE + ESTIMATED_HEADER_LEN
+        );
+    }
+
+    #[test]
+    fn test_chunk_len_follows_capacity() {
+        let mut rng = rand::thread_rng();
+        let strategy = ChunkingStrategy::Adaptive(PacketSizeDistribution::uniform(10_000..10_001));
+
+        // 紧凑的编码器携带更多数据
+        let octet = strategy.chunk_len(&OctetEncoder::default(), &mut rng);
+        let cfg = strategy.chunk_len(&CFGEncoder::default(), &mut rng);
+        assert!(octet > 5_000, "octet chunk {}", octet);
+        assert!(cfg < 500, "cfg chunk {}", cfg);
+
+        assert_eq!(
+            ChunkingStrategy::Fixed(100).chunk_len(&OctetEncoder::default(), &mut rng),
+            100
+        );
+        assert_eq!(
+            ChunkingStrategy::Fixed(0).chunk_len(&OctetEncoder::default(), &mut rng),
+            1
+        );
+    }
+}
@@ -0,0 +1,282 @@
+/*!
+ * Compression module shrinks the data of a message before it is steganographically encoded.
+ *
+ * Every encoder expands what it carries, so each byte saved before encoding saves several
+ * bytes on the wire. When enabled, a message is compressed as a whole, with deflate or zstd, before it is
+ * split into packets, and only kept compressed when that makes it smaller. Compressed data
+ * starts with the algorithm and the length of the data once decompressed, so that the
+ * receiver can inflate the reassembled message without knowing how it was compressed.
+ *
+ * Independently, [`ContentEncoding`] compresses the encoded HTTP body itself, the way a web
+ * server answers a client that sends `Accept-Encoding`.
+ */
+
+use std::io::{Read, Write};
+
+use flate2::{
+    read::{DeflateDecoder, GzDecoder, ZlibDecoder},
+    write::{DeflateEncoder, GzEncoder, ZlibEncoder},
+};
+use rand::Rng;
+
+use crate::{RainbowError, Result};
+
+/// Bodies and messages never inflate beyond this, whatever their header claims
+const MAX_DECODED_BODY_LEN: usize = 64 * 1024 * 1024;
+
+/// Algorithm byte and decompressed length before the compressed stream
+const HEADER_LEN: usize = 5;
+
+/// Algorithm bytes of compressed data
+const DEFLATE: u8 = 1;
+const ZSTD: u8 = 2;
+
+/// zstd's own default level
+const ZSTD_LEVEL: i32 = 3;
+
+/// How the data of each message is compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Data is carried as is
+    #[default]
+    None,
+    /// Raw deflate, kept only when it makes the data smaller
+    Deflate,
+    /// zstd, kept only when it makes the data smaller
+    Zstd,
+}
+
+impl Compression {
+    /// The compressed data, or `None` if compression is disabled or does not help
+    pub fn compress(&self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (algorithm, stream) = match self {
+            Self::None => return Ok(None),
+            Self::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                (DEFLATE, encoder.finish()?)
+            }
+            Self::Zstd => (ZSTD, zstd::bulk::compress(data, ZSTD_LEVEL)?),
+        };
+        if HEADER_LEN + stream.len() >= data.len() {
+            return Ok(None);
+        }
+
+        let mut compressed = Vec::with_capacity(HEADER_LEN + stream.len());
+        compressed.push(algorithm);
+        compressed.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        compressed.extend_from_slice(&stream);
+        Ok(Some(compressed))
+    }
+}
+
+/// Inflate data compressed by [`Compression::compress`], checking that it has the length
+/// announced in its header
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < HEADER_LEN {
+        return Err(RainbowError::DecodeFailed(
+            "Compressed data too short".to_string(),
+        ));
+    }
+    let (header, stream) = data.split_at(HEADER_LEN);
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if len > MAX_DECODED_BODY_LEN {
+        return Err(RainbowError::DecodeFailed(format!(
+            "Compressed data inflates to {} bytes, beyond {} bytes",
+            len, MAX_DECODED_BODY_LEN
+        )));
+    }
+    let reader: Box<dyn Read + '_> = match header[0] {
+        DEFLATE => Box::new(DeflateDecoder::new(stream)),
+        ZSTD => Box::new(zstd::stream::read::Decoder::with_buffer(stream)?),
+        other => {
+            return Err(RainbowError::DecodeFailed(format!(
+                "Unknown compression algorithm: {}",
+                other
+            )))
+        }
+    };
+
+    // 限制解压后的大小，防止压缩炸弹。不按头部声明的长度预先分配内存
+    let mut decompressed = Vec::new();
+    reader
+        .take(len as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| RainbowError::DecodeFailed(format!("Decompress: {}", e)))?;
+
+    if decompressed.len() != len {
+        return Err(RainbowError::LengthMismatch(
+            decompressed.len(),
+            len,
+            "decompressed data".to_string(),
+        ));
+    }
+    Ok(decompressed)
+}
+
+/// Which packets are sent with a [`ContentEncoding`], when their MIME type is compressible
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContentEncodingPolicy {
+    Never,
+    /// Only responses, like a web server answering `Accept-Encoding`
+    #[default]
+    Responses,
+    /// Request bodies too
+    Always,
+}
+
+/// Whether web servers commonly compress bodies of this MIME type
+pub fn is_compressible(mime_type: &str) -> bool {
+    mime_type.starts_with("text/")
+        || mime_type.ends_with("json")
+        || mime_type.ends_with("xml")
+        || mime_type.contains("javascript")
+}
+
+/// An HTTP `Content-Encoding` of a body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Gzip,
+    /// zlib-wrapped deflate, as HTTP defines `deflate`
+    Deflate,
+    Brotli,
+}
+
+impl ContentEncoding {
+    /// One of the encodings advertised by our requests, as often as servers pick them
+    pub fn random() -> Self {
+        match rand::thread_rng().gen_range(0..10) {
+            0..=4 => Self::Gzip,
+            5..=8 => Self::Brotli,
+            _ => Self::Deflate,
+        }
+    }
+
+    /// The encoding named by a `Content-Encoding` header value, `None` for `identity`
+    pub fn from_name(name: &str) -> Result<Option<Self>> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Ok(Some(Self::Gzip)),
+            "deflate" => Ok(Some(Self::Deflate)),
+            "br" => Ok(Some(Self::Brotli)),
+            "identity" | "" => Ok(None),
+            other => Err(RainbowError::InvalidData(format!(
+                "Unsupported Content-Encoding: {}",
+                other
+            ))),
+        }
+    }
+
+    /// The `Content-Encoding` header value
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+            Self::Brotli => "br",
+        }
+    }
+
+    pub fn encode(&self, body: &[u8]) -> Result<Vec<u8>> {
+        let level = flate2::Compression::default();
+        Ok(match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), level);
+                encoder.write_all(body)?;
+                encoder.finish()?
+            }
+            Self::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), level);
+                encoder.write_all(body)?;
+                encoder.finish()?
+            }
+            Self::Brotli => {
+                let mut encoded = Vec::new();
+                {
+                    let mut encoder = brotli::CompressorWriter::new(&mut encoded, 4096, 5, 22);
+                    encoder.write_all(body)?;
+                }
+                encoded
+            }
+        })
+    }
+
+    pub fn decode(&self, body: &[u8]) -> Result<Vec<u8>> {
+        let reader: Box<dyn Read + '_> = match self {
+            Self::Gzip => Box::new(GzDecoder::new(body)),
+            Self::Deflate => Box::new(ZlibDecoder::new(body)),
+            Self::Brotli => Box::new(brotli::Decompressor::new(body, 4096)),
+        };
+
+        let mut decoded = Vec::new();
+        reader
+            .take(MAX_DECODED_BODY_LEN as u64 + 1)
+            .read_to_end(&mut decoded)
+            .map_err(|e| RainbowError::DecodeFailed(format!("{}: {}", self.name(), e)))?;
+        if decoded.len() > MAX_DECODED_BODY_LEN {
+            return Err(RainbowError::DecodeFailed(format!(
+                "{} body inflates beyond {} bytes",
+                self.name(),
+                MAX_DECODED_BODY_LEN
+            )));
+        }
+        Ok(decoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_when_it_helps() {
+        let text = b"GET /index.html HTTP/1.1 GET /index.html HTTP/1.1 GET /index.html".repeat(20);
+        for compression in [Compression::Deflate, Compression::Zstd] {
+            let compressed = compression.compress(&text).unwrap().unwrap();
+            assert!(compressed.len() < text.len() / 4);
+            assert_eq!(decompress(&compressed).unwrap(), text);
+        }
+
+        // 随机数据压缩后不会变小
+        let random: Vec<u8> = (0..1000).map(|_| rand::random()).collect();
+        assert!(Compression::Deflate.compress(&random).unwrap().is_none());
+        assert!(Compression::Zstd.compress(&random).unwrap().is_none());
+        assert!(Compression::None.compress(&text).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_decompress_length_checked() {
+        let data = vec![0u8; 100_000];
+        for compression in [Compression::Deflate, Compression::Zstd] {
+            let mut compressed = compression.compress(&data).unwrap().unwrap();
+            compressed[1..HEADER_LEN].copy_from_slice(&1000u32.to_be_bytes());
+            assert!(matches!(
+                decompress(&compressed),
+                Err(RainbowError::LengthMismatch(1001, 1000, _))
+            ));
+        }
+        assert!(decompress(b"\x01\x00\x00\x00\x0anot deflate").is_err());
+        assert!(decompress(b"\x03\x00\x00\x00\x0anot known").is_err());
+        assert!(decompress(b"\x01\x00").is_err());
+    }
+
+    #[test]
+    fn test_content_encodings() {
+        let body = b"<div class=\"post\"><p>Lorem ipsum dolor sit amet</p></div>\n".repeat(30);
+        for encoding in [
+            ContentEncoding::Gzip,
+            ContentEncoding::Deflate,
+            ContentEncoding::Brotli,
+        ] {
+            let encoded = encoding.encode(&body).unwrap();
+            assert!(encoded.len() < body.len() / 4, "{}", encoding.name());
+            assert_eq!(encoding.decode(&encoded).unwrap(), body);
+            assert_eq!(
+                ContentEncoding::from_name(encoding.name()).unwrap(),
+                Some(encoding)
+            );
+        }
+
+        assert_eq!(ContentEncoding::from_name("identity").unwrap(), None);
+        assert!(ContentEncoding::from_name("compress").is_err());
+        assert!(ContentEncoding::Gzip.decode(b"not gzip").is_err());
+    }
+}
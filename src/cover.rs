@@ -0,0 +1,168 @@
+/*!
+ * Cover module keeps an idle channel from going silent with decoy packets.
+ *
+ * A [`CoverScheduler`] emits cover packets, see
+ * [`NetworkSteganographyProcessor::encode_cover`], at exponentially distributed intervals
+ * whose mean is set by the rate, like the arrivals of background traffic. Packets of data sent
+ * meanwhile count as traffic, and postpone the next cover packet.
+ *
+ * Like [`ReliableSession`](crate::reliable::ReliableSession), the scheduler does no I/O: it is
+ * polled for the packets to send, or runs on a thread of its own with [`CoverScheduler::spawn`]
+ * and hands the packets over a channel.
+ */
+
+use std::{
+    sync::mpsc::{self, Receiver},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use tracing::debug;
+
+use crate::{EncodeOptions, NetworkSteganographyProcessor, Result};
+
+/// Intervals are drawn at most this many times their mean, so that a rate is never silent for
+/// long
+const MAX_INTERVAL_FACTOR: f64 = 8.0;
+
+/// Emits cover packets at random intervals, at a given mean rate
+#[derive(Clone)]
+pub struct CoverScheduler {
+    processor: Box<dyn NetworkSteganographyProcessor>,
+    is_client: bool,
+    options: EncodeOptions,
+    /// Mean delay between two packets
+    mean_interval: Duration,
+    /// When the next cover packet is due
+    next_at: Instant,
+}
+
+impl CoverScheduler {
+    /// Emit `rate` cover packets per second on average. `is_client` is true if this side sends
+    /// requests, as in [`NetworkSteganographyProcessor::encode_write`].
+    ///
+    /// Panics if `rate` is not a positive number
+    pub fn new(
+        processor: Box<dyn NetworkSteganographyProcessor>,
+        is_client: bool,
+        rate: f64,
+    ) -> Self {
+        assert!(
+            rate > 0.0 && rate.is_finite(),
+            "Invalid cover rate: {}",
+            rate
+        );
+        let mean_interval = Duration::from_secs_f64(1.0 / rate);
+        Self {
+            processor,
+            is_client,
+            options: EncodeOptions::default(),
+            mean_interval,
+            next_at: Instant::now() + random_interval(mean_interval),
+        }
+    }
+
+    /// Options used to encode the cover packets, e.g. to pick the MIME types of the data
+    /// packets they hide among
+    pub fn with_options(mut self, options: EncodeOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// When the next cover packet is due
+    pub fn next_at(&self) -> Instant {
+        self.next_at
+    }
+
+    /// Take note of a packet of data sent at `now`, which postpones the next cover packet
+    pub fn on_traffic(&mut self, now: Instant) {
+        self.next_at = now + random_interval(self.mean_interval);
+    }
+
+    /// The cover packet due at `now`, if any
+    pub fn poll(&mut self, now: Instant) -> Result<Option<Vec<u8>>> {
+        if now < self.next_at {
+            return Ok(None);
+        }
+        let packet = self
+            .processor
+            .encode_cover(self.is_client, self.options.clone())?;
+        self.on_traffic(now);
+        debug!("Emitted cover packet of {} bytes", packet.len());
+        Ok(Some(packet))
+    }
+
+    /// Emit the cover packets on a thread of their own, until the receiver is dropped or
+    /// encoding fails
+    pub fn spawn(mut self) -> (Receiver<Vec<u8>>, JoinHandle<Result<()>>) {
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || loop {
+            thread::sleep(self.next_at.saturating_duration_since(Instant::now()));
+            if let Some(packet) = self.poll(Instant::now())? {
+                if sender.send(packet).is_err() {
+                    return Ok(());
+                }
+            }
+        });
+        (receiver, handle)
+    }
+}
+
+/// An exponentially distributed interval of mean `mean`, capped at [`MAX_INTERVAL_FACTOR`]
+/// times it
+fn random_interval(mean: Duration) -> Duration {
+    let u: f64 = rand::thread_rng().gen();
+    mean.mul_f64((-(1.0 - u).ln()).min(MAX_INTERVAL_FACTOR))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rainbow::Rainbow, session::DecodeSession};
+
+    fn init() {
+        let _ = tracing_subscriber::fmt()
+            .with_test_writer()
+            .with_max_level(tracing::Level::DEBUG)
+            .try_init();
+    }
+
+    #[test]
+    fn test_random_interval() {
+        let mean = Duration::from_millis(100);
+        let intervals: Vec<Duration> = (0..10_000).map(|_| random_interval(mean)).collect();
+        let average = intervals.iter().sum::<Duration>() / intervals.len() as u32;
+        assert!(average > mean.mul_f64(0.9) && average < mean.mul_f64(1.1));
+        assert!(intervals
+            .iter()
+            .all(|i| *i <= mean.mul_f64(MAX_INTERVAL_FACTOR)));
+    }
+
+    #[test]
+    fn test_poll_and_spawn() {
+        init();
+        let client = Rainbow::from_secret(b"cover");
+        let mut session = DecodeSession::new(Box::new(Rainbow::from_secret(b"cover")), true);
+        let mut scheduler = CoverScheduler::new(Box::new(client.clone()), true, 10.0);
+
+        // 到期前不发送，到期后发送一个并重新计时
+        let start = Instant::now();
+        scheduler.on_traffic(start);
+        assert!(scheduler.poll(start).unwrap().is_none());
+        let due = scheduler.next_at();
+        let packet = scheduler.poll(due).unwrap().unwrap();
+        assert!(scheduler.next_at() >= due);
+
+        // 接收方静默丢弃掩护数据包
+        assert!(session.feed(packet).unwrap().is_none());
+        assert_eq!(session.received(), 0);
+
+        let (packets, handle) = CoverScheduler::new(Box::new(client), true, 200.0).spawn();
+        for packet in packets.iter().take(5) {
+            assert!(session.feed(packet).unwrap().is_none());
+        }
+        drop(packets);
+        handle.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+/*!
+ * FEC module adds Reed-Solomon parity to the chunks of a message.
+ *
+ * The chunks of a message have different lengths, so each chunk is framed as a shard of the
+ * same length: its length as a big-endian u32, the chunk, then zeros. Parity shards are computed
+ * over the framed data shards, and carried by packets of their own. Data packets still carry
+ * the bare chunk, so a message whose packets all arrive decodes without this module.
+ *
+ * With `m` parity shards, any `k` of the `k + m` packets of a message rebuild every chunk, the
+ * length prefix of a rebuilt shard telling how much of it is data.
+ */
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use crate::{RainbowError, Result};
+
+/// Bytes added to a chunk when it is framed as a shard
+pub const SHARD_OVERHEAD: usize = 4;
+
+/// Maximum number of shards of a message, data and parity together
+pub const MAX_SHARDS: usize = 256;
+
+/// Frame `chunk` as a shard of `shard_len` bytes
+fn to_shard(chunk: &[u8], shard_len: usize) -> Vec<u8> {
+    let mut shard = Vec::with_capacity(shard_len);
+    shard.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+    shard.extend_from_slice(chunk);
+    shard.resize(shard_len, 0);
+    shard
+}
+
+/// The chunk framed in `shard`
+fn from_shard(mut shard: Vec<u8>) -> Result<Vec<u8>> {
+    if shard.len() < SHARD_OVERHEAD {
+        return Err(RainbowError::DecodeFailed(format!(
+            "Shard too short: {}",
+            shard.len()
+        )));
+    }
+    let len = u32::from_be_bytes([shard[0], shard[1], shard[2], shard[3]]) as usize;
+    if len > shard.len() - SHARD_OVERHEAD {
+        return Err(RainbowError::DecodeFailed(format!(
+            "Shard of {} bytes claims a chunk of {} bytes",
+            shard.len(),
+            len
+        )));
+    }
+    shard.truncate(SHARD_OVERHEAD + len);
+    shard.drain(..SHARD_OVERHEAD);
+    Ok(shard)
+}
+
+fn codec(data_shards: usize, parity_shards: usize) -> Result<ReedSolomon> {
+    if data_shards + parity_shards > MAX_SHARDS {
+        return Err(RainbowError::InvalidData(format!(
+            "{} data and {} parity packets exceed {} packets",
+            data_shards, parity_shards, MAX_SHARDS
+        )));
+    }
+    ReedSolomon::new(data_shards, parity_shards)
+        .map_err(|e| RainbowError::InvalidData(format!("Reed-Solomon: {}", e)))
+}
+
+/// Compute `parity` parity shards of `chunks`
+pub fn parity_shards(chunks: &[&[u8]], parity: usize) -> Result<Vec<Vec<u8>>> {
+    let codec = codec(chunks.len(), parity)?;
+    let shard_len = chunks.iter().map(|c| c.len()).max().unwrap_or(0) + SHARD_OVERHEAD;
+
+    let mut shards: Vec<Vec<u8>> = chunks
+        .iter()
+        .map(|chunk| to_shard(chunk, shard_len))
+        .chain((0..parity).map(|_| vec![0u8; shard_len]))
+        .collect();
+    codec
+        .encode(&mut shards)
+        .map_err(|e| RainbowError::EncodeFailed(format!("Reed-Solomon: {}", e)))?;
+
+    Ok(shards.split_off(chunks.len()))
+}
+
+/// Rebuild every data chunk from the chunks and parity shards received, `None` for the lost
+/// ones. At least as many must be present as there are data chunks
+pub fn reconstruct(
+    chunks: Vec<Option<Vec<u8>>>,
+    parity: Vec<Option<Vec<u8>>>,
+) -> Result<Vec<Vec<u8>>> {
+    if chunks.iter().all(Option::is_some) {
+        return Ok(chunks.into_iter().flatten().collect());
+    }
+
+    let shard_len = parity
+        .iter()
+        .flatten()
+        .map(Vec::len)
+        .next()
+        .ok_or_else(|| RainbowError::DecodeFailed("No parity shard received".to_string()))?;
+    if parity
+        .iter()
+        .flatten()
+        .any(|shard| shard.len() != shard_len)
+    {
+        return Err(RainbowError::DecodeFailed(
+            "Parity shards of different lengths".to_string(),
+        ));
+    }
+
+    let data_shards = chunks.len();
+    let codec = codec(data_shards, parity.len())?;
+    let mut shards = Vec::with_capacity(data_shards + parity.len());
+    for chunk in chunks {
+        shards.push(match chunk {
+            Some(chunk) if chunk.len() + SHARD_OVERHEAD > shard_len => {
+                return Err(RainbowError::DecodeFailed(format!(
+                    "Chunk of {} bytes does not fit in shards of {} bytes",
+                    chunk.len(),
+                    shard_len
+                )));
+            }
+            Some(chunk) => Some(to_shard(&chunk, shard_len)),
+            None => None,
+        });
+    }
+    shards.extend(parity);
+
+    codec
+        .reconstruct_data(&mut shards)
+        .map_err(|e| RainbowError::DecodeFailed(format!("Reed-Solomon: {}", e)))?;
+
+    shards
+        .into_iter()
+        .take(data_shards)
+        .map(|shard| from_shard(shard.unwrap_or_default()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reconstruct_any_k() {
+        let chunks: Vec<Vec<u8>> = vec![b"first chunk".to_vec(), vec![7u8; 40], b"end".to_vec()];
+        let refs: Vec<&[u8]> = chunks.iter().map(Vec::as_slice).collect();
+        let parity = parity_shards(&refs, 2).unwrap();
+        assert_eq!(parity.len(), 2);
+        assert!(parity.iter().all(|s| s.len() == 40 + SHARD_OVERHEAD));
+
+        // 丢失任意两个分片都能恢复
+        for lost in [[0, 1], [1, 2], [0, 3], [2, 4]] {
+            let mut received: Vec<Option<Vec<u8>>> = chunks
+                .iter()
+                .cloned()
+                .chain(parity.iter().cloned())
+                .map(Some)
+                .collect();
+            for i in lost {
+                received[i] = None;
+            }
+            let parity_received = received.split_off(chunks.len());
+            assert_eq!(reconstruct(received, parity_received).unwrap(), chunks);
+        }
+
+        let lost_three = vec![None, None, Some(chunks[2].clone())];
+        assert!(reconstruct(lost_three, vec![Some(parity[0].clone()), None]).is_err());
+    }
+
+    #[test]
+    fn test_too_many_shards() {
+        let chunk = [1u8; 4];
+        let chunks = vec![&chunk[..]; MAX_SHARDS];
+        assert!(matches!(
+            parity_shards(&chunks, 1),
+            Err(RainbowError::InvalidData(_))
+        ));
+    }
+}
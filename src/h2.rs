@@ -0,0 +1,583 @@
+/*!
+ * H2 module frames packets as HTTP/2 over cleartext TCP (h2c), with prior knowledge.
+ *
+ * Packets are built as HTTP/1.1 messages first. With [`HttpVersion::H2c`], each message is then
+ * sent as an HPACK-compressed HEADERS frame followed by DATA frames, on a stream of its own of a
+ * single connection: requests open odd streams in order, and each response answers the stream
+ * of the oldest request not answered yet. The first packet sent on the connection carries its
+ * preface, and the SETTINGS and DATA received are acknowledged by the frames preceding the next
+ * packet sent.
+ *
+ * Every header block is compressed on its own, never referring to entries added to the dynamic
+ * table by earlier blocks, so that packets can be decoded in any order. Received messages are
+ * turned back into HTTP/1.1 messages for decoding.
+ */
+
+use std::collections::VecDeque;
+
+use hpack::{Decoder, Encoder};
+
+use crate::{
+    status,
+    utils::{decode_chunked, find_crlf_crlf, is_chunked},
+    RainbowError, Result,
+};
+
+/// Which HTTP version the packets we send are framed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HttpVersion {
+    /// HTTP/1.1 text messages
+    #[default]
+    Http1,
+    /// HTTP/2 frames on a cleartext connection
+    H2c,
+}
+
+/// The connection preface sent by clients
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_HEADER_LEN: usize = 9;
+
+/// Default SETTINGS_MAX_FRAME_SIZE
+const MAX_FRAME_LEN: usize = 16_384;
+
+/// Padding a single DATA frame adds at most, its pad length byte included
+const MAX_FRAME_PADDING: usize = 256;
+
+/// Body bytes per DATA frame, leaving room for the longest padding
+const MAX_DATA_LEN: usize = MAX_FRAME_LEN - MAX_FRAME_PADDING;
+
+// 帧类型
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const SETTINGS: u8 = 0x4;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// 帧标志
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+/// SETTINGS sent by browsers: header table size, no push, stream window, header list size
+const CLIENT_SETTINGS: [(u16, u32); 4] =
+    [(0x1, 65_536), (0x2, 0), (0x4, 6_291_456), (0x6, 262_144)];
+
+/// SETTINGS sent by web servers: concurrent streams, stream window, frame size
+const SERVER_SETTINGS: [(u16, u32); 3] =
+    [(0x3, 128), (0x4, 1_048_576), (0x5, MAX_FRAME_LEN as u32)];
+
+/// Connection window increments following the SETTINGS of browsers and of web servers
+const CLIENT_WINDOW_INCREMENT: u32 = 15_663_105;
+const SERVER_WINDOW_INCREMENT: u32 = 2_147_418_112;
+
+/// Largest WINDOW_UPDATE increment
+const MAX_WINDOW_INCREMENT: u32 = 0x7fff_ffff;
+
+/// HTTP/1.1 headers that are specific to a connection, and not allowed in HTTP/2
+const CONNECTION_HEADERS: [&str; 6] = [
+    "connection",
+    "host",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+struct Frame<'a> {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: &'a [u8],
+}
+
+impl Frame<'_> {
+    /// The payload without its padding
+    fn unpadded(&self) -> Result<&[u8]> {
+        if self.flags & PADDED == 0 {
+            return Ok(self.payload);
+        }
+        let (&pad_len, rest) = self
+            .payload
+            .split_first()
+            .ok_or_else(|| RainbowError::InvalidData("Empty padded frame".to_string()))?;
+        rest.len()
+            .checked_sub(pad_len as usize)
+            .map(|len| &rest[..len])
+            .ok_or_else(|| RainbowError::InvalidData("Frame padding too long".to_string()))
+    }
+
+    /// The header block fragment of a HEADERS or CONTINUATION frame
+    fn header_block(&self) -> Result<&[u8]> {
+        let payload = self.unpadded()?;
+        if self.kind == HEADERS && self.flags & PRIORITY != 0 {
+            // 跳过流依赖和权重
+            return payload
+                .get(5..)
+                .ok_or_else(|| RainbowError::InvalidData("Truncated HEADERS frame".to_string()));
+        }
+        Ok(payload)
+    }
+}
+
+/// The frame at the start of `data`, and its length, or `None` if it is incomplete
+fn read_frame(data: &[u8]) -> Option<(Frame<'_>, usize)> {
+    if data.len() < FRAME_HEADER_LEN {
+        return None;
+    }
+    let len = u32::from_be_bytes([0, data[0], data[1], data[2]]) as usize;
+    let end = FRAME_HEADER_LEN + len;
+    if data.len() < end {
+        return None;
+    }
+    let frame = Frame {
+        kind: data[3],
+        flags: data[4],
+        stream_id: u32::from_be_bytes([data[5], data[6], data[7], data[8]]) & 0x7fff_ffff,
+        payload: &data[FRAME_HEADER_LEN..end],
+    };
+    Some((frame, end))
+}
+
+fn write_frame(out: &mut Vec<u8>, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.push(kind);
+    out.push(flags);
+    out.extend_from_slice(&stream_id.to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// Write a DATA frame grown by exactly `padding` bytes, at most [`MAX_FRAME_PADDING`]
+fn write_data(out: &mut Vec<u8>, stream_id: u32, chunk: &[u8], padding: usize, end: bool) {
+    let flags = if end { END_STREAM } else { 0 };
+    if padding == 0 {
+        write_frame(out, DATA, flags, stream_id, chunk);
+        return;
+    }
+
+    let mut payload = Vec::with_capacity(chunk.len() + padding);
+    payload.push((padding - 1) as u8);
+    payload.extend_from_slice(chunk);
+    payload.resize(chunk.len() + padding, 0);
+    write_frame(out, DATA, flags | PADDED, stream_id, &payload);
+}
+
+/// Whether `data` starts with HTTP/2 frames rather than an HTTP/1.1 message
+///
+/// Frames we send are never longer than 64 KiB, so they start with a zero byte, which no
+/// HTTP/1.1 message does.
+pub fn is_h2(data: &[u8]) -> bool {
+    match data.first() {
+        Some(0) => true,
+        Some(_) => data.starts_with(PREFACE) || PREFACE.starts_with(data),
+        None => false,
+    }
+}
+
+/// The frames of the message at the start of `data`, and its length, or `None` if the
+/// message is incomplete
+fn read_message(data: &[u8]) -> Result<Option<(Vec<Frame<'_>>, usize)>> {
+    let mut pos = if data.starts_with(PREFACE) {
+        PREFACE.len()
+    } else if PREFACE.starts_with(data) {
+        return Ok(None);
+    } else {
+        0
+    };
+
+    let mut frames = Vec::new();
+    let mut end_stream = false;
+    let mut end_headers = false;
+    while let Some((frame, len)) = read_frame(&data[pos..]) {
+        pos += len;
+        match frame.kind {
+            HEADERS | CONTINUATION => {
+                end_stream |= frame.kind == HEADERS && frame.flags & END_STREAM != 0;
+                end_headers = frame.flags & END_HEADERS != 0;
+            }
+            DATA => {
+                if !end_headers {
+                    return Err(RainbowError::InvalidData(
+                        "DATA frame before the headers".to_string(),
+                    ));
+                }
+                end_stream |= frame.flags & END_STREAM != 0;
+            }
+            _ => {}
+        }
+        frames.push(frame);
+        if end_stream && end_headers {
+            return Ok(Some((frames, pos)));
+        }
+    }
+    Ok(None)
+}
+
+/// Length of the HTTP/2 message at the start of `data`, with the connection frames preceding
+/// it, or `None` if it is incomplete
+pub fn message_len(data: &[u8]) -> Result<Option<usize>> {
+    Ok(read_message(data)?.map(|(_, len)| len))
+}
+
+/// Frame the HTTP/1.1 message `http1` on stream `stream_id`, grown by exactly `padding` bytes
+/// of DATA frame padding
+pub fn encode_message(http1: &[u8], stream_id: u32, padding: usize) -> Result<Vec<u8>> {
+    let split_pos = find_crlf_crlf(http1)
+        .ok_or_else(|| RainbowError::InvalidData("Missing end of headers".to_string()))?;
+    let head = String::from_utf8_lossy(&http1[..split_pos]);
+    let mut lines = head.split("\r\n");
+    let start_line = lines.next().unwrap_or_default();
+
+    let mut authority = "localhost";
+    let mut fields = Vec::new();
+    for line in lines {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| RainbowError::InvalidData(format!("Invalid header: {}", line)))?;
+        let name = name.trim().to_ascii_lowercase();
+        if name == "host" {
+            authority = value.trim();
+        }
+        if !CONNECTION_HEADERS.contains(&name.as_str()) {
+            fields.push((name.into_bytes(), value.trim().as_bytes().to_vec()));
+        }
+    }
+
+    // 伪头部必须排在普通头部之前
+    let mut parts = start_line.split(' ');
+    let pseudo: Vec<(&str, &str)> = match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/") => {
+            vec![(":status", status)]
+        }
+        (Some(method), Some(path)) => vec![
+            (":method", method),
+            (":scheme", "http"),
+            (":authority", authority),
+            (":path", path),
+        ],
+        _ => {
+            return Err(RainbowError::InvalidData(format!(
+                "Invalid start line: {}",
+                start_line
+            )))
+        }
+    };
+    let fields: Vec<(Vec<u8>, Vec<u8>)> = pseudo
+        .into_iter()
+        .map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+        .chain(fields)
+        .collect();
+
+    let body = &http1[split_pos + 4..];
+    let dechunked;
+    let body = if is_chunked(&head) {
+        dechunked = decode_chunked(body)?
+            .ok_or_else(|| RainbowError::InvalidData("Truncated chunked body".to_string()))?
+            .0;
+        &dechunked[..]
+    } else {
+        body
+    };
+    if padding > 0 && body.is_empty() {
+        return Err(RainbowError::InvalidData(
+            "Cannot pad a message without body".to_string(),
+        ));
+    }
+
+    let mut out = Vec::new();
+    let block = Encoder::new().encode(&fields);
+    let fragments: Vec<&[u8]> = block.chunks(MAX_FRAME_LEN).collect();
+    for (i, fragment) in fragments.iter().enumerate() {
+        let mut flags = if i + 1 == fragments.len() {
+            END_HEADERS
+        } else {
+            0
+        };
+        let kind = if i == 0 {
+            if body.is_empty() {
+                flags |= END_STREAM;
+            }
+            HEADERS
+        } else {
+            CONTINUATION
+        };
+        write_frame(&mut out, kind, flags, stream_id, fragment);
+    }
+
+    // 最后一个 DATA 帧最多填充 256 字节，其余填充由之前的空 DATA 帧承载
+    let mut padding = padding;
+    while padding > MAX_FRAME_PADDING {
+        let frame_len = (padding - 1).min(FRAME_HEADER_LEN + MAX_FRAME_PADDING);
+        write_data(
+            &mut out,
+            stream_id,
+            &[],
+            frame_len - FRAME_HEADER_LEN,
+            false,
+        );
+        padding -= frame_len;
+    }
+    let chunks: Vec<&[u8]> = body.chunks(MAX_DATA_LEN).collect();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let last = i + 1 == chunks.len();
+        write_data(
+            &mut out,
+            stream_id,
+            chunk,
+            if last { padding } else { 0 },
+            last,
+        );
+    }
+    Ok(out)
+}
+
+/// An HTTP/2 message received
+#[derive(Debug, Clone)]
+pub struct H2Message {
+    /// The message as HTTP/1.1
+    pub http1: Vec<u8>,
+    pub stream_id: u32,
+    /// Whether the peer sent SETTINGS along the message
+    pub settings: bool,
+    /// Flow-controlled bytes of the DATA frames received
+    pub data_len: usize,
+}
+
+/// Decode the HTTP/2 message at the start of `data` back into HTTP/1.1
+pub fn decode_message(data: &[u8]) -> Result<H2Message> {
+    let (frames, _) = read_message(data)?
+        .ok_or_else(|| RainbowError::InvalidData("Truncated HTTP/2 message".to_string()))?;
+
+    let mut stream_id = None;
+    let mut settings = false;
+    let mut block = Vec::new();
+    let mut body = Vec::new();
+    let mut data_len = 0;
+    for frame in &frames {
+        match frame.kind {
+            SETTINGS => settings |= frame.flags & ACK == 0,
+            HEADERS | CONTINUATION | DATA => {
+                if *stream_id.get_or_insert(frame.stream_id) != frame.stream_id {
+                    return Err(RainbowError::InvalidData(
+                        "Frames of several streams in one message".to_string(),
+                    ));
+                }
+                if frame.kind == DATA {
+                    data_len += frame.payload.len();
+                    body.extend_from_slice(frame.unpadded()?);
+                } else {
+                    block.extend_from_slice(frame.header_block()?);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let fields = Decoder::new()
+        .decode(&block)
+        .map_err(|e| RainbowError::DecodeFailed(format!("HPACK: {:?}", e)))?;
+    let pseudo = |name: &str| {
+        fields
+            .iter()
+            .find(|(n, _)| n == name.as_bytes())
+            .map(|(_, v)| String::from_utf8_lossy(v))
+            .ok_or_else(|| RainbowError::InvalidData(format!("Missing {} pseudo-header", name)))
+    };
+
+    let mut head = if fields.iter().any(|(n, _)| n == b":status") {
+        let status = pseudo(":status")?;
+        let code = status.parse().map_err(|_| {
+            RainbowError::InvalidData(format!("Invalid :status pseudo-header: {}", status))
+        })?;
+        status::status_line(code)
+    } else {
+        format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\n",
+            pseudo(":method")?,
+            pseudo(":path")?,
+            pseudo(":authority")?
+        )
+    };
+    for (name, value) in fields.iter().filter(|(n, _)| !n.starts_with(b":")) {
+        head.push_str(&format!(
+            "{}: {}\r\n",
+            String::from_utf8_lossy(name),
+            String::from_utf8_lossy(value)
+        ));
+    }
+    head.push_str("\r\n");
+
+    let mut http1 = head.into_bytes();
+    http1.extend_from_slice(&body);
+    Ok(H2Message {
+        http1,
+        stream_id: stream_id.unwrap_or_default(),
+        settings,
+        data_len,
+    })
+}
+
+/// The HTTP/2 connection the packets of a [`Rainbow`](crate::rainbow::Rainbow) are sent on
+#[derive(Debug)]
+pub struct H2Connection {
+    /// Whether our connection preface has been sent
+    preface_sent: bool,
+    /// Whether the peer sent SETTINGS we have not acknowledged yet
+    settings_to_ack: bool,
+    /// Bytes of DATA received and not credited back to the peer yet
+    window_to_update: u32,
+    /// Stream of our next request
+    next_stream_id: u32,
+    /// Streams of the requests received and not answered yet, oldest first
+    open_streams: VecDeque<u32>,
+}
+
+impl Default for H2Connection {
+    fn default() -> Self {
+        Self {
+            preface_sent: false,
+            settings_to_ack: false,
+            window_to_update: 0,
+            next_stream_id: 1,
+            open_streams: VecDeque::new(),
+        }
+    }
+}
+
+impl H2Connection {
+    /// The connection frames sent before our next message
+    pub fn preamble(&self, is_client: bool) -> Vec<u8> {
+        let mut out = Vec::new();
+        if !self.preface_sent {
+            let (settings, increment): (&[(u16, u32)], u32) = if is_client {
+                out.extend_from_slice(PREFACE);
+                (&CLIENT_SETTINGS, CLIENT_WINDOW_INCREMENT)
+            } else {
+                (&SERVER_SETTINGS, SERVER_WINDOW_INCREMENT)
+            };
+            let payload: Vec<u8> = settings
+                .iter()
+                .flat_map(|(id, value)| id.to_be_bytes().into_iter().chain(value.to_be_bytes()))
+                .collect();
+            write_frame(&mut out, SETTINGS, 0, 0, &payload);
+            write_frame(&mut out, WINDOW_UPDATE, 0, 0, &increment.to_be_bytes());
+        }
+        if self.settings_to_ack {
+            write_frame(&mut out, SETTINGS, ACK, 0, &[]);
+        }
+        if self.window_to_update > 0 {
+            write_frame(
+                &mut out,
+                WINDOW_UPDATE,
+                0,
+                0,
+                &self.window_to_update.to_be_bytes(),
+            );
+        }
+        out
+    }
+
+    /// The connection frames and the stream of our next message, which is then considered sent
+    pub fn start_message(&mut self, is_client: bool) -> (Vec<u8>, u32) {
+        let preamble = self.preamble(is_client);
+        self.preface_sent = true;
+        self.settings_to_ack = false;
+        self.window_to_update = 0;
+
+        let stream_id = match self.open_streams.pop_front() {
+            Some(stream_id) if !is_client => stream_id,
+            _ => {
+                let stream_id = self.next_stream_id;
+                self.next_stream_id = self.next_stream_id.wrapping_add(2) & 0x7fff_ffff;
+                stream_id
+            }
+        };
+        (preamble, stream_id)
+    }
+
+    /// Take note of a message received, `from_client` if it is a request
+    pub fn receive(&mut self, message: &H2Message, from_client: bool) {
+        self.settings_to_ack |= message.settings;
+        self.window_to_update = self
+            .window_to_update
+            .saturating_add(message.data_len as u32)
+            .min(MAX_WINDOW_INCREMENT);
+        if from_client {
+            self.open_streams.push_back(message.stream_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_roundtrip() {
+        let body = vec![b'x'; 40_000];
+        let mut http1 = b"POST /api/v1/data HTTP/1.1\r\nHost: example.com\r\nConnection: keep-alive\r\nContent-Type: text/html\r\nContent-Length: 40000\r\n\r\n".to_vec();
+        http1.extend_from_slice(&body);
+
+        let unpadded = encode_message(&http1, 3, 0).unwrap();
+        assert!(is_h2(&unpadded));
+        assert_eq!(message_len(&unpadded).unwrap(), Some(unpadded.len()));
+        assert_eq!(message_len(&unpadded[..unpadded.len() - 1]).unwrap(), None);
+
+        // 任意长度的填充都精确地增加消息长度
+        for padding in [0, 1, 255, 256, 257, 266, 1000] {
+            let framed = encode_message(&http1, 3, padding).unwrap();
+            assert_eq!(framed.len(), unpadded.len() + padding);
+
+            let message = decode_message(&framed).unwrap();
+            assert_eq!(message.stream_id, 3);
+            if padding <= MAX_FRAME_PADDING {
+                assert_eq!(message.data_len, body.len() + padding);
+            }
+            let decoded = String::from_utf8_lossy(&message.http1);
+            assert!(decoded.starts_with("POST /api/v1/data HTTP/1.1\r\nHost: example.com\r\n"));
+            assert!(decoded.contains("content-type: text/html\r\n"));
+            assert!(!decoded.contains("connection"));
+            assert!(message.http1.ends_with(&body));
+        }
+
+        let response = b"HTTP/1.1 404 Not Found\r\nServer: nginx\r\n\r\n";
+        let message = decode_message(&encode_message(response, 1, 0).unwrap()).unwrap();
+        assert_eq!(
+            message.http1,
+            b"HTTP/1.1 404 Not Found\r\nserver: nginx\r\n\r\n"
+        );
+        assert!(encode_message(response, 1, 10).is_err());
+    }
+
+    #[test]
+    fn test_connection() {
+        let mut client = H2Connection::default();
+        let mut server = H2Connection::default();
+
+        let (preamble, stream_id) = client.start_message(true);
+        assert!(preamble.starts_with(PREFACE));
+        assert_eq!(stream_id, 1);
+        let (preamble, stream_id) = client.start_message(true);
+        assert!(preamble.is_empty());
+        assert_eq!(stream_id, 3);
+
+        let mut request = client.preamble(true);
+        request.extend(encode_message(b"GET / HTTP/1.1\r\n\r\n", 3, 0).unwrap());
+        assert!(is_h2(&request[..1]) && message_len(&request[..10]).unwrap().is_none());
+        server.receive(&decode_message(&request).unwrap(), true);
+
+        // 服务器的第一个响应带有自己的设置，并确认客户端的设置
+        let (preamble, stream_id) = server.start_message(false);
+        assert_eq!(stream_id, 3);
+        let response = [
+            preamble,
+            encode_message(b"HTTP/1.1 200 OK\r\n\r\n", 3, 0).unwrap(),
+        ]
+        .concat();
+        let message = decode_message(&response).unwrap();
+        assert!(message.settings);
+        assert_eq!(message_len(&response).unwrap(), Some(response.len()));
+    }
+}
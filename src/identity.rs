@@ -0,0 +1,340 @@
+/*!
+ * Identity module keeps the packets of a conversation looking like they come from one browser.
+ *
+ * A [`TrafficProfile`] describes the headers of a kind of browser or web server, with values
+ * and optional headers to pick from. A [`SessionIdentity`] makes those picks once, when a
+ * conversation starts: the User-Agent, the Accept-Language, which optional headers are sent,
+ * the name of the cookie carrying the packet info and the values of the other cookies all stay
+ * the same for every packet of the conversation, as they would for one user. Correlated values,
+ * like a User-Agent and the client hints of the same browser, are picked as one variant of the
+ * profile. Only what changes between real messages changes: the date, random tags, the headers
+ * of each message, and the headers describing the kind of each request, like `Sec-Fetch-Mode`.
+ */
+
+use rand::{seq::SliceRandom, Rng};
+
+use crate::{
+    profile::{RequestKind, TrafficProfile},
+    utils::HTTP_CONSTANTS,
+};
+
+/// The picks of a [`TrafficProfile`] for the lifetime of a conversation
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionIdentity {
+    profile: TrafficProfile,
+    /// Value of each header of the profile, `None` for slots and headers not sent
+    values: Vec<Option<String>>,
+    /// Name of the cookie carrying the sealed packet info
+    info_cookie: String,
+    /// Other cookies, sent along with the packet info
+    cookies: Vec<(String, String)>,
+}
+
+impl SessionIdentity {
+    /// Pick the values of `profile` and the cookies of a new conversation
+    pub fn new(profile: TrafficProfile) -> Self {
+        let mut rng = rand::thread_rng();
+        let variant = profile.variants.choose(&mut rng);
+        let values = profile
+            .headers
+            .iter()
+            .map(|header| {
+                // 相关联的头部取同一个变体的值
+                let value = variant.and_then(|variant| {
+                    variant
+                        .iter()
+                        .find(|(name, _)| name.eq_ignore_ascii_case(&header.name))
+                });
+                if let Some((_, value)) = value {
+                    return Some(value.clone());
+                }
+                let sent = rng.gen_bool(header.probability.clamp(0.0, 1.0));
+                header.values.choose(&mut rng).filter(|_| sent).cloned()
+            })
+            .collect();
+
+        // 会话 ID 和常见的追踪 cookie
+        let mut cookies = vec![("sid".to_string(), uuid::Uuid::new_v4().to_string())];
+        if rng.gen::<bool>() {
+            cookies.push((
+                "_ga".to_string(),
+                format!("GA1.2.{}.{}", rng.gen::<u32>(), rng.gen::<u32>()),
+            ));
+        }
+        if rng.gen::<bool>() {
+            cookies.push(("_gid".to_string(), format!("GA1.2.{}", rng.gen::<u32>())));
+        }
+        if rng.gen::<bool>() {
+            cookies.push(("theme".to_string(), "light".to_string()));
+        }
+
+        // 携带包信息的 cookie 不与其他 cookie 重名
+        let names: Vec<&str> = HTTP_CONSTANTS
+            .cookie_names
+            .iter()
+            .copied()
+            .filter(|name| cookies.iter().all(|(n, _)| n != name))
+            .collect();
+        let info_cookie = names.choose(&mut rng).unwrap().to_string();
+
+        Self {
+            profile,
+            values,
+            info_cookie,
+            cookies,
+        }
+    }
+
+    pub fn profile(&self) -> &TrafficProfile {
+        &self.profile
+    }
+
+    /// The cookie carrying `info`, the sealed packet info, without the other cookies
+    pub fn info_cookie(&self, info: &str) -> String {
+        format!("{}={}", self.info_cookie, info)
+    }
+
+    /// Fill the `Host` slot of the profile with `host`, the authority requests are sent to.
+    /// Profiles without a `Host` header, like those of servers, are left as they are
+    pub fn with_host(mut self, host: &str) -> Self {
+        for (header, value) in self.profile.headers.iter().zip(self.values.iter_mut()) {
+            if header.name.eq_ignore_ascii_case("Host") {
+                *value = Some(host.to_string());
+            }
+        }
+        self
+    }
+
+    /// The `Host` sent by this identity, see [`SessionIdentity::with_host`]
+    pub fn host(&self) -> Option<&str> {
+        self.profile
+            .headers
+            .iter()
+            .zip(&self.values)
+            .find(|(header, _)| header.name.eq_ignore_ascii_case("Host"))
+            .and_then(|(_, value)| value.as_deref())
+    }
+
+    /// The header lines of a message, without its start line.
+    ///
+    /// `fields` are the header lines (`Name: value\r\n`) the message sets itself. They take
+    /// the place and casing of the header of the same name in the profile, or come after the
+    /// headers of the profile when it does not list them. `kind` is that of a request, `None`
+    /// for a response, and picks the values of the per-request headers of the profile
+    pub fn headers(&self, fields: &str, kind: Option<RequestKind>) -> String {
+        let mut rng = rand::thread_rng();
+        let mut fields: Vec<Option<(&str, &str)>> = fields
+            .split("\r\n")
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| Some((name.trim(), value.trim())))
+            .collect();
+
+        let mut headers = String::new();
+        for (header, value) in self.profile.headers.iter().zip(&self.values) {
+            let mut set = false;
+            for field in fields.iter_mut() {
+                if matches!(field, Some((name, _)) if name.eq_ignore_ascii_case(&header.name)) {
+                    let (_, value) = field.take().unwrap();
+                    headers.push_str(&format!("{}: {}\r\n", header.name, value));
+                    set = true;
+                }
+            }
+            if set {
+                continue;
+            }
+            // 每个请求按类型选择的头部，比如导航和脚本请求的 Sec-Fetch-Mode 不同
+            let value = value.clone().or_else(|| {
+                let values = header.per_request.get(&kind?)?;
+                let sent = rng.gen_bool(header.probability.clamp(0.0, 1.0));
+                values.choose(&mut rng).filter(|_| sent).cloned()
+            });
+            if let Some(value) = value {
+                headers.push_str(&format!("{}: {}\r\n", header.name, expand(&value)));
+            }
+        }
+        for (name, value) in fields.into_iter().flatten() {
+            headers.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        headers
+    }
+
+    /// The cookies of a packet, with `info` the sealed packet info
+    pub fn cookies(&self, info: &str) -> String {
+        let mut cookies = vec![self.info_cookie(info)];
+        cookies.extend(
+            self.cookies
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value)),
+        );
+        cookies.join("; ")
+    }
+}
+
+/// Replace the placeholders of a profile value, see [`ProfileHeader::values`]
+///
+/// [`ProfileHeader::values`]: crate::profile::ProfileHeader::values
+fn expand(value: &str) -> String {
+    let mut value = value.to_string();
+    if value.contains("{date}") {
+        let date = chrono::Utc::now()
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+        value = value.replace("{date}", &date);
+    }
+    if value.contains("{hex}") {
+        value = value.replace("{hex}", &format!("{:016x}", rand::random::<u64>()));
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::find_header_value;
+
+    #[test]
+    fn test_header_order() {
+        for _ in 0..20 {
+            let chrome = SessionIdentity::new(TrafficProfile::builtin("chrome").unwrap())
+                .with_host("example.com");
+            let headers = chrome.headers(
+                "cookie: a=1\r\nAccept: */*\r\nX-Extra: 1\r\n",
+                Some(RequestKind::Navigate),
+            );
+            let names: Vec<&str> = headers
+                .lines()
+                .map(|line| line.split_once(':').unwrap().0)
+                .collect();
+
+            // 按配置的顺序和大小写，未列出的头部在最后
+            let position = |name| names.iter().position(|n| *n == name).unwrap();
+            assert!(position("sec-ch-ua") < position("User-Agent"));
+            assert!(position("User-Agent") < position("Accept"));
+            assert!(position("Accept") < position("Accept-Encoding"));
+            assert!(position("Accept-Language") < position("Cookie"));
+            assert_eq!(names.last(), Some(&"X-Extra"));
+            assert_eq!(names.first(), Some(&"Host"));
+            assert_eq!(chrome.host(), Some("example.com"));
+
+            let header = format!("GET / HTTP/1.1\r\n{}", headers);
+            assert_eq!(find_header_value(&header, "Cookie"), Some("a=1"));
+            assert_eq!(find_header_value(&header, "Accept"), Some("*/*"));
+            assert_eq!(find_header_value(&header, "Host"), Some("example.com"));
+        }
+
+        // 没有指定 Host 时不发送
+        let curl = SessionIdentity::new(TrafficProfile::builtin("curl").unwrap());
+        assert_eq!(curl.host(), None);
+        assert!(curl
+            .headers("Cookie: a=1\r\n", Some(RequestKind::Cors))
+            .starts_with("User-Agent: curl/"));
+
+        // 消息自身的头部优先于配置的值
+        let nginx = SessionIdentity::new(TrafficProfile::builtin("nginx").unwrap())
+            .with_host("example.com");
+        let headers = nginx.headers("Connection: upgrade\r\n", None);
+        assert!(headers.starts_with("Server: nginx"));
+        assert!(!headers.contains("example.com"));
+        assert!(headers.contains("\r\nDate: ") && !headers.contains("{date}"));
+        assert!(headers.contains("Connection: upgrade\r\n"));
+        assert!(!headers.contains("keep-alive"));
+    }
+
+    #[test]
+    fn test_variant_is_consistent() {
+        let chrome = TrafficProfile::builtin("chrome").unwrap();
+        let mut user_agents = Vec::new();
+        for _ in 0..50 {
+            let headers = format!(
+                "GET / HTTP/1.1\r\n{}",
+                SessionIdentity::new(chrome.clone()).headers("", None)
+            );
+            let user_agent = find_header_value(&headers, "User-Agent").unwrap();
+            let brands = find_header_value(&headers, "sec-ch-ua").unwrap();
+            let platform = find_header_value(&headers, "sec-ch-ua-platform").unwrap();
+
+            // 客户端提示与 User-Agent 的版本和平台一致
+            let version = user_agent.split("Chrome/").nth(1).unwrap();
+            let version = version.split('.').next().unwrap();
+            assert!(brands.contains(&format!("\"Google Chrome\";v=\"{}\"", version)));
+            let os = match platform {
+                "\"Windows\"" => "Windows NT",
+                "\"macOS\"" => "Macintosh",
+                "\"Linux\"" => "Linux",
+                other => panic!("Unexpected platform {}", other),
+            };
+            assert!(user_agent.contains(os));
+            user_agents.push(user_agent.to_string());
+        }
+        user_agents.dedup();
+        assert!(user_agents.len() > 1);
+    }
+
+    #[test]
+    fn test_per_request_headers() {
+        let identity = SessionIdentity::new(TrafficProfile::builtin("chrome").unwrap());
+        let header_of = |kind| format!("GET / HTTP/1.1\r\n{}", identity.headers("", kind));
+
+        // 导航和脚本请求各自带上相应的头部，响应不带
+        for _ in 0..20 {
+            let navigate = header_of(Some(RequestKind::Navigate));
+            assert_eq!(
+                find_header_value(&navigate, "Sec-Fetch-Mode"),
+                Some("navigate")
+            );
+            assert_eq!(
+                find_header_value(&navigate, "Sec-Fetch-Dest"),
+                Some("document")
+            );
+            assert_eq!(find_header_value(&navigate, "Sec-Fetch-User"), Some("?1"));
+            assert_eq!(
+                find_header_value(&navigate, "Upgrade-Insecure-Requests"),
+                Some("1")
+            );
+
+            let cors = header_of(Some(RequestKind::Cors));
+            assert_eq!(find_header_value(&cors, "Sec-Fetch-Mode"), Some("cors"));
+            assert_eq!(find_header_value(&cors, "Sec-Fetch-Dest"), Some("empty"));
+            assert_eq!(
+                find_header_value(&cors, "Sec-Fetch-Site"),
+                Some("same-origin")
+            );
+            for name in [
+                "Sec-Fetch-User",
+                "Upgrade-Insecure-Requests",
+                "Cache-Control",
+            ] {
+                assert_eq!(find_header_value(&cors, name), None);
+            }
+
+            assert!(!header_of(None).contains("Sec-Fetch-"));
+        }
+    }
+
+    #[test]
+    fn test_identity_is_fixed() {
+        let firefox = TrafficProfile::builtin("firefox").unwrap();
+        let identity = SessionIdentity::new(firefox.clone());
+
+        // 同一会话的每个数据包头部和 cookie 都相同
+        let headers = identity.headers("Accept: */*\r\n", Some(RequestKind::Cors));
+        let cookies = identity.cookies("info");
+        for _ in 0..20 {
+            assert_eq!(
+                identity.headers("Accept: */*\r\n", Some(RequestKind::Cors)),
+                headers
+            );
+            assert_eq!(identity.cookies("info"), cookies);
+        }
+        assert!(cookies.contains("; sid="));
+        let (info_cookie, _) = cookies.split_once('=').unwrap();
+        assert!(HTTP_CONSTANTS.cookie_names.contains(&info_cookie));
+        assert_eq!(cookies.matches(&format!("{}=", info_cookie)).count(), 1);
+
+        // 不同的会话各自选择
+        let others: Vec<String> = (0..20)
+            .map(|_| SessionIdentity::new(firefox.clone()).cookies("info"))
+            .collect();
+        assert!(others.iter().any(|other| *other != cookies));
+    }
+}
@@ -6,17 +6,62 @@
  * different types of carrier files.
  *
  * Main components:
+ * - async_stream: Tokio AsyncRead/AsyncWrite adapter, behind the `tokio` feature
+ * - chunking: How much data each packet carries, from the capacity of its encoder
+ * - cover: Decoy packets sent at random intervals, so that idle channels do not go silent
+ * - compression: Optional deflate or zstd compression of the data of each message, when it makes it smaller
+ * - fec: Reed-Solomon parity packets, rebuilding a message from any k of its n packets
+ * - h2: HTTP/2 (h2c) framing of packets, with HPACK-compressed headers
+ * - identity: Picks of a traffic profile and cookies that stay the same for a whole conversation
+ * - profile: Header order, casing and values of browsers and web servers, from a data file
  * - rainbow: Implementation of [`NetworkSteganographyProcessor`] that utilizes the stego module
+ * - reliable: Acknowledgements and retransmission of messages for reliable delivery
+ * - replay: Freshness and replay checks of received packets
+ * - responder: Replies sized to the length expected by the peer
+ * - session: Reassembly of multi-packet messages and stream demultiplexing on the decode side
+ * - sitemap: Methods, paths and content types of requests, chosen from the data they carry
+ * - sse: Server-Sent Events carrying messages in a streamed text/event-stream response
+ * - status: Reason phrases, bodies and headers of responses per status code
  * - stego: Core steganography algorithms and traits
+ * - stream: Blocking Read/Write adapter carrying data in HTTP packets over any transport
+ * - timing: Delays before sending each packet, sampled like a browsing session
+ * - tunnel: SOCKS5 tunnel client and server relaying TCP connections over Rainbow
  * - utils: Common utility functions and helpers
+ * - websocket: WebSocket carrier, an Upgrade handshake followed by text and binary frames
  */
 
+use std::time::Duration;
+
+use chunking::ChunkingStrategy;
+use compression::Compression;
 use dyn_clone::DynClone;
+use reliable::Ack;
 use thiserror::Error;
-
+use timing::TimingModel;
+
+#[cfg(feature = "tokio")]
+pub mod async_stream;
+pub mod chunking;
+pub mod compression;
+pub mod cover;
+pub mod fec;
+pub mod h2;
+pub mod identity;
+pub mod profile;
 pub mod rainbow;
+pub mod reliable;
+pub mod replay;
+pub mod responder;
+pub mod session;
+pub mod sitemap;
+pub mod sse;
+pub mod status;
 pub mod stego;
+pub mod stream;
+pub mod timing;
+pub mod tunnel;
 pub mod utils;
+pub mod websocket;
 
 /// Error type for the library
 #[derive(Error, Debug)]
@@ -30,6 +75,18 @@ pub enum RainbowError {
     #[error("Decode failed: {0}")]
     DecodeFailed(String),
 
+    #[error("Authentication failed: {0}")]
+    AuthenticationFailed(String),
+
+    #[error("Replay rejected: {0}")]
+    ReplayRejected(String),
+
+    #[error("Duplicate packet: {0}")]
+    DuplicatePacket(usize),
+
+    #[error("Message {0} not acknowledged")]
+    Unacknowledged(u32),
+
     #[error("Length mismatch: {0} vs {1}, {2}")]
     LengthMismatch(usize, usize, String),
 
@@ -55,17 +112,75 @@ pub struct DecodeResult {
     pub data: Vec<u8>,
     pub expected_return_length: usize,
     pub is_read_end: bool,
+    /// Index of the packet within its message, as carried in the packet metadata
+    pub index: usize,
+    /// Total number of packets of the message, as carried in the packet metadata
+    pub total: usize,
+    /// Session the packet belongs to
+    pub session_id: u32,
+    /// Stream the packet belongs to
+    pub stream_id: u32,
+    /// Sequence number of the message within its stream
+    pub seq: u32,
+    /// Number of parity packets among the `total` packets of the message
+    pub parity: usize,
+    /// Whether the packet carries a parity shard rather than data, see [`fec`]
+    pub is_parity: bool,
+    /// Messages of the stream the peer acknowledges having received, see [`reliable`]
+    pub ack: Option<Ack>,
+    /// Whether the packet is a decoy carrying no data, see
+    /// [`NetworkSteganographyProcessor::encode_cover`], which sessions drop
+    pub is_cover: bool,
+    /// Whether `data` is a piece of a message compressed as a whole, see [`compression`], which
+    /// sessions decompress once the message is complete. Messages of a single packet are
+    /// decompressed right away
+    pub is_compressed: bool,
+    /// MIME type of the response the request asks for, from the route of its URL in the
+    /// [`SiteMap`](sitemap::SiteMap) of the decoder. `None` for responses and unknown URLs
+    pub reply_mime_type: Option<String>,
 }
 
 pub struct EncodeResult {
     pub encoded_packets: Vec<Vec<u8>>,
     pub expected_return_packet_lengths: Vec<usize>,
+    /// Delay to wait before sending each packet, see [`EncodeOptions::timing`]
+    pub send_delays: Vec<Duration>,
+}
+
+pub struct ReplyResult {
+    /// A single packet of the requested length
+    pub packet: Vec<u8>,
+    /// Number of bytes taken from the start of the data, 0 when the packet only carries cover
+    pub consumed: usize,
+    /// Length of the packet the peer is asked to answer with
+    pub expected_return_length: usize,
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct EncodeOptions {
     pub mime_type: Option<String>,
     pub encoder: Option<String>,
+    /// Logical stream the data belongs to, so several streams can share one conversation
+    pub stream_id: u32,
+    /// Sequence number of the message within its stream, assigned automatically if `None`
+    pub seq: Option<u32>,
+    /// How the data is split into packets
+    pub chunking: ChunkingStrategy,
+    /// Number of parity packets added to every message by
+    /// [`NetworkSteganographyProcessor::encode_write`], so that the message survives the loss
+    /// of as many packets
+    pub parity: usize,
+    /// Acknowledgement of the messages received from the peer, carried by every packet
+    pub ack: Option<Ack>,
+    /// How the data is compressed before being split into packets, not at all by default.
+    /// Compressed messages of several packets are only inflated by
+    /// [`DecodeSession`](session::DecodeSession)
+    pub compression: Compression,
+    /// When the packets are sent, all right away by default
+    pub timing: TimingModel,
+    /// MIME type tried first by [`NetworkSteganographyProcessor::encode_reply`], when neither
+    /// `mime_type` nor `encoder` is set, usually [`DecodeResult::reply_mime_type`]
+    pub preferred_mime_type: Option<String>,
 }
 
 /// Trait NetworkSteganographyProcessor provides a way to encode and decode data into a series of network packets.
@@ -74,7 +189,7 @@ pub struct EncodeOptions {
 /// packet is paired with a length of the expected return packet.
 ///
 /// Every read will parse out the data, and metadata like expected_return_length and is_read_end.
-pub trait NetworkSteganographyProcessor: DynClone {
+pub trait NetworkSteganographyProcessor: DynClone + Send + Sync {
     /// Encode data into a series of network packets by a certain mime type
     fn encode_write(
         &self,
@@ -90,9 +205,42 @@ pub trait NetworkSteganographyProcessor: DynClone {
         packet_index: usize,
         is_client: bool,
     ) -> Result<DecodeResult>;
+
+    /// Encode a single packet of `target_length` bytes, typically the
+    /// [`DecodeResult::expected_return_length`] of the packet being answered.
+    ///
+    /// The packet carries as much of the start of `data` as fits, or cover data when `data` is
+    /// empty, as a message of its own.
+    fn encode_reply(
+        &self,
+        data: &[u8],
+        is_client: bool,
+        target_length: usize,
+        options: EncodeOptions,
+    ) -> Result<ReplyResult>;
+
+    /// Encode a decoy packet, which looks like any packet carrying data but is flagged as
+    /// cover under the session key, so that its receiver drops it
+    fn encode_cover(&self, is_client: bool, options: EncodeOptions) -> Result<Vec<u8>>;
+
+    /// A processor for a new connection, with the keys and settings of this one but message
+    /// sequence numbers and transport state of its own, shared by its clones only.
+    ///
+    /// Streams wrap their transport with the processor returned here, so that concurrent
+    /// connections do not interleave their sequence numbers or framing.
+    fn for_connection(&self) -> Box<dyn NetworkSteganographyProcessor>;
 }
 dyn_clone::clone_trait_object!(NetworkSteganographyProcessor);
 
+impl From<RainbowError> for std::io::Error {
+    fn from(err: RainbowError) -> Self {
+        match err {
+            RainbowError::IoError(e) => e,
+            e => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
 impl From<std::string::FromUtf8Error> for RainbowError {
     fn from(err: std::string::FromUtf8Error) -> Self {
         RainbowError::Other(err.to_string())
@@ -1,8 +1,9 @@
 use std::fs;
 use std::path::PathBuf;
 
-use clap::{Parser, Subcommand};
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
 use rainbow::rainbow::Rainbow;
+use rainbow::tunnel::{TunnelClient, TunnelServer};
 use rainbow::{DecodeResult, EncodeOptions, EncodeResult, NetworkSteganographyProcessor};
 use tracing::info;
 
@@ -11,6 +12,11 @@ use tracing::info;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Shared secret; both sides must use the same one to decode each other's packets.
+    /// Required by the tunnel client and server
+    #[arg(long, global = true)]
+    secret: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -45,13 +51,31 @@ enum Commands {
         output: PathBuf,
 
         /// Packet index
-        #[arg(short, long, default_value = "0")]
+        #[arg(short = 'n', long, default_value = "0")]
         index: usize,
 
         /// Whether to decode as client
         #[arg(short, long)]
         client: bool,
     },
+
+    /// Run a local SOCKS5 proxy tunnelling connections to a Rainbow server
+    Client {
+        /// Local SOCKS5 listen address
+        #[arg(short, long, default_value = "127.0.0.1:1080")]
+        listen: String,
+
+        /// Tunnel server address
+        #[arg(short, long)]
+        server: String,
+    },
+
+    /// Run a tunnel server dialing the targets requested by Rainbow clients
+    Server {
+        /// Listen address
+        #[arg(short, long)]
+        listen: String,
+    },
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -59,7 +83,24 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init();
 
     let cli = Cli::parse();
-    let rainbow = Rainbow::new();
+
+    // A random key would leave the tunnel peer unable to decode anything we send
+    if matches!(
+        cli.command,
+        Commands::Client { .. } | Commands::Server { .. }
+    ) && cli.secret.is_none()
+    {
+        Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "the tunnel client and server require --secret <SECRET>, the same on both sides",
+            )
+            .exit();
+    }
+    let rainbow = match cli.secret.as_ref() {
+        Some(secret) => Rainbow::from_secret(secret.as_bytes()),
+        None => Rainbow::new(),
+    };
 
     match cli.command {
         Commands::Encode {
@@ -75,11 +116,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             let EncodeResult {
                 encoded_packets: packets,
                 expected_return_packet_lengths: lengths,
+                ..
             } = rainbow.encode_write(
                 &data,
                 client,
                 EncodeOptions {
-                    mime_type: mime_type,
+                    mime_type,
                     ..Default::default()
                 },
             )?;
@@ -112,8 +154,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 data: decoded,
                 expected_return_length: expected_length,
                 is_read_end: is_end,
+                is_compressed,
+                ..
             } = rainbow.decrypt_single_read(data, index, client)?;
 
+            // A piece of a compressed message is useless until the whole message is inflated
+            if is_compressed {
+                return Err(
+                    "packet is a piece of a compressed message, decode all its packets together"
+                        .into(),
+                );
+            }
+
             // Write decoded data
             fs::write(&output, decoded)?;
             info!(
@@ -121,6 +173,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 index, output, expected_length, is_end
             );
         }
+
+        Commands::Client { listen, server } => {
+            TunnelClient::new(server, Box::new(rainbow)).run(listen)?;
+        }
+
+        Commands::Server { listen } => {
+            TunnelServer::new(Box::new(rainbow)).run(listen)?;
+        }
     }
 
     Ok(())
@@ -0,0 +1,163 @@
+/*!
+ * Profile module describes the headers of the browsers and web servers whose traffic packets
+ * imitate.
+ *
+ * A [`TrafficProfile`] lists the headers of a client or a server in the order it sends them,
+ * with the casing it uses. Headers with values are the profile's own, sent with some
+ * probability and one of the values picked at random; headers without values are slots, where
+ * the headers a packet sets itself (`Cookie`, `Content-Type`, ...) go. Headers a packet sets
+ * that the profile does not list come after the others, followed by the headers delimiting the
+ * body.
+ *
+ * Values that only make sense together, like the User-Agent of Chrome and its `sec-ch-ua`
+ * client hints, are grouped in variants instead: one variant is picked, and its values fill
+ * the headers of the same names.
+ *
+ * Headers that describe a request rather than the browser, like `Sec-Fetch-Mode`, have values
+ * for each [`RequestKind`] instead, picked for every request: a page load navigates, while the
+ * requests of scripts are `cors` fetches.
+ *
+ * The built-in profiles (chrome, firefox, safari, curl, nginx, apache, cloudflare) are loaded
+ * from `res/profiles.json`, and more can be loaded from a file of the same format with
+ * [`TrafficProfile::load`]. The picks among the values of a profile are made once per
+ * conversation by a [`SessionIdentity`](crate::identity::SessionIdentity).
+ */
+
+use std::{collections::BTreeMap, fs, path::Path, sync::OnceLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// The built-in profiles
+const BUILTIN_PROFILES: &str = include_str!("../res/profiles.json");
+
+/// Profiles used unless another one is set
+const DEFAULT_CLIENT_PROFILE: &str = "chrome";
+const DEFAULT_SERVER_PROFILE: &str = "nginx";
+
+/// Whether a profile sends requests or responses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// What a request does, which decides the values of the per-request headers of a profile
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestKind {
+    /// Loading a page
+    Navigate,
+    /// A request of a script, with `fetch` or `XMLHttpRequest`
+    Cors,
+    /// The handshake opening a WebSocket
+    Websocket,
+}
+
+/// A header of a [`TrafficProfile`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProfileHeader {
+    /// The name, with the casing sent
+    pub name: String,
+    /// Values picked from at random, empty for a slot filled by the packet
+    ///
+    /// `{date}` is replaced by the current date, and `{hex}` by 16 random hex digits
+    #[serde(default)]
+    pub values: Vec<String>,
+    /// Values picked from at random for every request of each kind, instead of once per
+    /// conversation. The header is not sent with requests of the other kinds, nor responses
+    #[serde(default)]
+    pub per_request: BTreeMap<RequestKind, Vec<String>>,
+    /// Probability that the header is sent, when it has values
+    #[serde(default = "always")]
+    pub probability: f64,
+}
+
+fn always() -> f64 {
+    1.0
+}
+
+/// The headers a browser or a web server sends, in order
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrafficProfile {
+    pub name: String,
+    pub role: Role,
+    pub headers: Vec<ProfileHeader>,
+    /// Values of correlated headers, by header name, picked together. The headers are listed
+    /// in `headers` without values, where they are sent
+    #[serde(default)]
+    pub variants: Vec<BTreeMap<String, String>>,
+}
+
+impl TrafficProfile {
+    /// Parse the profiles of a JSON file, an array of profiles
+    pub fn from_json(json: &str) -> Result<Vec<Self>> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Load the profiles of a JSON file, see [`from_json`](Self::from_json)
+    pub fn load(path: impl AsRef<Path>) -> Result<Vec<Self>> {
+        Self::from_json(&fs::read_to_string(path)?)
+    }
+
+    /// The built-in profiles
+    pub fn builtins() -> &'static [TrafficProfile] {
+        static PROFILES: OnceLock<Vec<TrafficProfile>> = OnceLock::new();
+        PROFILES
+            .get_or_init(|| Self::from_json(BUILTIN_PROFILES).expect("built-in profiles are valid"))
+    }
+
+    /// The built-in profile named `name`
+    pub fn builtin(name: &str) -> Option<Self> {
+        Self::builtins()
+            .iter()
+            .find(|profile| profile.name.eq_ignore_ascii_case(name))
+            .cloned()
+    }
+
+    /// The profile used unless another one is set: Chrome for clients, nginx for servers
+    pub fn default_for(is_client: bool) -> Self {
+        let name = if is_client {
+            DEFAULT_CLIENT_PROFILE
+        } else {
+            DEFAULT_SERVER_PROFILE
+        };
+        Self::builtin(name).expect("default profiles are built in")
+    }
+
+    pub fn is_client(&self) -> bool {
+        self.role == Role::Client
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtins() {
+        let names: Vec<&str> = TrafficProfile::builtins()
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "chrome",
+                "firefox",
+                "safari",
+                "curl",
+                "nginx",
+                "apache",
+                "cloudflare"
+            ]
+        );
+        assert!(TrafficProfile::default_for(true).is_client());
+        assert!(!TrafficProfile::default_for(false).is_client());
+        assert_eq!(TrafficProfile::builtin("Firefox").unwrap().name, "firefox");
+        assert!(TrafficProfile::builtin("lynx").is_none());
+        assert!(TrafficProfile::from_json("[{\"name\": \"x\"}]").is_err());
+    }
+}
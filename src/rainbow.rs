@@ -8,55 +8,245 @@
  * - Handling base64 and other encoding schemes
  */
 
-use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use std::{
+    collections::HashMap,
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine};
+use bytes::{Buf, BufMut, BytesMut};
 use chrono::Utc;
 use http::header::{HeaderMap, HeaderValue, COOKIE, SET_COOKIE};
-use rand::Rng;
-use serde::{Deserialize, Serialize};
+use rand::{distributions::Alphanumeric, seq::SliceRandom, Rng, RngCore};
 use tracing::{debug, info};
 
 use crate::{
-    stego::EncoderRegistry,
-    utils::{find_crlf_crlf, generate_realistic_headers, validate_http_packet, HTTP_CONSTANTS},
-    DecodeResult, EncodeOptions, EncodeResult, NetworkSteganographyProcessor, RainbowError, Result,
+    compression::{self, is_compressible, ContentEncoding, ContentEncodingPolicy},
+    fec,
+    h2::{self, H2Connection, HttpVersion},
+    identity::SessionIdentity,
+    profile::{Role, TrafficProfile},
+    reliable::Ack,
+    replay::ReplayGuard,
+    sitemap::{self, Method, SiteMap, Target},
+    sse::{self, Event},
+    status,
+    stego::{
+        header::{self, HeaderEncoder},
+        octet, Encoder, EncoderRegistry,
+    },
+    utils::{
+        chunked_len, decode_chunked, derive_key, encode_chunked, find_crlf_crlf, find_header_value,
+        is_chunked, random_transfer_chunk_size, validate_http_packet, TransferEncoding,
+        HTTP_CONSTANTS,
+    },
+    websocket::{self, WebSocketConnection},
+    DecodeResult, EncodeOptions, EncodeResult, NetworkSteganographyProcessor, RainbowError,
+    ReplyResult, Result,
 };
 
-const CHUNK_SIZE: usize = 256;
+/// The plaintext of a sealed [`PacketInfo`] is padded to a multiple of this many bytes,
+/// so that every cookie has the same length as an opaque session token
+const PACKET_INFO_BLOCK_SIZE: usize = 16;
+
+/// Size of the fields of an encoded [`PacketInfo`], before padding
+const PACKET_INFO_LEN: usize = 1 + 1 + 8 + 4 + 4 + 4 + 4 + 4 + 4 + 4 + 4 + 4 + 4;
+
+/// [`PacketInfo::flags`] bit of packets carrying a parity shard, see [`fec`]
+const FLAG_PARITY: u8 = 0x01;
+
+/// [`PacketInfo::flags`] bit of packets carrying an [`Ack`]
+const FLAG_ACK: u8 = 0x02;
+
+/// [`PacketInfo::flags`] bit of packets carrying compressed data, see [`compression`]
+const FLAG_COMPRESSED: u8 = 0x04;
+
+/// [`PacketInfo::flags`] bit of decoy packets, see [`NetworkSteganographyProcessor::encode_cover`]
+const FLAG_COVER: u8 = 0x08;
+
+/// Length of a sealed [`PacketInfo`], which starts the payload of binary WebSocket frames
+const SEALED_PACKET_INFO_LEN: usize = octet::SEAL_NONCE_LEN
+    + PACKET_INFO_LEN.div_ceil(PACKET_INFO_BLOCK_SIZE) * PACKET_INFO_BLOCK_SIZE
+    + octet::SEAL_TAG_LEN;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// MIME types WebSocket frames carry: JSON in text frames, and binary frames for octet streams
+const WEBSOCKET_MIME_TYPES: [&str; 2] = ["application/json", "application/octet-stream"];
+
+/// Lengths of the responses a client asks for. The range starts above the size of the response
+/// headers, so that every length in it can be produced exactly by [`Rainbow::encode_reply`]
+const RESPONSE_LENGTH_RANGE: Range<usize> = 1000..8000;
+
+/// Lengths of the requests a server asks for, see [`RESPONSE_LENGTH_RANGE`]. Browsers send
+/// long headers, so the range starts above those of the longest client profile
+const REQUEST_LENGTH_RANGE: Range<usize> = 1200..3000;
+
+/// How often requests whose data fits in header fields are GET requests carrying it there
+const GET_REQUEST_PROBABILITY: f64 = 0.5;
+
+/// `Host` of the requests we send, unless set with [`Rainbow::with_authority`]
+const DEFAULT_AUTHORITY: &str = "localhost";
+
+#[derive(Debug, Clone, PartialEq)]
 struct PacketInfo {
     version: u8,
+    /// `FLAG_*` bits
+    flags: u8,
     timestamp: i64,
+    /// Identifies the conversation the packet belongs to
+    session_id: u32,
+    /// Identifies the logical stream within the session
+    stream_id: u32,
+    /// Sequence number of the message within the stream
+    seq: u32,
     index: usize,
     total: usize,
+    /// Number of parity packets among the `total` packets of the message
+    parity: usize,
     length: usize,
+    /// Length of the packet the receiver should answer with
+    expected_return_length: usize,
+    /// [`Ack::next`], valid with [`FLAG_ACK`]
+    ack_next: u32,
+    /// [`Ack::selective`], valid with [`FLAG_ACK`]
+    ack_selective: u32,
 }
 
 impl PacketInfo {
     fn new(index: usize, total: usize, length: usize) -> Self {
         Self {
             version: 1,
+            flags: 0,
             timestamp: Utc::now().timestamp(),
+            session_id: 0,
+            stream_id: 0,
+            seq: 0,
             index,
             total,
+            parity: 0,
             length,
+            expected_return_length: 0,
+            ack_next: 0,
+            ack_selective: 0,
+        }
+    }
+
+    /// Piggyback an acknowledgement of the messages received from the peer
+    fn with_ack(mut self, ack: Option<Ack>) -> Self {
+        if let Some(ack) = ack {
+            self.flags |= FLAG_ACK;
+            self.ack_next = ack.next;
+            self.ack_selective = ack.selective;
+        }
+        self
+    }
+
+    /// Whether the packet carries data the receiver decodes, not cover data
+    fn carries_data(&self) -> bool {
+        self.length > 0 && self.flags & FLAG_COVER == 0
+    }
+
+    fn ack(&self) -> Option<Ack> {
+        (self.flags & FLAG_ACK != 0).then_some(Ack {
+            next: self.ack_next,
+            selective: self.ack_selective,
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = BytesMut::with_capacity(PACKET_INFO_LEN);
+        buf.put_u8(self.version);
+        buf.put_u8(self.flags);
+        buf.put_i64(self.timestamp);
+        buf.put_u32(self.session_id);
+        buf.put_u32(self.stream_id);
+        buf.put_u32(self.seq);
+        buf.put_u32(self.index as u32);
+        buf.put_u32(self.total as u32);
+        buf.put_u32(self.parity as u32);
+        buf.put_u32(self.length as u32);
+        buf.put_u32(self.expected_return_length as u32);
+        buf.put_u32(self.ack_next);
+        buf.put_u32(self.ack_selective);
+
+        let padded_len = buf.len().div_ceil(PACKET_INFO_BLOCK_SIZE) * PACKET_INFO_BLOCK_SIZE;
+        buf.resize(padded_len, 0);
+        buf.to_vec()
+    }
+
+    fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < PACKET_INFO_LEN {
+            return Err(RainbowError::InvalidData(format!(
+                "Packet info too short: {}",
+                bytes.len()
+            )));
+        }
+
+        let version = bytes.get_u8();
+        if version != 1 {
+            return Err(RainbowError::InvalidData(format!(
+                "Unsupported packet info version: {}",
+                version
+            )));
         }
+
+        Ok(Self {
+            version,
+            flags: bytes.get_u8(),
+            timestamp: bytes.get_i64(),
+            session_id: bytes.get_u32(),
+            stream_id: bytes.get_u32(),
+            seq: bytes.get_u32(),
+            index: bytes.get_u32() as usize,
+            total: bytes.get_u32() as usize,
+            parity: bytes.get_u32() as usize,
+            length: bytes.get_u32() as usize,
+            expected_return_length: bytes.get_u32() as usize,
+            ack_next: bytes.get_u32(),
+            ack_selective: bytes.get_u32(),
+        })
+    }
+
+    /// Seal the packet info under `key`, in [`SEALED_PACKET_INFO_LEN`] bytes
+    fn seal(&self, key: &[u8; 32]) -> Result<Vec<u8>> {
+        octet::seal(key, &self.to_bytes())
+    }
+
+    /// Returns the packet info and the nonce it was sealed with, which is unique per packet.
+    ///
+    /// Fails with [`RainbowError::AuthenticationFailed`] if it was not sealed under `key`
+    fn open(sealed: &[u8], key: &[u8; 32]) -> Result<(Self, [u8; 12])> {
+        let info = Self::from_bytes(&octet::open(key, sealed)?)?;
+
+        let mut nonce = [0u8; octet::SEAL_NONCE_LEN];
+        nonce.copy_from_slice(&sealed[..octet::SEAL_NONCE_LEN]);
+        Ok((info, nonce))
     }
 
-    fn to_cookie(&self) -> Result<String> {
-        let json = serde_json::to_string(self)?;
-        Ok(BASE64.encode(json.as_bytes()))
+    /// Seal the packet info under `key`, the result looks like an opaque session token
+    fn to_cookie(&self, key: &[u8; 32]) -> Result<String> {
+        Ok(BASE64_URL.encode(self.seal(key)?))
     }
 
-    fn from_cookie(cookie: &str) -> Result<Self> {
-        let bytes = BASE64.decode(cookie)?;
-        Ok(serde_json::from_slice(&bytes)?)
+    /// Like [`PacketInfo::open`], from a cookie
+    fn from_cookie(cookie: &str, key: &[u8; 32]) -> Result<(Self, [u8; 12])> {
+        Self::open(&BASE64_URL.decode(cookie)?, key)
     }
 }
 
+/// The data of a packet encoded by [`Rainbow::encode_chunks`], before it is put in a carrier
+struct EncodedChunk {
+    packet_info: PacketInfo,
+    mime: String,
+    encoded: Vec<u8>,
+}
+
 #[derive(Debug)]
 pub struct StegoBandwidthStats {
     pub original_size: usize,
+    /// Bytes of data carried by the packets, smaller than `original_size` when compression helped
+    pub compressed_size: usize,
     pub total_packet_size: usize,
     pub packet_count: usize,
     pub expected_return_size: usize,
@@ -66,22 +256,330 @@ pub struct StegoBandwidthStats {
 #[derive(Debug, Clone)]
 pub struct Rainbow {
     pub registry: EncoderRegistry,
+    /// Session key sealing the packet info cookies
+    cookie_key: [u8; 32],
+    /// Identifies the conversation in the metadata of every packet we send
+    session_id: u32,
+    /// Next message sequence number of each stream, shared between clones of the same
+    /// connection
+    next_seqs: Arc<Mutex<HashMap<u32, u32>>>,
+    /// Rejects stale and replayed packets when enabled, shared between clones
+    replay_guard: Option<Arc<Mutex<ReplayGuard>>>,
+    /// How the bodies of the packets we send are delimited
+    transfer_encoding: TransferEncoding,
+    /// Which packets we send have a compressed body
+    content_encoding: ContentEncodingPolicy,
+    /// How the packets we send are framed
+    http_version: HttpVersion,
+    /// The HTTP/2 connection of [`HttpVersion::H2c`] packets, shared between clones of the
+    /// same connection
+    h2_connection: Arc<Mutex<H2Connection>>,
+    /// The WebSocket connection the packets we send are frames of when enabled, shared
+    /// between clones of the same connection
+    websocket: Option<Arc<Mutex<WebSocketConnection>>>,
+    /// Host the requests we send are addressed to
+    authority: String,
+    /// Headers of the requests we send
+    client_identity: Arc<SessionIdentity>,
+    /// Headers of the responses we send
+    server_identity: Arc<SessionIdentity>,
+    /// Methods, paths and content types of the requests we send
+    site_map: Arc<SiteMap>,
+    /// Hides data in the header fields of GET requests
+    request_header_encoder: HeaderEncoder,
+    /// Hides data in the header fields of 204 and 304 responses
+    response_header_encoder: HeaderEncoder,
 }
 
 impl Default for Rainbow {
-    /// use default encoders
+    /// use default encoders and an all-zero session key
     fn default() -> Self {
         Self {
             registry: EncoderRegistry::default(),
+            cookie_key: [0u8; 32],
+            session_id: rand::random(),
+            next_seqs: Arc::default(),
+            replay_guard: None,
+            transfer_encoding: TransferEncoding::default(),
+            content_encoding: ContentEncodingPolicy::default(),
+            http_version: HttpVersion::default(),
+            h2_connection: Arc::default(),
+            websocket: None,
+            authority: DEFAULT_AUTHORITY.to_string(),
+            client_identity: Arc::new(
+                SessionIdentity::new(TrafficProfile::default_for(true))
+                    .with_host(DEFAULT_AUTHORITY),
+            ),
+            server_identity: Arc::new(SessionIdentity::new(TrafficProfile::default_for(false))),
+            site_map: Arc::default(),
+            request_header_encoder: HeaderEncoder::for_requests([0u8; 32]),
+            response_header_encoder: HeaderEncoder::for_responses([0u8; 32]),
         }
     }
 }
 
 impl Rainbow {
-    /// use randomized encoders
+    /// use randomized encoders and a random session key
     pub fn new() -> Self {
+        let mut cookie_key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut cookie_key);
+        let mut header_key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut header_key);
         Self {
             registry: EncoderRegistry::new_randomized(),
+            cookie_key,
+            request_header_encoder: HeaderEncoder::for_requests(header_key),
+            response_header_encoder: HeaderEncoder::for_responses(header_key),
+            ..Self::default()
+        }
+    }
+
+    /// use randomized encoders and a session key, all derived from a shared secret
+    ///
+    /// Two instances created from the same secret can decode each other's packets.
+    pub fn from_secret(secret: &[u8]) -> Self {
+        let header_key = derive_key(secret, "header-fields");
+        Self {
+            registry: EncoderRegistry::from_secret(secret),
+            cookie_key: derive_key(secret, "packet-info"),
+            request_header_encoder: HeaderEncoder::for_requests(header_key),
+            response_header_encoder: HeaderEncoder::for_responses(header_key),
+            ..Self::default()
+        }
+    }
+
+    /// use the given session id instead of a random one
+    pub fn with_session_id(mut self, session_id: u32) -> Self {
+        self.session_id = session_id;
+        self
+    }
+
+    /// Reject received packets whose timestamp is more than `freshness_window` away from the
+    /// local clock, or that have been received before, with [`RainbowError::ReplayRejected`]
+    pub fn with_replay_protection(mut self, freshness_window: Duration) -> Self {
+        self.replay_guard = Some(Arc::new(Mutex::new(ReplayGuard::new(freshness_window))));
+        self
+    }
+
+    /// Delimit the bodies of the packets we send with `transfer_encoding`. Received packets
+    /// are decoded whatever their transfer encoding
+    pub fn with_transfer_encoding(mut self, transfer_encoding: TransferEncoding) -> Self {
+        self.transfer_encoding = transfer_encoding;
+        self
+    }
+
+    /// Compress the bodies of the packets we send as `policy` says. Received packets are
+    /// decompressed whatever their `Content-Encoding`
+    pub fn with_content_encoding(mut self, policy: ContentEncodingPolicy) -> Self {
+        self.content_encoding = policy;
+        self
+    }
+
+    /// Frame the packets we send with `http_version`. Received packets are decoded whatever
+    /// their framing.
+    ///
+    /// With [`HttpVersion::H2c`], the packets are the messages of a single HTTP/2 connection,
+    /// shared by the clones of this processor, so use a processor per connection, such as those
+    /// returned by [`NetworkSteganographyProcessor::for_connection`].
+    pub fn with_http_version(mut self, http_version: HttpVersion) -> Self {
+        self.http_version = http_version;
+        self
+    }
+
+    /// Send the packets as the frames of a WebSocket connection, opened by an `Upgrade`
+    /// handshake preceding the first packet, instead of HTTP messages. Received packets are
+    /// decoded whatever their carrier.
+    ///
+    /// Text frames carry the JSON encoders, and binary frames the octet encoder, so only
+    /// `application/json` and `application/octet-stream` can be selected. Like
+    /// [`HttpVersion::H2c`], the connection is shared by the clones of this processor, and
+    /// [`NetworkSteganographyProcessor::for_connection`] starts a new one.
+    pub fn with_websocket(mut self) -> Self {
+        self.websocket = Some(Arc::default());
+        self
+    }
+
+    /// Send the headers of `profile` instead of those of Chrome in requests, or of nginx in
+    /// responses, depending on its role
+    pub fn with_traffic_profile(mut self, profile: TrafficProfile) -> Self {
+        match profile.role {
+            Role::Client => {
+                self.client_identity =
+                    Arc::new(SessionIdentity::new(profile).with_host(&self.authority))
+            }
+            Role::Server => self.server_identity = Arc::new(SessionIdentity::new(profile)),
+        }
+        self
+    }
+
+    /// Send the headers and cookies of `identity`, in requests or in responses depending on
+    /// the role of its profile, instead of those picked when this processor was built
+    pub fn with_session_identity(mut self, identity: SessionIdentity) -> Self {
+        match identity.profile().role {
+            Role::Client => self.client_identity = Arc::new(identity),
+            Role::Server => self.server_identity = Arc::new(identity),
+        }
+        self
+    }
+
+    /// Address the requests we send to `authority`, the host and optional port of the site
+    /// they imitate, in their `Host` header or HTTP/2 `:authority`, instead of `localhost`
+    pub fn with_authority(mut self, authority: &str) -> Self {
+        self.authority = authority.to_string();
+        self.client_identity = Arc::new((*self.client_identity).clone().with_host(authority));
+        self
+    }
+
+    /// Send requests to the routes of `site_map` instead of those of [`SiteMap::default`], and
+    /// answer requests with the MIME type of their route
+    pub fn with_site_map(mut self, site_map: SiteMap) -> Self {
+        self.site_map = Arc::new(site_map);
+        self
+    }
+
+    pub fn site_map(&self) -> &SiteMap {
+        &self.site_map
+    }
+
+    /// The profile of the requests we send if `is_request`, or of the responses
+    pub fn traffic_profile(&self, is_request: bool) -> &TrafficProfile {
+        self.identity(is_request).profile()
+    }
+
+    /// The identity of the requests we send if `is_request`, or of the responses, the same for
+    /// every packet of this processor and its clones
+    pub fn identity(&self, is_request: bool) -> &SessionIdentity {
+        if is_request {
+            &self.client_identity
+        } else {
+            &self.server_identity
+        }
+    }
+
+    /// The session id carried in the metadata of every packet we send
+    pub fn session_id(&self) -> u32 {
+        self.session_id
+    }
+
+    /// Take the next message sequence number of a stream
+    fn next_seq(&self, stream_id: u32) -> u32 {
+        let mut next_seqs = self.next_seqs.lock().unwrap();
+        let seq = next_seqs.entry(stream_id).or_insert(0);
+        let current = *seq;
+        *seq = seq.wrapping_add(1);
+        current
+    }
+
+    /// The encoder hiding data in the header fields of the packets we send without a body
+    fn header_encoder(&self, is_request: bool) -> &HeaderEncoder {
+        if is_request {
+            &self.request_header_encoder
+        } else {
+            &self.response_header_encoder
+        }
+    }
+
+    /// The MIME type and encoder of the next packet: the ones requested in `options`, or a
+    /// random MIME type and one of its encoders
+    fn select_encoder(
+        &self,
+        options: &EncodeOptions,
+        is_request: bool,
+    ) -> Result<(String, &dyn Encoder)> {
+        // 头部字段编码器不在注册表中
+        if options.encoder.as_deref() == Some(header::NAME)
+            || options.mime_type.as_deref() == Some(header::MIME_TYPE)
+        {
+            if self.websocket.is_some() {
+                return Err(RainbowError::InvalidData(
+                    "WebSocket frames have no header fields".to_string(),
+                ));
+            }
+            return Ok((
+                header::MIME_TYPE.to_string(),
+                self.header_encoder(is_request),
+            ));
+        }
+
+        let encoder = options
+            .encoder
+            .as_deref()
+            .and_then(|name| self.registry.get(name));
+
+        let mime = if let Some(mime) = options.mime_type.clone() {
+            mime
+        } else if let Some(encoder) = encoder {
+            encoder.get_mime_type().to_string()
+        } else if self.websocket.is_some() {
+            WEBSOCKET_MIME_TYPES[rand::thread_rng().gen_range(0..WEBSOCKET_MIME_TYPES.len())]
+                .to_string()
+        } else {
+            self.registry.get_random_mime_type()
+        };
+        if self.websocket.is_some() && !WEBSOCKET_MIME_TYPES.contains(&mime.as_str()) {
+            return Err(RainbowError::InvalidData(format!(
+                "WebSocket frames cannot carry {}",
+                mime
+            )));
+        }
+
+        let encoder = match encoder {
+            Some(encoder) => encoder,
+            None => self.registry.choose_encoder(&mime)?,
+        };
+        Ok((mime, encoder))
+    }
+
+    /// Like [`Rainbow::select_encoder`], but retries a few random choices to find an encoder
+    /// that can carry `len` bytes in one packet
+    fn select_encoder_for(
+        &self,
+        options: &EncodeOptions,
+        is_request: bool,
+        len: usize,
+    ) -> Result<(String, &dyn Encoder)> {
+        let mut selected = self.select_encoder(options, is_request)?;
+        for _ in 0..8 {
+            if selected
+                .1
+                .capacity()
+                .max_data_len
+                .is_none_or(|max| len <= max)
+            {
+                break;
+            }
+            selected = self.select_encoder(options, is_request)?;
+        }
+        Ok(selected)
+    }
+
+    /// Whether the next packet, carrying `len` bytes, carries them in header fields: as a GET
+    /// request, or as a response as often as servers send 204 and 304. Only when `options`
+    /// leave the encoder to us
+    fn prefers_header_fields(&self, len: usize, is_request: bool, options: &EncodeOptions) -> bool {
+        if options.mime_type.is_some()
+            || options.encoder.is_some()
+            || self.websocket.is_some()
+            || len > self.header_encoder(is_request).max_data_len()
+        {
+            return false;
+        }
+        let mut rng = rand::thread_rng();
+        if is_request {
+            rng.gen_bool(GET_REQUEST_PROBABILITY)
+        } else {
+            !status::has_body(status::random_status_code(&mut rng, |_| true))
+        }
+    }
+
+    /// Pick the length of the packet the peer should answer with
+    fn random_return_length(is_client: bool) -> usize {
+        if is_client {
+            // 我们是客户端，对方返回 HTTP 响应
+            rand::thread_rng().gen_range(RESPONSE_LENGTH_RANGE)
+        } else {
+            // 我们是服务器，对方返回 HTTP 请求
+            rand::thread_rng().gen_range(REQUEST_LENGTH_RANGE)
         }
     }
 
@@ -105,88 +603,106 @@ impl Rainbow {
             .collect()
     }
 
-    // 提取公共的 HTTP 头部生成逻辑
-    fn build_common_headers(&self, is_request: bool) -> String {
-        let realistic_headers = generate_realistic_headers(is_request);
-        let mut headers = String::new();
-
-        // 添加基础头部
-        headers.push_str(&format!(
-            "Date: {}\r\n",
-            chrono::Utc::now().format("%a, %d %b %Y %H:%M:%S GMT")
-        ));
-
-        // 添加真实的头部
-        for (name, value) in realistic_headers.iter() {
-            if let Ok(value) = value.to_str() {
-                headers.push_str(&format!("{}: {}\r\n", name.as_str(), value));
-            }
-        }
-
-        headers
-    }
-
     // 提取 Cookie 生成逻辑
     fn build_cookie_header(&self, packet_info: &PacketInfo, is_request: bool) -> Result<String> {
-        let cookie_name = HTTP_CONSTANTS.cookie_names
-            [rand::thread_rng().gen_range(0..HTTP_CONSTANTS.cookie_names.len())];
-        let cookie_value = packet_info.to_cookie()?;
-
-        // 生成真实的 cookie 字符串
-        let mut cookies = Vec::new();
-
-        // 添加包含数据的主 cookie
-        cookies.push(format!("{}={}", cookie_name, cookie_value));
-
-        // 添加会话 ID
-        cookies.push(format!("sid={}", uuid::Uuid::new_v4()));
+        let cookie_value = packet_info.to_cookie(&self.cookie_key)?;
+        Ok(self.build_cookie_header_with_value(&cookie_value, is_request))
+    }
 
-        // 随机添加一些常见的 cookie
-        if rand::random::<bool>() {
-            cookies.push(format!(
-                "_ga=GA1.2.{}.{}",
-                rand::thread_rng().gen::<u32>(),
-                rand::thread_rng().gen::<u32>()
-            ));
-        }
-        if rand::random::<bool>() {
-            cookies.push(format!("_gid=GA1.2.{}", rand::thread_rng().gen::<u32>()));
-        }
-        if rand::random::<bool>() {
-            cookies.push("theme=light".to_string());
+    /// Cookie header carrying `cookie_value` as the sealed packet info, along with the other
+    /// cookies of our identity
+    fn build_cookie_header_with_value(&self, cookie_value: &str, is_request: bool) -> String {
+        let cookies = self.identity(is_request).cookies(cookie_value);
+        if is_request {
+            format!("Cookie: {}\r\n", cookies)
+        } else {
+            format!("Set-Cookie: {}\r\n", cookies)
         }
+    }
 
-        let cookie_str = cookies.join("; ");
-
-        Ok(if is_request {
-            format!("Cookie: {}\r\n", cookie_str)
-        } else {
-            format!("Set-Cookie: {}\r\n", cookie_str)
+    /// The status of a response we send: one carrying data in its body if `carries_data`, any
+    /// status otherwise. Sized responses have no `Content-Range`, whose length follows that of
+    /// the body, and as HTTP/2, no empty body, which could not be padded
+    fn random_status_code(&self, carries_data: bool, is_sized: bool) -> u16 {
+        let is_h2 = self.http_version == HttpVersion::H2c;
+        status::random_status_code(&mut rand::thread_rng(), |code| {
+            (!carries_data || status::carries_data(code))
+                && !(is_sized && (code == 206 || (is_h2 && !status::has_body(code))))
         })
     }
 
-    // 提取 Accept 头部生成逻辑
-    fn get_accept_header(&self, path: &str) -> &'static str {
-        match path {
-            p if p.ends_with(".css") => "text/css,*/*;q=0.1",
-            p if p.ends_with(".js") => "application/javascript,*/*;q=0.1",
-            p if p.ends_with(".png") => "image/png,image/*;q=0.8,*/*;q=0.5",
-            p if p.starts_with("/api/") => "application/json",
-            _ => "*/*",
+    /// The header fields carrying `encoded`, the output of a header encoder, and the cookie
+    /// header carrying `cookie_value`. Requests send all their cookies in one `Cookie` header
+    fn header_encoder_fields(
+        &self,
+        encoded: &[u8],
+        cookie_value: &str,
+        is_request: bool,
+    ) -> Result<String> {
+        let encoded = std::str::from_utf8(encoded)
+            .map_err(|_| RainbowError::EncodeFailed("Invalid header fields".to_string()))?;
+        let mut fields = String::new();
+        let mut cookies = self.identity(is_request).cookies(cookie_value);
+        for line in encoded.split_terminator("\r\n") {
+            match line.strip_prefix("Cookie: ") {
+                Some(cookie) if is_request => {
+                    cookies.push_str("; ");
+                    cookies.push_str(cookie);
+                }
+                _ => {
+                    fields.push_str(line);
+                    fields.push_str("\r\n");
+                }
+            }
+        }
+        if is_request {
+            fields.push_str(&format!("Cookie: {}\r\n", cookies));
+        } else {
+            fields.push_str(&format!("Set-Cookie: {}\r\n", cookies));
         }
+        Ok(fields)
     }
 
-    // 获取随机状态码
-    fn get_random_status_code(&self) -> u16 {
-        let rand_val = rand::random::<f32>();
-        let mut acc = 0.0;
-        for &(code, prob) in HTTP_CONSTANTS.status_codes {
-            acc += prob;
-            if rand_val < acc {
-                return code;
-            }
+    /// A response with a status whose body carries no data: redirects and errors have the page
+    /// of the server, 204 and 304 no body but header fields carrying `encoded`, the output of
+    /// the response header encoder, or cover data
+    fn build_status_response(
+        &self,
+        status: u16,
+        cookie_value: &str,
+        encoded: Option<&[u8]>,
+    ) -> Result<Vec<u8>> {
+        let mut headers = status::status_line(status);
+        let mut fields = String::new();
+        if status::is_redirect(status) {
+            let location = self.site_map.location(&mut rand::thread_rng());
+            fields.push_str(&format!("Location: {}\r\n", location));
+        }
+        let has_body = status::has_body(status);
+        let body = if has_body {
+            fields.push_str("Content-Type: text/html\r\n");
+            fields.push_str(&self.build_cookie_header_with_value(cookie_value, false));
+            status::status_page(status, &self.server_identity.profile().name).into_bytes()
+        } else {
+            let encoder = self.header_encoder(false);
+            let encoded = match encoded {
+                Some(encoded) => encoded.to_vec(),
+                None => encoder.encode(&random_cover_data(encoder))?,
+            };
+            fields.push_str(&self.header_encoder_fields(&encoded, cookie_value, false)?);
+            Vec::new()
+        };
+        headers.push_str(&self.server_identity.headers(&fields, None));
+        if has_body {
+            headers.push_str(&self.body_length_header(body.len()));
         }
-        200 // 默认返回 200
+        headers.push_str("\r\n");
+
+        let mut response = headers.into_bytes();
+        if has_body {
+            response.extend_from_slice(&self.frame_body(&body));
+        }
+        Ok(response)
     }
 
     /// The function do not encode data
@@ -196,40 +712,43 @@ impl Rainbow {
         packet_info: &PacketInfo,
         mime_type: &str,
     ) -> Result<Vec<u8>> {
-        let use_get = mime_type.contains("text/plain") || mime_type.contains("application/json");
-        let method = if use_get { "GET" } else { "POST" };
-
-        let paths = if use_get {
-            HTTP_CONSTANTS.get_paths
-        } else {
-            HTTP_CONSTANTS.post_paths
-        };
-        let path = paths[rand::thread_rng().gen_range(0..paths.len())];
+        let target = self.site_map.target(mime_type, &mut rand::thread_rng());
 
         let mut headers = String::new();
-        headers.push_str(&format!("{} {} HTTP/1.1\r\n", method, path));
-        headers.push_str(&self.build_common_headers(true));
-        headers.push_str(&format!("Accept: {}\r\n", self.get_accept_header(path)));
+        headers.push_str(&format!(
+            "{} {} HTTP/1.1\r\n",
+            target.method.as_str(),
+            target.path
+        ));
 
-        headers.push_str(&self.build_cookie_header(packet_info, true)?);
+        // 数据包自身的头部，由配置决定顺序和大小写
+        let mut fields = format!("Accept: {}\r\n", target.accept());
+        let cookie_value = packet_info.to_cookie(&self.cookie_key)?;
 
-        if method == "GET" {
-            headers.push_str(&format!("X-Data: {}\r\n", BASE64.encode(data)));
+        if target.method == Method::Get {
+            fields.push_str(&self.header_encoder_fields(data, &cookie_value, true)?);
+            headers.push_str(&self.client_identity.headers(&fields, Some(target.kind())));
             headers.push_str("\r\n");
             Ok(headers.into_bytes())
         } else {
-            headers.push_str(&format!("Content-Type: {}\r\n", mime_type));
-            headers.push_str(&format!("Content-Length: {}\r\n", data.len()));
+            let content_type = target.content_type();
+            let content_encoding = self.choose_content_encoding(true, mime_type);
+            let body = encode_body(&target.body(data), content_encoding)?;
+            fields.push_str(&self.build_cookie_header_with_value(&cookie_value, true));
+            fields.push_str(&format!("Content-Type: {}\r\n", content_type));
+            fields.push_str(&content_encoding_header(content_encoding, true));
+            headers.push_str(&self.client_identity.headers(&fields, Some(target.kind())));
+            headers.push_str(&self.body_length_header(body.len()));
             headers.push_str("\r\n");
 
             debug!(
-                "building request with method: {} content length: {}",
-                method,
-                data.len()
+                "building request to {} with content length: {}",
+                target.path,
+                body.len()
             );
 
             let mut v = headers.into_bytes();
-            v.extend_from_slice(data);
+            v.extend_from_slice(&self.frame_body(&body));
             Ok(v)
         }
     }
@@ -240,35 +759,190 @@ impl Rainbow {
         data: &[u8],
         packet_info: &PacketInfo,
         mime_type: &str,
-        _status_code: u16,
     ) -> Result<Vec<u8>> {
-        let mut headers = String::new();
+        // 数据在头部字段中的是 204、304 响应
+        // 不携带数据的数据包也可能是重定向、错误等没有数据的响应
+        if mime_type == header::MIME_TYPE {
+            let status = status::random_status_code(&mut rand::thread_rng(), |code| {
+                status::carries_header_data(code)
+            });
+            let cookie_value = packet_info.to_cookie(&self.cookie_key)?;
+            return self.build_status_response(status, &cookie_value, Some(data));
+        }
+        let status = self.random_status_code(packet_info.carries_data(), false);
+        if !status::carries_data(status) {
+            let cookie_value = packet_info.to_cookie(&self.cookie_key)?;
+            return self.build_status_response(status, &cookie_value, None);
+        }
+
         // 确保响应行是第一行
-        headers.push_str(&format!(
-            "HTTP/1.1 {} OK\r\n",
-            self.get_random_status_code()
-        ));
-        // 然后添加其他头部
-        headers.push_str(&self.build_common_headers(false));
-        headers.push_str(&format!("Content-Type: {}\r\n", mime_type));
-        headers.push_str(&format!("Content-Length: {}\r\n", data.len()));
-        headers.push_str(&self.build_cookie_header(packet_info, false)?);
+        let mut headers = status::status_line(status);
+        // 然后添加其他头部，描述消息体长度的头部在最后
+        let content_encoding = self.choose_content_encoding(false, mime_type);
+        let body = encode_body(data, content_encoding)?;
+        let mut fields = format!("Content-Type: {}\r\n", mime_type);
+        if status == 206 {
+            let range = status::content_range(body.len(), &mut rand::thread_rng());
+            fields.push_str(&format!("Content-Range: {}\r\n", range));
+        }
+        fields.push_str(&content_encoding_header(content_encoding, false));
+        fields.push_str(&self.build_cookie_header(packet_info, false)?);
+        headers.push_str(&self.server_identity.headers(&fields, None));
+        headers.push_str(&self.body_length_header(body.len()));
         headers.push_str("\r\n");
 
         let mut response = headers.into_bytes();
-        response.extend_from_slice(data);
+        response.extend_from_slice(&self.frame_body(&body));
         Ok(response)
     }
 
+    /// The Content-Encoding of the body of a packet we send, if any
+    fn choose_content_encoding(
+        &self,
+        is_request: bool,
+        mime_type: &str,
+    ) -> Option<ContentEncoding> {
+        let enabled = match self.content_encoding {
+            ContentEncodingPolicy::Never => false,
+            ContentEncodingPolicy::Responses => !is_request,
+            ContentEncodingPolicy::Always => true,
+        };
+        (enabled && is_compressible(mime_type)).then(ContentEncoding::random)
+    }
+
+    /// How the bodies of the packets we build as HTTP/1.1 are delimited, HTTP/2 frames
+    /// delimiting them on their own
+    fn transfer_encoding(&self) -> TransferEncoding {
+        match self.http_version {
+            HttpVersion::Http1 => self.transfer_encoding,
+            HttpVersion::H2c => TransferEncoding::Identity,
+        }
+    }
+
+    /// Frame a packet built as HTTP/1.1 the way `http_version` says
+    fn frame_packet(&self, packet: Vec<u8>, is_request: bool) -> Result<Vec<u8>> {
+        match self.http_version {
+            HttpVersion::Http1 => Ok(packet),
+            HttpVersion::H2c => {
+                let (mut framed, stream_id) =
+                    self.h2_connection.lock().unwrap().start_message(is_request);
+                framed.extend(h2::encode_message(&packet, stream_id, 0)?);
+                Ok(framed)
+            }
+        }
+    }
+
+    /// The header delimiting a body of `len` bytes
+    fn body_length_header(&self, len: usize) -> String {
+        match self.transfer_encoding() {
+            TransferEncoding::Identity => format!("Content-Length: {}\r\n", len),
+            TransferEncoding::Chunked => "Transfer-Encoding: chunked\r\n".to_string(),
+        }
+    }
+
+    /// The body as sent on the wire
+    fn frame_body(&self, body: &[u8]) -> Vec<u8> {
+        match self.transfer_encoding() {
+            TransferEncoding::Identity => body.to_vec(),
+            TransferEncoding::Chunked => encode_chunked(body, random_transfer_chunk_size()),
+        }
+    }
+
+    /// The connection of the packets we send, with [`Rainbow::with_websocket`]
+    fn websocket_connection(&self) -> Result<&Mutex<WebSocketConnection>> {
+        self.websocket
+            .as_deref()
+            .ok_or_else(|| RainbowError::InvalidData("WebSocket carrier not enabled".to_string()))
+    }
+
+    /// The opcode and payload of a WebSocket frame carrying data encoded for `mime_type`,
+    /// followed by `padding` bytes.
+    ///
+    /// Text frames wrap the JSON in an envelope with the sealed packet info as its id, and
+    /// binary frames start with the sealed packet info.
+    fn websocket_payload(
+        &self,
+        encoded: &[u8],
+        packet_info: &PacketInfo,
+        mime_type: &str,
+        padding: usize,
+    ) -> Result<(u8, Vec<u8>)> {
+        match mime_type {
+            "application/json" => {
+                let json = std::str::from_utf8(encoded).map_err(|_| {
+                    RainbowError::EncodeFailed("Text frames carry UTF-8 only".to_string())
+                })?;
+                let nonce: String = rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(padding)
+                    .map(char::from)
+                    .collect();
+                let envelope = format!(
+                    r#"{{"id":"{}","payload":{},"nonce":"{}"}}"#,
+                    packet_info.to_cookie(&self.cookie_key)?,
+                    json,
+                    nonce
+                );
+                Ok((websocket::TEXT, envelope.into_bytes()))
+            }
+            "application/octet-stream" => {
+                let mut payload = packet_info.seal(&self.cookie_key)?;
+                payload.extend_from_slice(encoded);
+                payload.extend((0..padding).map(|_| rand::random::<u8>()));
+                Ok((websocket::BINARY, payload))
+            }
+            _ => Err(RainbowError::InvalidData(format!(
+                "WebSocket frames cannot carry {}",
+                mime_type
+            ))),
+        }
+    }
+
+    /// A WebSocket packet: our handshake if it has not been sent yet, and a frame
+    fn build_websocket_packet(
+        &self,
+        encoded: &[u8],
+        packet_info: &PacketInfo,
+        mime_type: &str,
+        is_request: bool,
+    ) -> Result<Vec<u8>> {
+        let (opcode, payload) = self.websocket_payload(encoded, packet_info, mime_type, 0)?;
+        let mut packet = self
+            .websocket_connection()?
+            .lock()
+            .unwrap()
+            .start_message(is_request, self.identity(is_request));
+        // 客户端发送的帧必须加掩码
+        packet.extend(websocket::encode_frame(opcode, &payload, is_request));
+        Ok(packet)
+    }
+
     fn decode_single_packet(&self, packet: &[u8], packet_index: usize) -> Result<Vec<u8>> {
         let split_pos = find_crlf_crlf(packet).ok_or_else(|| {
             RainbowError::InvalidData(HTTP_CONSTANTS.error_details[3].1.to_string())
         })?;
 
-        let mut data_to_decode = Vec::new();
-
         let header = String::from_utf8_lossy(&packet[..split_pos]);
-        let body = &packet[split_pos + 4..];
+        let dechunked;
+        let body = if is_chunked(&header) {
+            dechunked = decode_chunked(&packet[split_pos + 4..])?
+                .ok_or_else(|| RainbowError::InvalidData("Truncated chunked body".to_string()))?
+                .0;
+            &dechunked[..]
+        } else {
+            &packet[split_pos + 4..]
+        };
+        let decompressed;
+        let body = match find_header_value(&header, "Content-Encoding") {
+            Some(name) => match ContentEncoding::from_name(name)? {
+                Some(encoding) => {
+                    decompressed = encoding.decode(body)?;
+                    &decompressed[..]
+                }
+                None => body,
+            },
+            None => body,
+        };
 
         // 获取请求方法
         let first_line = header
@@ -276,131 +950,288 @@ impl Rainbow {
             .next()
             .ok_or_else(|| RainbowError::InvalidData("Cannot get first line".to_string()))?;
 
-        // 处理 GET 请求中的 X-Data header
-        if first_line.starts_with("GET") {
-            let mut ok = false;
-            for line in header.lines() {
-                if line.to_lowercase().starts_with("x-data:") {
-                    let encoded_data =
-                        line.split_once(':').map(|(_, v)| v.trim()).ok_or_else(|| {
-                            RainbowError::InvalidData("Invalid X-Data header".to_string())
-                        })?;
-
-                    data_to_decode = BASE64.decode(encoded_data)?;
-                    ok = true;
-                    break;
+        // 204、304 响应和 GET 请求的数据在头部字段中
+        // 其他响应只有成功且有消息体的携带数据
+        if let Some(status) = first_line.strip_prefix("HTTP/1.1 ") {
+            let status = status.split(' ').next().unwrap_or_default();
+            match status.parse() {
+                Ok(code) if status::carries_header_data(code) => {
+                    return self.response_header_encoder.decode(header.as_bytes());
+                }
+                Ok(code) if status::carries_data(code) => {}
+                _ => {
+                    return Err(RainbowError::InvalidData(format!(
+                        "Responses with status {} carry no data",
+                        status
+                    )))
                 }
             }
-            if !ok {
-                return Err(RainbowError::InvalidData(
-                    "Missing X-Data header in GET request".to_string(),
-                ));
-            }
-        } else {
-            data_to_decode = body.to_vec();
+        } else if first_line.starts_with("GET") {
+            return self.request_header_encoder.decode(header.as_bytes());
         }
 
-        // 处理 POST 请求
         // 获取 MIME 类型
-        if first_line.starts_with("GET") {
-            let decoded = self.registry.decode_mime(&data_to_decode, "text/plain");
+        let mime_type = header
+            .lines()
+            .find(|line| line.to_lowercase().starts_with("content-type:"))
+            .and_then(|line| line.split_once(':'))
+            .map(|(_, value)| value.trim())
+            .ok_or_else(|| {
+                RainbowError::InvalidData(HTTP_CONSTANTS.error_details[0].1.to_string())
+            })?;
 
-            if let Ok(decoded) = decoded {
-                debug!("Successfully decoded content: length={}", decoded.len());
-                Ok(decoded)
-            } else {
-                let decoded = self
-                    .registry
-                    .decode_mime(&data_to_decode, "application/json");
-                if let Ok(decoded) = decoded {
-                    debug!("Successfully decoded content: length={}", decoded.len());
-                    Ok(decoded)
-                } else {
-                    Err(RainbowError::InvalidData(
-                        "Failed to decode content".to_string(),
-                    ))
-                }
-            }
-        } else {
-            let mime_type = header
-                .lines()
-                .find(|line| line.to_lowercase().starts_with("content-type:"))
-                .and_then(|line| line.split_once(':'))
-                .map(|(_, value)| value.trim())
-                .ok_or_else(|| {
-                    RainbowError::InvalidData(HTTP_CONSTANTS.error_details[0].1.to_string())
-                })?;
+        debug!(
+            "Processing packet {}: MIME type: {}, Content length: {}",
+            packet_index,
+            mime_type,
+            body.len()
+        );
 
-            debug!(
-                "Processing packet {}: MIME type: {}, Content length: {}",
-                packet_index,
-                mime_type,
-                body.len()
-            );
+        // 表单上传的数据在文件内容中
+        let (mime_type, data_to_decode) = if sitemap::is_multipart(mime_type) {
+            sitemap::read_upload(body, mime_type)?
+        } else {
+            (mime_type.to_string(), body)
+        };
 
-            // 解码数据
-            let decoded = self.registry.decode_mime(&data_to_decode, mime_type)?;
-            debug!("Successfully decoded content: length={}", decoded.len());
+        // 解码数据
+        let decoded = self.registry.decode_mime(data_to_decode, &mime_type)?;
+        debug!("Successfully decoded content: length={}", decoded.len());
 
-            Ok(decoded)
-        }
+        Ok(decoded)
     }
-    fn find_optimal_packet_size(
-        &self,
-        base_headers: &str,
-        target_length: usize,
-        mime_type: &str,
-    ) -> Result<(Vec<u8>, usize)> {
-        let base_header_length = base_headers.len();
-        let min_header_length = base_header_length - "0000000000\r\n\r\n".len() + "0\r\n\r\n".len();
+    /// Find and authenticate the packet info sealed in the cookies of a packet,
+    /// see [`PacketInfo::from_cookie`]
+    fn read_packet_info(&self, data: &[u8], is_client: bool) -> Result<(PacketInfo, [u8; 12])> {
+        // 从 Cookie 中获取包信息
+        let mut headers = HeaderMap::new();
 
-        if target_length < min_header_length {
-            return Err(RainbowError::InvalidData(format!(
+        let split_pos = find_crlf_crlf(data).ok_or_else(|| {
+            RainbowError::InvalidData(HTTP_CONSTANTS.error_details[3].1.to_string())
+        })?;
+        let header_part = String::from_utf8_lossy(&data[..split_pos]);
+
+        for line in header_part.lines() {
+            if is_client {
+                if line.to_lowercase().starts_with("cookie:") {
+                    if let Ok(value) = HeaderValue::from_str(line[7..].trim()) {
+                        headers.append(COOKIE, value);
+                    }
+                }
+            } else if line.to_lowercase().starts_with("set-cookie:") {
+                if let Ok(value) = HeaderValue::from_str(line[12..].trim()) {
+                    headers.append(SET_COOKIE, value);
+                }
+            }
+        }
+
+        // 使用 parse_cookies 解析所有 cookie
+        let mut auth_error = None;
+        for cookie in if is_client {
+            Rainbow::parse_cookies(&headers)
+        } else {
+            Rainbow::parse_set_cookies(&headers)
+        } {
+            if let Some((name, value)) = cookie.split_once('=') {
+                if HTTP_CONSTANTS.cookie_names.contains(&name.trim()) {
+                    match PacketInfo::from_cookie(value.trim(), &self.cookie_key) {
+                        Ok(found) => return Ok(found),
+                        // 记录认证失败，其他 cookie 可能只是普通的追踪 cookie
+                        Err(e @ RainbowError::AuthenticationFailed(_)) => auth_error = Some(e),
+                        Err(_) => {}
+                    }
+                }
+            }
+        }
+
+        Err(auth_error.unwrap_or_else(|| {
+            RainbowError::InvalidData(
+                "decrypt_single_read: Could not find valid packet info in cookies".to_string(),
+            )
+        }))
+    }
+
+    /// Authenticate a WebSocket packet, see [`Rainbow::websocket_payload`]. Returns its packet
+    /// info, the nonce it was sealed with, and the MIME type and content of its encoded data
+    fn read_websocket_packet(
+        &self,
+        data: &[u8],
+        is_client: bool,
+    ) -> Result<(PacketInfo, [u8; 12], &'static str, Vec<u8>)> {
+        let (handshake, frame) = websocket::split_handshake(data)?;
+        if let Some(header) = handshake {
+            if header.starts_with("HTTP/") == is_client {
+                return Err(RainbowError::InvalidData(
+                    "Handshake sent in the wrong direction".to_string(),
+                ));
+            }
+            if let Some(connection) = self.websocket.as_ref() {
+                connection
+                    .lock()
+                    .unwrap()
+                    .receive_handshake(&header, is_client)?;
+            }
+        }
+
+        let frame = websocket::decode_frame(frame)?;
+        if frame.masked != is_client {
+            return Err(RainbowError::InvalidData(
+                if is_client {
+                    "Client frames must be masked"
+                } else {
+                    "Server frames must not be masked"
+                }
+                .to_string(),
+            ));
+        }
+
+        match frame.opcode {
+            websocket::TEXT => {
+                let invalid = || RainbowError::InvalidData("Invalid text frame".to_string());
+                let text = std::str::from_utf8(&frame.payload).map_err(|_| invalid())?;
+                // 直接切分信封，不解析其中的 JSON
+                let (cookie, rest) = text
+                    .strip_prefix(r#"{"id":""#)
+                    .and_then(|rest| rest.split_once(r#"","payload":"#))
+                    .ok_or_else(invalid)?;
+                let (json, _) = rest
+                    .strip_suffix(r#""}"#)
+                    .and_then(|rest| rest.rsplit_once(r#","nonce":""#))
+                    .ok_or_else(invalid)?;
+                let (info, nonce) = PacketInfo::from_cookie(cookie, &self.cookie_key)?;
+                Ok((info, nonce, "application/json", json.as_bytes().to_vec()))
+            }
+            websocket::BINARY => {
+                if frame.payload.len() < SEALED_PACKET_INFO_LEN {
+                    return Err(RainbowError::InvalidData(
+                        "Binary frame too short".to_string(),
+                    ));
+                }
+                let (sealed, encoded) = frame.payload.split_at(SEALED_PACKET_INFO_LEN);
+                let (info, nonce) = PacketInfo::open(sealed, &self.cookie_key)?;
+                Ok((info, nonce, "application/octet-stream", encoded.to_vec()))
+            }
+            opcode => Err(RainbowError::InvalidData(format!(
+                "Unexpected frame opcode: {}",
+                opcode
+            ))),
+        }
+    }
+
+    /// Check the freshness of an authenticated packet, and `decode` its data unless it only
+    /// carries cover data
+    fn decode_packet(
+        &self,
+        info: PacketInfo,
+        nonce: [u8; 12],
+        packet_index: usize,
+        decode: impl FnOnce() -> Result<Vec<u8>>,
+    ) -> Result<DecodeResult> {
+        // 拒绝过期或重放的数据包
+        if let Some(guard) = self.replay_guard.as_ref() {
+            guard
+                .lock()
+                .unwrap()
+                .check(info.session_id, info.index, nonce, info.timestamp)?;
+        }
+
+        // 掩护数据包的内容不必解码
+        // 压缩的消息只有一个数据包时直接解压，否则由会话在重组后解压
+        let is_cover = info.flags & FLAG_COVER != 0;
+        let mut is_compressed = info.flags & FLAG_COMPRESSED != 0;
+        let decoded = if info.length == 0 || is_cover {
+            Vec::new()
+        } else if is_compressed && info.total == 1 {
+            is_compressed = false;
+            compression::decompress(&decode()?)?
+        } else {
+            decode()?
+        };
+
+        let is_read_end = packet_index + 1 >= info.total;
+
+        info!("Successfully decoded {} bytes from packet", decoded.len());
+        Ok(DecodeResult {
+            data: decoded,
+            expected_return_length: info.expected_return_length,
+            is_read_end,
+            index: info.index,
+            total: info.total,
+            session_id: info.session_id,
+            stream_id: info.stream_id,
+            seq: info.seq,
+            parity: info.parity,
+            is_parity: info.flags & FLAG_PARITY != 0,
+            ack: info.ack(),
+            is_cover,
+            is_compressed,
+            reply_mime_type: None,
+        })
+    }
+
+    fn find_optimal_packet_size(
+        &self,
+        base_headers: &str,
+        target_length: usize,
+        mime_type: &str,
+    ) -> Result<(Vec<u8>, usize)> {
+        let base_header_length = base_headers.len();
+        let min_header_length = base_header_length - "0000000000\r\n\r\n".len() + "0\r\n\r\n".len();
+
+        if target_length < min_header_length {
+            return Err(RainbowError::InvalidData(format!(
                 "Target length {} is too small for headers (min {})",
                 target_length, min_header_length
             )));
         }
 
-        let mut left = 1;
-        let mut right = target_length - min_header_length;
-        let mut best_result = None;
+        fn calculate_total_length(headers: &str, encoded: &[u8]) -> usize {
+            let content_length_str = encoded.len().to_string();
+            let header_length = headers.len() - "0000000000\r\n\r\n".len()
+                + content_length_str.len()
+                + "\r\n\r\n".len();
+            header_length + encoded.len()
+        }
 
-        while left <= right {
-            let mid = (left + right) / 2;
-            let random_data: Vec<u8> = (0..mid).map(|_| rand::random()).collect();
+        // 依次尝试这种类型的编码器，搜索过程中使用同一个编码器，长度才随数据量单调变化
+        let mut encoders = self.registry.encoders_for(mime_type);
+        encoders.shuffle(&mut rand::thread_rng());
 
-            let encoded = self.registry.encode_mime(&random_data, mime_type)?;
+        for encoder in encoders {
+            let mut left = 1;
+            let mut right = target_length - min_header_length;
+            let mut best_result = None;
 
-            fn calculate_total_length(headers: &str, encoded: &[u8]) -> Result<usize> {
-                let content_length_str = encoded.len().to_string();
-                let header_length = headers.len() - "0000000000\r\n\r\n".len()
-                    + content_length_str.len()
-                    + "\r\n\r\n".len();
-                Ok(header_length + encoded.len())
-            }
+            while left <= right {
+                let mid = (left + right) / 2;
+                let random_data: Vec<u8> = (0..mid).map(|_| rand::random()).collect();
 
-            let total_len = calculate_total_length(base_headers, &encoded)?;
+                let encoded = encoder.encode(&random_data)?;
+                let total_len = calculate_total_length(base_headers, &encoded);
 
-            match total_len.cmp(&target_length) {
-                std::cmp::Ordering::Equal => {
-                    return Ok((encoded, 0));
-                }
-                std::cmp::Ordering::Less => {
-                    best_result = Some((encoded, target_length - total_len));
-                    left = mid + 1;
-                }
-                std::cmp::Ordering::Greater => {
-                    right = mid - 1;
+                match total_len.cmp(&target_length) {
+                    std::cmp::Ordering::Equal => {
+                        return Ok((encoded, 0));
+                    }
+                    std::cmp::Ordering::Less => {
+                        best_result = Some((encoded, target_length - total_len));
+                        left = mid + 1;
+                    }
+                    std::cmp::Ordering::Greater => {
+                        right = mid - 1;
+                    }
                 }
             }
+
+            if let Some(result) = best_result {
+                return Ok(result);
+            }
         }
 
-        best_result.ok_or_else(|| {
-            RainbowError::InvalidData(format!(
-                "Could not generate packet of length {}",
-                target_length
-            ))
-        })
+        Err(RainbowError::InvalidData(format!(
+            "Could not generate packet of length {}",
+            target_length
+        )))
     }
 
     /// 生成指定长度的 HTTP 请求或响应包
@@ -416,66 +1247,53 @@ impl Rainbow {
         target_length: usize,
         is_request: bool,
     ) -> Result<Vec<u8>> {
-        // 提取基础头部生成到单独的函数
-        fn generate_base_headers(
-            is_request: bool,
-            is_small_packet: bool,
-        ) -> (String, &'static str) {
-            let mut headers = String::new();
-            let path = if is_request {
-                let method = if is_small_packet { "GET" } else { "POST" };
-                let paths = HTTP_CONSTANTS.get_paths;
-                let path = if is_small_packet {
-                    paths.iter().min_by_key(|p| p.len()).unwrap_or(&"/")
-                } else {
-                    paths[rand::thread_rng().gen_range(0..paths.len())]
-                };
-                headers.push_str(&format!("{} {} HTTP/1.1\r\n", method, path));
-                path
-            } else {
-                let rb = Rainbow::new();
-                headers.push_str(&format!("HTTP/1.1 {} OK\r\n", rb.get_random_status_code()));
-                ""
-            };
-            (headers, path)
-        }
-
-        let packet_info = PacketInfo::new(0, 1, target_length);
+        let packet_info = PacketInfo {
+            session_id: self.session_id,
+            ..PacketInfo::new(0, 1, target_length)
+        };
         let is_small_packet = target_length < 1000;
 
-        // 生成基础头部
-        let (mut headers, _path) = generate_base_headers(is_request, is_small_packet);
-
         // 选择合适的 MIME 类型
-        let mime_type = if is_small_packet {
+        let mut mime_type = if is_small_packet {
             "application/json".to_string()
         } else {
             self.registry.get_random_mime_type()
         };
 
+        // 生成基础头部，请求发往接收这种数据的路径，表单上传改为发送 JSON
+        let mut headers = String::new();
+        let mut kind = None;
+        if is_request {
+            let mut rng = rand::thread_rng();
+            let mut target = self.site_map.post_target(&mime_type, &mut rng);
+            if target.content_type() != mime_type {
+                mime_type = "application/json".to_string();
+                target = self.site_map.post_target(&mime_type, &mut rng);
+            }
+            headers.push_str(&format!("POST {} HTTP/1.1\r\n", target.path));
+            kind = Some(target.kind());
+        } else {
+            headers.push_str(&status::status_line(self.random_status_code(true, true)));
+        }
+
         // 添加基础头部
         if is_small_packet {
-            headers.push_str("Host: localhost\r\n");
+            headers.push_str(&format!("Host: {}\r\n", self.authority));
             headers.push_str("Connection: close\r\n");
         } else {
-            headers.push_str(&format!(
-                "Date: {}\r\n",
-                chrono::Utc::now().format("%a, %d %b %Y %H:%M:%S GMT")
-            ));
-
-            // 添加真实头部
-            let realistic_headers = generate_realistic_headers(is_request);
-            for (name, value) in realistic_headers {
-                if let Some(header_name) = name {
-                    if let Ok(value_str) = value.to_str() {
-                        headers.push_str(&format!("{}: {}\r\n", header_name, value_str));
-                    }
-                }
-            }
+            headers.push_str(&self.identity(is_request).headers("", kind));
         }
 
-        // 添加 Cookie 头部
-        headers.push_str(&generate_cookie_header(&packet_info, is_request)?);
+        // 添加 Cookie 头部，小包只带携带包信息的 cookie，给数据留出空间
+        if is_small_packet {
+            let cookie = self
+                .identity(is_request)
+                .info_cookie(&packet_info.to_cookie(&self.cookie_key)?);
+            let name = if is_request { "Cookie" } else { "Set-Cookie" };
+            headers.push_str(&format!("{}: {}\r\n", name, cookie));
+        } else {
+            headers.push_str(&self.build_cookie_header(&packet_info, is_request)?);
+        }
         headers.push_str(&format!("Content-Type: {}\r\n", mime_type));
 
         // 预留 Content-Length 占位符
@@ -500,109 +1318,495 @@ impl Rainbow {
         Ok(final_packet)
     }
 
-    pub fn analyze_bandwidth(
+    /// Headers of a packet built by [`Rainbow::build_sized_packet`], ending with a
+    /// Content-Length placeholder. Requests are sent to `target`
+    fn build_sized_headers(
+        &self,
+        target: Option<&Target>,
+        mime_type: &str,
+        cookie_value: &str,
+        content_encoding: Option<ContentEncoding>,
+    ) -> String {
+        let is_request = target.is_some();
+        let mut headers = String::new();
+        let mut fields = String::new();
+        let content_type = match target {
+            Some(target) => {
+                headers.push_str(&format!("POST {} HTTP/1.1\r\n", target.path));
+                fields.push_str(&format!("Accept: {}\r\n", target.accept()));
+                target.content_type()
+            }
+            None => {
+                headers.push_str(&status::status_line(self.random_status_code(true, true)));
+                mime_type.to_string()
+            }
+        };
+        fields.push_str(&self.build_cookie_header_with_value(cookie_value, is_request));
+        fields.push_str(&format!("Content-Type: {}\r\n", content_type));
+        fields.push_str(&content_encoding_header(content_encoding, is_request));
+        let kind = target.map(Target::kind);
+        headers.push_str(&self.identity(is_request).headers(&fields, kind));
+        match self.transfer_encoding() {
+            TransferEncoding::Identity => headers.push_str("Content-Length: 0000000000\r\n\r\n"),
+            TransferEncoding::Chunked => headers.push_str("Transfer-Encoding: chunked\r\n\r\n"),
+        }
+        headers
+    }
+
+    /// Build a packet of exactly `target_length` bytes, carrying as much of the start of `data`
+    /// as fits, or cover data when `data` is empty.
+    ///
+    /// Returns the packet and the number of bytes of `data` it carries, or `None` when even the
+    /// shortest packet of this MIME type is too long.
+    fn build_sized_packet(
         &self,
         data: &[u8],
-        mime_type: Option<String>,
-    ) -> Result<StegoBandwidthStats> {
-        let EncodeResult {
-            encoded_packets,
-            expected_return_packet_lengths,
-        } = self.encode_write(
-            data,
-            true,
-            EncodeOptions {
-                mime_type: mime_type,
-                ..Default::default()
-            },
-        )?;
+        is_request: bool,
+        target_length: usize,
+        mime_type: &str,
+        encoder: &dyn Encoder,
+        packet_info: PacketInfo,
+    ) -> Result<Option<(Vec<u8>, usize)>> {
+        if self.websocket.is_some() {
+            return self.build_sized_websocket_packet(
+                data,
+                is_request,
+                target_length,
+                mime_type,
+                encoder,
+                packet_info,
+            );
+        }
 
-        let stats = StegoBandwidthStats {
-            original_size: data.len(),
-            total_packet_size: encoded_packets.iter().map(|p| p.len()).sum(),
-            packet_count: encoded_packets.len(),
-            expected_return_size: expected_return_packet_lengths.iter().sum(),
+        // 204、304 响应的数据在头部字段中
+        // 没有数据的响应也可能是重定向、错误等不携带数据的状态
+        if !is_request {
+            let status = self.random_status_code(false, true);
+            if status::carries_header_data(status)
+                || (data.is_empty() && !status::carries_data(status))
+            {
+                if let Some(sized) = self.build_sized_status_packet(
+                    status,
+                    data,
+                    target_length,
+                    packet_info.clone(),
+                )? {
+                    return Ok(Some(sized));
+                }
+            }
+        }
+
+        // 密封后的包信息长度固定，先用占位符生成头部，选定数据长度后再替换
+        let cookie_len = packet_info.to_cookie(&self.cookie_key)?.len();
+        let placeholder = "~".repeat(cookie_len);
+        // 请求总是使用 POST，数据放在请求体中才能精确控制长度
+        let target = is_request.then(|| {
+            self.site_map
+                .post_target(mime_type, &mut rand::thread_rng())
+        });
+        let content_encoding = self.choose_content_encoding(is_request, mime_type);
+        let headers =
+            self.build_sized_headers(target.as_ref(), mime_type, &placeholder, content_encoding);
+        let chunk_size = random_transfer_chunk_size();
+        let (header_len, body_len): (usize, fn(usize, usize) -> usize) =
+            match self.transfer_encoding() {
+                TransferEncoding::Identity => (headers.len() - "0000000000".len(), |len, _| {
+                    len.to_string().len() + len
+                }),
+                TransferEncoding::Chunked => (headers.len(), chunked_len),
+            };
+        // HTTP/2 帧的长度要在编码头部之后才知道
+        let h2_preamble_len = match self.http_version {
+            HttpVersion::Http1 => None,
+            HttpVersion::H2c => Some(
+                self.h2_connection
+                    .lock()
+                    .unwrap()
+                    .preamble(is_request)
+                    .len(),
+            ),
         };
 
-        Ok(stats)
+        // 没有数据时搜索掩护数据的长度
+        let is_cover = data.is_empty();
+        let probe = |n: usize| -> Result<(Vec<u8>, usize)> {
+            let payload: Vec<u8> = if is_cover {
+                (0..n).map(|_| rand::random()).collect()
+            } else {
+                data[..n].to_vec()
+            };
+            let encoded = encoder.encode(&payload)?;
+            let encoded = match &target {
+                Some(target) => encode_body(&target.body(&encoded), content_encoding)?,
+                None => encode_body(&encoded, content_encoding)?,
+            };
+            let total_len = match h2_preamble_len {
+                Some(preamble_len) => {
+                    let packet = build_final_packet(&headers, &encoded)?;
+                    preamble_len + h2::encode_message(&packet, 1, 0)?.len()
+                }
+                None => header_len + body_len(encoded.len(), chunk_size),
+            };
+            Ok((encoded, total_len))
+        };
+        // 不足的部分由填充头补齐，但填充头有最小长度，DATA 帧则可以填充任意长度
+        let fits = |total_len: usize| {
+            total_len == target_length
+                || total_len + MIN_PADDING_LEN <= target_length
+                || (h2_preamble_len.is_some() && total_len < target_length)
+        };
+
+        let max = if is_cover {
+            target_length
+        } else {
+            encoder
+                .capacity()
+                .data_len_for(target_length)
+                .min(data.len())
+        };
+        let Some((encoded, n)) = search_largest_fit(max, target_length, probe, fits)? else {
+            return Ok(None);
+        };
+        let consumed = if is_cover { 0 } else { n };
+
+        let packet_info = PacketInfo {
+            length: consumed,
+            ..packet_info
+        };
+        let headers = headers.replace(&placeholder, &packet_info.to_cookie(&self.cookie_key)?);
+        let packet = match self.transfer_encoding() {
+            TransferEncoding::Identity => build_final_packet(&headers, &encoded)?,
+            TransferEncoding::Chunked => {
+                let mut packet = headers.into_bytes();
+                packet.extend_from_slice(&encode_chunked(&encoded, chunk_size));
+                packet
+            }
+        };
+        let packet = self.pad_packet(packet, is_request, target_length)?;
+
+        debug!(
+            "Built packet of {} bytes carrying {} bytes of data",
+            packet.len(),
+            consumed
+        );
+        Ok(Some((packet, consumed)))
     }
 
-    pub fn analyze_bandwidth_range(
+    /// Build a response of exactly `target_length` bytes with a status whose body carries no
+    /// data, see [`Rainbow::build_status_response`]. 204 and 304 responses carry as much of the
+    /// start of `data` as their header fields hold.
+    ///
+    /// Returns the packet and the number of bytes of `data` it carries, or `None` when it
+    /// cannot be padded to that length
+    fn build_sized_status_packet(
         &self,
-        sizes: &[usize],
-        mime_type: Option<String>,
-    ) -> Result<Vec<StegoBandwidthStats>> {
-        let mut results = Vec::new();
+        status: u16,
+        data: &[u8],
+        target_length: usize,
+        packet_info: PacketInfo,
+    ) -> Result<Option<(Vec<u8>, usize)>> {
+        let encoder = self.header_encoder(false);
+        let consumed = if status::carries_header_data(status) {
+            data.len().min(encoder.max_data_len())
+        } else {
+            0
+        };
+        let encoded = if consumed > 0 {
+            Some(encoder.encode(&data[..consumed])?)
+        } else {
+            None
+        };
+        let packet_info = PacketInfo {
+            length: consumed,
+            ..packet_info
+        };
+        let packet = self.build_status_response(
+            status,
+            &packet_info.to_cookie(&self.cookie_key)?,
+            encoded.as_deref(),
+        )?;
+        let len = match self.http_version {
+            HttpVersion::Http1 => packet.len(),
+            HttpVersion::H2c => {
+                self.h2_connection.lock().unwrap().preamble(false).len()
+                    + h2::encode_message(&packet, 1, 0)?.len()
+            }
+        };
+        let fits = len == target_length
+            || len + MIN_PADDING_LEN <= target_length
+            || (self.http_version == HttpVersion::H2c && len < target_length);
+        if !fits {
+            return Ok(None);
+        }
+        debug!(
+            "Built a {} response of {} bytes carrying {} bytes of data",
+            status, target_length, consumed
+        );
+        let packet = self.pad_packet(packet, false, target_length)?;
+        Ok(Some((packet, consumed)))
+    }
 
-        for &size in sizes {
-            let test_data = vec![0u8; size];
-            let stats = self.analyze_bandwidth(&test_data, mime_type.clone())?;
-            results.push(stats);
+    /// Pad a packet built as HTTP/1.1 to `target_length` bytes, framing it the way
+    /// `http_version` says
+    fn pad_packet(
+        &self,
+        mut packet: Vec<u8>,
+        is_request: bool,
+        target_length: usize,
+    ) -> Result<Vec<u8>> {
+        match self.http_version {
+            HttpVersion::Http1 => {
+                let padding_len = target_length - packet.len();
+                if padding_len > 0 {
+                    add_padding_to_packet(&mut packet, padding_len)?;
+                }
+                Ok(packet)
+            }
+            HttpVersion::H2c => {
+                let (mut framed, stream_id) =
+                    self.h2_connection.lock().unwrap().start_message(is_request);
+                let unpadded_len = framed.len() + h2::encode_message(&packet, stream_id, 0)?.len();
+                framed.extend(h2::encode_message(
+                    &packet,
+                    stream_id,
+                    target_length - unpadded_len,
+                )?);
+                Ok(framed)
+            }
         }
+    }
 
-        Ok(results)
+    /// [`Rainbow::build_sized_packet`] for WebSocket packets, padded inside their frame
+    fn build_sized_websocket_packet(
+        &self,
+        data: &[u8],
+        is_request: bool,
+        target_length: usize,
+        mime_type: &str,
+        encoder: &dyn Encoder,
+        packet_info: PacketInfo,
+    ) -> Result<Option<(Vec<u8>, usize)>> {
+        // 帧头的长度随载荷长度跳变，先由目标长度确定载荷长度
+        let connection = self.websocket_connection()?;
+        let preamble_len = connection
+            .lock()
+            .unwrap()
+            .preamble(is_request, self.identity(is_request))
+            .len();
+        let Some(target_payload_len) = target_length
+            .checked_sub(preamble_len)
+            .and_then(|frame_len| websocket::payload_len_for(frame_len, is_request))
+        else {
+            return Ok(None);
+        };
+
+        let is_cover = data.is_empty();
+        let probe = |n: usize| -> Result<(Vec<u8>, usize)> {
+            let payload: Vec<u8> = if is_cover {
+                (0..n).map(|_| rand::random()).collect()
+            } else {
+                data[..n].to_vec()
+            };
+            let encoded = encoder.encode(&payload)?;
+            let (_, frame_payload) =
+                self.websocket_payload(&encoded, &packet_info, mime_type, 0)?;
+            Ok((encoded, frame_payload.len()))
+        };
+        let max = if is_cover {
+            target_payload_len
+        } else {
+            encoder
+                .capacity()
+                .data_len_for(target_payload_len)
+                .min(data.len())
+        };
+        let Some((encoded, n)) = search_largest_fit(max, target_payload_len, probe, |len| {
+            len <= target_payload_len
+        })?
+        else {
+            return Ok(None);
+        };
+        let consumed = if is_cover { 0 } else { n };
+
+        let packet_info = PacketInfo {
+            length: consumed,
+            ..packet_info
+        };
+        // 密封后的包信息长度固定，填充补齐剩余的载荷
+        let (_, unpadded) = self.websocket_payload(&encoded, &packet_info, mime_type, 0)?;
+        let (opcode, frame_payload) = self.websocket_payload(
+            &encoded,
+            &packet_info,
+            mime_type,
+            target_payload_len - unpadded.len(),
+        )?;
+        let mut packet = connection
+            .lock()
+            .unwrap()
+            .start_message(is_request, self.identity(is_request));
+        packet.extend(websocket::encode_frame(opcode, &frame_payload, is_request));
+
+        debug!(
+            "Built WebSocket packet of {} bytes carrying {} bytes of data",
+            packet.len(),
+            consumed
+        );
+        Ok(Some((packet, consumed)))
     }
-}
 
-impl NetworkSteganographyProcessor for Rainbow {
-    fn encode_write(
+    /// Split a message into the chunks of its packets, and encode each of them. Also returns
+    /// the number of bytes of data they carry once compressed
+    fn encode_chunks(
         &self,
         data: &[u8],
         is_client: bool,
         options: EncodeOptions,
-    ) -> Result<EncodeResult> {
+    ) -> Result<(Vec<EncodedChunk>, usize)> {
         debug!("Encoding {} bytes of data", data.len());
 
-        let chunks: Vec<_> = data.chunks(CHUNK_SIZE).collect();
-        let total_chunks = chunks.len();
-
-        let mut packets = Vec::new();
-        let mut expected_lengths = Vec::new();
-
-        for (i, chunk) in chunks.iter().enumerate() {
-            let packet_info = PacketInfo::new(i, total_chunks, chunk.len());
-
-            let o = options.clone();
+        // 整个消息先压缩再切分，压缩后更小时才携带压缩的数据
+        let compressed = options.compression.compress(data)?;
+        let compressed_flag = if compressed.is_some() {
+            FLAG_COMPRESSED
+        } else {
+            0
+        };
+        let data = compressed.as_deref().unwrap_or(data);
+
+        // 先为每个数据包选定编码器，再按编码器的容量切分数据
+        // 空数据也生成一个数据包，其包信息中的长度为 0
+        // 启用纠错时，数据块在分片中还要加上长度前缀
+        let mut rng = rand::thread_rng();
+        let overhead = if options.parity > 0 {
+            fec::SHARD_OVERHEAD
+        } else {
+            0
+        };
+        let mut chunks = Vec::new();
+        let mut rest = data;
+        loop {
+            let (mime, encoder) =
+                if self.prefers_header_fields(rest.len() + overhead, is_client, &options) {
+                    (
+                        header::MIME_TYPE.to_string(),
+                        self.header_encoder(is_client) as &dyn Encoder,
+                    )
+                } else {
+                    self.select_encoder(&options, is_client)?
+                };
+            let len = options
+                .chunking
+                .chunk_len(encoder, &mut rng)
+                .saturating_sub(overhead)
+                .max(1)
+                .min(rest.len());
+            let (chunk, tail) = rest.split_at(len);
+            chunks.push((chunk, mime, encoder));
+            rest = tail;
+            if rest.is_empty() {
+                break;
+            }
+        }
 
-            let opt_encoder = if let Some(encoder) = o.encoder.as_ref() {
-                self.registry.encoders.get(encoder)
-            } else {
-                None
-            };
+        // 校验分片由各自的数据包携带，排在数据包之后
+        let parity = if options.parity > 0 {
+            let data_chunks: Vec<&[u8]> = chunks.iter().map(|(chunk, ..)| *chunk).collect();
+            fec::parity_shards(&data_chunks, options.parity)?
+        } else {
+            Vec::new()
+        };
+        let parity_chunks = parity
+            .iter()
+            .map(|shard| {
+                let (mime, encoder) = self.select_encoder_for(&options, is_client, shard.len())?;
+                Ok((shard.as_slice(), mime, encoder))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let data_chunks = chunks.len();
+        let total_chunks = data_chunks + parity_chunks.len();
+        let seq = options
+            .seq
+            .unwrap_or_else(|| self.next_seq(options.stream_id));
+
+        let mut encoded_chunks = Vec::new();
+        let mut carried = 0;
+
+        for (i, (chunk, mime, encoder)) in chunks.into_iter().chain(parity_chunks).enumerate() {
+            // 预期的返回包长度随包信息一起发给对方
+            let expected_length = Self::random_return_length(is_client);
+            let packet_info = PacketInfo {
+                flags: compressed_flag | if i >= data_chunks { FLAG_PARITY } else { 0 },
+                session_id: self.session_id,
+                stream_id: options.stream_id,
+                seq,
+                parity: total_chunks - data_chunks,
+                expected_return_length: expected_length,
+                ..PacketInfo::new(i, total_chunks, chunk.len())
+            }
+            .with_ack(options.ack);
 
-            let mime = if let Some(mime) = o.mime_type {
-                mime
-            } else if let Some(encoder) = opt_encoder.as_ref() {
-                encoder.get_mime_type().to_string()
+            // 空数据包携带随机的掩护数据，使其看起来与普通数据包一样
+            let cover;
+            let payload = if chunk.is_empty() {
+                cover = random_cover_data(encoder);
+                &cover
             } else {
-                self.registry.get_random_mime_type()
+                chunk
             };
+            if i < data_chunks {
+                carried += chunk.len();
+            }
 
-            let encoded = if let Some(encoder) = opt_encoder.as_ref() {
-                encoder.encode(chunk)
-            } else {
-                self.registry.encode_mime(chunk, &mime)
-            }?;
+            let encoded = encoder.encode(payload)?;
 
             debug!("encoded.len: {:?}", encoded.len());
+            encoded_chunks.push(EncodedChunk {
+                packet_info,
+                mime,
+                encoded,
+            });
+        }
 
-            // 生成数据包
-            let packet = if is_client {
-                self.build_http_request(&encoded, &packet_info, &mime)?
-            } else {
-                self.build_http_response(&encoded, &packet_info, &mime, 200)?
-            };
+        Ok((encoded_chunks, carried))
+    }
 
-            // 生成预期的返回包长度
-            let expected_length = if is_client {
-                // 如果我们是客户端，对方是服务器，预期返回 HTTP 响应
-                // 通常响应大小在 200-8000 字节之间
-                rand::thread_rng().gen_range(200..8000)
-            } else {
-                // 如果我们是服务器，对方是客户端，预期返回 HTTP 请求
-                // 通常请求大小在 100-2000 字节之间
-                rand::thread_rng().gen_range(100..2000)
-            };
+    /// The packet carrying an encoded chunk, as a request if `is_client`
+    fn build_packet(&self, chunk: &EncodedChunk, is_client: bool) -> Result<Vec<u8>> {
+        let EncodedChunk {
+            packet_info,
+            mime,
+            encoded,
+        } = chunk;
+        if self.websocket.is_some() {
+            return self.build_websocket_packet(encoded, packet_info, mime, is_client);
+        }
+        let packet = if is_client {
+            self.build_http_request(encoded, packet_info, mime)?
+        } else {
+            self.build_http_response(encoded, packet_info, mime)?
+        };
+        self.frame_packet(packet, is_client)
+    }
+
+    /// [`NetworkSteganographyProcessor::encode_write`], also returning the number of bytes
+    /// of data carried by the packets once compressed
+    fn encode_message(
+        &self,
+        data: &[u8],
+        is_client: bool,
+        options: EncodeOptions,
+    ) -> Result<(EncodeResult, usize)> {
+        let timing = options.timing.clone();
+        let (encoded_chunks, carried) = self.encode_chunks(data, is_client, options)?;
+        let total_chunks = encoded_chunks.len();
+
+        let mut packets = Vec::new();
+        let mut expected_lengths = Vec::new();
+
+        for (i, chunk) in encoded_chunks.iter().enumerate() {
+            let expected_length = chunk.packet_info.expected_return_length;
+            let packet = self.build_packet(chunk, is_client)?;
 
             let pl = packet.len();
 
@@ -624,11 +1828,135 @@ impl NetworkSteganographyProcessor for Rainbow {
             data.len()
         );
 
-        Ok(EncodeResult {
-            encoded_packets: packets,
-            expected_return_packet_lengths: expected_lengths,
+        Ok((
+            EncodeResult {
+                encoded_packets: packets,
+                expected_return_packet_lengths: expected_lengths,
+                send_delays: timing.delays(total_chunks, &mut rand::thread_rng()),
+            },
+            carried,
+        ))
+    }
+
+    pub fn analyze_bandwidth(
+        &self,
+        data: &[u8],
+        mime_type: Option<String>,
+    ) -> Result<StegoBandwidthStats> {
+        self.analyze_bandwidth_with_options(
+            data,
+            EncodeOptions {
+                mime_type,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Bandwidth of the packets encoding `data` as a request with `options`, such as a
+    /// [`Compression`](crate::compression::Compression)
+    pub fn analyze_bandwidth_with_options(
+        &self,
+        data: &[u8],
+        options: EncodeOptions,
+    ) -> Result<StegoBandwidthStats> {
+        let (
+            EncodeResult {
+                encoded_packets,
+                expected_return_packet_lengths,
+                ..
+            },
+            compressed_size,
+        ) = self.encode_message(data, true, options)?;
+
+        let stats = StegoBandwidthStats {
+            original_size: data.len(),
+            compressed_size,
+            total_packet_size: encoded_packets.iter().map(|p| p.len()).sum(),
+            packet_count: encoded_packets.len(),
+            expected_return_size: expected_return_packet_lengths.iter().sum(),
+        };
+
+        Ok(stats)
+    }
+
+    pub fn analyze_bandwidth_range(
+        &self,
+        sizes: &[usize],
+        mime_type: Option<String>,
+    ) -> Result<Vec<StegoBandwidthStats>> {
+        let mut results = Vec::new();
+
+        for &size in sizes {
+            let test_data = vec![0u8; size];
+            let stats = self.analyze_bandwidth(&test_data, mime_type.clone())?;
+            results.push(stats);
+        }
+
+        Ok(results)
+    }
+
+    /// Encode a message as the events of a `text/event-stream` response, one per packet, see
+    /// [`sse`]. Their data is JSON, and their id the sealed packet info
+    pub fn encode_events(&self, data: &[u8], options: EncodeOptions) -> Result<Vec<Event>> {
+        let options = if options.mime_type.is_none() && options.encoder.is_none() {
+            EncodeOptions {
+                mime_type: Some(sse::EVENT_DATA_MIME_TYPE.to_string()),
+                ..options
+            }
+        } else {
+            options
+        };
+        let (encoded_chunks, _) = self.encode_chunks(data, false, options)?;
+
+        encoded_chunks
+            .into_iter()
+            .map(|chunk| {
+                let EncodedChunk {
+                    packet_info,
+                    mime,
+                    encoded,
+                } = chunk;
+                if mime != sse::EVENT_DATA_MIME_TYPE {
+                    return Err(RainbowError::InvalidData(format!(
+                        "Events cannot carry {}",
+                        mime
+                    )));
+                }
+                // 数据按换行拆成多个 data 行，单独的回车也会被当作换行
+                let data = String::from_utf8(encoded)
+                    .ok()
+                    .filter(|data| !data.contains('\r'))
+                    .ok_or_else(|| RainbowError::EncodeFailed("Invalid event data".to_string()))?;
+                Ok(Event::new(packet_info.to_cookie(&self.cookie_key)?, data))
+            })
+            .collect()
+    }
+
+    /// Decode an event received in a `text/event-stream` response, see
+    /// [`Rainbow::encode_events`]
+    pub fn decode_event(&self, event: &Event, packet_index: usize) -> Result<DecodeResult> {
+        let id = event
+            .id
+            .as_deref()
+            .ok_or_else(|| RainbowError::InvalidData("Event without id".to_string()))?;
+        let (info, nonce) = PacketInfo::from_cookie(id, &self.cookie_key)?;
+        self.decode_packet(info, nonce, packet_index, || {
+            self.registry
+                .decode_mime(event.data.as_bytes(), sse::EVENT_DATA_MIME_TYPE)
         })
     }
+}
+
+impl NetworkSteganographyProcessor for Rainbow {
+    fn encode_write(
+        &self,
+        data: &[u8],
+        is_client: bool,
+        options: EncodeOptions,
+    ) -> Result<EncodeResult> {
+        self.encode_message(data, is_client, options)
+            .map(|(result, _)| result)
+    }
 
     fn decrypt_single_read(
         &self,
@@ -638,94 +1966,278 @@ impl NetworkSteganographyProcessor for Rainbow {
     ) -> Result<DecodeResult> {
         debug!("Decoding packet of {} bytes", data.len());
 
-        // 验证数据包
-        validate_http_packet(&data)?;
-
-        // 解码数据包
-        let decoded = self.decode_single_packet(&data, packet_index)?;
+        // HTTP/2 数据包先还原为 HTTP/1.1
+        let data = if h2::is_h2(&data) {
+            let message = h2::decode_message(&data)?;
+            self.h2_connection
+                .lock()
+                .unwrap()
+                .receive(&message, is_client);
+            message.http1
+        } else {
+            data
+        };
 
-        // 解析 HTTP 头以获取包信息
-        let mut total_packets = None;
-        let mut expected_length = 0;
+        // 先验证包信息，再解码数据包
+        // WebSocket 帧直接携带包信息和编码后的数据
+        let (info, nonce, frame) = if websocket::is_websocket(&data) {
+            let (info, nonce, mime, encoded) = self.read_websocket_packet(&data, is_client)?;
+            (info, nonce, Some((mime, encoded)))
+        } else {
+            // 验证数据包
+            validate_http_packet(&data)?;
 
-        // 检查是否为响应
-        let is_response = data.starts_with(b"HTTP/1.1");
+            // 检查是否为响应
+            let is_response = data.starts_with(b"HTTP/1.1");
 
-        // 验证请求/响应类型与 is_client 是否匹配
-        if is_client {
-            if is_response {
+            // 验证请求/响应类型与 is_client 是否匹配
+            if is_client {
+                if is_response {
+                    return Err(RainbowError::InvalidData(
+                        "Client should not receive responses".to_string(),
+                    ));
+                }
+            } else if !is_response {
                 return Err(RainbowError::InvalidData(
-                    "Client should not receive responses".to_string(),
+                    "Server should not receive requests".to_string(),
                 ));
             }
-        } else if !is_response {
-            return Err(RainbowError::InvalidData(
-                "Server should not receive requests".to_string(),
-            ));
-        }
 
-        // 从 Cookie 中获取包信息
-        let mut headers = HeaderMap::new();
+            let (info, nonce) = self.read_packet_info(&data, is_client)?;
+            (info, nonce, None)
+        };
 
-        let split_pos = find_crlf_crlf(&data).ok_or_else(|| {
-            RainbowError::InvalidData(HTTP_CONSTANTS.error_details[3].1.to_string())
+        // 解码数据包，长度为 0 的数据包只携带掩护数据
+        let result = self.decode_packet(info, nonce, packet_index, || match &frame {
+            Some((mime, encoded)) => self.registry.decode_mime(encoded, mime),
+            None => self.decode_single_packet(&data, packet_index),
         })?;
-        let header_part = String::from_utf8_lossy(&data[..split_pos]);
 
-        for line in header_part.lines() {
-            if is_client {
-                if line.to_lowercase().starts_with("cookie:") {
-                    if let Ok(value) = HeaderValue::from_str(line[7..].trim()) {
-                        headers.append(COOKIE, value);
-                    }
-                }
+        // 请求的路径决定响应的类型
+        let reply_mime_type = if is_client && frame.is_none() {
+            let request_line = data.split(|&b| b == b'\r').next().unwrap_or_default();
+            let request_line = String::from_utf8_lossy(request_line);
+            let mut parts = request_line.split(' ');
+            match (parts.next(), parts.next()) {
+                (Some(method), Some(path)) => self
+                    .site_map
+                    .reply_mime_type(method, path)
+                    .map(String::from),
+                _ => None,
+            }
+        } else {
+            None
+        };
+        Ok(DecodeResult {
+            reply_mime_type,
+            ..result
+        })
+    }
+
+    fn encode_reply(
+        &self,
+        data: &[u8],
+        is_client: bool,
+        target_length: usize,
+        options: EncodeOptions,
+    ) -> Result<ReplyResult> {
+        let encoder = options
+            .encoder
+            .as_deref()
+            .and_then(|name| self.registry.get(name));
+
+        let mime_types = if let Some(mime) = options.mime_type.clone() {
+            vec![mime]
+        } else if let Some(encoder) = encoder {
+            vec![encoder.get_mime_type().to_string()]
+        } else {
+            let mut mime_types: Vec<String> = if self.websocket.is_some() {
+                WEBSOCKET_MIME_TYPES.map(String::from).to_vec()
             } else {
-                if line.to_lowercase().starts_with("set-cookie:") {
-                    if let Ok(value) = HeaderValue::from_str(line[12..].trim()) {
-                        headers.append(SET_COOKIE, value);
-                    }
+                self.registry
+                    .get_all_mime_types()
+                    .into_iter()
+                    .map(String::from)
+                    .collect()
+            };
+            mime_types.shuffle(&mut rand::thread_rng());
+            // 优先使用请求所要求的类型
+            if let Some(preferred) = &options.preferred_mime_type {
+                if let Some(i) = mime_types.iter().position(|mime| mime == preferred) {
+                    let preferred = mime_types.remove(i);
+                    mime_types.insert(0, preferred);
                 }
             }
+            mime_types
+        };
+
+        let expected_return_length = Self::random_return_length(is_client);
+        let packet_info = PacketInfo {
+            session_id: self.session_id,
+            stream_id: options.stream_id,
+            seq: options
+                .seq
+                .unwrap_or_else(|| self.next_seq(options.stream_id)),
+            expected_return_length,
+            ..PacketInfo::new(0, 1, 0)
         }
+        .with_ack(options.ack);
 
-        // 使用 parse_cookies 解析所有 cookie
-        for cookie in if is_client {
-            Rainbow::parse_cookies(&headers)
+        // 优先携带数据，放不下时只携带掩护数据
+        let payloads: &[&[u8]] = if data.is_empty() {
+            &[&[]]
         } else {
-            Rainbow::parse_set_cookies(&headers)
-        } {
-            if let Some((name, value)) = cookie.split_once('=') {
-                // debug!("name: {:?}, value: {:?}", name, value);
-                if HTTP_CONSTANTS.cookie_names.contains(&name.trim()) {
-                    if let Ok(info) = PacketInfo::from_cookie(value.trim()) {
-                        total_packets = Some(info.total);
-                        expected_length = info.length;
-
-                        // 验证数据包长度
-                        break;
+            &[data, &[]]
+        };
+        for payload in payloads {
+            for mime in &mime_types {
+                // 一种编码器放不下时换同类型的其他编码器，以免放弃所要求的类型
+                let encoders = match encoder {
+                    Some(encoder) => vec![encoder],
+                    None => {
+                        let mut encoders = self.registry.encoders_for(mime);
+                        encoders.shuffle(&mut rand::thread_rng());
+                        encoders
+                    }
+                };
+                for encoder in encoders {
+                    if let Some((packet, consumed)) = self.build_sized_packet(
+                        payload,
+                        is_client,
+                        target_length,
+                        mime,
+                        encoder,
+                        packet_info.clone(),
+                    )? {
+                        return Ok(ReplyResult {
+                            packet,
+                            consumed,
+                            expected_return_length,
+                        });
                     }
                 }
             }
         }
 
-        let total = total_packets.ok_or_else(|| {
-            // debug!("headers: {:?}", headers);
-            RainbowError::InvalidData(
-                "decrypt_single_read: Could not find valid packet info in cookies".to_string(),
-            )
-        })?;
+        Err(RainbowError::InvalidData(format!(
+            "Cannot build a packet of {} bytes",
+            target_length
+        )))
+    }
 
-        let is_read_end = packet_index + 1 >= total;
+    fn encode_cover(&self, is_client: bool, options: EncodeOptions) -> Result<Vec<u8>> {
+        let mut rng = rand::thread_rng();
+        let (mime, encoder) = self.select_encoder(&options, is_client)?;
+
+        // 掩护数据与数据块一样长，不超过一个完整的数据块
+        let chunk_len = options.chunking.chunk_len(encoder, &mut rng).max(1);
+        let cover: Vec<u8> = (0..rng.gen_range(1..=chunk_len))
+            .map(|_| rng.gen())
+            .collect();
+        let packet_info = PacketInfo {
+            flags: FLAG_COVER,
+            session_id: self.session_id,
+            stream_id: options.stream_id,
+            seq: rng.gen(),
+            expected_return_length: Self::random_return_length(is_client),
+            ..PacketInfo::new(0, 1, cover.len())
+        }
+        .with_ack(options.ack);
 
-        info!("Successfully decoded {} bytes from packet", decoded.len());
-        Ok(DecodeResult {
-            data: decoded,
-            expected_return_length: expected_length,
-            is_read_end,
+        debug!("Encoding cover packet of {} bytes", cover.len());
+        let chunk = EncodedChunk {
+            packet_info,
+            mime,
+            encoded: encoder.encode(&cover)?,
+        };
+        self.build_packet(&chunk, is_client)
+    }
+
+    fn for_connection(&self) -> Box<dyn NetworkSteganographyProcessor> {
+        Box::new(Self {
+            next_seqs: Arc::default(),
+            h2_connection: Arc::default(),
+            websocket: self.websocket.as_ref().map(|_| Arc::default()),
+            ..self.clone()
         })
     }
 }
 
+/// The largest `n` in `1..=max` whose probe `fits`, with the output of that probe, stopping
+/// early at a probe of exactly `target_length`.
+///
+/// `probe(n)` returns its output and the length it amounts to, which grows with `n`
+fn search_largest_fit<T>(
+    max: usize,
+    target_length: usize,
+    probe: impl Fn(usize) -> Result<(T, usize)>,
+    fits: impl Fn(usize) -> bool,
+) -> Result<Option<(T, usize)>> {
+    let mut left = 1;
+    let mut right = max;
+    let mut best = None;
+
+    // 先倍增找到上界，有些编码器的膨胀率很高，不能从目标长度开始二分
+    let mut n = 1;
+    while n <= right {
+        let (output, len) = probe(n)?;
+        if !fits(len) {
+            right = n - 1;
+            break;
+        }
+        best = Some((output, n));
+        if len == target_length {
+            right = 0;
+            break;
+        }
+        left = n + 1;
+        n *= 2;
+    }
+
+    while left <= right {
+        let mid = (left + right) / 2;
+        let (output, len) = probe(mid)?;
+        if !fits(len) {
+            right = mid - 1;
+            continue;
+        }
+        best = Some((output, mid));
+        if len == target_length {
+            break;
+        }
+        left = mid + 1;
+    }
+
+    Ok(best)
+}
+
+/// The body of a packet, compressed with `content_encoding` if any
+fn encode_body(data: &[u8], content_encoding: Option<ContentEncoding>) -> Result<Vec<u8>> {
+    match content_encoding {
+        Some(encoding) => encoding.encode(data),
+        None => Ok(data.to_vec()),
+    }
+}
+
+/// The headers announcing a compressed body, empty without `content_encoding`
+fn content_encoding_header(content_encoding: Option<ContentEncoding>, is_request: bool) -> String {
+    match content_encoding {
+        Some(encoding) if is_request => format!("Content-Encoding: {}\r\n", encoding.name()),
+        Some(encoding) => format!(
+            "Content-Encoding: {}\r\nVary: Accept-Encoding\r\n",
+            encoding.name()
+        ),
+        None => String::new(),
+    }
+}
+
+/// Random bytes carried by packets that have no data, as many as `encoder` can carry
+fn random_cover_data(encoder: &dyn Encoder) -> Vec<u8> {
+    let max = encoder.capacity().max_data_len.unwrap_or(usize::MAX);
+    let len = rand::thread_rng().gen_range(16..128).min(max);
+    (0..len).map(|_| rand::random()).collect()
+}
+
 fn build_final_packet(headers: &str, encoded: &[u8]) -> Result<Vec<u8>> {
     let mut packet = headers
         .replace("0000000000\r\n\r\n", &format!("{}\r\n\r\n", encoded.len()))
@@ -734,55 +2246,27 @@ fn build_final_packet(headers: &str, encoded: &[u8]) -> Result<Vec<u8>> {
     Ok(packet)
 }
 
-fn generate_cookie_header(packet_info: &PacketInfo, is_request: bool) -> Result<String> {
-    let cookie_name = HTTP_CONSTANTS.cookie_names
-        [rand::thread_rng().gen_range(0..HTTP_CONSTANTS.cookie_names.len())];
-    let cookie_value = packet_info.to_cookie()?;
-
-    let mut cookies = vec![
-        format!("{}={}", cookie_name, cookie_value),
-        format!("sid={}", uuid::Uuid::new_v4()),
-    ];
-
-    // 添加随机常见 cookie
-    if rand::random::<bool>() {
-        cookies.push(format!(
-            "_ga=GA1.2.{}.{}",
-            rand::thread_rng().gen::<u32>(),
-            rand::thread_rng().gen::<u32>()
-        ));
-    }
-
-    let cookie_str = cookies.join("; ");
-    Ok(if is_request {
-        format!("Cookie: {}\r\n", cookie_str)
-    } else {
-        format!("Set-Cookie: {}\r\n", cookie_str)
-    })
-}
-
 const PADDING_HEADER: &str = "COOKIE2: ";
 const PADDING_HEADER_LEN: usize = PADDING_HEADER.len();
 
+/// The padding header and its line ending
+const MIN_PADDING_LEN: usize = PADDING_HEADER_LEN + 2;
+
+/// Grow a packet by exactly `padding_len` bytes with a padding header
 fn add_padding_to_packet(packet: &mut Vec<u8>, padding_len: usize) -> Result<()> {
-    if padding_len < PADDING_HEADER_LEN {
+    if padding_len < MIN_PADDING_LEN {
         return Err(RainbowError::InvalidData(format!(
             "Padding length {} is too small for header (min {})",
-            padding_len, PADDING_HEADER_LEN
+            padding_len, MIN_PADDING_LEN
         )));
     }
 
-    // 计算需要的原始随机字节数
-    // 由于 base64 编码会将每 3 个字节编码为 4 个字符
-    // 我们需要计算能得到目标长度的原始字节数
-    let target_base64_len = padding_len - PADDING_HEADER_LEN;
-    let raw_bytes_len = (target_base64_len * 3) / 4;
-
-    // 生成随机字节
-    let random_bytes: Vec<u8> = (0..raw_bytes_len).map(|_| rand::random()).collect();
-
-    // 进行 base64 编码
-    let padding = BASE64.encode(&random_bytes);
+    // 填充值的长度正好补齐目标长度
+    let padding: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(padding_len - MIN_PADDING_LEN)
+        .map(char::from)
+        .collect();
 
     if let Some(pos) = find_crlf_crlf(packet) {
         let mut new_packet = packet[..pos].to_vec();
@@ -802,7 +2286,14 @@ fn add_padding_to_packet(packet: &mut Vec<u8>, padding_len: usize) -> Result<()>
 
 #[cfg(test)]
 mod tests {
-    use crate::{utils::data_find, EncodeResult};
+    use crate::{
+        chunking::{ChunkingStrategy, PacketSizeDistribution, DEFAULT_CHUNK_SIZE},
+        compression::Compression,
+        session::DecodeSession,
+        timing::TimingModel,
+        utils::{data_find, http_message_len},
+        EncodeResult,
+    };
 
     use super::*;
 
@@ -871,6 +2362,7 @@ mod tests {
         let EncodeResult {
             encoded_packets: packets,
             expected_return_packet_lengths: lengths,
+            ..
         } = rainbow
             .encode_write(test_data, true, EncodeOptions::default())
             .unwrap();
@@ -880,18 +2372,51 @@ mod tests {
         assert!(lengths[0] >= 200 && lengths[0] <= 8000);
     }
 
+    #[test]
+    fn test_timing_send_delays() {
+        init();
+        let rainbow = Rainbow::new();
+        let encode = |timing| {
+            rainbow
+                .encode_write(
+                    &[0u8; 1000],
+                    true,
+                    EncodeOptions {
+                        encoder: Some("octet".to_string()),
+                        chunking: ChunkingStrategy::Fixed(100),
+                        timing,
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+                .send_delays
+        };
+
+        // 默认立即发送
+        assert_eq!(encode(TimingModel::default()), vec![Duration::ZERO; 10]);
+
+        let send_delays = encode(TimingModel::browsing());
+        assert_eq!(send_delays.len(), 10);
+        assert!(send_delays[0] >= Duration::from_millis(200));
+    }
+
     #[test]
     fn test_encode_write_large_data() {
         init();
         let rainbow = Rainbow::new();
+        let fixed = EncodeOptions {
+            chunking: ChunkingStrategy::Fixed(DEFAULT_CHUNK_SIZE),
+            ..Default::default()
+        };
 
         // Test case 1: Data size exactly two chunks
-        let test_data_exact = vec![0u8; CHUNK_SIZE * 2];
+        let test_data_exact = vec![0u8; DEFAULT_CHUNK_SIZE * 2];
         let EncodeResult {
             encoded_packets: packets_exact,
             expected_return_packet_lengths: lengths_exact,
+            ..
         } = rainbow
-            .encode_write(&test_data_exact, true, EncodeOptions::default())
+            .encode_write(&test_data_exact, true, fixed.clone())
             .unwrap();
         assert_eq!(
             packets_exact.len(),
@@ -901,12 +2426,13 @@ mod tests {
         assert_eq!(lengths_exact.len(), 2);
 
         // Test case 2: Data size slightly over two chunks
-        let test_data_over = vec![0u8; CHUNK_SIZE * 2 + 100];
+        let test_data_over = vec![0u8; DEFAULT_CHUNK_SIZE * 2 + 100];
         let EncodeResult {
             encoded_packets: packets_over,
             expected_return_packet_lengths: lengths_over,
+            ..
         } = rainbow
-            .encode_write(&test_data_over, true, EncodeOptions::default())
+            .encode_write(&test_data_over, true, fixed.clone())
             .unwrap();
         assert_eq!(
             packets_over.len(),
@@ -916,12 +2442,13 @@ mod tests {
         assert_eq!(lengths_over.len(), 3);
 
         // Test case 3: Data size just under three chunks
-        let test_data_under = vec![0u8; CHUNK_SIZE * 3 - 50];
+        let test_data_under = vec![0u8; DEFAULT_CHUNK_SIZE * 3 - 50];
         let EncodeResult {
             encoded_packets: packets_under,
             expected_return_packet_lengths: _,
+            ..
         } = rainbow
-            .encode_write(&test_data_under, true, EncodeOptions::default())
+            .encode_write(&test_data_under, true, fixed.clone())
             .unwrap();
         assert_eq!(
             packets_under.len(),
@@ -948,12 +2475,13 @@ mod tests {
         }
 
         // Test case 4: Very large data
-        let test_data_large = vec![0u8; CHUNK_SIZE * 5 + 233]; // 5 chunks plus some extra
+        let test_data_large = vec![0u8; DEFAULT_CHUNK_SIZE * 5 + 233]; // 5 chunks plus some extra
         let EncodeResult {
             encoded_packets: packets_large,
             expected_return_packet_lengths: lengths_large,
+            ..
         } = rainbow
-            .encode_write(&test_data_large, true, EncodeOptions::default())
+            .encode_write(&test_data_large, true, fixed.clone())
             .unwrap();
         assert_eq!(packets_large.len(), 6);
         assert_eq!(lengths_large.len(), 6);
@@ -966,48 +2494,540 @@ mod tests {
             );
         }
 
-        // Test case 5: Different MIME types with large data
-        let mime_types = vec!["text/html", "application/json", "application/octet-stream"];
-        for mime_type in mime_types {
-            let EncodeResult {
-                encoded_packets: packets_mime,
-                expected_return_packet_lengths: _,
-            } = rainbow
-                .encode_write(
-                    &test_data_over,
-                    true,
-                    EncodeOptions {
-                        mime_type: Some(mime_type.to_string()),
-                        ..Default::default()
-                    },
-                )
+        // Test case 5: Different MIME types with large data
+        let mime_types = vec!["text/html", "application/json", "application/octet-stream"];
+        for mime_type in mime_types {
+            let EncodeResult {
+                encoded_packets: packets_mime,
+                expected_return_packet_lengths: _,
+                ..
+            } = rainbow
+                .encode_write(
+                    &test_data_over,
+                    true,
+                    EncodeOptions {
+                        mime_type: Some(mime_type.to_string()),
+                        ..fixed.clone()
+                    },
+                )
+                .unwrap();
+
+            // Verify MIME type specific behavior
+            let first_packet = String::from_utf8_lossy(&packets_mime[0]);
+            if mime_type == "text/html" {
+                // HTML 作为文件上传
+                assert!(first_packet.starts_with("POST /upload/"));
+                assert!(first_packet.contains("Content-Type: multipart/form-data; boundary="));
+                assert!(first_packet.contains("Content-Type: text/html\r\n"));
+            } else {
+                assert!(first_packet.starts_with("POST /api/"));
+                assert!(first_packet.contains(&format!("Content-Type: {}", mime_type)));
+            }
+        }
+
+        // Test case 6: Verify packet info in cookies
+        let first_packet = String::from_utf8_lossy(&packets_large[0]);
+        assert!(first_packet.contains("Cookie: "));
+        let cookie_line = first_packet
+            .lines()
+            .find(|line| line.starts_with("Cookie: "))
+            .unwrap();
+
+        // Cookie should contain packet info
+        let cookie_value = cookie_line.trim_start_matches("Cookie: ");
+        assert!(HTTP_CONSTANTS
+            .cookie_names
+            .iter()
+            .any(|&name| cookie_value.contains(name)));
+    }
+
+    #[test]
+    fn test_adaptive_chunking() {
+        init();
+        let rainbow = Rainbow::from_secret(b"chunking");
+        // 随机数据无法压缩，数据包大小只取决于切分
+        let data: Vec<u8> = (0..100_000).map(|_| rand::random()).collect();
+
+        for encoder in ["octet", "cfg"] {
+            let options = EncodeOptions {
+                encoder: Some(encoder.to_string()),
+                chunking: ChunkingStrategy::Adaptive(PacketSizeDistribution::uniform(4_000..8_000)),
+                ..Default::default()
+            };
+            let EncodeResult {
+                encoded_packets: packets,
+                ..
+            } = rainbow
+                .encode_write(&data[..20_000], true, options)
+                .unwrap();
+
+            // 数据包大小接近目标分布，而不是由固定的块大小决定
+            for packet in &packets[..packets.len() - 1] {
+                assert!(
+                    (2_000..12_000).contains(&packet.len()),
+                    "{} packet of {} bytes",
+                    encoder,
+                    packet.len()
+                );
+            }
+
+            let mut session = DecodeSession::new(Box::new(rainbow.clone()), true);
+            let mut decoded = None;
+            for packet in packets {
+                decoded = session.feed(packet).unwrap().or(decoded);
+            }
+            assert_eq!(decoded.unwrap(), &data[..20_000]);
+        }
+
+        // 紧凑的编码器用更少的数据包传输大量数据
+        let EncodeResult {
+            encoded_packets: packets,
+            ..
+        } = rainbow
+            .encode_write(
+                &data,
+                true,
+                EncodeOptions {
+                    encoder: Some("octet".to_string()),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert!(packets.len() < data.len() / DEFAULT_CHUNK_SIZE / 4);
+    }
+
+    #[test]
+    fn test_compression() {
+        init();
+        let rainbow = Rainbow::from_secret(b"compression");
+        let text = b"<p>The quick brown fox jumps over the lazy dog.</p>\n".repeat(40);
+
+        let encode = |compression| {
+            rainbow
+                .encode_write(
+                    &text,
+                    true,
+                    EncodeOptions {
+                        encoder: Some("html".to_string()),
+                        chunking: ChunkingStrategy::Fixed(4096),
+                        compression,
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+                .encoded_packets
+        };
+        let plain = encode(Compression::None);
+        let compressed = encode(Compression::Deflate);
+        assert_eq!(compressed.len(), 1);
+        assert!(compressed[0].len() * 2 < plain.iter().map(Vec::len).sum());
+        assert!(encode(Compression::Zstd)[0].len() * 2 < plain.iter().map(Vec::len).sum());
+
+        let decoded = rainbow
+            .decrypt_single_read(compressed[0].clone(), 0, true)
+            .unwrap();
+        assert_eq!(decoded.data, text);
+
+        let stats = rainbow
+            .analyze_bandwidth_with_options(
+                &text,
+                EncodeOptions {
+                    mime_type: Some("text/html".to_string()),
+                    compression: Compression::Deflate,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(stats.original_size, text.len());
+        assert!(stats.compressed_size * 4 < stats.original_size);
+
+        // 整个消息压缩后再切分，由会话重组后解压
+        let packets = rainbow
+            .encode_write(
+                &text,
+                true,
+                EncodeOptions {
+                    encoder: Some("html".to_string()),
+                    chunking: ChunkingStrategy::Fixed(64),
+                    compression: Compression::Zstd,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .encoded_packets;
+        assert!(packets.len() > 1);
+        let mut session = DecodeSession::new(Box::new(rainbow.clone()), false);
+        let mut message = None;
+        for packet in packets.into_iter().rev() {
+            let result = rainbow.decrypt_single_read(packet, 0, true).unwrap();
+            assert!(result.is_compressed);
+            message = session.accept(result).unwrap();
+        }
+        assert_eq!(message.unwrap(), text);
+    }
+
+    #[test]
+    fn test_chunked_transfer_encoding() {
+        init();
+        let sender =
+            Rainbow::from_secret(b"chunked").with_transfer_encoding(TransferEncoding::Chunked);
+        let receiver = Rainbow::from_secret(b"chunked");
+        let data: Vec<u8> = (0..20_000).map(|_| rand::random()).collect();
+
+        let packets = sender
+            .encode_write(
+                &data,
+                false,
+                EncodeOptions {
+                    encoder: Some("octet".to_string()),
+                    chunking: ChunkingStrategy::Fixed(data.len()),
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .encoded_packets;
+        assert_eq!(packets.len(), 1);
+        let packet = &packets[0];
+        assert!(data_find(packet, b"Transfer-Encoding: chunked\r\n").is_some());
+        assert!(data_find(packet, b"Content-Length").is_none());
+        assert!(packet.ends_with(b"\r\n0\r\n\r\n"));
+
+        // 流式传输按分块编码确定消息边界
+        assert_eq!(http_message_len(packet).unwrap(), Some(packet.len()));
+        assert_eq!(http_message_len(&packet[..packet.len() - 1]).unwrap(), None);
+
+        let decoded = receiver
+            .decrypt_single_read(packet.clone(), 0, false)
+            .unwrap();
+        assert_eq!(decoded.data, data);
+
+        // 分块编码的响应也能精确控制长度
+        for target_length in [1500, 5000, 7999] {
+            let reply = sender
+                .encode_reply(&data, false, target_length, EncodeOptions::default())
+                .unwrap();
+            assert_eq!(reply.packet.len(), target_length);
+            let decoded = receiver
+                .decrypt_single_read(reply.packet, 0, false)
+                .unwrap();
+            assert_eq!(decoded.data, &data[..reply.consumed]);
+        }
+
+        // 分块扩展和尾部字段
+        let body = b"4;name=value\r\nWiki\r\n5\r\npedia\r\n0\r\nExpires: never\r\n\r\nnext";
+        let (dechunked, len) = decode_chunked(body).unwrap().unwrap();
+        assert_eq!(dechunked, b"Wikipedia");
+        assert_eq!(len, body.len() - 4);
+        assert!(decode_chunked(&body[..body.len() - 6]).unwrap().is_none());
+        assert!(decode_chunked(b"zz\r\n").is_err());
+    }
+
+    #[test]
+    fn test_content_encoding() {
+        init();
+        let sender = Rainbow::from_secret(b"content-encoding");
+        let receiver = Rainbow::from_secret(b"content-encoding");
+        let data: Vec<u8> = (0..800).map(|_| rand::random()).collect();
+        let html = EncodeOptions {
+            mime_type: Some("text/html".to_string()),
+            chunking: ChunkingStrategy::Fixed(data.len()),
+            ..Default::default()
+        };
+
+        // 默认只压缩响应
+        let response = &sender
+            .encode_write(&data, false, html.clone())
+            .unwrap()
+            .encoded_packets[0];
+        let header = String::from_utf8_lossy(&response[..find_crlf_crlf(response).unwrap()]);
+        let encoding = find_header_value(&header, "Content-Encoding").unwrap();
+        assert!(["gzip", "deflate", "br"].contains(&encoding));
+        assert_eq!(find_header_value(&header, "Vary"), Some("Accept-Encoding"));
+        assert_eq!(http_message_len(response).unwrap(), Some(response.len()));
+        let decoded = receiver
+            .decrypt_single_read(response.clone(), 0, false)
+            .unwrap();
+        assert_eq!(decoded.data, data);
+
+        let request = &sender
+            .encode_write(&data, true, html.clone())
+            .unwrap()
+            .encoded_packets[0];
+        assert!(data_find(request, b"Content-Encoding").is_none());
+
+        let always = Rainbow::from_secret(b"content-encoding")
+            .with_content_encoding(ContentEncodingPolicy::Always);
+        let request = &always
+            .encode_write(&data, true, html.clone())
+            .unwrap()
+            .encoded_packets[0];
+        assert!(data_find(request, b"Content-Encoding: ").is_some());
+        let decoded = receiver
+            .decrypt_single_read(request.clone(), 0, true)
+            .unwrap();
+        assert_eq!(decoded.data, data);
+
+        // 压缩后的响应也能精确控制长度
+        for target_length in [1500, 5000] {
+            let reply = sender
+                .encode_reply(&data, false, target_length, html.clone())
+                .unwrap();
+            assert_eq!(reply.packet.len(), target_length);
+            // 204、304 响应的数据在头部字段中，没有消息体
+            let status = String::from_utf8_lossy(&reply.packet[9..12])
+                .parse()
+                .unwrap();
+            assert!(
+                !status::carries_data(status)
+                    || data_find(&reply.packet, b"Content-Encoding: ").is_some()
+            );
+            let decoded = receiver
+                .decrypt_single_read(reply.packet, 0, false)
+                .unwrap();
+            assert_eq!(decoded.data, &data[..reply.consumed]);
+        }
+
+        let never = Rainbow::from_secret(b"content-encoding")
+            .with_content_encoding(ContentEncodingPolicy::Never);
+        let response = &never
+            .encode_write(&data, false, html)
+            .unwrap()
+            .encoded_packets[0];
+        assert!(data_find(response, b"Content-Encoding").is_none());
+    }
+
+    #[test]
+    fn test_h2c_framing() {
+        init();
+        let client = Rainbow::from_secret(b"h2c").with_http_version(HttpVersion::H2c);
+        let server = Rainbow::from_secret(b"h2c").with_http_version(HttpVersion::H2c);
+        let options = EncodeOptions {
+            encoder: Some("octet".to_string()),
+            ..Default::default()
+        };
+
+        // 第一个请求带有连接前言，请求依次使用奇数流
+        for (stream_id, message) in [(1, &b"first"[..]), (3, b"second")] {
+            let packets = client
+                .encode_write(message, true, options.clone())
+                .unwrap()
+                .encoded_packets;
+            let packet = &packets[0];
+            assert_eq!(packet.starts_with(h2::PREFACE), stream_id == 1);
+            assert_eq!(http_message_len(packet).unwrap(), Some(packet.len()));
+            assert_eq!(h2::decode_message(packet).unwrap().stream_id, stream_id);
+
+            let decoded = server.decrypt_single_read(packet.clone(), 0, true).unwrap();
+            assert_eq!(decoded.data, message);
+        }
+
+        // 响应按顺序回答请求的流，并由 DATA 帧的填充补齐长度
+        for (stream_id, target_length) in [(1, 1500), (3, 6000)] {
+            let reply = server
+                .encode_reply(b"reply", false, target_length, options.clone())
+                .unwrap();
+            assert_eq!(reply.packet.len(), target_length);
+            let message = h2::decode_message(&reply.packet).unwrap();
+            assert_eq!(message.stream_id, stream_id);
+            assert_eq!(message.settings, stream_id == 1);
+
+            let decoded = client.decrypt_single_read(reply.packet, 0, false).unwrap();
+            assert_eq!(decoded.data, b"reply");
+        }
+
+        // HTTP/1.1 的对端照样能解码
+        let packet = &client
+            .encode_write(b"third", true, EncodeOptions::default())
+            .unwrap()
+            .encoded_packets[0];
+        let decoded = Rainbow::from_secret(b"h2c")
+            .decrypt_single_read(packet.clone(), 0, true)
+            .unwrap();
+        assert_eq!(decoded.data, b"third");
+    }
+
+    #[test]
+    fn test_for_connection() {
+        init();
+        let client = Rainbow::from_secret(b"connection").with_http_version(HttpVersion::H2c);
+        let server = Rainbow::from_secret(b"connection");
+        let send = |processor: &dyn NetworkSteganographyProcessor, message: &[u8]| {
+            let packet = processor
+                .encode_write(message, true, EncodeOptions::default())
+                .unwrap()
+                .encoded_packets
+                .remove(0);
+            let seq = server
+                .decrypt_single_read(packet.clone(), 0, true)
+                .unwrap()
+                .seq;
+            (packet.starts_with(h2::PREFACE), seq)
+        };
+
+        assert_eq!(send(&client, b"first"), (true, 0));
+        assert_eq!(send(&client.clone(), b"second"), (false, 1));
+
+        // 新连接重新发送连接前言，序号从 0 开始，不影响原来的连接
+        let connection = client.for_connection();
+        assert_eq!(send(connection.as_ref(), b"first"), (true, 0));
+        assert_eq!(send(connection.as_ref(), b"second"), (false, 1));
+        assert_eq!(send(&client, b"third"), (false, 2));
+
+        let websocket = Rainbow::from_secret(b"connection").with_websocket();
+        for processor in [
+            websocket.clone().for_connection(),
+            websocket.for_connection(),
+        ] {
+            let packet = processor
+                .encode_write(b"first", true, EncodeOptions::default())
+                .unwrap()
+                .encoded_packets
+                .remove(0);
+            assert!(packet.starts_with(b"GET "));
+        }
+    }
+
+    #[test]
+    fn test_websocket_carrier() {
+        init();
+        let client = Rainbow::from_secret(b"websocket").with_websocket();
+        let server = Rainbow::from_secret(b"websocket").with_websocket();
+        let json = EncodeOptions {
+            encoder: Some("json".to_string()),
+            ..Default::default()
+        };
+        let octet = EncodeOptions {
+            encoder: Some("octet".to_string()),
+            ..Default::default()
+        };
+
+        // 第一个请求是握手加上第一个帧，之后只有帧，客户端的帧带掩码
+        for (i, (message, options)) in [(&b"first"[..], &json), (b"second", &octet)]
+            .into_iter()
+            .enumerate()
+        {
+            let packets = client
+                .encode_write(message, true, options.clone())
+                .unwrap()
+                .encoded_packets;
+            let packet = &packets[0];
+            assert_eq!(packet.starts_with(b"GET "), i == 0);
+            assert_eq!(websocket::is_frame(packet), i > 0);
+            assert_eq!(http_message_len(packet).unwrap(), Some(packet.len()));
+
+            let decoded = server.decrypt_single_read(packet.clone(), 0, true).unwrap();
+            assert_eq!(decoded.data, message);
+        }
+
+        // 第一个响应带有 101 应答，之后的响应精确到字节
+        for (i, target_length) in [1500, 1000, 6000, 70_000].into_iter().enumerate() {
+            for (j, options) in [&json, &octet].into_iter().enumerate() {
+                let reply = server
+                    .encode_reply(b"reply", false, target_length, options.clone())
+                    .unwrap();
+                assert_eq!(reply.packet.len(), target_length);
+                assert_eq!(
+                    reply
+                        .packet
+                        .starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"),
+                    i == 0 && j == 0
+                );
+
+                let decoded = client.decrypt_single_read(reply.packet, 0, false).unwrap();
+                assert_eq!(decoded.data, b"reply");
+            }
+        }
+
+        // 最短的二进制帧，帧头已是 4 字节
+        for target_length in 130..=140 {
+            let reply = server
+                .encode_reply(&[], false, target_length, octet.clone())
                 .unwrap();
+            assert_eq!(reply.packet.len(), target_length);
+            client.decrypt_single_read(reply.packet, 0, false).unwrap();
+        }
+        assert!(server.encode_reply(&[], false, 129, octet.clone()).is_err());
+
+        // 大消息分成多个帧
+        let data: Vec<u8> = (0..20_000).map(|_| rand::random()).collect();
+        let packets = client
+            .encode_write(&data, true, EncodeOptions::default())
+            .unwrap()
+            .encoded_packets;
+        let mut decoded = Vec::new();
+        for (i, packet) in packets.into_iter().enumerate() {
+            assert!(websocket::is_frame(&packet));
+            decoded.extend(server.decrypt_single_read(packet, i, true).unwrap().data);
+        }
+        assert_eq!(decoded, data);
 
-            // Verify MIME type specific behavior
-            let first_packet = String::from_utf8_lossy(&packets_mime[0]);
-            if mime_type == "text/plain" || mime_type == "application/json" {
-                assert!(first_packet.contains("GET "));
-                assert!(first_packet.contains("X-Data: "));
+        // 只有 JSON 和二进制数据能放进帧里
+        let html = EncodeOptions {
+            mime_type: Some("text/html".to_string()),
+            ..Default::default()
+        };
+        assert!(client.encode_write(b"html", true, html).is_err());
+    }
+
+    #[test]
+    fn test_encode_reply_exact_length() {
+        init();
+        let rainbow = Rainbow::from_secret(b"reply");
+        let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+
+        for is_client in [true, false] {
+            let range = if is_client {
+                REQUEST_LENGTH_RANGE
             } else {
-                assert!(first_packet.contains("POST "));
-                assert!(first_packet.contains(&format!("Content-Type: {}", mime_type)));
+                RESPONSE_LENGTH_RANGE
+            };
+            for target_length in [range.start, range.start + 1, range.end - 1] {
+                for payload in [&data[..], &data[..10], &[]] {
+                    let ReplyResult {
+                        packet,
+                        consumed,
+                        expected_return_length,
+                    } = rainbow
+                        .encode_reply(payload, is_client, target_length, EncodeOptions::default())
+                        .unwrap();
+                    assert_eq!(packet.len(), target_length);
+                    assert!(consumed <= payload.len());
+                    assert!(is_client || packet.starts_with(b"HTTP/1.1"));
+
+                    // 对方解码出被消耗的数据以及下一个预期长度
+                    let DecodeResult {
+                        data: decoded,
+                        expected_return_length: length,
+                        total,
+                        ..
+                    } = rainbow.decrypt_single_read(packet, 0, is_client).unwrap();
+                    assert_eq!(decoded, &payload[..consumed]);
+                    assert_eq!(length, expected_return_length);
+                    assert_eq!(total, 1);
+                }
             }
         }
 
-        // Test case 6: Verify packet info in cookies
-        let first_packet = String::from_utf8_lossy(&packets_large[0]);
-        assert!(first_packet.contains("Cookie: "));
-        let cookie_line = first_packet
-            .lines()
-            .find(|line| line.starts_with("Cookie: "))
-            .unwrap();
+        // 短于最小头部的长度无法生成
+        assert!(rainbow
+            .encode_reply(&data, false, 100, EncodeOptions::default())
+            .is_err());
+    }
 
-        // Cookie should contain packet info
-        let cookie_value = cookie_line.trim_start_matches("Cookie: ");
-        assert!(HTTP_CONSTANTS
-            .cookie_names
-            .iter()
-            .any(|&name| cookie_value.contains(name)));
+    #[test]
+    fn test_padding_exact_length() {
+        init();
+        let packet = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_vec();
+        for padding_len in [MIN_PADDING_LEN, MIN_PADDING_LEN + 1, 1000] {
+            let mut padded = packet.clone();
+            add_padding_to_packet(&mut padded, padding_len).unwrap();
+            assert_eq!(padded.len(), packet.len() + padding_len);
+            assert!(padded.ends_with(b"\r\n\r\nok"));
+            validate_http_packet(&padded).unwrap();
+        }
+
+        let mut padded = packet.clone();
+        assert!(add_padding_to_packet(&mut padded, MIN_PADDING_LEN - 1).is_err());
     }
 
     #[test]
@@ -1020,6 +3040,7 @@ mod tests {
         let EncodeResult {
             encoded_packets: _,
             expected_return_packet_lengths: client_lengths,
+            ..
         } = rainbow
             .encode_write(test_data, true, EncodeOptions::default())
             .unwrap();
@@ -1029,10 +3050,11 @@ mod tests {
         let EncodeResult {
             encoded_packets: _,
             expected_return_packet_lengths: server_lengths,
+            ..
         } = rainbow
             .encode_write(test_data, false, EncodeOptions::default())
             .unwrap();
-        assert!(server_lengths[0] >= 100 && server_lengths[0] <= 2000);
+        assert!(REQUEST_LENGTH_RANGE.contains(&server_lengths[0]));
     }
 
     #[test]
@@ -1045,6 +3067,7 @@ mod tests {
         let EncodeResult {
             encoded_packets: request_packets,
             expected_return_packet_lengths: _,
+            ..
         } = rainbow
             .encode_write(test_data, true, EncodeOptions::default())
             .unwrap();
@@ -1055,6 +3078,7 @@ mod tests {
         let EncodeResult {
             encoded_packets: response_packets,
             expected_return_packet_lengths: _,
+            ..
         } = rainbow
             .encode_write(test_data, false, EncodeOptions::default())
             .unwrap();
@@ -1062,17 +3086,522 @@ mod tests {
         assert!(response.starts_with(b"HTTP/1.1"));
     }
 
+    #[test]
+    fn test_traffic_profiles() {
+        init();
+        let rainbow = Rainbow::from_secret(b"profile")
+            .with_traffic_profile(TrafficProfile::builtin("curl").unwrap())
+            .with_traffic_profile(TrafficProfile::builtin("cloudflare").unwrap());
+        let peer = Rainbow::from_secret(b"profile");
+        assert_eq!(rainbow.traffic_profile(true).name, "curl");
+        assert_eq!(rainbow.traffic_profile(false).name, "cloudflare");
+        assert_eq!(peer.traffic_profile(false).name, "nginx");
+
+        for is_client in [true, false] {
+            let packets = rainbow
+                .encode_write(b"profile", is_client, EncodeOptions::default())
+                .unwrap()
+                .encoded_packets;
+            let (header, _) = packets[0].split_at(find_crlf_crlf(&packets[0]).unwrap());
+            let header = String::from_utf8_lossy(header);
+            if is_client {
+                assert!(find_header_value(&header, "User-Agent")
+                    .unwrap()
+                    .starts_with("curl/"));
+                assert!(!header.contains("Date:"));
+            } else {
+                assert_eq!(find_header_value(&header, "Server"), Some("cloudflare"));
+                assert!(find_header_value(&header, "CF-RAY").is_some());
+                assert!(header.contains("\r\nDate: "));
+            }
+
+            // 配置只影响头部，对方照常解码
+            let decoded = peer
+                .decrypt_single_read(packets[0].clone(), 0, is_client)
+                .unwrap();
+            assert_eq!(decoded.data, b"profile");
+        }
+
+        // 定长数据包同样使用配置
+        let reply = rainbow
+            .encode_reply(b"", false, 3000, EncodeOptions::default())
+            .unwrap();
+        assert_eq!(reply.packet.len(), 3000);
+        assert!(String::from_utf8_lossy(&reply.packet).contains("\r\nServer: cloudflare\r\n"));
+    }
+
+    #[test]
+    fn test_authority() {
+        init();
+        let host_of = |rainbow: &Rainbow| {
+            let packets = rainbow
+                .encode_write(b"authority", true, EncodeOptions::default())
+                .unwrap()
+                .encoded_packets;
+            let packet = match h2::decode_message(&packets[0]) {
+                Ok(message) => message.http1,
+                Err(_) => packets[0].clone(),
+            };
+            let header = String::from_utf8_lossy(&packet).to_string();
+            // Host 紧跟在请求行之后
+            let (_, rest) = header.split_once("\r\n").unwrap();
+            rest.strip_prefix("Host: ")
+                .and_then(|rest| rest.split_once("\r\n"))
+                .map(|(host, _)| host.to_string())
+        };
+
+        let rainbow = Rainbow::from_secret(b"authority");
+        assert_eq!(host_of(&rainbow).as_deref(), Some("localhost"));
+
+        // 之后选择的配置同样使用指定的主机
+        let rainbow = rainbow
+            .with_authority("www.example.com")
+            .with_traffic_profile(TrafficProfile::builtin("curl").unwrap());
+        assert_eq!(host_of(&rainbow).as_deref(), Some("www.example.com"));
+        let h2 = rainbow.with_http_version(HttpVersion::H2c);
+        assert_eq!(host_of(&h2).as_deref(), Some("www.example.com"));
+    }
+
+    #[test]
+    fn test_session_identity() {
+        init();
+        let rainbow = Rainbow::from_secret(b"identity");
+        let clone = rainbow.clone();
+
+        // 同一会话的数据包来自同一个浏览器：相同的头部和 cookie
+        let identity_of = |packet: &[u8]| {
+            let (header, _) = packet.split_at(find_crlf_crlf(packet).unwrap());
+            let header = String::from_utf8_lossy(header);
+            let cookies = find_header_value(&header, "Cookie").unwrap();
+            let (_, others) = cookies.split_once("; ").unwrap();
+            (
+                find_header_value(&header, "User-Agent")
+                    .unwrap()
+                    .to_string(),
+                find_header_value(&header, "Accept-Language")
+                    .unwrap()
+                    .to_string(),
+                others.to_string(),
+            )
+        };
+        let mut identities = Vec::new();
+        for processor in [&rainbow, &clone] {
+            for _ in 0..5 {
+                let request = processor
+                    .encode_reply(b"", true, 1500, EncodeOptions::default())
+                    .unwrap()
+                    .packet;
+                identities.push(identity_of(&request));
+            }
+        }
+        assert!(identities.iter().all(|identity| *identity == identities[0]));
+
+        let identity = SessionIdentity::new(TrafficProfile::builtin("safari").unwrap());
+        let rainbow = rainbow.with_session_identity(identity.clone());
+        assert_eq!(rainbow.identity(true), &identity);
+        let request = rainbow
+            .encode_reply(b"", true, 1500, EncodeOptions::default())
+            .unwrap()
+            .packet;
+        let (_, _, cookies) = identity_of(&request);
+        assert!(identity.cookies("").ends_with(&cookies));
+    }
+
+    #[test]
+    fn test_request_kind_headers() {
+        init();
+        let rainbow = Rainbow::from_secret(b"request-kind");
+        let header_of = |mime_type: &str| {
+            let options = EncodeOptions {
+                mime_type: Some(mime_type.to_string()),
+                ..Default::default()
+            };
+            let packet = rainbow
+                .encode_write(b"kind", true, options)
+                .unwrap()
+                .encoded_packets[0]
+                .clone();
+            String::from_utf8_lossy(&packet[..find_crlf_crlf(&packet).unwrap()]).to_string()
+        };
+
+        // 脚本发出的 API 请求不是导航，页面的 GET 请求才是
+        for _ in 0..20 {
+            let post = header_of("application/json");
+            assert!(post.starts_with("POST "));
+            assert_eq!(find_header_value(&post, "Sec-Fetch-Mode"), Some("cors"));
+            assert_eq!(find_header_value(&post, "Sec-Fetch-Dest"), Some("empty"));
+            assert_eq!(find_header_value(&post, "Upgrade-Insecure-Requests"), None);
+
+            let get = header_of(header::MIME_TYPE);
+            let path = get.split(' ').nth(1).unwrap();
+            let is_page = rainbow.site_map.reply_mime_type("GET", path) == Some("text/html");
+            let mode = find_header_value(&get, "Sec-Fetch-Mode").unwrap();
+            assert_eq!(mode == "navigate", is_page);
+        }
+    }
+
+    #[test]
+    fn test_site_map() {
+        init();
+        let client = Rainbow::from_secret(b"site-map");
+        let server = Rainbow::from_secret(b"site-map");
+        let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let request_line = |packet: &[u8]| {
+            let line = &packet[..find_crlf_crlf(packet).unwrap()];
+            let line = String::from_utf8_lossy(line);
+            line.lines().next().unwrap().to_string()
+        };
+
+        // PNG 数据作为表单中的文件上传
+        for is_sized in [false, true] {
+            let png = EncodeOptions {
+                mime_type: Some("image/png".to_string()),
+                ..Default::default()
+            };
+            let request = if is_sized {
+                client.encode_reply(&data, true, 2500, png).unwrap().packet
+            } else {
+                client
+                    .encode_write(&data, true, png)
+                    .unwrap()
+                    .encoded_packets[0]
+                    .clone()
+            };
+            let line = request_line(&request);
+            assert!(
+                line == "POST /upload/avatar.png HTTP/1.1"
+                    || line == "POST /api/v1/photos HTTP/1.1"
+            );
+            assert!(data_find(&request, b"Content-Type: multipart/form-data; boundary=").is_some());
+            let decoded = server.decrypt_single_read(request, 0, true).unwrap();
+            assert_eq!(decoded.data, &data[..decoded.data.len()]);
+            assert!(!decoded.data.is_empty());
+            assert_eq!(decoded.reply_mime_type.as_deref(), Some("application/json"));
+        }
+
+        // 路径、Accept 和响应的类型一致
+        for _ in 0..20 {
+            let request = client
+                .encode_write(b"", true, EncodeOptions::default())
+                .unwrap()
+                .encoded_packets
+                .remove(0);
+            let line = request_line(&request);
+            let header = String::from_utf8_lossy(&request[..find_crlf_crlf(&request).unwrap()]);
+            let accept = find_header_value(&header, "Accept").unwrap().to_string();
+            let decoded = server.decrypt_single_read(request, 0, true).unwrap();
+            let reply_mime_type = decoded.reply_mime_type.unwrap();
+            if line.contains(".css ") {
+                assert_eq!(reply_mime_type, "text/css");
+                assert!(accept.starts_with("text/css"));
+            }
+
+            let reply = server
+                .encode_reply(
+                    b"",
+                    false,
+                    decoded.expected_return_length,
+                    EncodeOptions {
+                        preferred_mime_type: Some(reply_mime_type.clone()),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            // 重定向和错误页面等不携带数据的响应除外
+            let header = String::from_utf8_lossy(&reply.packet);
+            if header.starts_with("HTTP/1.1 20") && !header.starts_with("HTTP/1.1 204") {
+                assert!(header.contains(&format!("Content-Type: {}\r\n", reply_mime_type)));
+            }
+        }
+
+        // 自定义的站点地图
+        let site_map = SiteMap::new()
+            .with_route(sitemap::Route::post(
+                "/wiki/{name}/edit",
+                "text/html",
+                "text/html",
+            ))
+            .with_route(sitemap::Route::get("/wiki/{name}", "text/html"));
+        let wiki = Rainbow::from_secret(b"site-map").with_site_map(site_map.clone());
+        assert_eq!(wiki.site_map(), &site_map);
+        let html = EncodeOptions {
+            mime_type: Some("text/html".to_string()),
+            ..Default::default()
+        };
+        let request = wiki
+            .encode_write(&data, true, html)
+            .unwrap()
+            .encoded_packets[0]
+            .clone();
+        let line = request_line(&request);
+        assert!(line.starts_with("POST /wiki/") && line.ends_with("/edit HTTP/1.1"));
+        let decoded = wiki.decrypt_single_read(request, 0, true).unwrap();
+        assert_eq!(decoded.data, &data[..decoded.data.len()]);
+        assert_eq!(decoded.reply_mime_type.as_deref(), Some("text/html"));
+    }
+
+    #[test]
+    fn test_status_codes() {
+        init();
+        let server = Rainbow::from_secret(b"status");
+        let client = Rainbow::from_secret(b"status");
+        let status_of =
+            |packet: &[u8]| -> u16 { String::from_utf8_lossy(&packet[9..12]).parse().unwrap() };
+
+        // 携带数据的响应总是成功且有消息体，206 带有 Content-Range
+        let data: Vec<u8> = (0..100).map(|i| i as u8).collect();
+        let octet = EncodeOptions {
+            encoder: Some("octet".to_string()),
+            ..Default::default()
+        };
+        let mut partial = 0;
+        for _ in 0..300 {
+            let packet = server
+                .encode_write(&data, false, octet.clone())
+                .unwrap()
+                .encoded_packets
+                .remove(0);
+            let status = status_of(&packet);
+            assert!(status::carries_data(status));
+            let split = find_crlf_crlf(&packet).unwrap();
+            let header = String::from_utf8_lossy(&packet[..split]);
+            assert!(header.starts_with(&status::status_line(status)));
+            if status == 206 {
+                partial += 1;
+                let range = find_header_value(&header, "Content-Range").unwrap();
+                let (start, end) = range[6..]
+                    .split_once('/')
+                    .unwrap()
+                    .0
+                    .split_once('-')
+                    .unwrap();
+                let body_len = packet.len() - split - 4;
+                assert_eq!(
+                    end.parse::<usize>().unwrap() - start.parse::<usize>().unwrap() + 1,
+                    body_len
+                );
+            } else {
+                assert!(find_header_value(&header, "Content-Range").is_none());
+            }
+            let decoded = client.decrypt_single_read(packet, 0, false).unwrap();
+            assert_eq!(decoded.data, data);
+        }
+        assert!(partial > 0);
+
+        // 没有数据时也有 204、304、重定向和错误页面
+        let mut seen = Vec::new();
+        for _ in 0..300 {
+            let reply = server
+                .encode_reply(b"", false, 1500, EncodeOptions::default())
+                .unwrap();
+            assert_eq!(reply.packet.len(), 1500);
+            let status = status_of(&reply.packet);
+            let split = find_crlf_crlf(&reply.packet).unwrap();
+            let header = String::from_utf8_lossy(&reply.packet[..split]);
+            assert!(header.starts_with(&status::status_line(status)));
+            match status {
+                204 | 304 => {
+                    assert_eq!(split + 4, reply.packet.len());
+                    assert!(find_header_value(&header, "Content-Length").is_none());
+                }
+                302 => {
+                    let location = find_header_value(&header, "Location").unwrap();
+                    assert!(location.starts_with('/'));
+                }
+                404 | 500 => {
+                    let body = String::from_utf8_lossy(&reply.packet[split + 4..]);
+                    assert!(body.contains(&format!(
+                        "<title>{} {}</title>",
+                        status,
+                        status::reason_phrase(status)
+                    )));
+                }
+                _ => {}
+            }
+            assert_eq!(http_message_len(&reply.packet).unwrap(), Some(1500));
+            let decoded = client.decrypt_single_read(reply.packet, 0, false).unwrap();
+            assert!(decoded.data.is_empty());
+            seen.push(status);
+        }
+        assert!(seen.iter().any(|&status| !status::carries_data(status)));
+        assert!(!seen.contains(&206));
+    }
+
+    #[test]
+    fn test_header_fields() {
+        init();
+        let client = Rainbow::from_secret(b"header-fields");
+        let server = Rainbow::from_secret(b"header-fields");
+        let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let headers = EncodeOptions {
+            encoder: Some(header::NAME.to_string()),
+            ..Default::default()
+        };
+
+        // GET 请求没有消息体，数据在头部字段中，令牌与其他 Cookie 在同一个头部
+        let packets = client
+            .encode_write(&data, true, headers.clone())
+            .unwrap()
+            .encoded_packets;
+        assert!(packets.len() >= data.len() / client.request_header_encoder.max_data_len());
+        let mut decoded = Vec::new();
+        for packet in packets {
+            let split = find_crlf_crlf(&packet).unwrap();
+            assert_eq!(split + 4, packet.len());
+            let header = String::from_utf8_lossy(&packet[..split]);
+            assert!(header.starts_with("GET "));
+            assert!(!header.contains("X-Data") && !header.contains("Content-Type"));
+            assert!(find_header_value(&header, "If-None-Match").is_some());
+            assert_eq!(header.matches("Cookie: ").count(), 1);
+            assert!(find_header_value(&header, "Cookie")
+                .unwrap()
+                .contains("; csrftoken="));
+            decoded.extend(server.decrypt_single_read(packet, 0, true).unwrap().data);
+        }
+        assert_eq!(decoded, data);
+
+        // 204、304 响应的数据在 ETag 等头部字段中
+        let status_of =
+            |packet: &[u8]| -> u16 { String::from_utf8_lossy(&packet[9..12]).parse().unwrap() };
+        let packet = server
+            .encode_write(&data[..40], false, headers.clone())
+            .unwrap()
+            .encoded_packets
+            .remove(0);
+        assert!(status::carries_header_data(status_of(&packet)));
+        let header = String::from_utf8_lossy(&packet);
+        assert!(find_header_value(&header, "ETag").is_some());
+        assert!(header.contains("Set-Cookie: XSRF-TOKEN="));
+        assert!(find_header_value(&header, "Content-Length").is_none());
+        let decoded = client.decrypt_single_read(packet, 0, false).unwrap();
+        assert_eq!(decoded.data, &data[..40]);
+
+        // 精确长度的 204、304 响应也携带数据
+        for status in [204, 304] {
+            let info = PacketInfo {
+                session_id: server.session_id,
+                ..PacketInfo::new(0, 1, 0)
+            };
+            let (packet, consumed) = server
+                .build_sized_status_packet(status, &data, 1500, info)
+                .unwrap()
+                .unwrap();
+            assert_eq!(packet.len(), 1500);
+            assert_eq!(consumed, server.response_header_encoder.max_data_len());
+            assert_eq!(status_of(&packet), status);
+            let decoded = client.decrypt_single_read(packet, 0, false).unwrap();
+            assert_eq!(decoded.data, &data[..consumed]);
+        }
+
+        // 没有指定编码器时，小的请求有时作为 GET 请求发送
+        let gets = (0..40)
+            .filter(|_| {
+                client
+                    .encode_write(b"poll", true, EncodeOptions::default())
+                    .unwrap()
+                    .encoded_packets[0]
+                    .starts_with(b"GET ")
+            })
+            .count();
+        assert!(gets > 0 && gets < 40);
+
+        // HTTP/2 的头部名称是小写的
+        let h2 = Rainbow::from_secret(b"header-fields").with_http_version(HttpVersion::H2c);
+        let packet = h2
+            .encode_write(&data[..20], true, headers)
+            .unwrap()
+            .encoded_packets
+            .remove(0);
+        let decoded = server.decrypt_single_read(packet, 0, true).unwrap();
+        assert_eq!(decoded.data, &data[..20]);
+    }
+
     #[test]
     fn test_packet_info_cookie() {
         init();
+        let key = [7u8; 32];
         let info = PacketInfo::new(0, 1, 10);
-        let cookie = info.to_cookie().unwrap();
-        let decoded = PacketInfo::from_cookie(&cookie).unwrap();
+        let cookie = info.to_cookie(&key).unwrap();
+        let (decoded, _) = PacketInfo::from_cookie(&cookie, &key).unwrap();
 
         assert_eq!(info.index, decoded.index);
         assert_eq!(info.total, decoded.total);
         assert_eq!(info.length, decoded.length);
         assert_eq!(info.version, decoded.version);
+
+        // 看起来像一个普通的会话 token
+        assert!(cookie
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        assert_eq!(
+            cookie.len(),
+            PacketInfo::new(1000, 2000, 256)
+                .to_cookie(&key)
+                .unwrap()
+                .len()
+        );
+        assert!(!String::from_utf8_lossy(&BASE64_URL.decode(&cookie).unwrap()).contains("index"));
+
+        assert!(matches!(
+            PacketInfo::from_cookie(&cookie, &[8u8; 32]),
+            Err(RainbowError::AuthenticationFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_tampered_packet_info_rejected() {
+        init();
+        let rainbow = Rainbow::from_secret(b"shared key");
+        let test_data = b"Hello, tamper!";
+        let EncodeResult {
+            encoded_packets: packets,
+            ..
+        } = rainbow
+            .encode_write(
+                test_data,
+                true,
+                EncodeOptions {
+                    encoder: Some("octet".to_string()),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        // 不同密钥的对端无法通过认证
+        let stranger = Rainbow::from_secret(b"another key");
+        assert!(matches!(
+            stranger.decrypt_single_read(packets[0].clone(), 0, true),
+            Err(RainbowError::AuthenticationFailed(_))
+        ));
+
+        // 篡改 cookie 中的一个字符
+        let packet = String::from_utf8_lossy(&packets[0]).to_string();
+        let cookie_line = packet
+            .lines()
+            .find(|line| line.starts_with("Cookie: "))
+            .unwrap();
+        let (name, rest) = cookie_line["Cookie: ".len()..].split_once('=').unwrap();
+        let token = rest.split(';').next().unwrap();
+        let mut tampered_token = token.to_string().into_bytes();
+        tampered_token[10] = if tampered_token[10] == b'A' {
+            b'B'
+        } else {
+            b'A'
+        };
+        let tampered = packet.replacen(
+            &format!("{}={}", name, token),
+            &format!("{}={}", name, String::from_utf8(tampered_token).unwrap()),
+            1,
+        );
+        assert!(matches!(
+            rainbow.decrypt_single_read(tampered.into_bytes(), 0, true),
+            Err(RainbowError::AuthenticationFailed(_))
+        ));
+
+        let DecodeResult { data, .. } = rainbow
+            .decrypt_single_read(packets[0].clone(), 0, true)
+            .unwrap();
+        assert_eq!(&data, test_data);
     }
 
     #[test]
@@ -1084,7 +3613,8 @@ mod tests {
         // 编码：模拟客户端发送请求，使用 application/octet-stream 强制 POST 请求
         let EncodeResult {
             encoded_packets: packets,
-            expected_return_packet_lengths: _lengths,
+            expected_return_packet_lengths: lengths,
+            ..
         } = rainbow
             .encode_write(
                 test_data,
@@ -1101,12 +3631,13 @@ mod tests {
             data: decoded,
             expected_return_length: length,
             is_read_end: is_end,
+            ..
         } = rainbow
             .decrypt_single_read(packets[0].clone(), 0, true)
             .unwrap();
 
         assert_eq!(&decoded, test_data);
-        assert_eq!(length, test_data.len());
+        assert_eq!(length, lengths[0]);
         assert!(is_end);
     }
 
@@ -1125,7 +3656,8 @@ mod tests {
             // 编码：模拟客户端发送请求
             let EncodeResult {
                 encoded_packets: packets,
-                expected_return_packet_lengths: _lengths,
+                expected_return_packet_lengths: lengths,
+                ..
             } = rainbow
                 .encode_write(
                     test_data,
@@ -1139,36 +3671,197 @@ mod tests {
 
             // 验证生成的数据包
             let packet_str = &packets[0];
-            if mime_type == "text/plain" || mime_type == "application/json" {
-                assert!(packet_str.starts_with(b"GET "));
-                assert!(data_find(packet_str, b"X-Data:").is_some());
-            } else {
-                assert!(packet_str.starts_with(b"POST "));
-                assert!(data_find(
-                    packet_str,
-                    format!("Content-Type: {}", mime_type).as_bytes()
-                )
-                .is_some());
-            }
+            // 数据直接作为请求体，或作为表单中的文件
+            assert!(packet_str.starts_with(b"POST "));
+            assert!(data_find(
+                packet_str,
+                format!("Content-Type: {}\r\n", mime_type).as_bytes()
+            )
+            .is_some());
 
             // 解码：模拟服务器接收请求
             let DecodeResult {
                 data: decoded,
                 expected_return_length: length,
                 is_read_end: is_end,
+                ..
             } = rainbow
                 .decrypt_single_read(packets[0].clone(), 0, true)
                 .unwrap();
 
             // 验证解码结果
             assert_eq!(&decoded, test_data, "Failed to decode {}", mime_type);
-            assert_eq!(length, test_data.len());
+            assert_eq!(length, lengths[0]);
             assert!(is_end);
 
             debug!("Successfully tested MIME type: {}", mime_type);
         }
     }
 
+    #[test]
+    fn test_from_secret_peers() {
+        init();
+        let client = Rainbow::from_secret(b"shared key");
+        let server = Rainbow::from_secret(b"shared key");
+        let test_data = b"Hello, peer!";
+
+        for mime_type in client.registry.get_all_mime_types() {
+            let EncodeResult {
+                encoded_packets: packets,
+                ..
+            } = client
+                .encode_write(
+                    test_data,
+                    true,
+                    EncodeOptions {
+                        mime_type: Some(mime_type.to_string()),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+
+            let DecodeResult { data: decoded, .. } = server
+                .decrypt_single_read(packets[0].clone(), 0, true)
+                .unwrap();
+            assert_eq!(&decoded, test_data, "Failed to decode {}", mime_type);
+        }
+    }
+
+    #[test]
+    fn test_replay_protection() {
+        init();
+        let rainbow =
+            Rainbow::from_secret(b"replay").with_replay_protection(Duration::from_secs(30));
+        let test_data = b"Hello, replay!";
+        let EncodeResult {
+            encoded_packets: packets,
+            ..
+        } = rainbow
+            .encode_write(
+                test_data,
+                true,
+                EncodeOptions {
+                    encoder: Some("octet".to_string()),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let DecodeResult { data, .. } = rainbow
+            .decrypt_single_read(packets[0].clone(), 0, true)
+            .unwrap();
+        assert_eq!(&data, test_data);
+
+        // 重放同一个包
+        assert!(matches!(
+            rainbow.decrypt_single_read(packets[0].clone(), 0, true),
+            Err(RainbowError::ReplayRejected(_))
+        ));
+
+        // 克隆共享同一个重放缓存
+        assert!(matches!(
+            rainbow
+                .clone()
+                .decrypt_single_read(packets[0].clone(), 0, true),
+            Err(RainbowError::ReplayRejected(_))
+        ));
+
+        // 过期的包
+        let stale_info = PacketInfo {
+            timestamp: Utc::now().timestamp() - 120,
+            ..PacketInfo::new(0, 1, test_data.len())
+        };
+        let encoded = rainbow.registry.encode_with(test_data, "octet").unwrap();
+        let stale = rainbow
+            .build_http_request(&encoded, &stale_info, "application/octet-stream")
+            .unwrap();
+        assert!(matches!(
+            rainbow.decrypt_single_read(stale.clone(), 0, true),
+            Err(RainbowError::ReplayRejected(_))
+        ));
+
+        // 未开启保护时照常解码
+        let unprotected = Rainbow::from_secret(b"replay");
+        assert!(unprotected.decrypt_single_read(stale, 0, true).is_ok());
+        assert!(unprotected
+            .decrypt_single_read(packets[0].clone(), 0, true)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_encode_decode_empty() {
+        init();
+        let rainbow = Rainbow::new();
+
+        for is_client in [true, false] {
+            let EncodeResult {
+                encoded_packets: packets,
+                expected_return_packet_lengths: lengths,
+                ..
+            } = rainbow
+                .encode_write(b"", is_client, EncodeOptions::default())
+                .unwrap();
+            assert_eq!(packets.len(), 1);
+            assert_eq!(lengths.len(), 1);
+
+            let DecodeResult {
+                data,
+                is_read_end,
+                total,
+                ..
+            } = rainbow
+                .decrypt_single_read(packets[0].clone(), 0, is_client)
+                .unwrap();
+            assert!(data.is_empty());
+            assert!(is_read_end);
+            assert_eq!(total, 1);
+        }
+    }
+
+    #[test]
+    fn test_cover_packets() {
+        init();
+        let rainbow =
+            Rainbow::from_secret(b"cover").with_replay_protection(Duration::from_secs(30));
+        let peer = Rainbow::from_secret(b"cover").with_replay_protection(Duration::from_secs(30));
+
+        for is_client in [true, false] {
+            for mime_type in ["text/html", "application/json", "application/octet-stream"] {
+                let options = EncodeOptions {
+                    mime_type: Some(mime_type.to_string()),
+                    ..Default::default()
+                };
+                let packet = rainbow.encode_cover(is_client, options).unwrap();
+                // 掩护数据包与数据包外观相同
+                let text = String::from_utf8_lossy(&packet);
+                assert_eq!(text.starts_with("HTTP/1.1 "), !is_client);
+
+                let result = peer.decrypt_single_read(packet, 0, is_client).unwrap();
+                assert!(result.is_cover);
+                assert!(result.data.is_empty());
+            }
+        }
+
+        // 数据包不是掩护数据包
+        let packets = rainbow
+            .encode_write(b"data", true, EncodeOptions::default())
+            .unwrap()
+            .encoded_packets;
+        let result = peer
+            .decrypt_single_read(packets[0].clone(), 0, true)
+            .unwrap();
+        assert!(!result.is_cover);
+        assert_eq!(result.data, b"data");
+
+        // 其他密钥下无法打开掩护数据包
+        let packet = rainbow
+            .encode_cover(true, EncodeOptions::default())
+            .unwrap();
+        assert!(Rainbow::from_secret(b"other")
+            .decrypt_single_read(packet, 0, true)
+            .is_err());
+    }
+
     #[test]
     fn test_invalid_packet_validation() {
         init();
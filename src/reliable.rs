@@ -0,0 +1,442 @@
+/*!
+ * Reliable module acknowledges received messages and retransmits lost ones.
+ *
+ * Every packet may piggyback an [`Ack`] of the messages its sender has received from the peer
+ * on the same stream. A [`ReliableSession`] numbers the messages it sends, keeps each of them
+ * until the peer acknowledges it, and encodes it again once it has waited longer than the
+ * retransmission timeout, doubling the timeout at every attempt. Retransmitted messages are
+ * encoded anew, so that they do not replay the packets already sent.
+ *
+ * Like [`Responder`](crate::responder::Responder), the session does no I/O: it returns the
+ * packets to send, and is fed the packets received, over any transport.
+ */
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use tracing::debug;
+
+use crate::{
+    session::DecodeSession, EncodeOptions, EncodeResult, NetworkSteganographyProcessor,
+    RainbowError, Result,
+};
+
+/// Retransmission timeout before any retry
+const DEFAULT_RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Retransmissions of a message before giving up
+const DEFAULT_MAX_RETRIES: u32 = 8;
+
+/// Acknowledgement of the messages of a stream received so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Ack {
+    /// Every message with a sequence number below this has been received
+    pub next: u32,
+    /// Bit `i` is set if message `next + 1 + i` has been received
+    pub selective: u32,
+}
+
+impl Ack {
+    /// Whether message `seq` has been received
+    pub fn acknowledges(&self, seq: u32) -> bool {
+        if seq_before(seq, self.next) {
+            return true;
+        }
+        let offset = seq.wrapping_sub(self.next);
+        (1..=32).contains(&offset) && self.selective & 1 << (offset - 1) != 0
+    }
+}
+
+/// Whether sequence number `a` comes before `b`, in serial number arithmetic (RFC 1982):
+/// sequence numbers wrap around, so `u32::MAX` comes before `0`
+pub(crate) fn seq_before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// A message sent and not acknowledged yet
+#[derive(Debug, Clone)]
+struct Unacked {
+    data: Vec<u8>,
+    sent_at: Instant,
+    retries: u32,
+}
+
+/// Sends messages until they are acknowledged, and acknowledges the messages received
+#[derive(Clone)]
+pub struct ReliableSession {
+    processor: Box<dyn NetworkSteganographyProcessor>,
+    is_client: bool,
+    options: EncodeOptions,
+    retransmit_timeout: Duration,
+    max_retries: u32,
+    /// Sequence number of the next message sent
+    next_seq: u32,
+    unacked: BTreeMap<u32, Unacked>,
+    /// Messages received from the peer
+    session: DecodeSession,
+    /// Whether a message has been received since the last packet sent
+    ack_pending: bool,
+}
+
+impl ReliableSession {
+    /// `is_client` is true if this side sends requests, as in
+    /// [`NetworkSteganographyProcessor::encode_write`]
+    pub fn new(processor: Box<dyn NetworkSteganographyProcessor>, is_client: bool) -> Self {
+        Self {
+            session: DecodeSession::new(processor.clone(), !is_client),
+            processor,
+            is_client,
+            options: EncodeOptions::default(),
+            retransmit_timeout: DEFAULT_RETRANSMIT_TIMEOUT,
+            max_retries: DEFAULT_MAX_RETRIES,
+            next_seq: 0,
+            unacked: BTreeMap::new(),
+            ack_pending: false,
+        }
+    }
+
+    /// Options used to encode the messages, their `seq` and `ack` are set by the session
+    pub fn with_options(mut self, options: EncodeOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// How long a message waits for its acknowledgement before its first retransmission
+    pub fn with_retransmit_timeout(mut self, timeout: Duration) -> Self {
+        self.retransmit_timeout = timeout;
+        self
+    }
+
+    /// Number of retransmissions of a message before [`retransmit`](Self::retransmit) fails
+    /// with [`RainbowError::Unacknowledged`]
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Encode a message, it is kept until the peer acknowledges it
+    pub fn send(&mut self, data: &[u8]) -> Result<Vec<Vec<u8>>> {
+        if data.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        let packets = self.encode(data, seq)?;
+        self.unacked.insert(
+            seq,
+            Unacked {
+                data: data.to_vec(),
+                sent_at: Instant::now(),
+                retries: 0,
+            },
+        );
+        debug!("Sent message {} in {} packets", seq, packets.len());
+        Ok(packets)
+    }
+
+    /// Decode a packet, apply the acknowledgement it carries, and return the message it
+    /// completes, if any
+    pub fn receive(&mut self, packet: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let result =
+            self.processor
+                .decrypt_single_read(packet, self.session.received(), !self.is_client)?;
+        if let Some(ack) = result.ack {
+            self.acknowledge(ack);
+        }
+
+        // 只携带确认的数据包和掩护数据包不属于任何消息。send 不发送空消息，
+        // 只携带确认的数据包总是不带校验包的单个空数据包
+        let is_ack_only = result.total == 1 && result.parity == 0 && result.data.is_empty();
+        if result.is_cover || is_ack_only {
+            return Ok(None);
+        }
+
+        self.ack_pending = true;
+        match self.session.accept(result) {
+            // 对方没有收到确认，重传了已收到的数据包
+            Err(RainbowError::DuplicatePacket(index)) => {
+                debug!("Dropped retransmitted packet {}", index);
+                Ok(None)
+            }
+            result => result,
+        }
+    }
+
+    /// A packet carrying only an acknowledgement, if messages have been received since the
+    /// last packet sent. Send it when there is no message to piggyback the acknowledgement on
+    pub fn ack_packet(&mut self) -> Result<Option<Vec<u8>>> {
+        if !self.ack_pending {
+            return Ok(None);
+        }
+        // 不添加校验包，对方才能把它和消息区分开
+        let options = EncodeOptions {
+            parity: 0,
+            ..self.options.clone()
+        };
+        Ok(self
+            .encode_with(&[], self.next_seq, options)?
+            .into_iter()
+            .next())
+    }
+
+    /// Encode again the messages that waited too long for their acknowledgement.
+    ///
+    /// Fails with [`RainbowError::Unacknowledged`] once a message has been retransmitted
+    /// more than the maximum number of retries.
+    pub fn retransmit(&mut self) -> Result<Vec<Vec<u8>>> {
+        let now = Instant::now();
+        let expired: Vec<u32> = self
+            .unacked
+            .iter()
+            .filter(|(_, m)| now.duration_since(m.sent_at) >= self.timeout(m.retries))
+            .map(|(seq, _)| *seq)
+            .collect();
+
+        // 先检查所有消息，以免已经重新编码的数据包随错误一起丢失
+        if let Some(seq) = expired
+            .iter()
+            .find(|seq| self.unacked[seq].retries >= self.max_retries)
+        {
+            return Err(RainbowError::Unacknowledged(*seq));
+        }
+
+        let mut packets = Vec::new();
+        for seq in expired {
+            let data = self.unacked[&seq].data.clone();
+            packets.extend(self.encode(&data, seq)?);
+
+            let message = self.unacked.get_mut(&seq).unwrap();
+            message.retries += 1;
+            message.sent_at = now;
+            debug!("Retransmitted message {}, attempt {}", seq, message.retries);
+        }
+        Ok(packets)
+    }
+
+    /// Acknowledgement of the messages received so far
+    pub fn ack(&self) -> Ack {
+        self.session.ack()
+    }
+
+    /// Number of messages sent and not acknowledged yet
+    pub fn unacked(&self) -> usize {
+        self.unacked.len()
+    }
+
+    /// Whether every message sent has been acknowledged
+    pub fn is_idle(&self) -> bool {
+        self.unacked.is_empty()
+    }
+
+    fn acknowledge(&mut self, ack: Ack) {
+        let before = self.unacked.len();
+        self.unacked.retain(|seq, _| !ack.acknowledges(*seq));
+        if self.unacked.len() < before {
+            debug!("{} messages acknowledged", before - self.unacked.len());
+        }
+    }
+
+    /// Retransmission timeout after `retries` retries
+    fn timeout(&self, retries: u32) -> Duration {
+        self.retransmit_timeout.saturating_mul(1 << retries.min(16))
+    }
+
+    fn encode(&mut self, data: &[u8], seq: u32) -> Result<Vec<Vec<u8>>> {
+        self.encode_with(data, seq, self.options.clone())
+    }
+
+    fn encode_with(
+        &mut self,
+        data: &[u8],
+        seq: u32,
+        options: EncodeOptions,
+    ) -> Result<Vec<Vec<u8>>> {
+        let options = EncodeOptions {
+            seq: Some(seq),
+            ack: Some(self.ack()),
+            ..options
+        };
+        let EncodeResult {
+            encoded_packets, ..
+        } = self.processor.encode_write(data, self.is_client, options)?;
+        self.ack_pending = false;
+        Ok(encoded_packets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rainbow::Rainbow;
+
+    fn init() {
+        let _ = tracing_subscriber::fmt()
+            .with_test_writer()
+            .with_max_level(tracing::Level::DEBUG)
+            .try_init();
+    }
+
+    #[test]
+    fn test_ack_acknowledges() {
+        let ack = Ack {
+            next: 5,
+            selective: 0b101,
+        };
+        assert!(ack.acknowledges(0));
+        assert!(ack.acknowledges(4));
+        assert!(!ack.acknowledges(5));
+        assert!(ack.acknowledges(6));
+        assert!(!ack.acknowledges(7));
+        assert!(ack.acknowledges(8));
+        assert!(!ack.acknowledges(100));
+    }
+
+    #[test]
+    fn test_ack_wraps_around() {
+        let ack = Ack {
+            next: u32::MAX - 1,
+            selective: 0b110,
+        };
+        assert!(ack.acknowledges(u32::MAX - 2));
+        assert!(!ack.acknowledges(u32::MAX - 1));
+        assert!(!ack.acknowledges(u32::MAX));
+        assert!(ack.acknowledges(0));
+        assert!(ack.acknowledges(1));
+        assert!(!ack.acknowledges(2));
+
+        assert!(seq_before(u32::MAX, 0));
+        assert!(!seq_before(0, u32::MAX));
+        assert!(!seq_before(7, 7));
+    }
+
+    #[test]
+    fn test_retransmit_lost_message() {
+        init();
+        let options = EncodeOptions {
+            encoder: Some("octet".to_string()),
+            ..Default::default()
+        };
+        let mut client = ReliableSession::new(Box::new(Rainbow::from_secret(b"reliable")), true)
+            .with_options(options.clone())
+            .with_retransmit_timeout(Duration::ZERO);
+        let mut server = ReliableSession::new(Box::new(Rainbow::from_secret(b"reliable")), false)
+            .with_options(options);
+
+        let first = client.send(b"first").unwrap();
+        let lost = client.send(b"second").unwrap();
+        let third = client.send(b"third").unwrap();
+        assert_eq!(client.unacked(), 3);
+        drop(lost);
+
+        let mut received = Vec::new();
+        for packet in first.into_iter().chain(third) {
+            received.extend(server.receive(packet).unwrap());
+        }
+        assert_eq!(received, vec![b"first".to_vec(), b"third".to_vec()]);
+        assert_eq!(
+            server.ack(),
+            Ack {
+                next: 1,
+                selective: 0b1
+            }
+        );
+
+        // 确认随服务器的响应返回，客户端只重传丢失的消息
+        let ack = server.ack_packet().unwrap().unwrap();
+        assert!(server.ack_packet().unwrap().is_none());
+        assert!(client.receive(ack).unwrap().is_none());
+        assert_eq!(client.unacked(), 1);
+
+        let retransmitted = client.retransmit().unwrap();
+        assert!(!retransmitted.is_empty());
+        for packet in retransmitted {
+            if let Some(message) = server.receive(packet).unwrap() {
+                assert_eq!(message, b"second");
+            }
+        }
+        assert_eq!(
+            server.ack(),
+            Ack {
+                next: 3,
+                selective: 0
+            }
+        );
+
+        let reply = server.send(b"reply").unwrap();
+        let mut replies = Vec::new();
+        for packet in reply {
+            replies.extend(client.receive(packet).unwrap());
+        }
+        assert_eq!(replies, vec![b"reply".to_vec()]);
+        assert!(client.is_idle());
+    }
+
+    #[test]
+    fn test_give_up_after_max_retries() {
+        init();
+        let mut client = ReliableSession::new(Box::new(Rainbow::new()), true)
+            .with_retransmit_timeout(Duration::ZERO)
+            .with_max_retries(2);
+
+        client.send(b"never acknowledged").unwrap();
+        assert!(!client.retransmit().unwrap().is_empty());
+        assert!(!client.retransmit().unwrap().is_empty());
+        assert!(matches!(
+            client.retransmit(),
+            Err(RainbowError::Unacknowledged(0))
+        ));
+    }
+
+    #[test]
+    fn test_give_up_before_retransmitting() {
+        init();
+        let mut client = ReliableSession::new(Box::new(Rainbow::new()), true)
+            .with_retransmit_timeout(Duration::ZERO)
+            .with_max_retries(1);
+
+        client.send(b"first").unwrap();
+        assert!(!client.retransmit().unwrap().is_empty());
+        client.send(b"second").unwrap();
+
+        // 第一条消息超过重试次数时，第二条消息不应被记为已重传
+        assert!(matches!(
+            client.retransmit(),
+            Err(RainbowError::Unacknowledged(0))
+        ));
+        assert_eq!(client.unacked[&1].retries, 0);
+    }
+
+    #[test]
+    fn test_ack_packet_with_parity() {
+        init();
+        let options = EncodeOptions {
+            encoder: Some("octet".to_string()),
+            parity: 2,
+            ..Default::default()
+        };
+        let mut client = ReliableSession::new(Box::new(Rainbow::from_secret(b"reliable")), true)
+            .with_options(options.clone());
+        let mut server = ReliableSession::new(Box::new(Rainbow::from_secret(b"reliable")), false)
+            .with_options(options);
+
+        let mut received = Vec::new();
+        for packet in client.send(b"request").unwrap() {
+            received.extend(server.receive(packet).unwrap());
+        }
+        assert_eq!(received, vec![b"request".to_vec()]);
+
+        // 只携带确认的数据包不能占用服务器下一条消息的序号
+        let ack = server.ack_packet().unwrap().unwrap();
+        assert!(client.receive(ack).unwrap().is_none());
+        assert!(client.is_idle());
+
+        let mut replies = Vec::new();
+        for packet in server.send(b"reply").unwrap() {
+            replies.extend(client.receive(packet).unwrap());
+        }
+        assert_eq!(replies, vec![b"reply".to_vec()]);
+    }
+}
@@ -0,0 +1,162 @@
+/*!
+ * Replay module protects the decode side against re-injected packets.
+ *
+ * Every packet carries a timestamp and a unique nonce in its sealed metadata. A [`ReplayGuard`]
+ * rejects packets whose timestamp is outside a freshness window, and remembers the
+ * (session, index, nonce) of every fresh packet it has seen, so a captured packet cannot be
+ * accepted twice. Entries are forgotten once the packet they describe would be stale anyway.
+ */
+
+use std::collections::{BTreeSet, HashSet};
+use std::time::Duration;
+
+use chrono::Utc;
+
+use crate::{RainbowError, Result};
+
+/// Identifies a packet: (session id, packet index, metadata nonce)
+type PacketKey = (u32, usize, [u8; 12]);
+
+/// Rejects stale and replayed packets
+#[derive(Debug, Clone)]
+pub struct ReplayGuard {
+    window: i64,
+    seen: HashSet<PacketKey>,
+    /// The same entries as `seen`, ordered by packet timestamp for expiry
+    by_time: BTreeSet<(i64, PacketKey)>,
+}
+
+impl ReplayGuard {
+    /// Accept packets whose timestamp is within `window` of the local clock, in either direction
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window: window.as_secs() as i64,
+            seen: HashSet::new(),
+            by_time: BTreeSet::new(),
+        }
+    }
+
+    /// The freshness window
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window as u64)
+    }
+
+    /// Number of packets currently remembered
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    /// Check a packet against the local clock and record it.
+    ///
+    /// Fails with [`RainbowError::ReplayRejected`] if the packet is stale or has been seen before.
+    pub fn check(
+        &mut self,
+        session_id: u32,
+        index: usize,
+        nonce: [u8; 12],
+        timestamp: i64,
+    ) -> Result<()> {
+        self.check_at(session_id, index, nonce, timestamp, Utc::now().timestamp())
+    }
+
+    fn check_at(
+        &mut self,
+        session_id: u32,
+        index: usize,
+        nonce: [u8; 12],
+        timestamp: i64,
+        now: i64,
+    ) -> Result<()> {
+        self.expire(now);
+
+        if (now - timestamp).abs() > self.window {
+            return Err(RainbowError::ReplayRejected(format!(
+                "stale packet: timestamp {} is {}s away from now",
+                timestamp,
+                now - timestamp
+            )));
+        }
+
+        let key = (session_id, index, nonce);
+        if !self.seen.insert(key) {
+            return Err(RainbowError::ReplayRejected(format!(
+                "packet {} of session {} already seen",
+                index, session_id
+            )));
+        }
+        self.by_time.insert((timestamp, key));
+        Ok(())
+    }
+
+    /// Forget packets that would fail the freshness check anyway
+    fn expire(&mut self, now: i64) {
+        while let Some(&(timestamp, key)) = self.by_time.first() {
+            if now - timestamp <= self.window {
+                break;
+            }
+            self.by_time.pop_first();
+            self.seen.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay() {
+        let mut guard = ReplayGuard::new(Duration::from_secs(30));
+        let now = 1_700_000_000;
+
+        guard.check_at(1, 0, [1; 12], now, now).unwrap();
+        assert!(matches!(
+            guard.check_at(1, 0, [1; 12], now, now + 1),
+            Err(RainbowError::ReplayRejected(_))
+        ));
+
+        // 不同的 nonce、索引或会话不是重放
+        guard.check_at(1, 0, [2; 12], now, now + 1).unwrap();
+        guard.check_at(1, 1, [1; 12], now, now + 1).unwrap();
+        guard.check_at(2, 0, [1; 12], now, now + 1).unwrap();
+        assert_eq!(guard.len(), 4);
+    }
+
+    #[test]
+    fn test_freshness() {
+        let mut guard = ReplayGuard::new(Duration::from_secs(30));
+        let now = 1_700_000_000;
+
+        guard.check_at(1, 0, [1; 12], now - 30, now).unwrap();
+        guard.check_at(1, 0, [2; 12], now + 30, now).unwrap();
+        assert!(matches!(
+            guard.check_at(1, 0, [3; 12], now - 31, now),
+            Err(RainbowError::ReplayRejected(_))
+        ));
+        assert!(matches!(
+            guard.check_at(1, 0, [4; 12], now + 31, now),
+            Err(RainbowError::ReplayRejected(_))
+        ));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut guard = ReplayGuard::new(Duration::from_secs(30));
+        let now = 1_700_000_000;
+
+        guard.check_at(1, 0, [1; 12], now, now).unwrap();
+        guard.check_at(1, 1, [1; 12], now + 20, now + 20).unwrap();
+        assert_eq!(guard.len(), 2);
+
+        // 第一个包过期后被遗忘，但重放它仍然会因为不新鲜而被拒绝
+        assert!(matches!(
+            guard.check_at(1, 0, [1; 12], now, now + 31),
+            Err(RainbowError::ReplayRejected(_))
+        ));
+        assert_eq!(guard.len(), 1);
+    }
+}
@@ -0,0 +1,142 @@
+/*!
+ * Responder module answers received packets with packets of the length the peer asked for.
+ *
+ * Every packet carries the length of the packet expected in return, see
+ * [`DecodeResult::expected_return_length`]. A [`Responder`] keeps the data waiting to be sent
+ * and builds each reply with [`NetworkSteganographyProcessor::encode_reply`], so the sizes of
+ * request/response pairs follow the distribution chosen by the sender of the request, whether
+ * there is data to send or not.
+ */
+
+use crate::{DecodeResult, EncodeOptions, NetworkSteganographyProcessor, ReplyResult, Result};
+
+/// Answers every received packet with a packet of the expected length
+#[derive(Clone)]
+pub struct Responder {
+    processor: Box<dyn NetworkSteganographyProcessor>,
+    is_client: bool,
+    options: EncodeOptions,
+    /// Data not sent yet
+    pending: Vec<u8>,
+}
+
+impl Responder {
+    /// `is_client` has the same meaning as in [`NetworkSteganographyProcessor::encode_reply`]
+    pub fn new(processor: Box<dyn NetworkSteganographyProcessor>, is_client: bool) -> Self {
+        Self {
+            processor,
+            is_client,
+            options: EncodeOptions::default(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Options used to encode the replies
+    pub fn with_options(mut self, options: EncodeOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Queue data, it is carried by the next replies
+    pub fn push(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+    }
+
+    /// Number of queued bytes not sent yet
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Build the reply to a received packet, of the MIME type its route asks for when possible
+    pub fn respond(&mut self, received: &DecodeResult) -> Result<ReplyResult> {
+        self.build_reply(
+            received.expected_return_length,
+            EncodeOptions {
+                preferred_mime_type: received.reply_mime_type.clone(),
+                ..self.options.clone()
+            },
+        )
+    }
+
+    /// Build a packet of `target_length` bytes, carrying queued data or cover data if there is
+    /// none
+    pub fn reply(&mut self, target_length: usize) -> Result<ReplyResult> {
+        self.build_reply(target_length, self.options.clone())
+    }
+
+    fn build_reply(&mut self, target_length: usize, options: EncodeOptions) -> Result<ReplyResult> {
+        let result =
+            self.processor
+                .encode_reply(&self.pending, self.is_client, target_length, options)?;
+        self.pending.drain(..result.consumed);
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rainbow::Rainbow, session::DecodeSession, EncodeResult};
+
+    fn init() {
+        let _ = tracing_subscriber::fmt()
+            .with_test_writer()
+            .with_max_level(tracing::Level::DEBUG)
+            .try_init();
+    }
+
+    #[test]
+    fn test_replies_follow_expected_lengths() {
+        init();
+        let client = Rainbow::from_secret(b"responder");
+        let server = Rainbow::from_secret(b"responder");
+        let options = EncodeOptions {
+            encoder: Some("octet".to_string()),
+            ..Default::default()
+        };
+
+        let mut responder = Responder::new(Box::new(server.clone()), false).with_options(options);
+        let message: Vec<u8> = (0..600).map(|i| (i % 251) as u8).collect();
+        responder.push(&message);
+
+        let mut session = DecodeSession::new(Box::new(client.clone()), false);
+        let mut received = Vec::new();
+        let mut cover_replies = 0;
+
+        // 客户端不断轮询，服务器的每个响应都符合请求中的预期长度
+        for _ in 0..10 {
+            let EncodeResult {
+                encoded_packets,
+                expected_return_packet_lengths,
+                ..
+            } = client
+                .encode_write(b"", true, EncodeOptions::default())
+                .unwrap();
+            let request = server
+                .decrypt_single_read(encoded_packets[0].clone(), 0, true)
+                .unwrap();
+            assert_eq!(
+                request.expected_return_length,
+                expected_return_packet_lengths[0]
+            );
+
+            let reply = responder.respond(&request).unwrap();
+            assert_eq!(reply.packet.len(), request.expected_return_length);
+            if reply.consumed == 0 {
+                cover_replies += 1;
+            }
+
+            if let Some(data) = session.feed(reply.packet).unwrap() {
+                received.extend(data);
+            }
+        }
+
+        assert_eq!(received, message);
+        assert!(responder.is_empty());
+        assert!(cover_replies > 0);
+    }
+}
@@ -0,0 +1,567 @@
+/*!
+ * Session module reassembles multi-packet messages on the decode side.
+ *
+ * [`NetworkSteganographyProcessor::encode_write`] splits a message into several packets, and
+ * [`NetworkSteganographyProcessor::decrypt_single_read`] only ever returns the chunk of a single
+ * packet. A [`DecodeSession`] buffers those chunks by the seq/index/total carried in the packet
+ * metadata, so packets can arrive in any order, and yields each original message once every
+ * packet of it has arrived. A message sent with parity packets is yielded once any `k` of its
+ * `n` packets have arrived, lost data packets being rebuilt by [`fec::reconstruct`]. A message
+ * compressed as a whole before being split is decompressed once reassembled.
+ *
+ * A [`Demultiplexer`] routes decoded packets of several sessions and streams to one
+ * [`DecodeSession`] each, so one conversation can carry several logical streams.
+ */
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use tracing::debug;
+
+use crate::{
+    compression, fec,
+    reliable::{seq_before, Ack},
+    DecodeResult, NetworkSteganographyProcessor, RainbowError, Result,
+};
+
+/// The packets of one message received so far
+#[derive(Debug, Clone, Default)]
+struct PartialMessage {
+    total: usize,
+    /// Number of parity packets, at the end of the `total` packets
+    parity: usize,
+    /// Whether the message was compressed as a whole before being split
+    is_compressed: bool,
+    chunks: BTreeMap<usize, Vec<u8>>,
+}
+
+impl PartialMessage {
+    fn is_complete(&self) -> bool {
+        self.chunks.len() >= self.total - self.parity
+    }
+
+    /// The message, rebuilding lost data packets from the parity packets and decompressing it
+    fn into_data(self) -> Result<Vec<u8>> {
+        let is_compressed = self.is_compressed;
+        let data = self.reassemble()?;
+        if is_compressed {
+            compression::decompress(&data)
+        } else {
+            Ok(data)
+        }
+    }
+
+    /// The data carried by the packets, rebuilding lost data packets from the parity packets
+    fn reassemble(mut self) -> Result<Vec<u8>> {
+        let data_chunks = self.total - self.parity;
+        if self.parity == 0 || (0..data_chunks).all(|i| self.chunks.contains_key(&i)) {
+            return Ok(self
+                .chunks
+                .into_values()
+                .take(data_chunks)
+                .flatten()
+                .collect());
+        }
+
+        let mut shards: Vec<Option<Vec<u8>>> =
+            (0..self.total).map(|i| self.chunks.remove(&i)).collect();
+        let parity = shards.split_off(data_chunks);
+        debug!(
+            "Rebuilding {} lost packets from {} parity packets",
+            shards.iter().filter(|s| s.is_none()).count(),
+            parity.iter().filter(|s| s.is_some()).count()
+        );
+        Ok(fec::reconstruct(shards, parity)?.concat())
+    }
+}
+
+/// Reassembles the messages of a stream, with their packets in any order
+#[derive(Clone)]
+pub struct DecodeSession {
+    processor: Box<dyn NetworkSteganographyProcessor>,
+    is_client: bool,
+    /// Messages with at least one packet received, by sequence number
+    pending: BTreeMap<u32, PartialMessage>,
+    /// Every message with a sequence number below this has been delivered
+    delivered_below: u32,
+    /// Messages delivered out of order, at or above `delivered_below`
+    delivered: BTreeSet<u32>,
+}
+
+impl DecodeSession {
+    /// `is_client` has the same meaning as in [`NetworkSteganographyProcessor::decrypt_single_read`]
+    pub fn new(processor: Box<dyn NetworkSteganographyProcessor>, is_client: bool) -> Self {
+        Self {
+            processor,
+            is_client,
+            pending: BTreeMap::new(),
+            delivered_below: 0,
+            delivered: BTreeSet::new(),
+        }
+    }
+
+    /// Decode a packet and buffer its chunk.
+    ///
+    /// Returns the complete message once every packet of it has arrived.
+    pub fn feed(&mut self, packet: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let result = self
+            .processor
+            .decrypt_single_read(packet, self.received(), self.is_client)?;
+        self.accept(result)
+    }
+
+    /// Buffer an already decoded packet, see [`DecodeSession::feed`]. Cover packets are
+    /// dropped
+    pub fn accept(&mut self, result: DecodeResult) -> Result<Option<Vec<u8>>> {
+        if result.is_cover {
+            debug!("Dropped cover packet");
+            return Ok(None);
+        }
+
+        if result.total == 0 || result.index >= result.total {
+            return Err(RainbowError::InvalidData(format!(
+                "Packet index {} out of range for {} packets",
+                result.index, result.total
+            )));
+        }
+
+        if result.parity >= result.total {
+            return Err(RainbowError::InvalidData(format!(
+                "{} parity packets out of {} packets",
+                result.parity, result.total
+            )));
+        }
+
+        if self.is_delivered(result.seq) {
+            // 消息恢复后，剩余的数据包和校验包是意料之中的
+            if result.parity > 0 {
+                return Ok(None);
+            }
+            return Err(RainbowError::DuplicatePacket(result.index));
+        }
+
+        let message = self
+            .pending
+            .entry(result.seq)
+            .or_insert_with(|| PartialMessage {
+                total: result.total,
+                parity: result.parity,
+                is_compressed: result.is_compressed,
+                chunks: BTreeMap::new(),
+            });
+
+        // 重传的消息可能被切分成不同数量的数据包，此时丢弃之前收到的数据包
+        if message.total != result.total
+            || message.parity != result.parity
+            || message.is_compressed != result.is_compressed
+        {
+            debug!(
+                "Message {} sent again in {} packets instead of {}, restarting it",
+                result.seq, result.total, message.total
+            );
+            *message = PartialMessage {
+                total: result.total,
+                parity: result.parity,
+                is_compressed: result.is_compressed,
+                chunks: BTreeMap::new(),
+            };
+        }
+        if message.chunks.contains_key(&result.index) {
+            return Err(RainbowError::DuplicatePacket(result.index));
+        }
+        message.chunks.insert(result.index, result.data);
+
+        debug!(
+            "Buffered packet {}/{} of message {}, {} received",
+            result.index + 1,
+            result.total,
+            result.seq,
+            message.chunks.len()
+        );
+
+        if !message.is_complete() {
+            return Ok(None);
+        }
+
+        let message = self.pending.remove(&result.seq).unwrap_or_default();
+        self.mark_delivered(result.seq);
+        message.into_data().map(Some)
+    }
+
+    /// Whether the message `seq` has been delivered
+    pub fn is_delivered(&self, seq: u32) -> bool {
+        seq_before(seq, self.delivered_below) || self.delivered.contains(&seq)
+    }
+
+    fn mark_delivered(&mut self, seq: u32) {
+        self.delivered.insert(seq);
+        while self.delivered.remove(&self.delivered_below) {
+            self.delivered_below = self.delivered_below.wrapping_add(1);
+        }
+    }
+
+    /// The oldest message that has not been fully received yet
+    fn current(&self) -> Option<&PartialMessage> {
+        self.pending.values().next()
+    }
+
+    /// Whether no message is partially received
+    pub fn is_complete(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Number of packets of the oldest incomplete message received so far
+    pub fn received(&self) -> usize {
+        self.current().map_or(0, |m| m.chunks.len())
+    }
+
+    /// Total number of packets of the oldest incomplete message, if any
+    pub fn total(&self) -> Option<usize> {
+        self.current().map(|m| m.total)
+    }
+
+    /// Indices of the packets of the oldest incomplete message that have not arrived yet
+    pub fn missing(&self) -> Vec<usize> {
+        self.current().map_or_else(Vec::new, |m| {
+            (0..m.total).filter(|i| !m.chunks.contains_key(i)).collect()
+        })
+    }
+
+    /// Acknowledgement of the messages delivered so far
+    pub fn ack(&self) -> Ack {
+        let selective = (0..32)
+            .filter(|i| {
+                self.delivered
+                    .contains(&self.delivered_below.wrapping_add(1 + i))
+            })
+            .fold(0, |bits, i| bits | 1 << i);
+        Ack {
+            next: self.delivered_below,
+            selective,
+        }
+    }
+
+    /// Drop every buffered packet of incomplete messages
+    pub fn reset(&mut self) {
+        self.pending.clear();
+    }
+}
+
+/// A complete message routed by a [`Demultiplexer`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamMessage {
+    pub session_id: u32,
+    pub stream_id: u32,
+    pub seq: u32,
+    pub data: Vec<u8>,
+}
+
+/// Routes every decoded packet to the [`DecodeSession`] of its session and stream
+#[derive(Clone)]
+pub struct Demultiplexer {
+    processor: Box<dyn NetworkSteganographyProcessor>,
+    is_client: bool,
+    streams: HashMap<(u32, u32), DecodeSession>,
+}
+
+impl Demultiplexer {
+    /// `is_client` has the same meaning as in [`NetworkSteganographyProcessor::decrypt_single_read`]
+    pub fn new(processor: Box<dyn NetworkSteganographyProcessor>, is_client: bool) -> Self {
+        Self {
+            processor,
+            is_client,
+            streams: HashMap::new(),
+        }
+    }
+
+    /// Decode a packet and route it to its stream.
+    ///
+    /// Returns a message once every packet of it has arrived.
+    pub fn feed(&mut self, packet: Vec<u8>) -> Result<Option<StreamMessage>> {
+        let result = self
+            .processor
+            .decrypt_single_read(packet, 0, self.is_client)?;
+        self.accept(result)
+    }
+
+    /// Route an already decoded packet, see [`Demultiplexer::feed`]. Cover packets are
+    /// dropped without opening a stream
+    pub fn accept(&mut self, result: DecodeResult) -> Result<Option<StreamMessage>> {
+        if result.is_cover {
+            return Ok(None);
+        }
+        let (session_id, stream_id, seq) = (result.session_id, result.stream_id, result.seq);
+        let session = self
+            .streams
+            .entry((session_id, stream_id))
+            .or_insert_with(|| {
+                debug!("New stream {} in session {}", stream_id, session_id);
+                DecodeSession::new(self.processor.clone(), self.is_client)
+            });
+
+        Ok(session.accept(result)?.map(|data| StreamMessage {
+            session_id,
+            stream_id,
+            seq,
+            data,
+        }))
+    }
+
+    /// The (session id, stream id) of every stream seen so far
+    pub fn streams(&self) -> Vec<(u32, u32)> {
+        self.streams.keys().copied().collect()
+    }
+
+    /// The reassembly state of a stream
+    pub fn stream(&self, session_id: u32, stream_id: u32) -> Option<&DecodeSession> {
+        self.streams.get(&(session_id, stream_id))
+    }
+
+    /// Forget a stream and drop its buffered packets
+    pub fn close(&mut self, session_id: u32, stream_id: u32) {
+        self.streams.remove(&(session_id, stream_id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chunking::{ChunkingStrategy, DEFAULT_CHUNK_SIZE},
+        rainbow::Rainbow,
+        EncodeOptions, EncodeResult,
+    };
+
+    fn init() {
+        let _ = tracing_subscriber::fmt()
+            .with_test_writer()
+            .with_max_level(tracing::Level::DEBUG)
+            .try_init();
+    }
+
+    fn encode_stream(rainbow: &Rainbow, data: &[u8], stream_id: u32) -> Vec<Vec<u8>> {
+        let EncodeResult {
+            encoded_packets, ..
+        } = rainbow
+            .encode_write(
+                data,
+                true,
+                EncodeOptions {
+                    encoder: Some("octet".to_string()),
+                    stream_id,
+                    chunking: ChunkingStrategy::Fixed(DEFAULT_CHUNK_SIZE),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        encoded_packets
+    }
+
+    fn encode(rainbow: &Rainbow, data: &[u8]) -> Vec<Vec<u8>> {
+        encode_stream(rainbow, data, 0)
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        init();
+        let rainbow = Rainbow::new();
+        let test_data: Vec<u8> = (0..1000).map(|i| (i % 256) as u8).collect();
+        let mut packets = encode(&rainbow, &test_data);
+        assert_eq!(packets.len(), 4);
+        packets.swap(0, 3);
+        packets.swap(1, 2);
+
+        let mut session = DecodeSession::new(Box::new(rainbow), true);
+        let last = packets.pop().unwrap();
+        for packet in packets {
+            assert!(session.feed(packet).unwrap().is_none());
+        }
+        assert_eq!(session.total(), Some(4));
+        assert_eq!(session.missing(), vec![0]);
+
+        let message = session.feed(last).unwrap().unwrap();
+        assert_eq!(message, test_data);
+        assert_eq!(session.received(), 0);
+        assert!(session.missing().is_empty());
+    }
+
+    #[test]
+    fn test_duplicates_and_gaps() {
+        init();
+        let rainbow = Rainbow::new();
+        let test_data = vec![7u8; 600];
+        let packets = encode(&rainbow, &test_data);
+        assert_eq!(packets.len(), 3);
+
+        let mut session = DecodeSession::new(Box::new(rainbow), true);
+        assert!(session.feed(packets[2].clone()).unwrap().is_none());
+        assert!(matches!(
+            session.feed(packets[2].clone()),
+            Err(RainbowError::DuplicatePacket(2))
+        ));
+        assert_eq!(session.missing(), vec![0, 1]);
+
+        assert!(session.feed(packets[0].clone()).unwrap().is_none());
+        assert_eq!(session.missing(), vec![1]);
+        let message = session.feed(packets[1].clone()).unwrap().unwrap();
+        assert_eq!(message, test_data);
+
+        // 已交付消息的迟到副本
+        assert!(matches!(
+            session.feed(packets[0].clone()),
+            Err(RainbowError::DuplicatePacket(0))
+        ));
+    }
+
+    #[test]
+    fn test_rebuild_from_parity() {
+        init();
+        let rainbow = Rainbow::new();
+        let test_data: Vec<u8> = (0..1200).map(|i| (i * 7 % 256) as u8).collect();
+        let EncodeResult {
+            encoded_packets: packets,
+            ..
+        } = rainbow
+            .encode_write(
+                &test_data,
+                true,
+                EncodeOptions {
+                    encoder: Some("octet".to_string()),
+                    chunking: ChunkingStrategy::Fixed(DEFAULT_CHUNK_SIZE),
+                    parity: 2,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(packets.len(), 7);
+
+        let last = rainbow
+            .decrypt_single_read(packets[6].clone(), 6, true)
+            .unwrap();
+        assert!(last.is_parity);
+        assert_eq!(last.parity, 2);
+
+        // 丢失两个数据包，任意五个数据包即可恢复消息
+        let mut session = DecodeSession::new(Box::new(rainbow), true);
+        let mut message = None;
+        for i in [6, 3, 1, 4, 5] {
+            assert!(message.is_none());
+            message = session.feed(packets[i].clone()).unwrap();
+        }
+        assert_eq!(message.unwrap(), test_data);
+        assert!(session.is_complete());
+
+        // 迟到的数据包被忽略
+        assert!(session.feed(packets[0].clone()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_consecutive_messages() {
+        init();
+        let rainbow = Rainbow::new();
+        let mut session = DecodeSession::new(Box::new(rainbow.clone()), true);
+
+        for message in [b"first message".to_vec(), vec![1u8; 300]] {
+            let mut received = None;
+            for packet in encode(&rainbow, &message) {
+                received = session.feed(packet).unwrap();
+            }
+            assert_eq!(received.unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn test_delivered_wraps_around() {
+        let mut session = DecodeSession::new(Box::new(Rainbow::new()), true);
+        session.delivered_below = u32::MAX - 1;
+
+        session.mark_delivered(u32::MAX);
+        assert!(!session.is_delivered(u32::MAX - 1));
+        assert!(session.is_delivered(u32::MAX));
+        assert_eq!(session.ack().selective, 0b1);
+
+        // 序号回绕之后，回绕前的消息仍算已交付，新消息不算
+        session.mark_delivered(u32::MAX - 1);
+        assert_eq!(session.ack().next, 0);
+        assert!(session.delivered.is_empty());
+        assert!(session.is_delivered(u32::MAX - 1));
+        assert!(session.is_delivered(u32::MAX));
+        assert!(!session.is_delivered(0));
+        assert!(!session.is_delivered(1));
+    }
+
+    #[test]
+    fn test_interleaved_messages() {
+        init();
+        let rainbow = Rainbow::new();
+        let first = vec![1u8; 600];
+        let second = vec![2u8; 400];
+        let first_packets = encode(&rainbow, &first);
+        let second_packets = encode(&rainbow, &second);
+
+        let mut session = DecodeSession::new(Box::new(rainbow), true);
+        assert!(session.feed(first_packets[0].clone()).unwrap().is_none());
+        assert!(session.feed(second_packets[1].clone()).unwrap().is_none());
+        assert!(session.feed(first_packets[1].clone()).unwrap().is_none());
+        assert_eq!(
+            session.feed(second_packets[0].clone()).unwrap().unwrap(),
+            second
+        );
+        assert_eq!(
+            session.feed(first_packets[2].clone()).unwrap().unwrap(),
+            first
+        );
+    }
+
+    #[test]
+    fn test_demultiplexer() {
+        init();
+        let client = Rainbow::from_secret(b"mux").with_session_id(42);
+        let server = Rainbow::from_secret(b"mux");
+
+        let streams: Vec<(u32, Vec<u8>)> = vec![
+            (1, vec![1u8; 700]),
+            (2, b"short message".to_vec()),
+            (3, vec![3u8; 300]),
+        ];
+
+        // 交错发送不同流的数据包
+        let mut packets: Vec<Vec<u8>> = Vec::new();
+        let encoded: Vec<Vec<Vec<u8>>> = streams
+            .iter()
+            .map(|(stream_id, data)| encode_stream(&client, data, *stream_id))
+            .collect();
+        let max_len = encoded.iter().map(|p| p.len()).max().unwrap();
+        for i in 0..max_len {
+            for stream_packets in &encoded {
+                if let Some(packet) = stream_packets.get(i) {
+                    packets.push(packet.clone());
+                }
+            }
+        }
+
+        let mut demux = Demultiplexer::new(Box::new(server), true);
+        let mut received = HashMap::new();
+        for packet in packets {
+            if let Some(message) = demux.feed(packet).unwrap() {
+                assert_eq!(message.session_id, 42);
+                assert_eq!(message.seq, 0);
+                received.insert(message.stream_id, message.data);
+            }
+        }
+
+        assert_eq!(demux.streams().len(), 3);
+        for (stream_id, data) in streams {
+            assert_eq!(received[&stream_id], data);
+        }
+
+        // 同一流的下一条消息使用下一个序列号
+        let packets = encode_stream(&client, b"next", 2);
+        let message = demux.feed(packets[0].clone()).unwrap().unwrap();
+        assert_eq!(message.stream_id, 2);
+        assert_eq!(message.seq, 1);
+        assert_eq!(message.data, b"next");
+
+        demux.close(42, 2);
+        assert!(demux.stream(42, 2).is_none());
+    }
+}
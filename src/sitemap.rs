@@ -0,0 +1,524 @@
+/*!
+ * Sitemap module decides the method, URL and content types of the requests packets imitate.
+ *
+ * A real site does not serve stylesheets from `/submit`, nor take PNG bodies on
+ * `/assets/main.css`: every URL has a method, a file extension, and the content types it takes
+ * and returns. A [`SiteMap`] lists such [`Route`]s. A request carrying data of some MIME type
+ * goes to a route taking that type, with the `Accept` header of the type the route returns, and
+ * the server answers with that type when it can, see
+ * [`DecodeResult::reply_mime_type`](crate::DecodeResult::reply_mime_type).
+ *
+ * GET routes take their data in header fields, see [`stego::header`](crate::stego::header).
+ * POST routes take it in the body, as is or as a file uploaded with a `multipart/form-data`
+ * form. Data no route takes is uploaded to `/upload`.
+ *
+ * The default site map covers the MIME types of the built-in encoders, and others can be built
+ * with [`SiteMap::with_route`] or loaded from a JSON array of routes with [`SiteMap::load`].
+ */
+
+use std::{fs, path::Path};
+
+use rand::{distributions::Alphanumeric, seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    profile::RequestKind,
+    stego::header,
+    utils::{data_find, mime_to_extension},
+    RainbowError, Result,
+};
+
+/// Words replacing `{name}` in paths
+const NAMES: &[&str] = &[
+    "main", "app", "logo", "banner", "latest", "report", "theme", "avatar", "intro", "summary",
+];
+
+/// Content-Type of the bodies of upload forms
+const MULTIPART_FORM_DATA: &str = "multipart/form-data";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Method {
+    Get,
+    Post,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Get => "GET",
+            Self::Post => "POST",
+        }
+    }
+}
+
+/// A URL of a [`SiteMap`] and the content types it takes and returns
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Route {
+    pub method: Method,
+    /// The path, where `{id}` stands for a number and `{name}` for a word, both picked for
+    /// every request
+    pub path: String,
+    /// MIME type of the request body, for POST routes
+    #[serde(default)]
+    pub request: Option<String>,
+    /// Whether the request body is a file uploaded with a `multipart/form-data` form
+    #[serde(default)]
+    pub multipart: bool,
+    /// MIME type of the response
+    pub response: String,
+}
+
+impl Route {
+    /// A route fetching a resource of type `response`
+    pub fn get(path: &str, response: &str) -> Self {
+        Self {
+            method: Method::Get,
+            path: path.to_string(),
+            request: None,
+            multipart: false,
+            response: response.to_string(),
+        }
+    }
+
+    /// A route taking a body of type `request` as is
+    pub fn post(path: &str, request: &str, response: &str) -> Self {
+        Self {
+            method: Method::Post,
+            path: path.to_string(),
+            request: Some(request.to_string()),
+            multipart: false,
+            response: response.to_string(),
+        }
+    }
+
+    /// A route taking a file of type `request` uploaded with a form
+    pub fn upload(path: &str, request: &str, response: &str) -> Self {
+        Self {
+            multipart: true,
+            ..Self::post(path, request, response)
+        }
+    }
+
+    /// Whether the request line `method path` is one of this route
+    fn matches(&self, method: &str, path: &str) -> bool {
+        let path = path.split('?').next().unwrap_or_default();
+        self.method.as_str() == method && matches_template(&self.path, path)
+    }
+}
+
+/// The routes of the site requests are sent to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SiteMap {
+    routes: Vec<Route>,
+}
+
+impl Default for SiteMap {
+    /// Pages, stylesheets, images and APIs of a typical site, covering the MIME types of the
+    /// built-in encoders
+    fn default() -> Self {
+        Self::new()
+            .with_route(Route::get("/", "text/html"))
+            .with_route(Route::get("/index.html", "text/html"))
+            .with_route(Route::get("/blog/{name}", "text/html"))
+            .with_route(Route::get("/docs/{name}.html", "text/html"))
+            .with_route(Route::get("/assets/{name}.css", "text/css"))
+            .with_route(Route::get("/static/css/{name}.css", "text/css"))
+            .with_route(Route::get("/images/{name}.png", "image/png"))
+            .with_route(Route::get("/media/{id}.png", "image/png"))
+            .with_route(Route::get("/icons/{name}.svg", "image/svg+xml"))
+            .with_route(Route::get("/audio/{name}.wav", "audio/wav"))
+            .with_route(Route::get("/api/v1/{name}", "application/json"))
+            .with_route(Route::get("/api/v1/items/{id}", "application/json"))
+            .with_route(Route::get("/feed.xml", "application/xml"))
+            .with_route(Route::get("/sitemap.xml", "application/xml"))
+            .with_route(Route::get("/notes/{id}.txt", "text/plain"))
+            .with_route(Route::get(
+                "/downloads/{name}.bin",
+                "application/octet-stream",
+            ))
+            .with_route(Route::post(
+                "/api/v1/events",
+                "application/json",
+                "application/json",
+            ))
+            .with_route(Route::post(
+                "/api/v2/sync",
+                "application/json",
+                "application/json",
+            ))
+            .with_route(Route::post(
+                "/api/v1/logs",
+                "text/plain",
+                "application/json",
+            ))
+            .with_route(Route::post(
+                "/xmlrpc.php",
+                "application/xml",
+                "application/xml",
+            ))
+            .with_route(Route::post(
+                "/api/v1/blobs",
+                "application/octet-stream",
+                "application/json",
+            ))
+            .with_route(Route::upload(
+                "/upload/avatar.png",
+                "image/png",
+                "application/json",
+            ))
+            .with_route(Route::upload(
+                "/api/v1/photos",
+                "image/png",
+                "application/json",
+            ))
+            .with_route(Route::upload(
+                "/upload/{name}.svg",
+                "image/svg+xml",
+                "application/json",
+            ))
+            .with_route(Route::upload(
+                "/api/v1/voice-notes",
+                "audio/wav",
+                "application/json",
+            ))
+            .with_route(Route::upload(
+                "/upload/{name}.html",
+                "text/html",
+                "application/json",
+            ))
+            .with_route(Route::upload(
+                "/admin/themes/upload",
+                "text/css",
+                "text/html",
+            ))
+    }
+}
+
+impl SiteMap {
+    /// A site map without routes, add some with [`with_route`](Self::with_route)
+    pub fn new() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    pub fn with_route(mut self, route: Route) -> Self {
+        self.routes.push(route);
+        self
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    /// Parse a site map from a JSON array of routes
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Load a site map from a JSON file, see [`from_json`](Self::from_json)
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_json(&fs::read_to_string(path)?)
+    }
+
+    /// Pick a request carrying data of type `mime_type`, among the routes taking it
+    pub fn target<R: Rng + ?Sized>(&self, mime_type: &str, rng: &mut R) -> Target {
+        let candidates: Vec<&Route> = self
+            .routes
+            .iter()
+            .filter(|route| match route.method {
+                Method::Get => mime_type == header::MIME_TYPE,
+                Method::Post => route.request.as_deref() == Some(mime_type),
+            })
+            .collect();
+        Self::pick(&candidates, mime_type, rng)
+    }
+
+    /// Like [`target`](Self::target), but only among POST routes, whose bodies can be padded
+    pub fn post_target<R: Rng + ?Sized>(&self, mime_type: &str, rng: &mut R) -> Target {
+        let candidates: Vec<&Route> = self
+            .routes
+            .iter()
+            .filter(|route| {
+                route.method == Method::Post && route.request.as_deref() == Some(mime_type)
+            })
+            .collect();
+        Self::pick(&candidates, mime_type, rng)
+    }
+
+    /// MIME type of the response to the request `method path`, if it is one of our routes
+    pub fn reply_mime_type(&self, method: &str, path: &str) -> Option<&str> {
+        self.routes
+            .iter()
+            .find(|route| route.matches(method, path))
+            .map(|route| route.response.as_str())
+    }
+
+    /// A path to redirect to, one of the pages of the GET routes
+    pub fn location<R: Rng + ?Sized>(&self, rng: &mut R) -> String {
+        let pages: Vec<&Route> = self
+            .routes
+            .iter()
+            .filter(|route| route.method == Method::Get && route.response == "text/html")
+            .collect();
+        pages
+            .choose(rng)
+            .map_or_else(|| "/".to_string(), |route| expand(&route.path, rng))
+    }
+
+    fn pick<R: Rng + ?Sized>(candidates: &[&Route], mime_type: &str, rng: &mut R) -> Target {
+        // 没有路由接收这种数据时，作为文件上传，头部字段中的数据则随首页的请求发送
+        let fallback = if mime_type == header::MIME_TYPE {
+            Route::get("/", "text/html")
+        } else {
+            Route::upload("/upload", mime_type, "application/json")
+        };
+        let route = candidates.choose(rng).copied().unwrap_or(&fallback);
+        Target::new(route, mime_type, rng)
+    }
+}
+
+/// A request picked from a [`SiteMap`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub method: Method,
+    /// The path, placeholders replaced
+    pub path: String,
+    /// MIME type of the response the request asks for
+    pub response: String,
+    /// MIME type of the data carried
+    pub mime_type: String,
+    /// Boundary and file name of the upload form, for multipart routes
+    upload: Option<(String, String)>,
+}
+
+impl Target {
+    fn new<R: Rng + ?Sized>(route: &Route, mime_type: &str, rng: &mut R) -> Self {
+        let path = expand(&route.path, rng);
+        let upload = route.multipart.then(|| {
+            let boundary: String = (0..16).map(|_| rng.sample(Alphanumeric) as char).collect();
+            // 路径本身是文件名时沿用，否则按类型取扩展名
+            let last = path.rsplit('/').next().unwrap_or_default();
+            let extension = mime_to_extension(mime_type);
+            let file_name = if last.ends_with(&format!(".{}", extension)) {
+                last.to_string()
+            } else {
+                format!("{}.{}", NAMES.choose(rng).unwrap(), extension)
+            };
+            (format!("----WebKitFormBoundary{}", boundary), file_name)
+        });
+        Self {
+            method: route.method,
+            path,
+            response: route.response.clone(),
+            mime_type: mime_type.to_string(),
+            upload,
+        }
+    }
+
+    /// What the request does: a GET of a page loads it, other requests are made by its
+    /// scripts
+    pub fn kind(&self) -> RequestKind {
+        if self.method == Method::Get && self.response == "text/html" {
+            RequestKind::Navigate
+        } else {
+            RequestKind::Cors
+        }
+    }
+
+    /// The `Accept` header, asking for the type of the response
+    pub fn accept(&self) -> &'static str {
+        accept_header(&self.response)
+    }
+
+    /// The `Content-Type` header of the body
+    pub fn content_type(&self) -> String {
+        match &self.upload {
+            Some((boundary, _)) => format!("{}; boundary={}", MULTIPART_FORM_DATA, boundary),
+            None => self.mime_type.clone(),
+        }
+    }
+
+    /// The body carrying `data`, the encoded data of the packet
+    pub fn body(&self, data: &[u8]) -> Vec<u8> {
+        let Some((boundary, file_name)) = &self.upload else {
+            return data.to_vec();
+        };
+        let mut body = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\
+             Content-Type: {}\r\n\r\n",
+            boundary, file_name, self.mime_type
+        )
+        .into_bytes();
+        body.extend_from_slice(data);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+        body
+    }
+}
+
+/// Whether `content_type` is that of an upload form
+pub fn is_multipart(content_type: &str) -> bool {
+    content_type.starts_with(MULTIPART_FORM_DATA)
+}
+
+/// The MIME type and content of the file uploaded by a form built by [`Target::body`]
+pub fn read_upload<'a>(body: &'a [u8], content_type: &str) -> Result<(String, &'a [u8])> {
+    let invalid = || RainbowError::InvalidData("Invalid multipart body".to_string());
+    let boundary = content_type
+        .split(';')
+        .filter_map(|param| param.trim().strip_prefix("boundary="))
+        .next()
+        .ok_or_else(invalid)?;
+
+    let start = data_find(body, b"\r\n\r\n").ok_or_else(invalid)?;
+    let part_headers = String::from_utf8_lossy(&body[..start]);
+    let mime_type = part_headers
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-type"))
+        .map(|(_, value)| value.trim().to_string())
+        .ok_or_else(invalid)?;
+
+    let content = &body[start + 4..];
+    let end = data_find(content, format!("\r\n--{}--", boundary).as_bytes()).ok_or_else(invalid)?;
+    Ok((mime_type, &content[..end]))
+}
+
+/// The `Accept` header of a browser asking for a resource of type `mime_type`
+fn accept_header(mime_type: &str) -> &'static str {
+    match mime_type {
+        "text/html" => {
+            "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,*/*;q=0.8"
+        }
+        "text/css" => "text/css,*/*;q=0.1",
+        "application/json" => "application/json, text/plain, */*",
+        "application/xml" => "application/xml,text/xml;q=0.9,*/*;q=0.8",
+        m if m.starts_with("image/") => "image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8",
+        m if m.starts_with("audio/") => {
+            "audio/webm,audio/ogg,audio/wav,audio/*;q=0.9,application/ogg;q=0.7,video/*;q=0.6,*/*;q=0.5"
+        }
+        _ => "*/*",
+    }
+}
+
+/// Replace the placeholders of a route path
+fn expand<R: Rng + ?Sized>(template: &str, rng: &mut R) -> String {
+    let mut path = template.to_string();
+    while let Some(start) = path.find("{id}") {
+        path.replace_range(start..start + 4, &rng.gen_range(1..100_000).to_string());
+    }
+    while let Some(start) = path.find("{name}") {
+        path.replace_range(start..start + 6, NAMES.choose(rng).unwrap());
+    }
+    path
+}
+
+/// Whether `path` is one of the paths of the route path `template`, where a placeholder stands
+/// for any text up to the next `/` or `.`
+fn matches_template(template: &str, path: &str) -> bool {
+    let (mut template, mut path) = (template, path);
+    while let Some(start) = template.find('{') {
+        let Some(rest) = path.strip_prefix(&template[..start]) else {
+            return false;
+        };
+        let len = rest.find(['/', '.']).unwrap_or(rest.len());
+        if len == 0 {
+            return false;
+        }
+        let end = template[start..]
+            .find('}')
+            .map_or(template.len(), |i| start + i + 1);
+        template = &template[end..];
+        path = &rest[len..];
+    }
+    template == path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_targets() {
+        let site_map = SiteMap::default();
+        let mut rng = rand::thread_rng();
+        for _ in 0..50 {
+            // 上传的图片是表单中的文件
+            let png = site_map.post_target("image/png", &mut rng);
+            assert_eq!(png.method, Method::Post);
+            assert!(png.path == "/upload/avatar.png" || png.path == "/api/v1/photos");
+            assert!(png
+                .content_type()
+                .starts_with("multipart/form-data; boundary="));
+            let body = png.body(b"\x89PNG");
+            let text = String::from_utf8_lossy(&body);
+            assert!(text.contains("filename=\"") && text.contains(".png\""));
+            let (mime, file) = read_upload(&body, &png.content_type()).unwrap();
+            assert_eq!((mime.as_str(), file), ("image/png", &b"\x89PNG"[..]));
+            assert_eq!(
+                site_map.reply_mime_type("POST", &png.path),
+                Some("application/json")
+            );
+
+            // GET 请求的路径决定响应的类型
+            let get = site_map.target(header::MIME_TYPE, &mut rng);
+            assert_eq!(get.method, Method::Get);
+            let response = site_map
+                .reply_mime_type(get.method.as_str(), &get.path)
+                .unwrap();
+            assert_eq!(response, get.response);
+            assert_eq!(get.kind() == RequestKind::Navigate, response == "text/html");
+            assert_eq!(png.kind(), RequestKind::Cors);
+            if response == "text/css" {
+                assert!(get.path.ends_with(".css"));
+                assert_eq!(get.accept(), "text/css,*/*;q=0.1");
+            }
+            assert_eq!(
+                site_map.target("application/json", &mut rng).method,
+                Method::Post
+            );
+
+            let xml = site_map.target("application/xml", &mut rng);
+            assert_eq!(xml.method, Method::Post);
+            assert_eq!(xml.content_type(), "application/xml");
+            assert_eq!(xml.body(b"<a/>"), b"<a/>");
+        }
+
+        // 没有路由接收的数据作为文件上传
+        let font = site_map.target("font/woff2", &mut rng);
+        assert_eq!(font.path, "/upload");
+        assert!(is_multipart(&font.content_type()));
+        assert!(String::from_utf8_lossy(&font.body(b"")).contains(".woff2\""));
+    }
+
+    #[test]
+    fn test_reply_mime_type() {
+        let site_map = SiteMap::default();
+        assert_eq!(
+            site_map.reply_mime_type("GET", "/assets/main.css"),
+            Some("text/css")
+        );
+        assert_eq!(
+            site_map.reply_mime_type("GET", "/api/v1/items/42?page=2"),
+            Some("application/json")
+        );
+        assert_eq!(site_map.reply_mime_type("GET", "/assets/main.js"), None);
+        assert_eq!(site_map.reply_mime_type("GET", "/assets/.css"), None);
+        assert_eq!(site_map.reply_mime_type("POST", "/assets/main.css"), None);
+        let location = site_map.location(&mut rand::thread_rng());
+        assert_eq!(
+            site_map.reply_mime_type("GET", &location),
+            Some("text/html")
+        );
+        assert_eq!(SiteMap::new().location(&mut rand::thread_rng()), "/");
+
+        let json = serde_json::to_string(&site_map).unwrap();
+        assert_eq!(SiteMap::from_json(&json).unwrap(), site_map);
+        let custom = SiteMap::from_json(
+            r#"[{"method": "GET", "path": "/wiki/{name}", "response": "text/html"}]"#,
+        )
+        .unwrap();
+        assert_eq!(custom.routes(), [Route::get("/wiki/{name}", "text/html")]);
+        assert!(SiteMap::from_json(r#"[{"method": "PUT", "path": "/"}]"#).is_err());
+    }
+}
@@ -0,0 +1,446 @@
+/*!
+ * SSE module streams messages from a server as Server-Sent Events.
+ *
+ * An [`EventStreamWriter`] answers a request with a single long-lived `text/event-stream`
+ * response, and sends every message written to it as more events of that response, one per
+ * packet: the `data:` lines of an event carry JSON produced by the encoders, and its `id:`
+ * carries the sealed packet info, see [`Rainbow::encode_events`]. The events are flushed as the
+ * chunks of a chunked body, with comments keeping the connection alive in between, as sites
+ * pushing live updates do.
+ *
+ * On the client, an [`EventStreamParser`] parses the response incrementally, returning each
+ * event as soon as its blank line has arrived, and an [`EventStreamReader`] decodes those
+ * events into messages.
+ */
+
+use rand::Rng;
+
+use crate::{
+    identity::SessionIdentity,
+    rainbow::Rainbow,
+    session::DecodeSession,
+    utils::{find_crlf, find_crlf_crlf, find_header_value, is_chunked},
+    EncodeOptions, RainbowError, Result,
+};
+
+pub const EVENT_STREAM_MIME_TYPE: &str = "text/event-stream";
+
+/// What the data of the events we send is encoded as
+pub const EVENT_DATA_MIME_TYPE: &str = "application/json";
+
+/// Event types of live update feeds
+const EVENT_NAMES: [&str; 6] = [
+    "message",
+    "update",
+    "notification",
+    "status",
+    "price",
+    "heartbeat",
+];
+
+/// Comments servers send to keep an idle event stream open
+const KEEP_ALIVE_COMMENTS: [&str; 3] = [": ping", ": keep-alive", ":"];
+
+/// An event of a `text/event-stream` body
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Event {
+    /// The `event:` type, `None` for the default `message`
+    pub event: Option<String>,
+    /// The `id:` of the event, if it has one
+    pub id: Option<String>,
+    /// The `data:` lines, joined by line feeds
+    pub data: String,
+}
+
+impl Event {
+    /// An event of a random type
+    pub fn new(id: String, data: String) -> Self {
+        let name = EVENT_NAMES[rand::thread_rng().gen_range(0..EVENT_NAMES.len())];
+        Self {
+            event: (name != "message").then(|| name.to_string()),
+            id: Some(id),
+            data,
+        }
+    }
+
+    /// The event as sent on the wire, ending with its blank line
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        if let Some(event) = &self.event {
+            text.push_str(&format!("event: {}\n", event));
+        }
+        if let Some(id) = &self.id {
+            text.push_str(&format!("id: {}\n", id));
+        }
+        for line in self.data.split('\n') {
+            text.push_str(&format!("data: {}\n", line));
+        }
+        text.push('\n');
+        text
+    }
+}
+
+/// The head of a `text/event-stream` response with the headers of `identity`, with a chunked
+/// body
+pub fn response_head(identity: &SessionIdentity) -> String {
+    let fields = format!(
+        "Content-Type: {}; charset=utf-8\r\nCache-Control: no-cache\r\nConnection: keep-alive\r\n\
+         X-Accel-Buffering: no\r\n",
+        EVENT_STREAM_MIME_TYPE
+    );
+    format!(
+        "HTTP/1.1 200 OK\r\n{}Transfer-Encoding: chunked\r\n\r\n",
+        identity.headers(&fields, None)
+    )
+}
+
+/// A chunk of a chunked body
+fn chunk(data: &[u8]) -> Vec<u8> {
+    let mut chunk = format!("{:x}\r\n", data.len()).into_bytes();
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(b"\r\n");
+    chunk
+}
+
+/// Sends messages as the events of a single `text/event-stream` response
+#[derive(Debug, Clone)]
+pub struct EventStreamWriter {
+    rainbow: Rainbow,
+    options: EncodeOptions,
+    /// Whether the response head has been sent
+    head_sent: bool,
+}
+
+impl EventStreamWriter {
+    pub fn new(rainbow: Rainbow) -> Self {
+        Self {
+            rainbow,
+            options: EncodeOptions::default(),
+            head_sent: false,
+        }
+    }
+
+    /// Options used to encode the messages, their MIME type must be [`EVENT_DATA_MIME_TYPE`]
+    pub fn with_options(mut self, options: EncodeOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// The response head, before the first chunk sent
+    fn start(&mut self) -> Vec<u8> {
+        if self.head_sent {
+            return Vec::new();
+        }
+        self.head_sent = true;
+        response_head(self.rainbow.identity(false)).into_bytes()
+    }
+
+    /// The bytes to send for a message: an event per packet, in a chunk each
+    pub fn write(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let events = self.rainbow.encode_events(data, self.options.clone())?;
+        let mut out = self.start();
+        for event in events {
+            out.extend(chunk(event.to_text().as_bytes()));
+        }
+        Ok(out)
+    }
+
+    /// A comment keeping the connection open while there is nothing to send
+    pub fn keep_alive(&mut self) -> Vec<u8> {
+        let comment =
+            KEEP_ALIVE_COMMENTS[rand::thread_rng().gen_range(0..KEEP_ALIVE_COMMENTS.len())];
+        let mut out = self.start();
+        out.extend(chunk(format!("{}\n\n", comment).as_bytes()));
+        out
+    }
+
+    /// The end of the response
+    pub fn finish(mut self) -> Vec<u8> {
+        let mut out = self.start();
+        out.extend_from_slice(b"0\r\n\r\n");
+        out
+    }
+}
+
+/// Parses a `text/event-stream` response as its bytes arrive
+#[derive(Debug, Clone, Default)]
+pub struct EventStreamParser {
+    /// Bytes received and not parsed yet
+    buf: Vec<u8>,
+    /// Whether the body is chunked, once the response head has been parsed
+    chunked: Option<bool>,
+    /// Body received and not split in lines yet
+    text: Vec<u8>,
+    /// The event being parsed, and whether it has data
+    event: Event,
+    has_data: bool,
+    /// Whether the end of a chunked body has been received
+    finished: bool,
+}
+
+impl EventStreamParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse the bytes received, and return the events they complete
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<Event>> {
+        self.buf.extend_from_slice(data);
+
+        if self.chunked.is_none() {
+            let Some(split_pos) = find_crlf_crlf(&self.buf) else {
+                return Ok(Vec::new());
+            };
+            let header = String::from_utf8_lossy(&self.buf[..split_pos]).into_owned();
+            if !header.starts_with("HTTP/1.1 200") {
+                return Err(RainbowError::InvalidData(format!(
+                    "Unexpected event stream status: {}",
+                    header.lines().next().unwrap_or_default()
+                )));
+            }
+            if !find_header_value(&header, "Content-Type")
+                .is_some_and(|value| value.starts_with(EVENT_STREAM_MIME_TYPE))
+            {
+                return Err(RainbowError::InvalidData("Not an event stream".to_string()));
+            }
+            self.chunked = Some(is_chunked(&header));
+            self.buf.drain(..split_pos + 4);
+        }
+
+        if self.chunked == Some(true) {
+            self.dechunk()?;
+        } else {
+            self.text.append(&mut self.buf);
+        }
+        self.parse_lines()
+    }
+
+    /// Whether the end of the response has been received
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Move the complete chunks received to the body text
+    fn dechunk(&mut self) -> Result<()> {
+        let mut pos = 0;
+        while !self.finished {
+            let Some(line_len) = find_crlf(&self.buf[pos..]) else {
+                break;
+            };
+            let line = String::from_utf8_lossy(&self.buf[pos..pos + line_len]);
+            let size_str = line.split(';').next().unwrap_or_default().trim();
+            let size = usize::from_str_radix(size_str, 16).map_err(|_| {
+                RainbowError::InvalidData(format!("Invalid chunk size: {}", size_str))
+            })?;
+            if size == 0 {
+                // 忽略尾部字段
+                self.finished = true;
+                pos = self.buf.len();
+                break;
+            }
+
+            let start = pos + line_len + 2;
+            let end = start.checked_add(size).ok_or_else(|| {
+                RainbowError::InvalidData(format!("Chunk size too large: {}", size_str))
+            })?;
+            if self.buf.len() < end + 2 {
+                break;
+            }
+            if &self.buf[end..end + 2] != b"\r\n" {
+                return Err(RainbowError::InvalidData(
+                    "Chunk not followed by CRLF".to_string(),
+                ));
+            }
+            self.text.extend_from_slice(&self.buf[start..end]);
+            pos = end + 2;
+        }
+        self.buf.drain(..pos);
+        Ok(())
+    }
+
+    /// Parse the complete lines of the body text, which end with CRLF, LF or CR
+    fn parse_lines(&mut self) -> Result<Vec<Event>> {
+        let mut events = Vec::new();
+        let mut pos = 0;
+        while let Some(len) = self.text[pos..]
+            .iter()
+            .position(|&b| b == b'\n' || b == b'\r')
+        {
+            let end = pos + len;
+            let next = match self.text[end] {
+                b'\r' if end + 1 == self.text.len() && !self.finished => break,
+                b'\r' if self.text.get(end + 1) == Some(&b'\n') => end + 2,
+                _ => end + 1,
+            };
+            let line = String::from_utf8(self.text[pos..end].to_vec())
+                .map_err(|_| RainbowError::InvalidData("Event stream is not UTF-8".to_string()))?;
+            if let Some(event) = self.parse_line(&line) {
+                events.push(event);
+            }
+            pos = next;
+        }
+        self.text.drain(..pos);
+        Ok(events)
+    }
+
+    /// Apply a line to the event being parsed, and return the event if the line ends it
+    fn parse_line(&mut self, line: &str) -> Option<Event> {
+        if line.is_empty() {
+            let mut event = std::mem::take(&mut self.event);
+            // 没有数据的事件不会被分发
+            if !std::mem::take(&mut self.has_data) {
+                return None;
+            }
+            event.data.pop();
+            return Some(event);
+        }
+        if line.starts_with(':') {
+            return None;
+        }
+
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "event" => self.event.event = Some(value.to_string()),
+            "id" if !value.contains('\0') => self.event.id = Some(value.to_string()),
+            "data" => {
+                self.event.data.push_str(value);
+                self.event.data.push('\n');
+                self.has_data = true;
+            }
+            // retry 和未知字段都忽略
+            _ => {}
+        }
+        None
+    }
+}
+
+/// Decodes the messages carried by a `text/event-stream` response as its bytes arrive
+#[derive(Clone)]
+pub struct EventStreamReader {
+    rainbow: Rainbow,
+    parser: EventStreamParser,
+    session: DecodeSession,
+}
+
+impl EventStreamReader {
+    pub fn new(rainbow: Rainbow) -> Self {
+        Self {
+            session: DecodeSession::new(Box::new(rainbow.clone()), false),
+            rainbow,
+            parser: EventStreamParser::new(),
+        }
+    }
+
+    /// Parse and decode the bytes received, and return the messages they complete
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let mut messages = Vec::new();
+        for event in self.parser.feed(data)? {
+            // 没有 id 的事件不是我们发送的
+            if event.id.is_none() {
+                continue;
+            }
+            let result = self.rainbow.decode_event(&event, self.session.received())?;
+            messages.extend(self.session.accept(result)?);
+        }
+        Ok(messages)
+    }
+
+    /// Whether the end of the response has been received
+    pub fn is_finished(&self) -> bool {
+        self.parser.is_finished()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::TrafficProfile;
+
+    fn init() {
+        let _ = tracing_subscriber::fmt()
+            .with_test_writer()
+            .with_max_level(tracing::Level::DEBUG)
+            .try_init();
+    }
+
+    #[test]
+    fn test_parse_events() {
+        let mut parser = EventStreamParser::new();
+        let body = "retry: 3000\r\n: comment\r\nevent: update\r\nid: 7\r\ndata: {\"a\":\r\ndata:1}\r\n\r\n\
+                    data: plain\n\nid: 8\n\ndata\rdata: last\r\r";
+        let response = [
+            response_head(&SessionIdentity::new(TrafficProfile::default_for(false))).into_bytes(),
+            chunk(body.as_bytes()),
+            b"0\r\n\r\n".to_vec(),
+        ]
+        .concat();
+
+        // 逐字节到达时，事件在空行到达后立即返回
+        let mut events = Vec::new();
+        for byte in &response {
+            events.extend(parser.feed(std::slice::from_ref(byte)).unwrap());
+        }
+        assert!(parser.is_finished());
+        assert_eq!(
+            events,
+            vec![
+                Event {
+                    event: Some("update".to_string()),
+                    id: Some("7".to_string()),
+                    data: "{\"a\":\n1}".to_string(),
+                },
+                Event {
+                    event: None,
+                    id: None,
+                    data: "plain".to_string(),
+                },
+                Event {
+                    event: None,
+                    id: None,
+                    data: "\nlast".to_string(),
+                },
+            ]
+        );
+
+        let event = Event::new("token".to_string(), "{\n}".to_string());
+        let mut parser = EventStreamParser::new();
+        let mut unchunked = b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\r\n".to_vec();
+        unchunked.extend_from_slice(event.to_text().as_bytes());
+        assert_eq!(parser.feed(&unchunked).unwrap(), vec![event]);
+
+        let mut parser = EventStreamParser::new();
+        assert!(parser
+            .feed(b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n")
+            .is_err());
+    }
+
+    #[test]
+    fn test_stream_messages() {
+        init();
+        let mut writer = EventStreamWriter::new(Rainbow::from_secret(b"sse"));
+        let mut reader = EventStreamReader::new(Rainbow::from_secret(b"sse"));
+
+        let large: Vec<u8> = (0..5000).map(|_| rand::random()).collect();
+        let mut response = writer.write(b"first").unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        response.extend(writer.keep_alive());
+        response.extend(writer.write(&large).unwrap());
+        response.extend(writer.write(b"").unwrap());
+        response.extend(writer.finish());
+
+        let mut messages = Vec::new();
+        for piece in response.chunks(100) {
+            messages.extend(reader.feed(piece).unwrap());
+        }
+        assert!(reader.is_finished());
+        assert_eq!(messages, vec![b"first".to_vec(), large, Vec::new()]);
+
+        let mut writer =
+            EventStreamWriter::new(Rainbow::from_secret(b"sse")).with_options(EncodeOptions {
+                mime_type: Some("text/html".to_string()),
+                ..Default::default()
+            });
+        assert!(writer.write(b"html").is_err());
+    }
+}
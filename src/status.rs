@@ -0,0 +1,170 @@
+/*!
+ * Status module gives the responses packets imitate the shape their status code calls for.
+ *
+ * A `204 No Content` with a body, or a `206 OK` without `Content-Range`, gives a packet away.
+ * Responses are built per status code:
+ *
+ * - 200, 201, 202 and 206 carry data in their body, 206 with the `Content-Range` of a part of
+ *   some larger resource
+ * - 204 and 304 have no body, but header fields hiding data such as an `ETag`
+ * - redirects have a `Location` and the short page servers send along
+ * - 404, 500 and the other errors have the error page of the server
+ *
+ * Successful statuses with a body carry data in it, 204 and 304 in their header fields. The
+ * others are sent by packets carrying none, such as cover replies, whose packet info says so.
+ */
+
+use rand::Rng;
+
+use crate::utils::HTTP_CONSTANTS;
+
+/// The reason phrase of a status code
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "OK",
+    }
+}
+
+/// The status line of a response
+pub fn status_line(status: u16) -> String {
+    format!("HTTP/1.1 {} {}\r\n", status, reason_phrase(status))
+}
+
+/// Whether responses with this status have a body, all but 1xx, 204 and 304
+pub fn has_body(status: u16) -> bool {
+    !(100..200).contains(&status) && status != 204 && status != 304
+}
+
+/// Whether responses with this status carry data in their body, the successful ones with a
+/// body
+pub fn carries_data(status: u16) -> bool {
+    (200..300).contains(&status) && has_body(status)
+}
+
+/// Whether responses with this status carry data in header fields, 204 and 304 which have no
+/// body, see [`stego::header`](crate::stego::header)
+pub fn carries_header_data(status: u16) -> bool {
+    status >= 200 && !has_body(status)
+}
+
+/// Whether responses with this status have a `Location`
+pub fn is_redirect(status: u16) -> bool {
+    (300..400).contains(&status) && status != 304
+}
+
+/// Pick a status code among those `accept` accepts, as often as servers send them, or 200
+pub fn random_status_code<R: Rng + ?Sized>(rng: &mut R, accept: impl Fn(u16) -> bool) -> u16 {
+    let codes: Vec<(u16, f32)> = HTTP_CONSTANTS
+        .status_codes
+        .iter()
+        .copied()
+        .filter(|&(code, _)| accept(code))
+        .collect();
+    let total: f32 = codes.iter().map(|(_, prob)| prob).sum();
+
+    let mut pick = rng.gen::<f32>() * total;
+    for &(code, prob) in &codes {
+        if pick < prob {
+            return code;
+        }
+        pick -= prob;
+    }
+    // 浮点误差落在最后一个状态码，没有可选的状态码时返回 200
+    codes.last().map_or(200, |&(code, _)| code)
+}
+
+/// The `Content-Range` of a 206 response whose body is `len` bytes, a range of a larger
+/// resource
+pub fn content_range<R: Rng + ?Sized>(len: usize, rng: &mut R) -> String {
+    let start = rng.gen_range(0..=1 << 20);
+    let end = start + len.max(1) - 1;
+    let total = end + 1 + rng.gen_range(0..=1 << 20);
+    format!("bytes {}-{}/{}", start, end, total)
+}
+
+/// The page a server named `server` sends along a redirect or an error
+pub fn status_page(status: u16, server: &str) -> String {
+    let title = format!("{} {}", status, reason_phrase(status));
+    if server.eq_ignore_ascii_case("apache") {
+        format!(
+            "<!DOCTYPE HTML PUBLIC \"-//IETF//DTD HTML 2.0//EN\">\n<html><head>\n\
+             <title>{}</title>\n</head><body>\n<h1>{}</h1>\n</body></html>\n",
+            title,
+            reason_phrase(status)
+        )
+    } else {
+        // nginx 和 cloudflare 的页面
+        format!(
+            "<html>\r\n<head><title>{}</title></head>\r\n<body>\r\n<center><h1>{}</h1></center>\r\n\
+             <hr><center>{}</center>\r\n</body>\r\n</html>\r\n",
+            title, title, server
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_codes() {
+        let mut rng = rand::thread_rng();
+        assert_eq!(status_line(204), "HTTP/1.1 204 No Content\r\n");
+        assert_eq!(status_line(206), "HTTP/1.1 206 Partial Content\r\n");
+        assert!(!has_body(204) && !has_body(304) && has_body(404));
+        assert!(carries_data(206) && !carries_data(204) && !carries_data(302));
+        assert!(carries_header_data(204) && carries_header_data(304));
+        assert!(!carries_header_data(101) && !carries_header_data(200));
+        assert!(is_redirect(302) && !is_redirect(304));
+
+        for _ in 0..200 {
+            assert!(carries_data(random_status_code(&mut rng, carries_data)));
+            assert_eq!(random_status_code(&mut rng, |code| code == 404), 404);
+        }
+        assert_eq!(random_status_code(&mut rng, |_| false), 200);
+        let codes: Vec<u16> = (0..2000)
+            .map(|_| random_status_code(&mut rng, |_| true))
+            .collect();
+        assert!(codes.iter().filter(|&&code| code == 200).count() > 1500);
+        assert!(codes.iter().any(|&code| !carries_data(code)));
+
+        // 范围的长度与消息体一致
+        let range = content_range(100, &mut rng);
+        let (start, rest) = range
+            .strip_prefix("bytes ")
+            .unwrap()
+            .split_once('-')
+            .unwrap();
+        let (end, total) = rest.split_once('/').unwrap();
+        let (start, end, total): (usize, usize, usize) = (
+            start.parse().unwrap(),
+            end.parse().unwrap(),
+            total.parse().unwrap(),
+        );
+        assert_eq!(end - start + 1, 100);
+        assert!(total > end);
+
+        assert!(status_page(404, "nginx").contains("<center>nginx</center>"));
+        assert!(status_page(500, "apache").contains("<h1>Internal Server Error</h1>"));
+    }
+}
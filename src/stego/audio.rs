@@ -18,7 +18,10 @@ Best used for:
 */
 
 use crate::Result;
-use crate::{stego::Encoder, RainbowError};
+use crate::{
+    stego::{Capacity, Encoder},
+    RainbowError,
+};
 use base64::{engine::general_purpose, Engine as _};
 use std::f64::consts::PI;
 use tracing::{debug, warn};
@@ -88,7 +91,7 @@ impl AudioEncoder {
             }
 
             // Add brief silence between bytes
-            samples.extend(std::iter::repeat(0.0).take(4));
+            samples.extend(std::iter::repeat_n(0.0, 4));
         }
 
         samples
@@ -218,6 +221,10 @@ impl Encoder for AudioHtmlEncoder {
     fn get_mime_type(&self) -> &'static str {
         "text/html"
     }
+
+    fn capacity(&self) -> Capacity {
+        Capacity::new(850, 192.0).with_max_data_len(1000)
+    }
 }
 
 #[derive(Debug, Clone, Default)]
@@ -233,6 +240,10 @@ impl Encoder for AudioWavEncoder {
     fn get_mime_type(&self) -> &'static str {
         "audio/wav"
     }
+
+    fn capacity(&self) -> Capacity {
+        Capacity::new(470, 144.0)
+    }
     fn encode(&self, data: &[u8]) -> Result<Vec<u8>> {
         debug!("Encoding data using WAV stego");
 
@@ -276,11 +287,7 @@ impl Encoder for AudioWavEncoder {
         let mut reader =
             hound::WavReader::new(buf_reader).map_err(|e| RainbowError::Other(e.to_string()))?;
 
-        let samples: Vec<f64> = reader
-            .samples::<f32>()
-            .into_iter()
-            .map(|x| x.unwrap() as f64)
-            .collect();
+        let samples: Vec<f64> = reader.samples::<f32>().map(|x| x.unwrap() as f64).collect();
 
         if let Some(data) = self.encoder.extract_data(&samples) {
             return Ok(data);
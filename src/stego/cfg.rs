@@ -7,11 +7,14 @@
 use std::collections::{BTreeMap, HashMap};
 
 use bytes::{BufMut, BytesMut};
-use rand::seq::SliceRandom;
+use rand::{seq::SliceRandom, Rng};
 use serde::{Deserialize, Serialize};
 
 use crate::utils::find_matching_brace;
-use crate::{stego::Encoder, RainbowError, Result};
+use crate::{
+    stego::{Capacity, Encoder},
+    RainbowError, Result,
+};
 
 use fake::{faker::company::en::*, faker::name::en::*, Fake};
 
@@ -466,17 +469,31 @@ impl CFG {
 }
 use super::Random;
 
+/// `n` distinct values drawn from `fake`
+fn unique_fakes(n: usize, mut fake: impl FnMut() -> String) -> Vec<String> {
+    let mut values = Vec::with_capacity(n);
+    let mut seen = std::collections::HashSet::new();
+    while values.len() < n {
+        let value = fake();
+        if seen.insert(value.clone()) {
+            values.push(value);
+        }
+    }
+    values
+}
+
 impl Random for CFG {
     /// Generate a random CFG that looks like a news headline and has 32 bits capacity
-    fn random() -> Self {
+    fn random_with_rng<R: Rng + ?Sized>(rng: &mut R) -> Self {
         let start = vec!["{HEADLINE}\n{DATELINE}\n{CONTENT}\n{QUOTE_INTRO} {QUOTE}".to_string()];
 
         let headline = vec!["{SUBJECT} {VERB} {OBJECT}".to_string()];
 
         // 生成随机主语 (32)
-        let subjects: Vec<String> = (0..8)
-            .flat_map(|_| {
-                let company = CompanyName().fake::<String>();
+        // 公司名不能重复，否则同一文本对应多个选择，无法解码
+        let subjects: Vec<String> = unique_fakes(8, || CompanyName().fake_with_rng(rng))
+            .into_iter()
+            .flat_map(|company| {
                 vec![
                     company.clone(),
                     format!("The {} team", company),
@@ -515,7 +532,7 @@ impl Random for CFG {
                 .flat_map(|a| noun.iter().map(move |n| format!("{} {}", a, n)))
                 .collect();
             assert_eq!(combinations.len(), 64); // 8x8=64 种组合
-            combinations.shuffle(&mut rand::thread_rng());
+            combinations.shuffle(rng);
             combinations.into_iter().take(32).collect()
         };
 
@@ -528,7 +545,7 @@ impl Random for CFG {
 
             while cities.len() < 32 {
                 let city = fake::faker::address::en::CityName()
-                    .fake::<String>()
+                    .fake_with_rng::<String, _>(rng)
                     .to_uppercase();
 
                 if seen.insert(city.clone()) {
@@ -536,7 +553,7 @@ impl Random for CFG {
                 }
             }
 
-            cities.shuffle(&mut rand::thread_rng());
+            cities.shuffle(rng);
             cities
         };
 
@@ -560,7 +577,7 @@ impl Random for CFG {
                 .iter()
                 .flat_map(|t| impacts.iter().map(move |i| t.replace("{}", i).to_string()))
                 .collect();
-            combinations.shuffle(&mut rand::thread_rng());
+            combinations.shuffle(rng);
             combinations.into_iter().take(8).collect()
         };
 
@@ -568,9 +585,11 @@ impl Random for CFG {
 
         // 生成随机引述 (8个)
         let quotes: Vec<String> = {
-            let names: Vec<String> = (0..16).map(|_| Name().fake::<String>()).collect();
+            let names: Vec<String> = (0..16)
+                .map(|_| Name().fake_with_rng::<String, _>(rng))
+                .collect();
             let titles: Vec<String> = (0..16)
-                .map(|_| fake::faker::job::en::Title().fake::<String>())
+                .map(|_| fake::faker::job::en::Title().fake_with_rng::<String, _>(rng))
                 .collect();
             let templates = [
                 "\"We are excited about the potential impact of this development,\" said {}, {}.",
@@ -591,7 +610,7 @@ impl Random for CFG {
                         .to_string()
                 })
                 .collect();
-            combinations.shuffle(&mut rand::thread_rng());
+            combinations.shuffle(rng);
             combinations
         };
 
@@ -599,9 +618,8 @@ impl Random for CFG {
 
         // 生成随机引述介绍 (4个)
         let quote_intros: Vec<String> = {
-            let positions: Vec<String> = (0..8)
-                .map(|_| fake::faker::job::en::Title().fake::<String>())
-                .collect();
+            let positions: Vec<String> =
+                unique_fakes(8, || fake::faker::job::en::Title().fake_with_rng(rng));
             let verbs = [
                 "stated",
                 "commented",
@@ -616,7 +634,7 @@ impl Random for CFG {
                 .iter()
                 .flat_map(|p| verbs.iter().map(move |v| format!("The {} {},", p, v)))
                 .collect();
-            combinations.shuffle(&mut rand::thread_rng());
+            combinations.shuffle(rng);
             combinations.into_iter().take(4).collect()
         };
 
@@ -670,9 +688,11 @@ impl Default for CFGEncoder {
 }
 
 impl Random for CFGEncoder {
-    /// cfg: CFG::random()
-    fn random() -> Self {
-        Self { cfg: CFG::random() }
+    /// cfg: CFG::random_with_rng(rng)
+    fn random_with_rng<R: Rng + ?Sized>(rng: &mut R) -> Self {
+        Self {
+            cfg: CFG::random_with_rng(rng),
+        }
     }
 }
 
@@ -685,6 +705,10 @@ impl Encoder for CFGEncoder {
         "text/plain"
     }
 
+    fn capacity(&self) -> Capacity {
+        Capacity::new(570, 76.0)
+    }
+
     fn encode(&self, data: &[u8]) -> Result<Vec<u8>> {
         // 在直接编码时，需要要求 data 的 长度大于 capacity/8 字节
 
@@ -21,7 +21,7 @@ use fake::{faker::*, Fake};
 use rand::{thread_rng, Rng};
 use regex;
 
-use crate::stego::{Encoder, Random};
+use crate::stego::{Capacity, Encoder, Random};
 
 #[derive(Debug, Clone)]
 pub struct CssEncoder {
@@ -33,9 +33,7 @@ pub struct CssEncoder {
 }
 
 impl Random for CssEncoder {
-    fn random() -> Self {
-        let mut rng = thread_rng();
-
+    fn random_with_rng<R: Rng + ?Sized>(rng: &mut R) -> Self {
         // 随机生成两个不重叠的范围
         // delay_one 在 0.1..0.4 之间生成一个 0.1 宽度的范围
         let one_start = rng.gen_range(0.1..0.3);
@@ -46,14 +44,18 @@ impl Random for CssEncoder {
         let delay_zero = zero_start..(zero_start + 0.1);
 
         Self {
-            content_text: lorem::en::Paragraph(1..3).fake::<String>(),
+            content_text: lorem::en::Paragraph(1..3).fake_with_rng::<String, _>(rng),
             anim_prefix: format!(
                 "anim-{}",
-                name::en::FirstName().fake::<String>().to_lowercase()
+                name::en::FirstName()
+                    .fake_with_rng::<String, _>(rng)
+                    .to_lowercase()
             ),
             elem_prefix: format!(
                 "elem-{}",
-                name::en::LastName().fake::<String>().to_lowercase()
+                name::en::LastName()
+                    .fake_with_rng::<String, _>(rng)
+                    .to_lowercase()
             ),
             delay_one,
             delay_zero,
@@ -96,6 +98,10 @@ impl Encoder for CssEncoder {
     fn get_mime_type(&self) -> &'static str {
         "text/css"
     }
+
+    fn capacity(&self) -> Capacity {
+        Capacity::new(390, 343.0)
+    }
 }
 
 /// Encode data into CSS animation. Output is a CSS file.
@@ -20,10 +20,11 @@ Key features:
 
 use crate::Result;
 use fake::{faker::*, Fake};
+use rand::Rng;
 use regex::Regex;
 use tracing::{debug, info, trace, warn};
 
-use crate::stego::{Encoder, Random};
+use crate::stego::{Capacity, Encoder, Random};
 
 #[derive(Debug, Clone)]
 
@@ -35,18 +36,21 @@ pub struct FontEncoder {
 }
 
 impl Random for FontEncoder {
-    fn random() -> Self {
+    fn random_with_rng<R: Rng + ?Sized>(rng: &mut R) -> Self {
         Self {
             page_title: format!(
                 "Font Gallery - {}",
-                company::en::CompanyName().fake::<String>()
+                company::en::CompanyName().fake_with_rng::<String, _>(rng)
+            ),
+            font_family: format!(
+                "{} Sans",
+                name::en::LastName().fake_with_rng::<String, _>(rng)
             ),
-            font_family: format!("{} Sans", name::en::LastName().fake::<String>()),
             heading: format!(
                 "Typography by {}",
-                company::en::CompanyName().fake::<String>()
+                company::en::CompanyName().fake_with_rng::<String, _>(rng)
             ),
-            tail_text: lorem::en::Paragraph(2..4).fake::<String>(),
+            tail_text: lorem::en::Paragraph(2..4).fake_with_rng::<String, _>(rng),
         }
     }
 }
@@ -84,6 +88,10 @@ impl Encoder for FontEncoder {
     fn get_mime_type(&self) -> &'static str {
         "text/html"
     }
+
+    fn capacity(&self) -> Capacity {
+        Capacity::new(960, 178.0)
+    }
 }
 
 /// Convert bytes to font variation settings
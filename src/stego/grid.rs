@@ -16,10 +16,11 @@ Suitable for hiding data in CSS stylesheets where Grid/Flex layouts are expected
 
 use crate::Result;
 use fake::{faker::*, Fake};
+use rand::Rng;
 use regex::Regex;
 use tracing::debug;
 
-use crate::stego::{Encoder, Random};
+use crate::stego::{Capacity, Encoder, Random};
 
 #[derive(Debug, Clone)]
 
@@ -28,12 +29,16 @@ pub struct GridEncoder {
 }
 
 impl Random for GridEncoder {
-    fn random() -> Self {
+    fn random_with_rng<R: Rng + ?Sized>(rng: &mut R) -> Self {
         Self {
             container_class: format!(
                 "grid-{}-{}",
-                name::en::FirstName().fake::<String>().to_lowercase(),
-                name::en::LastName().fake::<String>().to_lowercase()
+                name::en::FirstName()
+                    .fake_with_rng::<String, _>(rng)
+                    .to_lowercase(),
+                name::en::LastName()
+                    .fake_with_rng::<String, _>(rng)
+                    .to_lowercase()
             ),
         }
     }
@@ -63,6 +68,10 @@ impl Encoder for GridEncoder {
     fn get_mime_type(&self) -> &'static str {
         "text/css"
     }
+
+    fn capacity(&self) -> Capacity {
+        Capacity::new(115, 10.2)
+    }
 }
 
 /// Encode byte data as CSS Grid/Flex properties
@@ -0,0 +1,466 @@
+/*!
+ * mod header provides a [`HeaderEncoder`] that hides data in the values of header fields, for
+ * the packets without a body: GET requests, and `204 No Content` or `304 Not Modified`
+ * responses.
+ *
+ * Each [`HeaderField`] hides a few bytes in a value that looks like any other:
+ *
+ * - `ETag` and `If-None-Match`: the hex digits of a weak entity tag (16 bytes)
+ * - `Last-Modified` and `If-Modified-Since`: the seconds of a date in 2024 (3 bytes)
+ * - `X-Request-ID`: the random digits of a UUID v4 (15 bytes)
+ * - `Cache-Control`: a `max-age` in minutes (1 byte)
+ * - `Cookie` and `Set-Cookie`: a CSRF token (24 bytes)
+ *
+ * The bytes the fields hide are a random salt, then the length of the data and the data,
+ * whitened by a keystream derived from the key and the salt, then random filler. Every value
+ * thus looks random, and only a peer with the key finds where the data ends.
+ */
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, NaiveDateTime};
+use rand::{seq::SliceRandom, Rng, RngCore};
+
+use super::{Capacity, Encoder, Random};
+use crate::{utils::secret_rng, RainbowError, Result};
+
+/// Name of the header encoders, to request them in
+/// [`EncodeOptions::encoder`](crate::EncodeOptions::encoder)
+pub const NAME: &str = "headers";
+
+/// MIME type of the header encoders. Never sent as a `Content-Type`, it marks the packets
+/// carrying their data in header fields
+pub const MIME_TYPE: &str = "message/http";
+
+/// Length of the salt of the keystream
+const SALT_LEN: usize = 4;
+
+/// Length of the length prefix of the data
+const LENGTH_LEN: usize = 2;
+
+/// The dates start at 2024-01-01 00:00:00 UTC
+const DATE_EPOCH: i64 = 1_704_067_200;
+
+/// Format of the dates of HTTP headers
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Cookie of the token requests send
+const REQUEST_TOKEN_COOKIE: &str = "csrftoken";
+
+/// Cookie of the token responses set
+const RESPONSE_TOKEN_COOKIE: &str = "XSRF-TOKEN";
+
+/// A header field whose value hides a fixed number of bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderField {
+    /// `ETag: W/"<32 hex digits>"`
+    ETag,
+    /// `If-None-Match: W/"<32 hex digits>"`
+    IfNoneMatch,
+    /// `Last-Modified: <date>`
+    LastModified,
+    /// `If-Modified-Since: <date>`
+    IfModifiedSince,
+    /// `X-Request-ID: <uuid>`
+    RequestId,
+    /// `Cache-Control: public, max-age=<seconds>`
+    CacheControl,
+    /// `Cookie: csrftoken=<token>`
+    Cookie,
+    /// `Set-Cookie: XSRF-TOKEN=<token>; Path=/; SameSite=Lax`
+    SetCookie,
+}
+
+impl HeaderField {
+    /// Name of the header field
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ETag => "ETag",
+            Self::IfNoneMatch => "If-None-Match",
+            Self::LastModified => "Last-Modified",
+            Self::IfModifiedSince => "If-Modified-Since",
+            Self::RequestId => "X-Request-ID",
+            Self::CacheControl => "Cache-Control",
+            Self::Cookie => "Cookie",
+            Self::SetCookie => "Set-Cookie",
+        }
+    }
+
+    /// Bytes the value hides
+    pub fn capacity(&self) -> usize {
+        match self {
+            Self::ETag | Self::IfNoneMatch => 16,
+            Self::LastModified | Self::IfModifiedSince => 3,
+            Self::RequestId => 15,
+            Self::CacheControl => 1,
+            Self::Cookie | Self::SetCookie => 24,
+        }
+    }
+
+    /// The value hiding `bytes`, [`capacity`](Self::capacity) bytes long
+    fn write<R: Rng + ?Sized>(&self, bytes: &[u8], rng: &mut R) -> String {
+        match self {
+            Self::ETag | Self::IfNoneMatch => format!("W/\"{}\"", to_hex(bytes)),
+            Self::LastModified | Self::IfModifiedSince => {
+                let seconds = bytes.iter().fold(0i64, |n, &b| n << 8 | b as i64);
+                DateTime::from_timestamp(DATE_EPOCH + seconds, 0)
+                    .unwrap_or_default()
+                    .format(HTTP_DATE)
+                    .to_string()
+            }
+            Self::RequestId => {
+                // 版本位固定为 4，变体位随机，其余 30 位十六进制数携带数据
+                let digits = to_hex(bytes);
+                let variant = *['8', '9', 'a', 'b'].choose(rng).unwrap();
+                format!(
+                    "{}-{}-4{}-{}{}-{}",
+                    &digits[..8],
+                    &digits[8..12],
+                    &digits[12..15],
+                    variant,
+                    &digits[15..18],
+                    &digits[18..]
+                )
+            }
+            Self::CacheControl => format!("public, max-age={}", (bytes[0] as u32 + 1) * 60),
+            Self::Cookie => format!("{}={}", REQUEST_TOKEN_COOKIE, URL_SAFE_NO_PAD.encode(bytes)),
+            Self::SetCookie => format!(
+                "{}={}; Path=/; SameSite=Lax",
+                RESPONSE_TOKEN_COOKIE,
+                URL_SAFE_NO_PAD.encode(bytes)
+            ),
+        }
+    }
+
+    /// The bytes `value` hides, if it is a value of this field
+    fn read(&self, value: &str) -> Option<Vec<u8>> {
+        let bytes = match self {
+            Self::ETag | Self::IfNoneMatch => {
+                from_hex(value.strip_prefix("W/\"")?.strip_suffix('"')?)?
+            }
+            Self::LastModified | Self::IfModifiedSince => {
+                let date = NaiveDateTime::parse_from_str(value, HTTP_DATE).ok()?;
+                let seconds = date.and_utc().timestamp() - DATE_EPOCH;
+                if !(0..1 << 24).contains(&seconds) {
+                    return None;
+                }
+                seconds.to_be_bytes()[5..].to_vec()
+            }
+            Self::RequestId => {
+                let digits: Vec<char> = value.chars().filter(|&c| c != '-').collect();
+                if digits.len() != 32 {
+                    return None;
+                }
+                let digits: String = digits
+                    .iter()
+                    .enumerate()
+                    .filter(|&(i, _)| i != 12 && i != 16)
+                    .map(|(_, c)| c)
+                    .collect();
+                from_hex(&digits)?
+            }
+            Self::CacheControl => {
+                let max_age: u32 = value.split("max-age=").nth(1)?.parse().ok()?;
+                if !max_age.is_multiple_of(60) {
+                    return None;
+                }
+                vec![u8::try_from((max_age / 60).checked_sub(1)?).ok()?]
+            }
+            Self::Cookie | Self::SetCookie => {
+                let name = if *self == Self::Cookie {
+                    REQUEST_TOKEN_COOKIE
+                } else {
+                    RESPONSE_TOKEN_COOKIE
+                };
+                // Cookie 头部中还有其他的 Cookie
+                let token = value
+                    .split(';')
+                    .find_map(|cookie| cookie.trim().strip_prefix(name)?.strip_prefix('='))?;
+                URL_SAFE_NO_PAD.decode(token).ok()?
+            }
+        };
+        (bytes.len() == self.capacity()).then_some(bytes)
+    }
+}
+
+/// HeaderEncoder hides data in the values of a set of header fields, see the
+/// [module documentation](self)
+#[derive(Debug, Clone)]
+pub struct HeaderEncoder {
+    fields: Vec<HeaderField>,
+    /// Key of the keystream whitening the data
+    key: [u8; 32],
+}
+
+impl Default for HeaderEncoder {
+    /// the fields of requests, with an all-zero key
+    fn default() -> Self {
+        Self::for_requests([0u8; 32])
+    }
+}
+
+impl Random for HeaderEncoder {
+    /// the fields of requests, with a random key
+    fn random_with_rng<R: Rng + ?Sized>(rng: &mut R) -> Self {
+        let mut key = [0u8; 32];
+        rng.fill_bytes(&mut key);
+        Self::for_requests(key)
+    }
+}
+
+impl HeaderEncoder {
+    /// Create a HeaderEncoder hiding data in `fields`, in this order
+    pub fn new(fields: Vec<HeaderField>, key: [u8; 32]) -> Self {
+        Self { fields, key }
+    }
+
+    /// The fields a GET request sends along with a cookie: `If-None-Match`,
+    /// `If-Modified-Since`, `X-Request-ID` and `Cookie`
+    pub fn for_requests(key: [u8; 32]) -> Self {
+        Self::new(
+            vec![
+                HeaderField::IfNoneMatch,
+                HeaderField::IfModifiedSince,
+                HeaderField::RequestId,
+                HeaderField::Cookie,
+            ],
+            key,
+        )
+    }
+
+    /// The fields a `204` or `304` response sends: `ETag`, `Last-Modified`, `Cache-Control`,
+    /// `X-Request-ID` and `Set-Cookie`
+    pub fn for_responses(key: [u8; 32]) -> Self {
+        Self::new(
+            vec![
+                HeaderField::ETag,
+                HeaderField::LastModified,
+                HeaderField::CacheControl,
+                HeaderField::RequestId,
+                HeaderField::SetCookie,
+            ],
+            key,
+        )
+    }
+
+    pub fn fields(&self) -> &[HeaderField] {
+        &self.fields
+    }
+
+    /// Bytes all the fields hide, salt and length included
+    fn field_capacity(&self) -> usize {
+        self.fields.iter().map(HeaderField::capacity).sum()
+    }
+
+    /// Most data the fields carry
+    pub fn max_data_len(&self) -> usize {
+        self.field_capacity().saturating_sub(SALT_LEN + LENGTH_LEN)
+    }
+
+    /// XOR `bytes` with the keystream of `salt`
+    fn whiten(&self, salt: &[u8], bytes: &mut [u8]) {
+        let mut seed = self.key.to_vec();
+        seed.extend_from_slice(salt);
+        let mut keystream = vec![0u8; bytes.len()];
+        secret_rng(&seed, "header-fields").fill_bytes(&mut keystream);
+        bytes
+            .iter_mut()
+            .zip(keystream)
+            .for_each(|(byte, key)| *byte ^= key);
+    }
+}
+
+impl Encoder for HeaderEncoder {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    /// Encode data into header fields, one `Name: value\r\n` line each
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() > self.max_data_len() {
+            return Err(RainbowError::EncodeFailed(format!(
+                "Header fields carry at most {} bytes, got {}",
+                self.max_data_len(),
+                data.len()
+            )));
+        }
+        let mut rng = rand::thread_rng();
+
+        let mut stream = vec![0u8; SALT_LEN];
+        rng.fill_bytes(&mut stream);
+        let mut body = (data.len() as u16).to_be_bytes().to_vec();
+        body.extend_from_slice(data);
+        self.whiten(&stream[..SALT_LEN], &mut body);
+        stream.extend(body);
+        stream.extend((stream.len()..self.field_capacity()).map(|_| rng.gen::<u8>()));
+
+        let mut lines = String::new();
+        let mut rest = &stream[..];
+        for field in &self.fields {
+            let (bytes, tail) = rest.split_at(field.capacity());
+            lines.push_str(&format!(
+                "{}: {}\r\n",
+                field.name(),
+                field.write(bytes, &mut rng)
+            ));
+            rest = tail;
+        }
+        Ok(lines.into_bytes())
+    }
+
+    /// Decode data from the header of a packet, other fields and the start line ignored
+    fn decode(&self, content: &[u8]) -> Result<Vec<u8>> {
+        let content = String::from_utf8_lossy(content);
+        let mut stream = Vec::with_capacity(self.field_capacity());
+        for field in &self.fields {
+            // 同名的头部可能有多个，如 Set-Cookie
+            let bytes = content
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .filter(|(name, _)| name.trim().eq_ignore_ascii_case(field.name()))
+                .find_map(|(_, value)| field.read(value.trim()))
+                .ok_or_else(|| {
+                    RainbowError::InvalidData(format!("Missing {} header", field.name()))
+                })?;
+            stream.extend(bytes);
+        }
+
+        if stream.len() < SALT_LEN + LENGTH_LEN {
+            return Err(RainbowError::InvalidData(
+                "Header fields too short".to_string(),
+            ));
+        }
+        let (salt, body) = stream.split_at_mut(SALT_LEN);
+        self.whiten(salt, body);
+        let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+        if len > self.max_data_len() {
+            return Err(RainbowError::InvalidData(format!(
+                "Invalid data length in header fields: {}",
+                len
+            )));
+        }
+        Ok(body[LENGTH_LEN..LENGTH_LEN + len].to_vec())
+    }
+
+    fn get_mime_type(&self) -> &'static str {
+        MIME_TYPE
+    }
+
+    /// The header fields have about the same length whatever the data, at most that of the
+    /// longest values
+    fn capacity(&self) -> Capacity {
+        let mut rng = rand::thread_rng();
+        let overhead = self
+            .fields
+            .iter()
+            .map(|field| {
+                let value = field.write(&vec![0xff; field.capacity()], &mut rng);
+                field.name().len() + value.len() + 4
+            })
+            .sum();
+        Capacity::new(overhead, 0.0).with_max_data_len(self.max_data_len())
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(digits: &str) -> Option<Vec<u8>> {
+    if !digits.len().is_multiple_of(2) || !digits.is_ascii() {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_fields() {
+        let mut rng = rand::thread_rng();
+        let fields = [
+            HeaderField::ETag,
+            HeaderField::LastModified,
+            HeaderField::RequestId,
+            HeaderField::CacheControl,
+            HeaderField::Cookie,
+            HeaderField::SetCookie,
+        ];
+        for field in fields {
+            for _ in 0..50 {
+                let bytes: Vec<u8> = (0..field.capacity()).map(|_| rng.gen()).collect();
+                let value = field.write(&bytes, &mut rng);
+                assert_eq!(
+                    field.read(&value),
+                    Some(bytes),
+                    "{}: {}",
+                    field.name(),
+                    value
+                );
+            }
+        }
+
+        let id = HeaderField::RequestId.write(&[0xab; 15], &mut rng);
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "4");
+        assert!("89ab".contains(&id[19..20]));
+        let date = HeaderField::LastModified.write(&[0, 0, 0], &mut rng);
+        assert_eq!(date, "Mon, 01 Jan 2024 00:00:00 GMT");
+        assert_eq!(
+            HeaderField::Cookie.read("_ga=GA1.1.1; csrftoken=AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"),
+            Some(vec![0; 24])
+        );
+        assert_eq!(HeaderField::CacheControl.read("max-age=90"), None);
+    }
+
+    #[test]
+    fn test_header_encoder() {
+        let key = [7u8; 32];
+        for encoder in [
+            HeaderEncoder::for_requests(key),
+            HeaderEncoder::for_responses(key),
+        ] {
+            let capacity = encoder.capacity();
+            assert_eq!(capacity.max_data_len, Some(encoder.max_data_len()));
+            assert!(encoder.max_data_len() > 48);
+
+            for len in [0, 1, 20, encoder.max_data_len()] {
+                let data: Vec<u8> = (0..len).map(|_| rand::random()).collect();
+                let encoded = encoder.encode(&data).unwrap();
+                let text = String::from_utf8(encoded.clone()).unwrap();
+                assert_eq!(text.lines().count(), encoder.fields().len());
+                assert!(encoded.len() <= capacity.overhead);
+
+                // 数据包的其他头部不影响解码
+                let packet = format!("GET / HTTP/1.1\r\nHost: example.com\r\n{}", text);
+                assert_eq!(encoder.decode(packet.as_bytes()).unwrap(), data);
+                // HTTP/2 的头部名称是小写的
+                let lowercase: String = text
+                    .lines()
+                    .filter_map(|line| line.split_once(": "))
+                    .map(|(name, value)| format!("{}: {}\r\n", name.to_lowercase(), value))
+                    .collect();
+                assert_eq!(encoder.decode(lowercase.as_bytes()).unwrap(), data);
+            }
+            assert!(encoder
+                .encode(&vec![0; encoder.max_data_len() + 1])
+                .is_err());
+
+            // 相同的数据每次编码的值都不同
+            let (a, b) = (
+                encoder.encode(b"hi").unwrap(),
+                encoder.encode(b"hi").unwrap(),
+            );
+            assert_ne!(a, b);
+
+            // 没有密钥无法找到数据
+            let other = HeaderEncoder::new(encoder.fields().to_vec(), [8u8; 32]);
+            assert_ne!(other.decode(&a).ok(), Some(b"hi".to_vec()));
+            assert!(encoder.decode(b"GET / HTTP/1.1\r\n").is_err());
+        }
+    }
+}
@@ -17,10 +17,11 @@
  */
 
 use fake::{Fake, Faker};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::stego::{Encoder, Random};
+use crate::stego::{Capacity, Encoder, Random};
 use crate::Result;
 
 #[derive(Debug, Clone)]
@@ -33,12 +34,21 @@ pub struct HoudiniEncoder {
 }
 
 impl Random for HoudiniEncoder {
-    fn random() -> Self {
+    fn random_with_rng<R: Rng + ?Sized>(rng: &mut R) -> Self {
         Self {
-            worklet_name: format!("paint-{}", Faker.fake::<String>().to_lowercase()),
-            class_name: format!("container-{}", Faker.fake::<String>().to_lowercase()),
-            property_name: format!("--param-{}", Faker.fake::<String>().to_lowercase()),
-            painter_class_name: format!("Painter{}", Faker.fake::<String>()),
+            worklet_name: format!(
+                "paint-{}",
+                Faker.fake_with_rng::<String, _>(rng).to_lowercase()
+            ),
+            class_name: format!(
+                "container-{}",
+                Faker.fake_with_rng::<String, _>(rng).to_lowercase()
+            ),
+            property_name: format!(
+                "--param-{}",
+                Faker.fake_with_rng::<String, _>(rng).to_lowercase()
+            ),
+            painter_class_name: format!("Painter{}", Faker.fake_with_rng::<String, _>(rng)),
         }
     }
 }
@@ -76,6 +86,10 @@ impl Encoder for HoudiniEncoder {
     fn get_mime_type(&self) -> &'static str {
         "application/json"
     }
+
+    fn capacity(&self) -> Capacity {
+        Capacity::new(1110, 189.0)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -15,9 +15,9 @@ Key features:
 
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use fake::{faker::*, Fake};
-use rand::seq::SliceRandom;
+use rand::{seq::SliceRandom, Rng};
 
-use crate::stego::{Encoder, Random};
+use crate::stego::{Capacity, Encoder, Random};
 use crate::Result;
 
 #[derive(Debug, Clone)]
@@ -29,15 +29,18 @@ pub struct HtmlEncoder {
 }
 
 impl Random for HtmlEncoder {
-    fn random() -> Self {
+    fn random_with_rng<R: Rng + ?Sized>(rng: &mut R) -> Self {
         Self {
             page_title: format!(
                 "{} - {}",
-                company::en::CompanyName().fake::<String>(),
-                company::en::Industry().fake::<String>()
+                company::en::CompanyName().fake_with_rng::<String, _>(rng),
+                company::en::Industry().fake_with_rng::<String, _>(rng)
             ),
-            heading: format!("Welcome to {}", company::en::CompanyName().fake::<String>()),
-            content: lorem::en::Paragraph(2..4).fake::<String>(),
+            heading: format!(
+                "Welcome to {}",
+                company::en::CompanyName().fake_with_rng::<String, _>(rng)
+            ),
+            content: lorem::en::Paragraph(2..4).fake_with_rng::<String, _>(rng),
         }
     }
 }
@@ -68,6 +71,10 @@ impl Encoder for HtmlEncoder {
     fn get_mime_type(&self) -> &'static str {
         "text/html"
     }
+
+    fn capacity(&self) -> Capacity {
+        Capacity::new(450, 1.4)
+    }
 }
 
 const HTML_TEMPLATES: &[&str] = &[
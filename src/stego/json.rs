@@ -20,24 +20,22 @@ Use cases:
 
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use chrono::Utc;
+use rand::Rng;
 use serde_json::{json, Value};
 use tracing::{debug, info, warn};
 
-use crate::stego::{Encoder, Random};
+use crate::stego::{Capacity, Encoder, Random};
 use crate::Result;
 
-#[derive(Debug, Clone)]
-
-#[derive(Default)]
+#[derive(Debug, Clone, Default)]
 pub struct JsonEncoder {}
 
 impl Random for JsonEncoder {
-    fn random() -> Self {
+    fn random_with_rng<R: Rng + ?Sized>(_rng: &mut R) -> Self {
         Self {}
     }
 }
 
-
 impl Encoder for JsonEncoder {
     fn name(&self) -> &'static str {
         "json"
@@ -54,6 +52,10 @@ impl Encoder for JsonEncoder {
     fn get_mime_type(&self) -> &'static str {
         "application/json"
     }
+
+    fn capacity(&self) -> Capacity {
+        Capacity::new(130, 1.35)
+    }
 }
 
 /// Encode data into JSON metadata
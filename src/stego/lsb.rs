@@ -6,10 +6,10 @@
 
 use crate::{RainbowError, Result};
 use image::{DynamicImage, GenericImageView, ImageBuffer, Rgba};
-use rand::seq::SliceRandom;
+use rand::{seq::SliceRandom, Rng};
 use std::{fs, path::PathBuf};
 
-use super::{Encoder, Random};
+use super::{Capacity, Encoder, Random};
 
 fn create_random_image(width: u32, height: u32) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
     let mut img = ImageBuffer::new(width, height);
@@ -52,9 +52,9 @@ impl Default for LSBEncoder {
 
 impl Random for LSBEncoder {
     /// 随机生成一个 LSBEncoder. 使用 空 image_dir 和 随机图片作为 cover_image
-    fn random() -> Self {
+    fn random_with_rng<R: Rng + ?Sized>(rng: &mut R) -> Self {
         Self {
-            lsb_bits: rand::random::<u8>() % 3 + 1, // Use 1-3 LSB bits
+            lsb_bits: rng.gen_range(1..=3), // Use 1-3 LSB bits
             image_dir: None,
             cover_image: None,
         }
@@ -145,6 +145,28 @@ impl Encoder for LSBEncoder {
         "image/png"
     }
 
+    fn capacity(&self) -> Capacity {
+        let bits_per_pixel = self.lsb_bits as usize * 3;
+        match &self.cover_image {
+            // 载体图片大小固定，可携带的数据量取决于像素数，与 encode 中的余量一致
+            Some(cover) => {
+                let pixels = (cover.width() * cover.height()) as usize;
+                let usable_pixels = if self.lsb_bits > 1 {
+                    pixels / 2
+                } else {
+                    pixels.saturating_sub(100)
+                };
+                Capacity::new(0, 0.0)
+                    .with_max_data_len((usable_pixels * bits_per_pixel / 8).saturating_sub(4))
+            }
+            // 随机生成的 RGBA 图片无法压缩，每个像素 4 字节
+            None => {
+                let margin = if self.lsb_bits > 1 { 2.0 } else { 1.0 };
+                Capacity::new(400, margin * 8.0 * 4.0 / bits_per_pixel as f64)
+            }
+        }
+    }
+
     fn encode(&self, data: &[u8]) -> Result<Vec<u8>> {
         // Calculate required size
         let data_len = data.len();
@@ -8,6 +8,7 @@
  * - CSS-based data hiding techniques
  * - Font-based steganography
  * - Grid-based data encoding
+ * - Header field steganography for packets without a body
  * - HTML and web-based hiding methods
  * - JSON structure manipulation
  * - RSS feed steganography
@@ -25,6 +26,7 @@ pub mod cfg;
 pub mod css;
 pub mod font;
 pub mod grid;
+pub mod header;
 pub mod houdini;
 pub mod html;
 pub mod json;
@@ -40,14 +42,24 @@ use std::collections::{HashMap, HashSet};
 use rand::{seq::SliceRandom, Rng};
 use tracing::debug;
 
-use crate::{RainbowError, Result};
+use crate::{chunking::DEFAULT_CHUNK_SIZE, utils::secret_rng, RainbowError, Result};
 use audio::{AudioHtmlEncoder, AudioWavEncoder};
 use lsb::LSBEncoder;
 
 /// A trait for types that can be randomly generated
 pub trait Random {
     /// Create a new random instance of this type
-    fn random() -> Self;
+    fn random() -> Self
+    where
+        Self: Sized,
+    {
+        Self::random_with_rng(&mut rand::thread_rng())
+    }
+
+    /// Create a new random instance of this type, drawing every random choice from `rng`.
+    ///
+    /// Two calls with identically seeded rngs must produce identical instances.
+    fn random_with_rng<R: Rng + ?Sized>(rng: &mut R) -> Self;
 }
 
 /// A trait for types that can encode and decode data.
@@ -70,6 +82,49 @@ pub trait Encoder: std::fmt::Debug + dyn_clone::DynClone + Send + Sync {
     ///
     /// This means the encoder can encode data and present it in this MIME type
     fn get_mime_type(&self) -> &'static str;
+
+    /// How much data fits in an encoded body of a given size, used to choose chunk sizes.
+    ///
+    /// Encoders that do not describe their capacity carry [`DEFAULT_CHUNK_SIZE`] bytes per packet.
+    fn capacity(&self) -> Capacity {
+        Capacity::new(0, 1.0).with_max_data_len(DEFAULT_CHUNK_SIZE)
+    }
+}
+
+/// Size of the bodies produced by an [`Encoder`], as `overhead + expansion_ratio * data length`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capacity {
+    /// Size of a body carrying no data
+    pub overhead: usize,
+    /// Encoded bytes per byte of data, 0 when the body size does not depend on the data
+    pub expansion_ratio: f64,
+    /// Most data a single body can carry, if bounded
+    pub max_data_len: Option<usize>,
+}
+
+impl Capacity {
+    pub fn new(overhead: usize, expansion_ratio: f64) -> Self {
+        Self {
+            overhead,
+            expansion_ratio,
+            max_data_len: None,
+        }
+    }
+
+    pub fn with_max_data_len(mut self, max_data_len: usize) -> Self {
+        self.max_data_len = Some(max_data_len);
+        self
+    }
+
+    /// Most data fitting in a body of `body_len` bytes
+    pub fn data_len_for(&self, body_len: usize) -> usize {
+        let len = if self.expansion_ratio > 0.0 {
+            (body_len.saturating_sub(self.overhead) as f64 / self.expansion_ratio) as usize
+        } else {
+            usize::MAX
+        };
+        self.max_data_len.map_or(len, |max| len.min(max))
+    }
 }
 
 dyn_clone::clone_trait_object!(Encoder);
@@ -123,20 +178,62 @@ impl Default for EncoderRegistry {
 impl EncoderRegistry {
     /// Create a new registry of encoders with all encoders initialized to random values
     pub fn new_randomized() -> Self {
+        Self::new_randomized_with_rng(&mut rand::thread_rng())
+    }
+
+    /// Create a new registry whose randomized encoders are all derived from a shared secret.
+    ///
+    /// Both peers of a conversation calling this with the same secret get byte-identical
+    /// encoders (same octet keys, same CFG grammar, ...), so they can decode each other's packets.
+    pub fn from_secret(secret: &[u8]) -> Self {
+        Self::new_randomized_with_rng(&mut secret_rng(secret, "encoder-registry"))
+    }
+
+    /// Create a new registry of encoders with all encoders initialized from `rng`
+    ///
+    /// Encoders are always drawn in the same order, so the result only depends on the rng state.
+    pub fn new_randomized_with_rng<R: Rng + ?Sized>(rng: &mut R) -> Self {
         let mut encoders: HashMap<String, Box<dyn Encoder>> = HashMap::new();
-        encoders.insert("html".to_string(), Box::new(html::HtmlEncoder::random()));
-        encoders.insert("cfg".to_string(), Box::new(cfg::CFGEncoder::random()));
-        encoders.insert("json".to_string(), Box::new(json::JsonEncoder::random()));
-        encoders.insert("prism".to_string(), Box::new(prism::PrismEncoder::random()));
-        encoders.insert("font".to_string(), Box::new(font::FontEncoder::random()));
-        encoders.insert("css".to_string(), Box::new(css::CssEncoder::random()));
+        encoders.insert(
+            "html".to_string(),
+            Box::new(html::HtmlEncoder::random_with_rng(rng)),
+        );
+        encoders.insert(
+            "cfg".to_string(),
+            Box::new(cfg::CFGEncoder::random_with_rng(rng)),
+        );
+        encoders.insert(
+            "json".to_string(),
+            Box::new(json::JsonEncoder::random_with_rng(rng)),
+        );
+        encoders.insert(
+            "prism".to_string(),
+            Box::new(prism::PrismEncoder::random_with_rng(rng)),
+        );
+        encoders.insert(
+            "font".to_string(),
+            Box::new(font::FontEncoder::random_with_rng(rng)),
+        );
+        encoders.insert(
+            "css".to_string(),
+            Box::new(css::CssEncoder::random_with_rng(rng)),
+        );
         encoders.insert(
             "houdini".to_string(),
-            Box::new(houdini::HoudiniEncoder::random()),
+            Box::new(houdini::HoudiniEncoder::random_with_rng(rng)),
+        );
+        encoders.insert(
+            "grid".to_string(),
+            Box::new(grid::GridEncoder::random_with_rng(rng)),
+        );
+        encoders.insert(
+            "xml".to_string(),
+            Box::new(xml::XmlEncoder::random_with_rng(rng)),
+        );
+        encoders.insert(
+            "rss".to_string(),
+            Box::new(rss::RssEncoder::random_with_rng(rng)),
         );
-        encoders.insert("grid".to_string(), Box::new(grid::GridEncoder::random()));
-        encoders.insert("xml".to_string(), Box::new(xml::XmlEncoder::random()));
-        encoders.insert("rss".to_string(), Box::new(rss::RssEncoder::random()));
         encoders.insert(
             "audio_html".to_string(),
             Box::new(AudioHtmlEncoder::default()),
@@ -145,12 +242,18 @@ impl EncoderRegistry {
             "audio_wav".to_string(),
             Box::new(AudioWavEncoder::default()),
         );
-        encoders.insert("lsb".to_string(), Box::new(LSBEncoder::random()));
+        encoders.insert(
+            "lsb".to_string(),
+            Box::new(LSBEncoder::random_with_rng(rng)),
+        );
         encoders.insert(
             "svg_path".to_string(),
-            Box::new(svg_path::SvgPathEncoder::random()),
+            Box::new(svg_path::SvgPathEncoder::random_with_rng(rng)),
+        );
+        encoders.insert(
+            "octet".to_string(),
+            Box::new(octet::OctetEncoder::random_with_rng(rng)),
         );
-        encoders.insert("octet".to_string(), Box::new(octet::OctetEncoder::random()));
         Self { encoders }
     }
 
@@ -215,8 +318,17 @@ impl EncoderRegistry {
             .decode(data)
     }
 
-    /// Encode data based on MIME type, will use a random encoder from the matching encoders
-    pub fn encode_mime(&self, data: &[u8], mime_type: &str) -> Result<Vec<u8>> {
+    /// All the encoders producing `mime_type`
+    pub fn encoders_for(&self, mime_type: &str) -> Vec<&dyn Encoder> {
+        self.encoders
+            .values()
+            .filter(|encoder| encoder.get_mime_type() == mime_type)
+            .map(|encoder| encoder.as_ref())
+            .collect()
+    }
+
+    /// Choose a random encoder among the ones producing `mime_type`
+    pub fn choose_encoder(&self, mime_type: &str) -> Result<&dyn Encoder> {
         // Get all encoders that match the MIME type
         let matching_encoders: Vec<_> = self
             .encoders
@@ -240,7 +352,12 @@ impl EncoderRegistry {
             mime_type, name
         );
 
-        encoder.encode(data)
+        Ok(encoder.as_ref())
+    }
+
+    /// Encode data based on MIME type, will use a random encoder from the matching encoders
+    pub fn encode_mime(&self, data: &[u8], mime_type: &str) -> Result<Vec<u8>> {
+        self.choose_encoder(mime_type)?.encode(data)
     }
 
     /// Decode data based on MIME type, will try to use every matching encoder until one succeeds
@@ -327,6 +444,27 @@ mod tests {
         assert!(encoders.get_all_mime_types().contains(&mime_type.as_str()));
     }
 
+    #[test]
+    fn test_from_secret_is_deterministic() {
+        init();
+        let test_data = b"Hello, shared secret!";
+        let client = EncoderRegistry::from_secret(b"correct horse battery staple");
+        let server = EncoderRegistry::from_secret(b"correct horse battery staple");
+
+        for (name, encoder) in client.encoders.iter() {
+            let peer = server.get(name).unwrap();
+            assert_eq!(format!("{:?}", encoder), format!("{:?}", peer));
+
+            let encoded = encoder.encode(test_data).unwrap();
+            let decoded = peer.decode(&encoded).unwrap();
+            assert_eq!(decoded, test_data, "encoder {} failed across peers", name);
+        }
+
+        let other = EncoderRegistry::from_secret(b"another secret");
+        let encoded = client.encode_with(test_data, "octet").unwrap();
+        assert!(other.decode_with(&encoded, "octet").is_err());
+    }
+
     #[test]
     fn test_unsupported_mime_type() {
         let test_data = b"Hello, Unsupported MIME Type!";
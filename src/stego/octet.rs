@@ -9,27 +9,22 @@ use aes_gcm::{
     Aes256Gcm, Nonce,
 };
 use chacha20poly1305::{ChaCha20Poly1305, Key};
-use rand::RngCore;
+use rand::{Rng, RngCore};
 use tracing::debug;
 
-use super::{Encoder, Random};
+use super::{Capacity, Encoder, Random};
 use crate::{RainbowError, Result};
 
 /// Encryption method supported by OctetEncoder
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub enum EncryptionMethod {
     /// AES-256-GCM
     Aes,
     /// ChaCha20-Poly1305
+    #[default]
     ChaCha,
 }
 
-impl Default for EncryptionMethod {
-    fn default() -> Self {
-        Self::ChaCha
-    }
-}
-
 /// OctetEncoder implements steganography for application/octet-stream MIME type
 /// It encrypts data using either AES-GCM or ChaCha20-Poly1305
 #[derive(Debug, Clone)]
@@ -51,11 +46,11 @@ impl Default for OctetEncoder {
 }
 
 impl Random for OctetEncoder {
-    fn random() -> Self {
+    fn random_with_rng<R: Rng + ?Sized>(rng: &mut R) -> Self {
         let mut key = [0u8; 32];
-        rand::thread_rng().fill_bytes(&mut key);
+        rng.fill_bytes(&mut key);
         Self {
-            method: if rand::random() {
+            method: if rng.gen() {
                 EncryptionMethod::Aes
             } else {
                 EncryptionMethod::ChaCha
@@ -104,6 +99,49 @@ impl OctetEncoder {
     }
 }
 
+/// Length of the nonce prepended by [`seal`]
+pub const SEAL_NONCE_LEN: usize = 12;
+
+/// Length of the authentication tag appended by [`seal`]
+pub const SEAL_TAG_LEN: usize = 16;
+
+/// Encrypt and authenticate data with ChaCha20-Poly1305 under `key`.
+///
+/// Unlike [`OctetEncoder::encode`], the output carries no method or length marker:
+/// it is just `nonce || ciphertext || tag`, which looks uniformly random.
+pub fn seal(key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; SEAL_NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let encrypted = cipher
+        .encrypt(chacha20poly1305::Nonce::from_slice(&nonce), data)
+        .map_err(|e| RainbowError::Other(format!("ChaCha encryption failed: {}", e)))?;
+
+    let mut output = Vec::with_capacity(SEAL_NONCE_LEN + encrypted.len());
+    output.extend_from_slice(&nonce);
+    output.extend_from_slice(&encrypted);
+    Ok(output)
+}
+
+/// Verify and decrypt data produced by [`seal`].
+///
+/// Fails with [`RainbowError::AuthenticationFailed`] if the data was not sealed under `key`
+/// or has been tampered with.
+pub fn open(key: &[u8; 32], sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < SEAL_NONCE_LEN + SEAL_TAG_LEN {
+        return Err(RainbowError::InvalidData(
+            "Sealed data too short".to_string(),
+        ));
+    }
+    let (nonce, encrypted) = sealed.split_at(SEAL_NONCE_LEN);
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    cipher
+        .decrypt(chacha20poly1305::Nonce::from_slice(nonce), encrypted)
+        .map_err(|e| RainbowError::AuthenticationFailed(e.to_string()))
+}
+
 impl Encoder for OctetEncoder {
     /// octet
     fn name(&self) -> &'static str {
@@ -115,6 +153,10 @@ impl Encoder for OctetEncoder {
         "application/octet-stream"
     }
 
+    fn capacity(&self) -> Capacity {
+        Capacity::new(34, 1.0)
+    }
+
     /// Encode data using the specified encryption method
     fn encode(&self, data: &[u8]) -> Result<Vec<u8>> {
         let mut rng = rand::thread_rng();
@@ -237,8 +279,8 @@ mod tests {
 
     fn get_test_key() -> [u8; 32] {
         let mut key = [0u8; 32];
-        for i in 0..32 {
-            key[i] = i as u8;
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = i as u8;
         }
         key
     }
@@ -352,6 +394,29 @@ mod tests {
         assert!(encoder2.decode(&encoded).is_err());
     }
 
+    #[test]
+    fn test_seal_open() {
+        let key = get_test_key();
+        let sealed = seal(&key, b"sealed data").unwrap();
+        assert_eq!(sealed.len(), SEAL_NONCE_LEN + 11 + SEAL_TAG_LEN);
+        assert_eq!(open(&key, &sealed).unwrap(), b"sealed data");
+
+        let mut tampered = sealed.clone();
+        tampered[SEAL_NONCE_LEN] ^= 1;
+        assert!(matches!(
+            open(&key, &tampered),
+            Err(RainbowError::AuthenticationFailed(_))
+        ));
+
+        let mut wrong_key = key;
+        wrong_key[0] ^= 1;
+        assert!(matches!(
+            open(&wrong_key, &sealed),
+            Err(RainbowError::AuthenticationFailed(_))
+        ));
+        assert!(open(&key, &sealed[..10]).is_err());
+    }
+
     #[test]
     fn test_invalid_data() {
         let encoder = OctetEncoder::default();
@@ -25,7 +25,7 @@ use fake::{faker::*, Fake};
 use rand::Rng;
 use tracing::{debug, info};
 
-use crate::stego::{Encoder, Random};
+use crate::stego::{Capacity, Encoder, Random};
 use crate::Result;
 
 const MIN_LAYERS: usize = 20;
@@ -95,7 +95,7 @@ pub fn decode(data: &[u8]) -> Result<Vec<u8>> {
             let mut in_tag = false;
             let mut found_char = None;
 
-            for (i, c) in line.chars().enumerate() {
+            for (i, c) in line.char_indices() {
                 match c {
                     '<' => {
                         in_tag = true;
@@ -146,12 +146,12 @@ pub struct PrismEncoder {
 }
 
 impl Random for PrismEncoder {
-    fn random() -> Self {
+    fn random_with_rng<R: Rng + ?Sized>(rng: &mut R) -> Self {
         Self {
             page_title: format!(
                 "{} - {}",
-                company::en::CompanyName().fake::<String>(),
-                company::en::Industry().fake::<String>()
+                company::en::CompanyName().fake_with_rng::<String, _>(rng),
+                company::en::Industry().fake_with_rng::<String, _>(rng)
             ),
         }
     }
@@ -181,6 +181,10 @@ impl Encoder for PrismEncoder {
     fn get_mime_type(&self) -> &'static str {
         "text/html"
     }
+
+    fn capacity(&self) -> Capacity {
+        Capacity::new(14_500, 4_230.0)
+    }
 }
 
 #[cfg(test)]
@@ -16,8 +16,9 @@
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use chrono::prelude::*;
 use fake::{faker::*, Fake};
+use rand::Rng;
 
-use crate::stego::{Encoder, Random};
+use crate::stego::{Capacity, Encoder, Random};
 use crate::{RainbowError, Result};
 
 #[derive(Debug, Clone)]
@@ -31,9 +32,9 @@ pub struct RssEncoder {
 }
 
 impl Random for RssEncoder {
-    fn random() -> Self {
-        let company = company::en::CompanyName().fake::<String>();
-        let domain = internet::en::DomainSuffix().fake::<String>();
+    fn random_with_rng<R: Rng + ?Sized>(rng: &mut R) -> Self {
+        let company = company::en::CompanyName().fake_with_rng::<String, _>(rng);
+        let domain = internet::en::DomainSuffix().fake_with_rng::<String, _>(rng);
         Self {
             feed_title: format!("{} News Feed", company),
             feed_link: format!(
@@ -44,10 +45,10 @@ impl Random for RssEncoder {
             feed_description: format!("Latest updates from {}", company),
             item_title: format!(
                 "{} {}",
-                company::en::Industry().fake::<String>(),
-                lorem::en::Word().fake::<String>()
+                company::en::Industry().fake_with_rng::<String, _>(rng),
+                lorem::en::Word().fake_with_rng::<String, _>(rng)
             ),
-            item_description: lorem::en::Paragraph(1..3).fake::<String>(),
+            item_description: lorem::en::Paragraph(1..3).fake_with_rng::<String, _>(rng),
         }
     }
 }
@@ -87,6 +88,10 @@ impl Encoder for RssEncoder {
     fn get_mime_type(&self) -> &'static str {
         "application/xml"
     }
+
+    fn capacity(&self) -> Capacity {
+        Capacity::new(790, 1.35)
+    }
 }
 
 const RSS_TEMPLATE: &str = r#"<?xml version="1.0" encoding="UTF-8" ?>
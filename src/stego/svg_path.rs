@@ -23,7 +23,7 @@ use crate::Result;
 use rand::Rng;
 use tracing::{debug, info, trace, warn};
 
-use crate::stego::{Encoder, Random};
+use crate::stego::{Capacity, Encoder, Random};
 
 #[derive(Debug, Clone)]
 
@@ -32,8 +32,7 @@ pub struct SvgPathEncoder {
 }
 
 impl Random for SvgPathEncoder {
-    fn random() -> Self {
-        let mut rng = rand::thread_rng();
+    fn random_with_rng<R: Rng + ?Sized>(rng: &mut R) -> Self {
         let width = rng.gen_range(400..1200);
         let height = (width as f32 * rng.gen_range(0.5..1.5)) as u32;
         Self {
@@ -66,6 +65,10 @@ impl Encoder for SvgPathEncoder {
     fn get_mime_type(&self) -> &'static str {
         "image/svg+xml"
     }
+
+    fn capacity(&self) -> Capacity {
+        Capacity::new(500, 282.0)
+    }
 }
 
 /// Convert bytes to SVG path animation
@@ -23,7 +23,7 @@ use fake::{faker::*, Fake};
 use rand::{seq::SliceRandom, Rng};
 use tracing::{debug, info};
 
-use crate::stego::{Encoder, Random};
+use crate::stego::{Capacity, Encoder, Random};
 use crate::{RainbowError, Result};
 
 const VISIBLE_VALUES: &[&str] = &["default", "enabled", "true", "active", "1"];
@@ -35,12 +35,12 @@ pub struct XmlEncoder {
 }
 
 impl Random for XmlEncoder {
-    fn random() -> Self {
+    fn random_with_rng<R: Rng + ?Sized>(rng: &mut R) -> Self {
         Self {
             root_tag: format!(
                 "{}-config",
                 company::en::CompanyName()
-                    .fake::<String>()
+                    .fake_with_rng::<String, _>(rng)
                     .to_lowercase()
                     .replace(" ", "-")
             ),
@@ -72,6 +72,10 @@ impl Encoder for XmlEncoder {
     fn get_mime_type(&self) -> &'static str {
         "application/xml"
     }
+
+    fn capacity(&self) -> Capacity {
+        Capacity::new(345, 1.35)
+    }
 }
 
 /// Encode data into XML
@@ -0,0 +1,499 @@
+/*!
+ * Stream module adapts a [`NetworkSteganographyProcessor`] to [`std::io::Read`] and
+ * [`std::io::Write`].
+ *
+ * A [`StegoStream`] wraps any byte transport (typically a `TcpStream`). Written data is encoded
+ * into HTTP packets and framed on the wire, received packets are decoded and reassembled, so a
+ * Rainbow channel can be used like a socket.
+ *
+ * HTTP only lets the server speak when the client asks, so the stream handles the alternation:
+ * - the client sends one request per packet and reads the response to each one; when it has
+ *   nothing to send but wants to read, it polls the server with empty requests; each request
+ *   waits for its delay in [`EncodeResult::send_delays`] first
+ * - the server answers every request with one response of the length the request asks for,
+ *   carrying queued data when there is some, and cover data otherwise
+ */
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    thread,
+    time::Duration,
+};
+
+use tracing::debug;
+
+use crate::{
+    session::DecodeSession, utils::http_message_len, EncodeOptions, EncodeResult,
+    NetworkSteganographyProcessor, ReplyResult,
+};
+
+/// Default delay between two polls of a client waiting for data
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Written data is flushed automatically once this many bytes are buffered
+const MAX_WRITE_BUFFER: usize = 64 * 1024;
+
+/// A blocking [`Read`] + [`Write`] adapter carrying data in steganographic HTTP packets.
+///
+/// The stream encodes with [`NetworkSteganographyProcessor::for_connection`] of the processor it
+/// is given, so one processor can be cloned for any number of streams.
+pub struct StegoStream<T: Read + Write> {
+    inner: T,
+    processor: Box<dyn NetworkSteganographyProcessor>,
+    is_client: bool,
+    options: EncodeOptions,
+    poll_interval: Duration,
+    session: DecodeSession,
+    /// Raw bytes received from the transport that do not form a whole packet yet
+    recv_buf: Vec<u8>,
+    /// Decoded data not yet read by the user
+    read_buf: VecDeque<u8>,
+    /// Data written by the user, not yet encoded
+    write_buf: Vec<u8>,
+    /// Encoded packets waiting to be sent, with the delay to wait before sending each one,
+    /// only used by the client
+    outgoing: VecDeque<(Vec<u8>, Duration)>,
+    eof: bool,
+}
+
+impl<T: Read + Write> StegoStream<T> {
+    /// Wrap a transport connected to a Rainbow server, we send requests
+    pub fn client(inner: T, processor: Box<dyn NetworkSteganographyProcessor>) -> Self {
+        Self::new(inner, processor, true)
+    }
+
+    /// Wrap a transport connected to a Rainbow client, we send responses
+    pub fn server(inner: T, processor: Box<dyn NetworkSteganographyProcessor>) -> Self {
+        Self::new(inner, processor, false)
+    }
+
+    fn new(inner: T, processor: Box<dyn NetworkSteganographyProcessor>, is_client: bool) -> Self {
+        // 每个连接有自己的序号和连接状态，客户端接收响应，服务器接收请求
+        let processor = processor.for_connection();
+        let session = DecodeSession::new(processor.clone(), !is_client);
+        Self {
+            inner,
+            processor,
+            is_client,
+            options: EncodeOptions::default(),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            session,
+            recv_buf: Vec::new(),
+            read_buf: VecDeque::new(),
+            write_buf: Vec::new(),
+            outgoing: VecDeque::new(),
+            eof: false,
+        }
+    }
+
+    /// Options used to encode written data, e.g. to force a MIME type or set the stream id
+    pub fn with_options(mut self, options: EncodeOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Delay between two polls of a client waiting for data
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn is_client(&self) -> bool {
+        self.is_client
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Number of decoded bytes that can be read without blocking
+    pub fn available(&self) -> usize {
+        self.read_buf.len()
+    }
+
+    /// Whether the peer has closed the transport
+    pub fn is_eof(&self) -> bool {
+        self.eof
+    }
+
+    /// Perform one exchange without user data.
+    ///
+    /// A client sends a packet (queued data, or an empty poll) and reads the response, a server
+    /// waits for one request and answers it. Received data becomes [`available`](Self::available).
+    pub fn poll(&mut self) -> io::Result<()> {
+        if self.is_client {
+            self.encode_pending()?;
+            if self.outgoing.is_empty() {
+                self.queue_message(&[])?;
+            }
+            self.client_round_trip()
+        } else {
+            self.serve_request()
+        }
+    }
+
+    /// Encode buffered written data into outgoing packets
+    fn encode_pending(&mut self) -> io::Result<()> {
+        if self.write_buf.is_empty() {
+            return Ok(());
+        }
+        let data = std::mem::take(&mut self.write_buf);
+        self.queue_message(&data)
+    }
+
+    fn queue_message(&mut self, data: &[u8]) -> io::Result<()> {
+        let EncodeResult {
+            encoded_packets,
+            send_delays,
+            ..
+        } = self
+            .processor
+            .encode_write(data, self.is_client, self.options.clone())?;
+        debug!(
+            "Queued {} bytes in {} packets",
+            data.len(),
+            encoded_packets.len()
+        );
+        self.outgoing
+            .extend(encoded_packets.into_iter().zip(send_delays));
+        Ok(())
+    }
+
+    /// Send the next outgoing request and process the response
+    fn client_round_trip(&mut self) -> io::Result<()> {
+        if let Some((packet, delay)) = self.outgoing.pop_front() {
+            if !delay.is_zero() {
+                debug!("Waiting {:?} before sending the next packet", delay);
+                thread::sleep(delay);
+            }
+            self.inner.write_all(&packet)?;
+            self.inner.flush()?;
+
+            let response = self
+                .read_packet()?
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+            self.receive(response)?;
+        }
+        Ok(())
+    }
+
+    /// Wait for a request, process it and answer with a response of the expected length
+    fn serve_request(&mut self) -> io::Result<()> {
+        let Some(request) = self.read_packet()? else {
+            return Ok(());
+        };
+        let (target_length, reply_mime_type) = self.receive(request)?;
+
+        let ReplyResult {
+            packet, consumed, ..
+        } = self.processor.encode_reply(
+            &self.write_buf,
+            self.is_client,
+            target_length,
+            EncodeOptions {
+                preferred_mime_type: reply_mime_type,
+                ..self.options.clone()
+            },
+        )?;
+        self.write_buf.drain(..consumed);
+        debug!("Answered with {} bytes of data", consumed);
+
+        self.inner.write_all(&packet)?;
+        self.inner.flush()?;
+        Ok(())
+    }
+
+    /// Decode a packet, returns the length and the preferred MIME type of the packet the peer
+    /// expects in return
+    fn receive(&mut self, packet: Vec<u8>) -> io::Result<(usize, Option<String>)> {
+        let result =
+            self.processor
+                .decrypt_single_read(packet, self.session.received(), !self.is_client)?;
+        let expected_return_length = result.expected_return_length;
+        let reply_mime_type = result.reply_mime_type.clone();
+        if let Some(message) = self.session.accept(result)? {
+            self.read_buf.extend(message);
+        }
+        Ok((expected_return_length, reply_mime_type))
+    }
+
+    /// Read one whole HTTP packet from the transport, `None` on a clean end of stream
+    fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(len) = http_message_len(&self.recv_buf)? {
+                return Ok(Some(self.recv_buf.drain(..len).collect()));
+            }
+
+            let mut buf = [0u8; 8192];
+            let n = self.inner.read(&mut buf)?;
+            if n == 0 {
+                self.eof = true;
+                return if self.recv_buf.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "stream closed in the middle of a packet",
+                    ))
+                };
+            }
+            self.recv_buf.extend_from_slice(&buf[..n]);
+        }
+    }
+}
+
+impl<T: Read + Write> Read for StegoStream<T> {
+    /// Block until some data has been decoded, or the peer closes the transport
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read_buf.is_empty() && !self.eof {
+            let had_outgoing = !self.write_buf.is_empty() || !self.outgoing.is_empty();
+            self.poll()?;
+
+            // 没有数据可发送也没有收到数据时，等待一段时间再轮询
+            if self.is_client && self.read_buf.is_empty() && !had_outgoing {
+                thread::sleep(self.poll_interval);
+            }
+        }
+
+        let n = buf.len().min(self.read_buf.len());
+        for (dst, src) in buf.iter_mut().zip(self.read_buf.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl<T: Read + Write> Write for StegoStream<T> {
+    /// Buffer data, it is sent on [`flush`](Write::flush)
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_buf.extend_from_slice(buf);
+        if self.write_buf.len() >= MAX_WRITE_BUFFER {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Send every buffered byte.
+    ///
+    /// A server can only send in responses, so it serves incoming requests until everything
+    /// has been sent; data received meanwhile is kept for [`read`](Read::read).
+    fn flush(&mut self) -> io::Result<()> {
+        if self.is_client {
+            self.encode_pending()?;
+            while !self.outgoing.is_empty() {
+                self.client_round_trip()?;
+            }
+        } else {
+            while !self.write_buf.is_empty() {
+                if self.eof {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+                self.serve_request()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        time::Instant,
+    };
+
+    use super::*;
+    use crate::{
+        chunking::ChunkingStrategy,
+        h2::HttpVersion,
+        rainbow::Rainbow,
+        timing::{DelayDistribution, TimingModel},
+    };
+
+    fn init() {
+        let _ = tracing_subscriber::fmt()
+            .with_test_writer()
+            .with_max_level(tracing::Level::DEBUG)
+            .try_init();
+    }
+
+    fn octet_options() -> EncodeOptions {
+        EncodeOptions {
+            encoder: Some("octet".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_echo_over_tcp() {
+        init();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let mut stream = StegoStream::server(socket, Box::new(Rainbow::from_secret(b"echo")))
+                .with_options(octet_options());
+
+            let mut received = vec![0u8; 1000];
+            stream.read_exact(&mut received).unwrap();
+            stream.write_all(&received).unwrap();
+            stream.write_all(b" and more").unwrap();
+            stream.flush().unwrap();
+        });
+
+        let socket = TcpStream::connect(addr).unwrap();
+        let mut stream = StegoStream::client(socket, Box::new(Rainbow::from_secret(b"echo")))
+            .with_options(octet_options())
+            .with_poll_interval(Duration::from_millis(5));
+
+        let message: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        stream.write_all(&message).unwrap();
+        stream.flush().unwrap();
+
+        let mut echoed = vec![0u8; 1009];
+        stream.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed[..1000], &message[..]);
+        assert_eq!(&echoed[1000..], b" and more");
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_echo_over_h2c() {
+        init();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // 每一端的 Rainbow 对应各自的 HTTP/2 连接
+        let h2c = || Box::new(Rainbow::from_secret(b"h2c").with_http_version(HttpVersion::H2c));
+
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let mut stream = StegoStream::server(socket, h2c()).with_options(octet_options());
+
+            let mut received = vec![0u8; 5000];
+            stream.read_exact(&mut received).unwrap();
+            stream.write_all(&received).unwrap();
+            stream.flush().unwrap();
+        });
+
+        let socket = TcpStream::connect(addr).unwrap();
+        let mut stream = StegoStream::client(socket, h2c())
+            .with_options(octet_options())
+            .with_poll_interval(Duration::from_millis(5));
+
+        let message: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        stream.write_all(&message).unwrap();
+        stream.flush().unwrap();
+
+        let mut echoed = vec![0u8; 5000];
+        stream.read_exact(&mut echoed).unwrap();
+        assert_eq!(echoed, message);
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_echo_over_websocket() {
+        init();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // 文本帧和二进制帧随机交替
+        let websocket = || Box::new(Rainbow::from_secret(b"websocket").with_websocket());
+
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let mut stream = StegoStream::server(socket, websocket());
+
+            let mut received = vec![0u8; 5000];
+            stream.read_exact(&mut received).unwrap();
+            stream.write_all(&received).unwrap();
+            stream.flush().unwrap();
+        });
+
+        let socket = TcpStream::connect(addr).unwrap();
+        let mut stream =
+            StegoStream::client(socket, websocket()).with_poll_interval(Duration::from_millis(5));
+
+        let message: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        stream.write_all(&message).unwrap();
+        stream.flush().unwrap();
+
+        let mut echoed = vec![0u8; 5000];
+        stream.read_exact(&mut echoed).unwrap();
+        assert_eq!(echoed, message);
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_send_delays() {
+        init();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let mut stream = StegoStream::server(socket, Box::new(Rainbow::from_secret(b"timing")));
+            let mut received = vec![0u8; 300];
+            stream.read_exact(&mut received).unwrap();
+            received
+        });
+
+        let socket = TcpStream::connect(addr).unwrap();
+        let mut stream = StegoStream::client(socket, Box::new(Rainbow::from_secret(b"timing")))
+            .with_options(EncodeOptions {
+                chunking: ChunkingStrategy::Fixed(100),
+                timing: TimingModel::Sampled {
+                    think: DelayDistribution::uniform(
+                        Duration::from_millis(100)..Duration::from_millis(110),
+                    ),
+                    burst: DelayDistribution::uniform(
+                        Duration::from_millis(50)..Duration::from_millis(60),
+                    ),
+                },
+                ..octet_options()
+            });
+
+        // 三个数据包：一次思考时间加两次突发间隔
+        let message = [7u8; 300];
+        let start = Instant::now();
+        stream.write_all(&message).unwrap();
+        stream.flush().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        assert_eq!(server.join().unwrap(), message);
+    }
+
+    #[test]
+    fn test_eof() {
+        init();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let socket = TcpStream::connect(addr).unwrap();
+            let mut stream = StegoStream::client(socket, Box::new(Rainbow::from_secret(b"eof")));
+            stream.write_all(b"bye").unwrap();
+            stream.flush().unwrap();
+        });
+
+        let (socket, _) = listener.accept().unwrap();
+        let mut stream = StegoStream::server(socket, Box::new(Rainbow::from_secret(b"eof")));
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"bye");
+        assert!(stream.is_eof());
+
+        client.join().unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+/*!
+ * Timing module decides when each packet of a message is sent.
+ *
+ * Packets sent back to back, as fast as the transport takes them, look nothing like a person
+ * browsing. A [`TimingModel`] attaches to every packet of an [`EncodeResult`](crate::EncodeResult)
+ * a delay to wait before sending it, and the stream adapters wait that long.
+ *
+ * [`TimingModel::browsing`] follows a browsing session: the first packet of a message waits
+ * like a user reading a page before following a link, and the following packets like the
+ * resources that page loads, in quick succession.
+ */
+
+use std::{ops::Range, time::Duration};
+
+use rand::Rng;
+
+/// When the packets of a message are sent
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum TimingModel {
+    /// Every packet is sent right away
+    #[default]
+    Immediate,
+    /// Delays drawn from distributions
+    Sampled {
+        /// Delay before the first packet of a message
+        think: DelayDistribution,
+        /// Delay between two packets of a message
+        burst: DelayDistribution,
+    },
+}
+
+impl TimingModel {
+    /// A user reading pages for a few seconds, and pages loading a handful of resources
+    pub fn browsing() -> Self {
+        Self::Sampled {
+            think: DelayDistribution::new()
+                .with_bucket(Duration::from_millis(200)..Duration::from_secs(2), 5)
+                .with_bucket(Duration::from_secs(2)..Duration::from_secs(10), 3)
+                .with_bucket(Duration::from_secs(10)..Duration::from_secs(30), 1),
+            burst: DelayDistribution::new()
+                .with_bucket(Duration::ZERO..Duration::from_millis(30), 6)
+                .with_bucket(Duration::from_millis(30)..Duration::from_millis(300), 3)
+                .with_bucket(Duration::from_millis(300)..Duration::from_secs(1), 1),
+        }
+    }
+
+    /// Delays before each of the `packets` packets of a message
+    pub fn delays<R: Rng + ?Sized>(&self, packets: usize, rng: &mut R) -> Vec<Duration> {
+        match self {
+            Self::Immediate => vec![Duration::ZERO; packets],
+            Self::Sampled { think, burst } => (0..packets)
+                .map(|i| if i == 0 { think } else { burst }.sample(rng))
+                .collect(),
+        }
+    }
+}
+
+/// A weighted set of ranges of delays
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DelayDistribution {
+    buckets: Vec<(Range<Duration>, u32)>,
+}
+
+impl DelayDistribution {
+    /// An empty distribution, add buckets with [`with_bucket`](Self::with_bucket)
+    pub fn new() -> Self {
+        Self {
+            buckets: Vec::new(),
+        }
+    }
+
+    /// Delays drawn uniformly from `range`
+    pub fn uniform(range: Range<Duration>) -> Self {
+        Self::new().with_bucket(range, 1)
+    }
+
+    /// Draw delays from `range` with the given relative weight
+    pub fn with_bucket(mut self, range: Range<Duration>, weight: u32) -> Self {
+        if !range.is_empty() && weight > 0 {
+            self.buckets.push((range, weight));
+        }
+        self
+    }
+
+    /// Draw a delay, zero if the distribution is empty
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Duration {
+        let total: u32 = self.buckets.iter().map(|(_, weight)| weight).sum();
+        if total == 0 {
+            return Duration::ZERO;
+        }
+
+        let mut pick = rng.gen_range(0..total);
+        for (range, weight) in &self.buckets {
+            if pick < *weight {
+                return rng.gen_range(range.clone());
+            }
+            pick -= weight;
+        }
+        unreachable!("pick is below the total weight")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delays() {
+        let mut rng = rand::thread_rng();
+        assert_eq!(
+            TimingModel::Immediate.delays(3, &mut rng),
+            vec![Duration::ZERO; 3]
+        );
+
+        let model = TimingModel::Sampled {
+            think: DelayDistribution::uniform(Duration::from_secs(1)..Duration::from_secs(2)),
+            burst: DelayDistribution::uniform(Duration::ZERO..Duration::from_millis(10)),
+        };
+        for _ in 0..50 {
+            let delays = model.delays(4, &mut rng);
+            assert_eq!(delays.len(), 4);
+            // 消息的第一个数据包等待较久，其余数据包紧随其后
+            assert!(delays[0] >= Duration::from_secs(1) && delays[0] < Duration::from_secs(2));
+            assert!(delays[1..].iter().all(|d| *d < Duration::from_millis(10)));
+        }
+        assert!(model.delays(0, &mut rng).is_empty());
+
+        assert_eq!(DelayDistribution::new().sample(&mut rng), Duration::ZERO);
+        let TimingModel::Sampled { think, burst } = TimingModel::browsing() else {
+            panic!("browsing samples its delays");
+        };
+        assert!(think.sample(&mut rng) >= Duration::from_millis(200));
+        assert!(burst.sample(&mut rng) < Duration::from_secs(1));
+    }
+}
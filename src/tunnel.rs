@@ -0,0 +1,549 @@
+/*!
+ * Tunnel module carries TCP connections inside Rainbow HTTP traffic.
+ *
+ * - [`TunnelClient`] accepts SOCKS5 CONNECT requests locally and opens one Rainbow connection
+ *   to the tunnel server for each of them
+ * - [`TunnelServer`] accepts Rainbow connections, dials the requested target and relays bytes
+ *   between the target and the Rainbow stream
+ *
+ * The first message on a Rainbow connection is the target address as `host:port\n`, the server
+ * answers with a single status byte before relaying. Closing either end closes the whole tunnel
+ * connection, half-closed connections are not supported.
+ */
+
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs},
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+    time::Duration,
+};
+
+use tracing::{debug, info, warn};
+
+use crate::{stream::StegoStream, EncodeOptions, NetworkSteganographyProcessor};
+
+const SOCKS_VERSION: u8 = 0x05;
+const SOCKS_NO_AUTH: u8 = 0x00;
+const SOCKS_NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const SOCKS_CMD_CONNECT: u8 = 0x01;
+const SOCKS_ATYP_IPV4: u8 = 0x01;
+const SOCKS_ATYP_DOMAIN: u8 = 0x03;
+const SOCKS_ATYP_IPV6: u8 = 0x04;
+
+const SOCKS_REP_SUCCEEDED: u8 = 0x00;
+const SOCKS_REP_GENERAL_FAILURE: u8 = 0x01;
+const SOCKS_REP_HOST_UNREACHABLE: u8 = 0x04;
+const SOCKS_REP_CONNECTION_REFUSED: u8 = 0x05;
+const SOCKS_REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const SOCKS_REP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+/// Default delay between two polls of an idle tunnel connection
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Size of the chunks read from plain TCP sockets
+const RELAY_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Accepts SOCKS5 connections and forwards them to a [`TunnelServer`]
+#[derive(Clone)]
+pub struct TunnelClient {
+    server: String,
+    processor: Box<dyn NetworkSteganographyProcessor>,
+    options: EncodeOptions,
+    poll_interval: Duration,
+}
+
+impl TunnelClient {
+    /// `server` is the address of the tunnel server, as `host:port`
+    pub fn new(
+        server: impl Into<String>,
+        processor: Box<dyn NetworkSteganographyProcessor>,
+    ) -> Self {
+        Self {
+            server: server.into(),
+            processor,
+            options: EncodeOptions::default(),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+        }
+    }
+
+    /// Options used to encode the data sent to the server
+    pub fn with_options(mut self, options: EncodeOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Delay between two polls of an idle connection
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Bind `listen` and serve SOCKS5 clients forever
+    pub fn run(&self, listen: impl ToSocketAddrs) -> io::Result<()> {
+        let listener = TcpListener::bind(listen)?;
+        info!(
+            "SOCKS5 tunnel client listening on {}",
+            listener.local_addr()?
+        );
+        self.serve(listener)
+    }
+
+    /// Serve SOCKS5 clients accepted on `listener`, one thread per connection
+    pub fn serve(&self, listener: TcpListener) -> io::Result<()> {
+        for local in listener.incoming() {
+            let local = local?;
+            let client = self.clone();
+            thread::spawn(move || {
+                let peer = local.peer_addr().ok();
+                if let Err(e) = client.handle(local) {
+                    warn!("Tunnel connection from {:?} failed: {}", peer, e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Handle one SOCKS5 connection until either end closes it
+    pub fn handle(&self, mut local: TcpStream) -> io::Result<()> {
+        let Some(target) = socks_handshake(&mut local)? else {
+            return Ok(());
+        };
+        debug!("SOCKS5 CONNECT to {}", target);
+
+        let transport = match TcpStream::connect(&self.server) {
+            Ok(transport) => transport,
+            Err(e) => {
+                socks_reply(&mut local, SOCKS_REP_GENERAL_FAILURE)?;
+                return Err(e);
+            }
+        };
+        transport.set_nodelay(true)?;
+        let mut stream = StegoStream::client(transport, self.processor.clone())
+            .with_options(self.options.clone())
+            .with_poll_interval(self.poll_interval);
+
+        // 先告诉服务器要连接的目标，再等待连接结果
+        stream.write_all(format!("{}\n", target).as_bytes())?;
+        stream.flush()?;
+        let mut status = [0u8; 1];
+        stream.read_exact(&mut status)?;
+        socks_reply(&mut local, status[0])?;
+        if status[0] != SOCKS_REP_SUCCEEDED {
+            return Ok(());
+        }
+
+        let result = relay_client(&mut stream, &local, self.poll_interval);
+        let _ = local.shutdown(Shutdown::Both);
+        result
+    }
+}
+
+/// Accepts Rainbow connections from [`TunnelClient`]s and dials their targets
+#[derive(Clone)]
+pub struct TunnelServer {
+    processor: Box<dyn NetworkSteganographyProcessor>,
+    options: EncodeOptions,
+    connect_timeout: Duration,
+}
+
+impl TunnelServer {
+    pub fn new(processor: Box<dyn NetworkSteganographyProcessor>) -> Self {
+        Self {
+            processor,
+            options: EncodeOptions::default(),
+            connect_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Options used to encode the data sent to the client
+    pub fn with_options(mut self, options: EncodeOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// How long to wait for a target to accept the connection
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Bind `listen` and serve tunnel clients forever
+    pub fn run(&self, listen: impl ToSocketAddrs) -> io::Result<()> {
+        let listener = TcpListener::bind(listen)?;
+        info!("Tunnel server listening on {}", listener.local_addr()?);
+        self.serve(listener)
+    }
+
+    /// Serve tunnel clients accepted on `listener`, one thread per connection
+    pub fn serve(&self, listener: TcpListener) -> io::Result<()> {
+        for transport in listener.incoming() {
+            let transport = transport?;
+            let server = self.clone();
+            thread::spawn(move || {
+                let peer = transport.peer_addr().ok();
+                if let Err(e) = server.handle(transport) {
+                    warn!("Tunnel connection from {:?} failed: {}", peer, e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Handle one tunnel connection until either end closes it
+    pub fn handle(&self, transport: TcpStream) -> io::Result<()> {
+        transport.set_nodelay(true)?;
+        let mut stream = StegoStream::server(transport, self.processor.clone())
+            .with_options(self.options.clone());
+
+        let Some(target) = read_target(&mut stream)? else {
+            return Ok(());
+        };
+        debug!("Tunnel connecting to {}", target);
+
+        let remote = match self.connect(&target) {
+            Ok(remote) => remote,
+            Err(e) => {
+                stream.write_all(&[connect_error_reply(&e)])?;
+                stream.flush()?;
+                return Err(e);
+            }
+        };
+        stream.write_all(&[SOCKS_REP_SUCCEEDED])?;
+
+        let result = relay_server(&mut stream, &remote);
+        let _ = remote.shutdown(Shutdown::Both);
+        result
+    }
+
+    fn connect(&self, target: &str) -> io::Result<TcpStream> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "target did not resolve");
+        for addr in target.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.connect_timeout) {
+                Ok(remote) => return Ok(remote),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// Negotiate a SOCKS5 CONNECT, returns the requested target as `host:port`.
+///
+/// Returns `None` after answering the local client with an error when the request cannot be
+/// served.
+fn socks_handshake(local: &mut TcpStream) -> io::Result<Option<String>> {
+    let mut header = [0u8; 2];
+    local.read_exact(&mut header)?;
+    if header[0] != SOCKS_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unsupported SOCKS version {}", header[0]),
+        ));
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    local.read_exact(&mut methods)?;
+    if !methods.contains(&SOCKS_NO_AUTH) {
+        local.write_all(&[SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHOD])?;
+        return Ok(None);
+    }
+    local.write_all(&[SOCKS_VERSION, SOCKS_NO_AUTH])?;
+
+    // VER CMD RSV ATYP
+    let mut request = [0u8; 4];
+    local.read_exact(&mut request)?;
+    let host = match request[3] {
+        SOCKS_ATYP_IPV4 => {
+            let mut addr = [0u8; 4];
+            local.read_exact(&mut addr)?;
+            std::net::Ipv4Addr::from(addr).to_string()
+        }
+        SOCKS_ATYP_IPV6 => {
+            let mut addr = [0u8; 16];
+            local.read_exact(&mut addr)?;
+            format!("[{}]", std::net::Ipv6Addr::from(addr))
+        }
+        SOCKS_ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            local.read_exact(&mut len)?;
+            let mut domain = vec![0u8; len[0] as usize];
+            local.read_exact(&mut domain)?;
+            String::from_utf8(domain).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        }
+        _ => {
+            socks_reply(local, SOCKS_REP_ADDRESS_TYPE_NOT_SUPPORTED)?;
+            return Ok(None);
+        }
+    };
+    let mut port = [0u8; 2];
+    local.read_exact(&mut port)?;
+
+    if request[1] != SOCKS_CMD_CONNECT {
+        socks_reply(local, SOCKS_REP_COMMAND_NOT_SUPPORTED)?;
+        return Ok(None);
+    }
+    Ok(Some(format!("{}:{}", host, u16::from_be_bytes(port))))
+}
+
+/// Answer a SOCKS5 request, the bound address is not meaningful through the tunnel
+fn socks_reply(local: &mut TcpStream, reply: u8) -> io::Result<()> {
+    local.write_all(&[
+        SOCKS_VERSION,
+        reply,
+        0x00,
+        SOCKS_ATYP_IPV4,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+    ])?;
+    local.flush()
+}
+
+fn connect_error_reply(error: &io::Error) -> u8 {
+    match error.kind() {
+        io::ErrorKind::ConnectionRefused => SOCKS_REP_CONNECTION_REFUSED,
+        io::ErrorKind::NotFound | io::ErrorKind::TimedOut => SOCKS_REP_HOST_UNREACHABLE,
+        _ => SOCKS_REP_GENERAL_FAILURE,
+    }
+}
+
+/// Read the `host:port\n` line sent by the client, `None` if it closed the connection first
+fn read_target<T: Read + Write>(stream: &mut StegoStream<T>) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        if byte[0] == b'\n' {
+            break;
+        }
+        line.push(byte[0]);
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Read a plain socket in a thread, every chunk is sent on the returned channel.
+///
+/// The channel disconnects when the socket reaches end of stream.
+fn spawn_reader(socket: &TcpStream) -> io::Result<Receiver<Vec<u8>>> {
+    let mut socket = socket.try_clone()?;
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = vec![0u8; RELAY_BUFFER_SIZE];
+        loop {
+            match socket.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if tx.send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    Ok(rx)
+}
+
+/// Move the data received on `rx` into `stream`.
+///
+/// Returns whether the socket is still open and whether any data was written.
+fn drain_reader<T: Read + Write>(
+    rx: &Receiver<Vec<u8>>,
+    stream: &mut StegoStream<T>,
+) -> io::Result<(bool, bool)> {
+    let mut wrote = false;
+    loop {
+        match rx.try_recv() {
+            Ok(data) => {
+                stream.write_all(&data)?;
+                wrote = true;
+            }
+            Err(TryRecvError::Empty) => return Ok((true, wrote)),
+            Err(TryRecvError::Disconnected) => return Ok((false, wrote)),
+        }
+    }
+}
+
+/// Write every decoded byte of `stream` to `socket`, returns whether there was any
+fn drain_stream<T: Read + Write>(
+    stream: &mut StegoStream<T>,
+    mut socket: &TcpStream,
+) -> io::Result<bool> {
+    let mut buf = vec![0u8; RELAY_BUFFER_SIZE];
+    let mut received = false;
+    while stream.available() > 0 {
+        let n = stream.read(&mut buf)?;
+        socket.write_all(&buf[..n])?;
+        received = true;
+    }
+    Ok(received)
+}
+
+fn relay_client<T: Read + Write>(
+    stream: &mut StegoStream<T>,
+    local: &TcpStream,
+    poll_interval: Duration,
+) -> io::Result<()> {
+    let rx = spawn_reader(local)?;
+    loop {
+        let (open, wrote) = drain_reader(&rx, stream)?;
+        let exchanged = if wrote { stream.flush() } else { stream.poll() };
+        match exchanged {
+            Ok(()) => {}
+            // 服务器关闭连接说明目标已经断开
+            Err(_) if stream.is_eof() => {
+                drain_stream(stream, local)?;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+        let received = drain_stream(stream, local)?;
+
+        if !open || stream.is_eof() {
+            return Ok(());
+        }
+        if !wrote && !received {
+            thread::sleep(poll_interval);
+        }
+    }
+}
+
+fn relay_server<T: Read + Write>(
+    stream: &mut StegoStream<T>,
+    remote: &TcpStream,
+) -> io::Result<()> {
+    let rx = spawn_reader(remote)?;
+    loop {
+        let (open, _) = drain_reader(&rx, stream)?;
+        if !open {
+            // 目标断开：把剩余数据发完后关闭连接
+            return stream.flush();
+        }
+
+        // 响应只能跟随请求发送，等待客户端的下一个请求
+        stream.poll()?;
+        drain_stream(stream, remote)?;
+        if stream.is_eof() {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::rainbow::Rainbow;
+
+    fn init() {
+        let _ = tracing_subscriber::fmt()
+            .with_test_writer()
+            .with_max_level(tracing::Level::DEBUG)
+            .try_init();
+    }
+
+    fn octet_options() -> EncodeOptions {
+        EncodeOptions {
+            encoder: Some("octet".to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Start an echo target, a tunnel server and a tunnel client, returns the SOCKS5 address
+    /// and the echo address
+    fn start_tunnel() -> (SocketAddr, SocketAddr) {
+        let echo = TcpListener::bind("127.0.0.1:0").unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        thread::spawn(move || {
+            for socket in echo.incoming() {
+                let mut socket = socket.unwrap();
+                thread::spawn(move || {
+                    let mut reader = socket.try_clone().unwrap();
+                    io::copy(&mut reader, &mut socket).ok();
+                });
+            }
+        });
+
+        let server_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = server_listener.local_addr().unwrap();
+        let server = TunnelServer::new(Box::new(Rainbow::from_secret(b"tunnel")))
+            .with_options(octet_options());
+        thread::spawn(move || server.serve(server_listener));
+
+        let client_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socks_addr = client_listener.local_addr().unwrap();
+        let client = TunnelClient::new(
+            server_addr.to_string(),
+            Box::new(Rainbow::from_secret(b"tunnel")),
+        )
+        .with_options(octet_options())
+        .with_poll_interval(Duration::from_millis(5));
+        thread::spawn(move || client.serve(client_listener));
+
+        (socks_addr, echo_addr)
+    }
+
+    /// Open a SOCKS5 connection to `target`, returns the reply code
+    fn socks_connect(socks_addr: SocketAddr, target: SocketAddr) -> (TcpStream, u8) {
+        let mut socket = TcpStream::connect(socks_addr).unwrap();
+        socket
+            .write_all(&[SOCKS_VERSION, 1, SOCKS_NO_AUTH])
+            .unwrap();
+        let mut method = [0u8; 2];
+        socket.read_exact(&mut method).unwrap();
+        assert_eq!(method, [SOCKS_VERSION, SOCKS_NO_AUTH]);
+
+        let SocketAddr::V4(target) = target else {
+            panic!("IPv4 target expected");
+        };
+        let mut request = vec![SOCKS_VERSION, SOCKS_CMD_CONNECT, 0, SOCKS_ATYP_IPV4];
+        request.extend_from_slice(&target.ip().octets());
+        request.extend_from_slice(&target.port().to_be_bytes());
+        socket.write_all(&request).unwrap();
+
+        let mut reply = [0u8; 10];
+        socket.read_exact(&mut reply).unwrap();
+        (socket, reply[1])
+    }
+
+    #[test]
+    fn test_socks_echo() {
+        init();
+        let (socks_addr, echo_addr) = start_tunnel();
+
+        let (mut socket, reply) = socks_connect(socks_addr, echo_addr);
+        assert_eq!(reply, SOCKS_REP_SUCCEEDED);
+
+        let message: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        socket.write_all(&message).unwrap();
+        let mut echoed = vec![0u8; message.len()];
+        socket.read_exact(&mut echoed).unwrap();
+        assert_eq!(echoed, message);
+
+        socket.write_all(b"second round").unwrap();
+        let mut echoed = [0u8; 12];
+        socket.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"second round");
+    }
+
+    #[test]
+    fn test_connection_refused() {
+        init();
+        let (socks_addr, _) = start_tunnel();
+
+        // 绑定后立即释放端口，连接会被拒绝
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+
+        let (_, reply) = socks_connect(socks_addr, closed_addr);
+        assert_eq!(reply, SOCKS_REP_CONNECTION_REFUSED);
+    }
+}
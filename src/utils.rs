@@ -11,7 +11,9 @@ common programming tasks throughout the application.
 */
 
 use http::header::{HeaderMap, HeaderName, HeaderValue};
-use rand::{distributions::Alphanumeric, Rng};
+use rand::{distributions::Alphanumeric, Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
 
 use crate::RainbowError;
 
@@ -47,11 +49,11 @@ pub fn find_matching_brace(text: &str, left_bracket_index: usize) -> Option<usiz
 }
 
 pub fn mime_to_extension(mime_type: &str) -> &str {
-    let sub_str = mime_type.split('/').last().unwrap_or_default();
+    let sub_str = mime_type.split('/').next_back().unwrap_or_default();
 
     match sub_str {
         "plain" => "txt",
-        "octet-stream" => return "bin",
+        "octet-stream" => "bin",
         "svg+xml" => "svg",
         _ => sub_str,
     }
@@ -87,10 +89,21 @@ fn test_find_matching_brace() {
     assert_eq!(find_matching_brace(text, 17), Some(23));
 }
 
+#[test]
+fn test_http_message_len() {
+    let request = b"GET / HTTP/1.1\r\nHost: a\r\n\r\nGET";
+    assert_eq!(http_message_len(request).unwrap(), Some(27));
+    assert_eq!(http_message_len(&request[..20]).unwrap(), None);
+
+    let response = b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nhello world";
+    assert_eq!(http_message_len(response).unwrap(), Some(43));
+    assert_eq!(http_message_len(&response[..42]).unwrap(), None);
+
+    assert!(http_message_len(b"HTTP/1.1 200 OK\r\nContent-Length: x\r\n\r\n").is_err());
+}
+
 pub struct HttpConstants {
     pub cookie_names: &'static [&'static str],
-    pub post_paths: &'static [&'static str],
-    pub get_paths: &'static [&'static str],
     pub error_details: &'static [(&'static str, &'static str)], // (状态码, 出现概率)
     pub status_codes: &'static [(u16, f32)],                    // (状态码, 出现概率)
 }
@@ -105,23 +118,6 @@ pub const HTTP_CONSTANTS: HttpConstants = HttpConstants {
         "JSESSIONID",
         "cf_id",
     ],
-    post_paths: &[
-        "/api/v1/data",
-        "/api/v1/upload",
-        "/api/v2/submit",
-        "/upload",
-        "/submit",
-        "/process",
-    ],
-    get_paths: &[
-        "/",
-        "/index.html",
-        "/assets/main.css",
-        "/js/app.js",
-        "/images/logo.png",
-        "/blog/latest",
-        "/docs/guide",
-    ],
     error_details: &[
         ("MIME_TYPE_MISSING", "Missing Content-Type header"),
         ("CONTENT_MISSING", "Missing content body"),
@@ -130,11 +126,15 @@ pub const HTTP_CONSTANTS: HttpConstants = HttpConstants {
         ("UNSUPPORTED_MIME_TYPE", "Unsupported MIME type"),
     ],
     status_codes: &[
-        (200, 0.9), // 90% 概率
-        (201, 0.025),
-        (202, 0.025),
-        (204, 0.025),
-        (206, 0.025),
+        (200, 0.86), // 86% 概率
+        (201, 0.02),
+        (202, 0.02),
+        (204, 0.02),
+        (206, 0.02),
+        (302, 0.02),
+        (304, 0.02),
+        (404, 0.015),
+        (500, 0.005),
     ],
 };
 
@@ -147,70 +147,21 @@ pub fn random_string(length: usize) -> String {
         .collect()
 }
 
-/// Generate realistic HTTP headers
-pub fn generate_realistic_headers(is_request: bool) -> HeaderMap {
-    let mut headers = HeaderMap::new();
-
-    if is_request {
-        headers.insert(
-            "User-Agent",
-            HeaderValue::from_static(
-                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36",
-            ),
-        );
-        headers.insert(
-            "Accept-Language",
-            HeaderValue::from_static("en-US,en;q=0.9"),
-        );
-        headers.insert(
-            "Accept-Encoding",
-            HeaderValue::from_static("gzip, deflate, br"),
-        );
-
-        // 随机添加一些可选头部
-        if rand::random::<bool>() {
-            headers.insert("DNT", HeaderValue::from_static("1"));
-        }
-        if rand::random::<bool>() {
-            headers.insert("Cache-Control", HeaderValue::from_static("max-age=0"));
-        }
-    } else {
-        headers.insert("Server", HeaderValue::from_static("nginx/1.18.0"));
-        headers.insert("X-Frame-Options", HeaderValue::from_static("SAMEORIGIN"));
-        headers.insert(
-            "X-Content-Type-Options",
-            HeaderValue::from_static("nosniff"),
-        );
-
-        // 随机添加一些安全相关的头部
-        if rand::random::<bool>() {
-            headers.insert(
-                "Strict-Transport-Security",
-                HeaderValue::from_static("max-age=31536000; includeSubDomains"),
-            );
-        }
-        if rand::random::<bool>() {
-            headers.insert(
-                "Content-Security-Policy",
-                HeaderValue::from_static("default-src 'self'"),
-            );
-        }
-    }
-
-    headers
-}
-
-/// Generate random API path
-pub fn generate_random_post_path() -> String {
-    let api_paths = HTTP_CONSTANTS.post_paths;
-    api_paths[rand::thread_rng().gen_range(0..api_paths.len())].to_string()
+/// Derive a 32 byte key from a shared secret, separated by `context`
+///
+/// Different contexts yield independent keys, so one secret can seed several components.
+pub fn derive_key(secret: &[u8], context: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"rainbow:");
+    hasher.update(context.as_bytes());
+    hasher.update([0]);
+    hasher.update(secret);
+    hasher.finalize().into()
 }
 
-/// Generate random static resource path
-pub fn generate_random_get_path() -> String {
-    let static_paths = HTTP_CONSTANTS.get_paths;
-
-    static_paths[rand::thread_rng().gen_range(0..static_paths.len())].to_string()
+/// Create a deterministic rng stream from a shared secret, separated by `context`
+pub fn secret_rng(secret: &[u8], context: &str) -> ChaCha20Rng {
+    ChaCha20Rng::from_seed(derive_key(secret, context))
 }
 
 /// Check HTTP packet validity
@@ -239,6 +190,147 @@ pub fn validate_http_packet(packet: &[u8]) -> crate::Result<()> {
     Err(RainbowError::InvalidData("Invalid HTTP format".to_string()))
 }
 
+/// Get the value of a header from the header part of an HTTP packet, case-insensitively
+pub fn find_header_value<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header.lines().skip(1).find_map(|line| {
+        line.split_once(':')
+            .filter(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.trim())
+    })
+}
+
+/// Get the length of the first complete HTTP message in `data`, whose body is delimited by
+/// `Content-Length` or by `Transfer-Encoding: chunked`, or of the first HTTP/2 message, see
+/// [`h2::message_len`](crate::h2::message_len), or of the first WebSocket frame, following
+/// the handshake if there is one
+///
+/// Returns `None` if `data` does not hold a complete message yet.
+pub fn http_message_len(data: &[u8]) -> crate::Result<Option<usize>> {
+    if crate::h2::is_h2(data) {
+        return crate::h2::message_len(data);
+    }
+    if crate::websocket::is_frame(data) {
+        return crate::websocket::frame_len(data);
+    }
+    let Some(split_pos) = find_crlf_crlf(data) else {
+        return Ok(None);
+    };
+    let header = String::from_utf8_lossy(&data[..split_pos]);
+    let body_start = split_pos + 4;
+
+    // WebSocket 握手之后紧跟第一个帧
+    if crate::websocket::is_handshake(&header) {
+        return Ok(crate::websocket::frame_len(&data[body_start..])?.map(|len| body_start + len));
+    }
+
+    if is_chunked(&header) {
+        return Ok(decode_chunked(&data[body_start..])?.map(|(_, len)| body_start + len));
+    }
+
+    let content_length = match find_header_value(&header, "Content-Length") {
+        Some(value) => value
+            .parse::<usize>()
+            .map_err(|_| RainbowError::InvalidData(format!("Invalid Content-Length: {}", value)))?,
+        None => 0,
+    };
+
+    let total = body_start + content_length;
+    Ok((data.len() >= total).then_some(total))
+}
+
+/// How the body of a generated HTTP packet is delimited
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransferEncoding {
+    /// A `Content-Length` header
+    #[default]
+    Identity,
+    /// `Transfer-Encoding: chunked`, in chunks of a size real servers commonly flush
+    Chunked,
+}
+
+/// Sizes of the chunks of chunked bodies, the buffer sizes common servers and proxies flush
+const TRANSFER_CHUNK_SIZES: [usize; 5] = [1024, 2048, 4096, 8192, 16384];
+
+/// A chunk size of [`TransferEncoding::Chunked`] bodies
+pub fn random_transfer_chunk_size() -> usize {
+    TRANSFER_CHUNK_SIZES[rand::thread_rng().gen_range(0..TRANSFER_CHUNK_SIZES.len())]
+}
+
+/// Whether the header part of an HTTP packet declares a chunked body
+pub fn is_chunked(header: &str) -> bool {
+    find_header_value(header, "Transfer-Encoding")
+        .is_some_and(|value| value.to_ascii_lowercase().contains("chunked"))
+}
+
+/// Frame `body` in chunks of `chunk_size` bytes, the last one shorter
+pub fn encode_chunked(body: &[u8], chunk_size: usize) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(chunked_len(body.len(), chunk_size));
+    for chunk in body.chunks(chunk_size.max(1)) {
+        framed.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+        framed.extend_from_slice(chunk);
+        framed.extend_from_slice(b"\r\n");
+    }
+    framed.extend_from_slice(b"0\r\n\r\n");
+    framed
+}
+
+/// Length of a body of `len` bytes framed by [`encode_chunked`]
+pub fn chunked_len(len: usize, chunk_size: usize) -> usize {
+    let chunk_size = chunk_size.max(1);
+    let frame = |size: usize| format!("{:x}", size).len() + 2 + size + 2;
+    let full = len / chunk_size;
+    let rest = len % chunk_size;
+    full * frame(chunk_size) + if rest > 0 { frame(rest) } else { 0 } + 5
+}
+
+/// De-chunk a chunked body at the start of `data`, skipping chunk extensions and trailers
+///
+/// Returns the body and the length of its chunked framing, or `None` if `data` does not hold
+/// the whole body yet.
+pub fn decode_chunked(data: &[u8]) -> crate::Result<Option<(Vec<u8>, usize)>> {
+    let mut body = Vec::new();
+    let mut pos = 0;
+    loop {
+        let Some(line_len) = find_crlf(&data[pos..]) else {
+            return Ok(None);
+        };
+        let line = String::from_utf8_lossy(&data[pos..pos + line_len]);
+        let size_str = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size_str, 16)
+            .map_err(|_| RainbowError::InvalidData(format!("Invalid chunk size: {}", size_str)))?;
+        pos += line_len + 2;
+
+        if size == 0 {
+            break;
+        }
+
+        let end = pos.checked_add(size).ok_or_else(|| {
+            RainbowError::InvalidData(format!("Chunk size too large: {}", size_str))
+        })?;
+        if data.len() < end + 2 {
+            return Ok(None);
+        }
+        if &data[end..end + 2] != b"\r\n" {
+            return Err(RainbowError::InvalidData(
+                "Chunk not followed by CRLF".to_string(),
+            ));
+        }
+        body.extend_from_slice(&data[pos..end]);
+        pos = end + 2;
+    }
+
+    // 跳过尾部字段，以空行结束
+    loop {
+        let Some(line_len) = find_crlf(&data[pos..]) else {
+            return Ok(None);
+        };
+        pos += line_len + 2;
+        if line_len == 0 {
+            return Ok(Some((body, pos)));
+        }
+    }
+}
+
 /// Extract headers and content from HTTP packet
 pub fn extract_http_parts(data: &[u8]) -> Option<(HeaderMap, Vec<u8>)> {
     let data_str = String::from_utf8_lossy(data);
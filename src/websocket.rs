@@ -0,0 +1,414 @@
+/*!
+ * WebSocket module carries packets as the frames of a WebSocket connection (RFC 6455).
+ *
+ * The first packet a client sends is a genuine HTTP `Upgrade: websocket` handshake request
+ * followed by its first frame, and the first packet a server sends is the `101 Switching
+ * Protocols` answer to it followed by its first frame. Every packet after that is a single
+ * frame: a text frame carrying JSON, or a binary frame. Frames sent by clients are masked, and
+ * frames sent by servers are not, as the protocol requires.
+ */
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use rand::{Rng, RngCore};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    identity::SessionIdentity,
+    profile::RequestKind,
+    utils::{find_crlf_crlf, find_header_value},
+    RainbowError, Result,
+};
+
+/// Appended to the `Sec-WebSocket-Key` of a handshake to compute its `Sec-WebSocket-Accept`
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Paths web applications commonly serve their WebSocket endpoint on
+const PATHS: [&str; 6] = [
+    "/ws",
+    "/socket",
+    "/realtime",
+    "/api/ws",
+    "/live/updates",
+    "/socket.io/?EIO=4&transport=websocket",
+];
+
+// 帧操作码
+pub const TEXT: u8 = 0x1;
+pub const BINARY: u8 = 0x2;
+
+const FIN: u8 = 0x80;
+const MASKED: u8 = 0x80;
+const MASK_LEN: usize = 4;
+
+/// Largest payload length encoded in the first length byte, and the markers of the 16 and
+/// 64 bits extended lengths
+const MAX_SHORT_LEN: usize = 125;
+const LEN_16: u8 = 126;
+const LEN_64: u8 = 127;
+
+/// A data frame, unfragmented
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub opcode: u8,
+    /// Whether the frame was masked, as frames sent by clients are
+    pub masked: bool,
+    /// The payload, unmasked
+    pub payload: Vec<u8>,
+}
+
+/// Whether `data` starts with a frame, which HTTP messages never do
+pub fn is_frame(data: &[u8]) -> bool {
+    data.first().is_some_and(|&b| b & FIN != 0)
+}
+
+/// Whether the header part of an HTTP message is a WebSocket handshake
+pub fn is_handshake(header: &str) -> bool {
+    find_header_value(header, "Upgrade")
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
+
+/// Whether `data` is a frame, or a handshake followed by a frame
+pub fn is_websocket(data: &[u8]) -> bool {
+    is_frame(data)
+        || find_crlf_crlf(data)
+            .is_some_and(|pos| is_handshake(&String::from_utf8_lossy(&data[..pos])))
+}
+
+/// Length of the header of a frame carrying `payload_len` bytes
+pub fn frame_header_len(payload_len: usize, masked: bool) -> usize {
+    let len = match payload_len {
+        0..=MAX_SHORT_LEN => 2,
+        126..=0xffff => 4,
+        _ => 10,
+    };
+    if masked {
+        len + MASK_LEN
+    } else {
+        len
+    }
+}
+
+/// The payload length of a frame of exactly `frame_len` bytes, or `None` if no frame has that
+/// length, the header growing by two or eight bytes at once
+pub fn payload_len_for(frame_len: usize, masked: bool) -> Option<usize> {
+    [2, 4, 10].into_iter().find_map(|len| {
+        let payload_len = frame_len.checked_sub(len + if masked { MASK_LEN } else { 0 })?;
+        (frame_header_len(payload_len, masked) == frame_len - payload_len).then_some(payload_len)
+    })
+}
+
+pub fn encode_frame(opcode: u8, payload: &[u8], masked: bool) -> Vec<u8> {
+    let mut out = Vec::with_capacity(frame_header_len(payload.len(), masked) + payload.len());
+    out.push(FIN | opcode);
+    let mask_bit = if masked { MASKED } else { 0 };
+    match payload.len() {
+        len @ 0..=MAX_SHORT_LEN => out.push(mask_bit | len as u8),
+        len @ 126..=0xffff => {
+            out.push(mask_bit | LEN_16);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(mask_bit | LEN_64);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+
+    if masked {
+        let mut mask = [0u8; MASK_LEN];
+        rand::thread_rng().fill_bytes(&mut mask);
+        out.extend_from_slice(&mask);
+        out.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, b)| b ^ mask[i % MASK_LEN]),
+        );
+    } else {
+        out.extend_from_slice(payload);
+    }
+    out
+}
+
+/// The lengths of the header and of the payload of the frame at the start of `data`, or `None`
+/// if its header is incomplete
+fn read_header(data: &[u8]) -> Result<Option<(usize, usize)>> {
+    let [_, second, ..] = data else {
+        return Ok(None);
+    };
+    let (len_bytes, payload_len) = match second & !MASKED {
+        LEN_16 => (2, None),
+        LEN_64 => (8, None),
+        len => (0, Some(len as usize)),
+    };
+    let header_len = 2 + len_bytes + if second & MASKED != 0 { MASK_LEN } else { 0 };
+    if data.len() < header_len {
+        return Ok(None);
+    }
+
+    let payload_len = match payload_len {
+        Some(len) => len,
+        None => {
+            let len = data[2..2 + len_bytes]
+                .iter()
+                .fold(0u64, |acc, &b| acc << 8 | b as u64);
+            usize::try_from(len)
+                .ok()
+                .filter(|len| *len <= isize::MAX as usize)
+                .ok_or_else(|| {
+                    RainbowError::InvalidData(format!("Frame payload too long: {}", len))
+                })?
+        }
+    };
+    Ok(Some((header_len, payload_len)))
+}
+
+/// Length of the frame at the start of `data`, or `None` if it is incomplete
+pub fn frame_len(data: &[u8]) -> Result<Option<usize>> {
+    Ok(read_header(data)?
+        .map(|(header_len, payload_len)| header_len + payload_len)
+        .filter(|len| data.len() >= *len))
+}
+
+/// Decode the frame `data` consists of
+pub fn decode_frame(data: &[u8]) -> Result<Frame> {
+    let (header_len, payload_len) = read_header(data)?
+        .ok_or_else(|| RainbowError::InvalidData("Truncated frame header".to_string()))?;
+    if data.len() != header_len + payload_len {
+        return Err(RainbowError::LengthMismatch(
+            data.len(),
+            header_len + payload_len,
+            "frame".to_string(),
+        ));
+    }
+    if data[0] & FIN == 0 {
+        return Err(RainbowError::InvalidData(
+            "Fragmented frames are not supported".to_string(),
+        ));
+    }
+
+    let masked = data[1] & MASKED != 0;
+    let payload = &data[header_len..];
+    let payload = if masked {
+        let mask = &data[header_len - MASK_LEN..header_len];
+        payload
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ mask[i % MASK_LEN])
+            .collect()
+    } else {
+        payload.to_vec()
+    };
+    Ok(Frame {
+        opcode: data[0] & 0x0f,
+        masked,
+        payload,
+    })
+}
+
+/// The `Sec-WebSocket-Accept` answering a `Sec-WebSocket-Key`
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(ACCEPT_GUID.as_bytes());
+    BASE64.encode(hasher.finalize())
+}
+
+/// A random `Sec-WebSocket-Key`
+fn random_key() -> String {
+    BASE64.encode(rand::random::<[u8; 16]>())
+}
+
+/// The handshake request of a browser, sending the headers of `identity`, opening a WebSocket
+/// with `key`
+pub fn handshake_request(key: &str, identity: &SessionIdentity) -> String {
+    let path = PATHS[rand::thread_rng().gen_range(0..PATHS.len())];
+    let fields = format!(
+        "Connection: Upgrade\r\nUpgrade: websocket\r\nOrigin: http://{}\r\n\
+         Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: {}\r\n",
+        identity.host().unwrap_or("localhost"),
+        key
+    );
+    format!(
+        "GET {} HTTP/1.1\r\n{}\r\n",
+        path,
+        identity.headers(&fields, Some(RequestKind::Websocket))
+    )
+}
+
+/// The answer of a web server, sending the headers of `identity`, accepting the handshake
+/// request with `key`
+pub fn handshake_response(key: &str, identity: &SessionIdentity) -> String {
+    let fields = format!(
+        "Connection: upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Accept: {}\r\n",
+        accept_key(key)
+    );
+    format!(
+        "HTTP/1.1 101 Switching Protocols\r\n{}\r\n",
+        identity.headers(&fields, None)
+    )
+}
+
+/// Split a received packet into its handshake, if any, and its frame
+pub fn split_handshake(data: &[u8]) -> Result<(Option<String>, &[u8])> {
+    if is_frame(data) {
+        return Ok((None, data));
+    }
+    let split_pos = find_crlf_crlf(data)
+        .ok_or_else(|| RainbowError::InvalidData("Truncated WebSocket handshake".to_string()))?;
+    let header = String::from_utf8_lossy(&data[..split_pos]).into_owned();
+    if !is_handshake(&header) {
+        return Err(RainbowError::InvalidData(
+            "Not a WebSocket handshake".to_string(),
+        ));
+    }
+    Ok((Some(header), &data[split_pos + 4..]))
+}
+
+/// The WebSocket connection the packets of a [`Rainbow`](crate::rainbow::Rainbow) are sent on
+#[derive(Debug, Default)]
+pub struct WebSocketConnection {
+    /// Our handshake, built ahead of the packet it starts so that its length is known
+    handshake: Option<String>,
+    /// Whether our handshake has been sent
+    handshake_sent: bool,
+    /// `Sec-WebSocket-Key` of the handshake request, ours or the one received
+    key: Option<String>,
+}
+
+impl WebSocketConnection {
+    /// The handshake, with the headers of `identity`, sent before our next frame, empty once it
+    /// has been sent
+    pub fn preamble(&mut self, is_client: bool, identity: &SessionIdentity) -> Vec<u8> {
+        if self.handshake_sent {
+            return Vec::new();
+        }
+        let key = &mut self.key;
+        self.handshake
+            .get_or_insert_with(|| {
+                if is_client {
+                    handshake_request(key.get_or_insert_with(random_key), identity)
+                } else {
+                    // 没有收到握手请求时，以随机的密钥应答
+                    handshake_response(key.as_deref().unwrap_or(&random_key()), identity)
+                }
+            })
+            .clone()
+            .into_bytes()
+    }
+
+    /// The handshake sent before our next frame, which is then considered sent
+    pub fn start_message(&mut self, is_client: bool, identity: &SessionIdentity) -> Vec<u8> {
+        let preamble = self.preamble(is_client, identity);
+        self.handshake = None;
+        self.handshake_sent = true;
+        preamble
+    }
+
+    /// Take note of a handshake received, `from_client` if it is a request.
+    ///
+    /// Fails with [`RainbowError::AuthenticationFailed`] if a server answers another key
+    /// than the one of our request
+    pub fn receive_handshake(&mut self, header: &str, from_client: bool) -> Result<()> {
+        if from_client {
+            let key = find_header_value(header, "Sec-WebSocket-Key").ok_or_else(|| {
+                RainbowError::InvalidData("Missing Sec-WebSocket-Key".to_string())
+            })?;
+            self.key = Some(key.to_string());
+            return Ok(());
+        }
+
+        let accept = find_header_value(header, "Sec-WebSocket-Accept")
+            .ok_or_else(|| RainbowError::InvalidData("Missing Sec-WebSocket-Accept".to_string()))?;
+        match &self.key {
+            Some(key) if accept != accept_key(key) => Err(RainbowError::AuthenticationFailed(
+                "Sec-WebSocket-Accept does not match our key".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::TrafficProfile;
+
+    #[test]
+    fn test_frame_roundtrip() {
+        for len in [0, 1, 125, 126, 1000, 0xffff, 0x10000] {
+            let payload: Vec<u8> = (0..len).map(|_| rand::random()).collect();
+            for masked in [false, true] {
+                let frame = encode_frame(BINARY, &payload, masked);
+                assert!(is_frame(&frame));
+                assert_eq!(frame.len(), frame_header_len(len, masked) + len);
+                assert_eq!(payload_len_for(frame.len(), masked), Some(len));
+                assert_eq!(frame_len(&frame).unwrap(), Some(frame.len()));
+                assert_eq!(frame_len(&frame[..frame.len() - 1]).unwrap(), None);
+
+                let decoded = decode_frame(&frame).unwrap();
+                assert_eq!(decoded.opcode, BINARY);
+                assert_eq!(decoded.masked, masked);
+                assert_eq!(decoded.payload, payload);
+            }
+        }
+
+        // 头部从 2 字节跳到 4 字节，中间的长度无法构成帧
+        assert_eq!(payload_len_for(131, true), Some(125));
+        assert_eq!(payload_len_for(132, true), None);
+        assert_eq!(payload_len_for(133, true), None);
+        assert_eq!(payload_len_for(134, true), Some(126));
+        assert!(decode_frame(&encode_frame(TEXT, b"abc", false)[..4]).is_err());
+    }
+
+    #[test]
+    fn test_handshake() {
+        // RFC 6455 的示例
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+
+        let mut client = WebSocketConnection::default();
+        let mut server = WebSocketConnection::default();
+        let browser = SessionIdentity::new(TrafficProfile::builtin("firefox").unwrap())
+            .with_host("example.com");
+        let web_server = SessionIdentity::new(TrafficProfile::builtin("apache").unwrap());
+
+        // 握手在发送前保持不变，发送后不再重复
+        let request = client.preamble(true, &browser);
+        assert_eq!(client.start_message(true, &browser), request);
+        assert!(client.start_message(true, &browser).is_empty());
+
+        let (header, frame) = split_handshake(&request).unwrap();
+        let header = header.unwrap();
+        assert!(header.starts_with("GET ") && frame.is_empty());
+        assert!(header.contains("\r\nUser-Agent: Mozilla/5.0 ") && header.contains("Firefox/"));
+        assert_eq!(
+            find_header_value(&header, "Sec-Fetch-Mode"),
+            Some("websocket")
+        );
+        assert_eq!(find_header_value(&header, "Host"), Some("example.com"));
+        assert_eq!(
+            find_header_value(&header, "Origin"),
+            Some("http://example.com")
+        );
+        server.receive_handshake(&header, true).unwrap();
+
+        let response = server.start_message(false, &web_server);
+        let (header, _) = split_handshake(&response).unwrap();
+        let header = header.unwrap();
+        assert!(header.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(header.contains("\r\nServer: Apache"));
+        client.receive_handshake(&header, false).unwrap();
+
+        let forged = header.replace(
+            find_header_value(&header, "Sec-WebSocket-Accept").unwrap(),
+            &accept_key(&random_key()),
+        );
+        assert!(matches!(
+            client.receive_handshake(&forged, false),
+            Err(RainbowError::AuthenticationFailed(_))
+        ));
+        assert!(split_handshake(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").is_err());
+    }
+}